@@ -0,0 +1,47 @@
+use common::*;
+
+// Nodes that supply parameters list the CAN IDs those parameters are mapped
+// from (see parameters.rs), so that the node is alive exactly when its values
+// are fresh. Nodes without mapped parameters, like the inverter, list the IDs
+// they are known to send and are only supervised for the lost DTC.
+//
+// lost_dtc: U-codes for lost communication (SAE J2012, e.g. U0111 = lost
+// communication with battery energy control module)
+
+define_can_nodes! {
+    Bms {
+        name: "BMS",
+        can_ids: &[
+            standard_id(0x100),
+            standard_id(0x101),
+            standard_id(0x102),
+            standard_id(0x104),
+        ],
+        timeout_ms: 5000,
+        lost_dtc: 0xC11100,
+    },
+    Obc {
+        name: "OBC",
+        can_ids: &[standard_id(0x377), standard_id(0x389)],
+        timeout_ms: 5000,
+        lost_dtc: 0xC1A100,
+    },
+    Heater {
+        name: "Heater",
+        can_ids: &[standard_id(0x398)],
+        timeout_ms: 5000,
+        lost_dtc: 0xC16400,
+    },
+    Foccci {
+        name: "Foccci",
+        can_ids: &[standard_id(0x506)],
+        timeout_ms: 5000,
+        lost_dtc: 0xC29800,
+    },
+    Inverter {
+        name: "Inverter",
+        can_ids: &[standard_id(0x1DA), standard_id(0x55A)],
+        timeout_ms: 5000,
+        lost_dtc: 0xC11000,
+    },
+}
@@ -0,0 +1,408 @@
+use crate::parameters::*;
+use common::can_integrity::{ChecksumField, ChecksumKind, CounterField, FrameIntegrity};
+use common::can_scheduler::{CanScheduler, PeriodicMessage};
+use common::*;
+
+use bitvec::prelude::*;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Periodically transmitted CAN messages
+//
+// Offsets are chosen so that messages of the same period don't all go out on
+// the same logic tick.
+
+pub fn register_messages<const N: usize>(scheduler: &mut CanScheduler<N>) {
+    scheduler.register(PeriodicMessage {
+        name: "HV status 0x285",
+        period_ms: 30,
+        offset_ms: 0,
+        enable: Some(|| get_parameter(ParameterId::MainContactor).value > 0.5),
+        encode: encode_outlander_hv_status,
+        integrity: None,
+    });
+    scheduler.register(PeriodicMessage {
+        name: "Heater 0x188",
+        period_ms: 200,
+        offset_ms: 10,
+        enable: None,
+        encode: encode_outlander_heater_control,
+        integrity: None,
+    });
+    scheduler.register(PeriodicMessage {
+        name: "OBC 0x286",
+        period_ms: 200,
+        offset_ms: 50,
+        enable: None,
+        encode: encode_outlander_obc_control,
+        integrity: None,
+    });
+    scheduler.register(PeriodicMessage {
+        name: "PDM 0x200",
+        period_ms: 200,
+        offset_ms: 90,
+        enable: None,
+        encode: encode_pdm_status,
+        integrity: None,
+    });
+    scheduler.register(PeriodicMessage {
+        name: "BMS setting 0x120",
+        period_ms: 500,
+        offset_ms: 170,
+        enable: Some(|| {
+            get_parameter(ParameterId::BmsChargeCompleteVoltageSetting).value as u16
+                != get_parameter(ParameterId::ChargeCompleteVoltage).value as u16
+        }),
+        encode: encode_bms_charge_complete_voltage_setting,
+        integrity: None,
+    });
+    scheduler.register(PeriodicMessage {
+        name: "Inputs 0x204",
+        period_ms: 500,
+        offset_ms: 250,
+        enable: None,
+        encode: encode_inputs_1,
+        integrity: None,
+    });
+    scheduler.register(PeriodicMessage {
+        name: "Inputs 0x205",
+        period_ms: 500,
+        offset_ms: 330,
+        enable: None,
+        encode: encode_inputs_2,
+        integrity: Some(INPUTS_2_INTEGRITY),
+    });
+    scheduler.register(PeriodicMessage {
+        name: "Currents 0x206",
+        period_ms: 500,
+        offset_ms: 410,
+        enable: None,
+        encode: encode_currents,
+        integrity: None,
+    });
+    scheduler.register(PeriodicMessage {
+        name: "Outputs 0x207",
+        period_ms: 500,
+        offset_ms: 490,
+        enable: None,
+        encode: encode_outputs,
+        integrity: None,
+    });
+}
+
+pub fn normal_frame(frame_id: u16, data: &[u8]) -> Option<bxcan::Frame> {
+    if let Some(frame_data) = bxcan::Data::new(data) {
+        Some(bxcan::Frame::new_data(
+            bxcan::StandardId::new(frame_id).unwrap(),
+            frame_data,
+        ))
+    } else {
+        warn!(
+            "-!- normal_frame(): Invalid data for frame {:?}: {:?}",
+            frame_id, data
+        );
+        None
+    }
+}
+
+pub fn extended_frame(frame_id: u32, data: &[u8]) -> Option<bxcan::Frame> {
+    if let Some(frame_data) = bxcan::Data::new(data) {
+        Some(bxcan::Frame::new_data(
+            bxcan::ExtendedId::new(frame_id).unwrap(),
+            frame_data,
+        ))
+    } else {
+        warn!(
+            "-!- extended_frame(): Invalid data for frame {:?}: {:?}",
+            frame_id, data
+        );
+        None
+    }
+}
+
+pub fn setting_frame(
+    frame_id: u16,
+    setting_id: u8,
+    old_value: u16,
+    new_value: u16,
+) -> Option<bxcan::Frame> {
+    let mut data: [u8; 8] = [0; 8];
+    data[0] = setting_id;
+    data[1..3].copy_from_slice(&old_value.to_be_bytes());
+    data[3..5].copy_from_slice(&new_value.to_be_bytes());
+    normal_frame(frame_id, &data)
+}
+
+fn encode_outlander_hv_status(_hw: &mut dyn HardwareInterface) -> Option<bxcan::Frame> {
+    // Outlander HV status message (for heater and OBC)
+    // 10...30ms is fine for this (EV-Omega uses 30ms)
+    let activate_evse = get_parameter(ParameterId::ActivateObc).value > 0.5;
+    normal_frame(
+        0x285,
+        &[
+            0x00,
+            0x00,
+            0x14 | if activate_evse { 0xb6 } else { 0 }, // 0xb6 = Activate EVSE (OBC)
+            0x21,
+            0x90,
+            0xfe,
+            0x0c,
+            0x10,
+        ],
+    )
+}
+
+fn encode_outlander_heater_control(_hw: &mut dyn HardwareInterface) -> Option<bxcan::Frame> {
+    let requested_power_command = if get_parameter(ParameterId::ReqHeaterPowerPercent).value > 70.0
+    {
+        0xa2
+    } else if get_parameter(ParameterId::ReqHeaterPowerPercent).value > 30.0 {
+        0x32
+    } else {
+        0
+    };
+    normal_frame(
+        0x188,
+        &[
+            0x03,
+            0x50,
+            requested_power_command,
+            0x4D,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+        ],
+    )
+}
+
+fn encode_outlander_obc_control(_hw: &mut dyn HardwareInterface) -> Option<bxcan::Frame> {
+    let charge_voltage_setpoint_Vx10: u16 = 3020;
+
+    let user_current_request_ACA: f32 = get_parameter(ParameterId::MaxAcChargeCurrent).value;
+
+    let ac_v = get_parameter(ParameterId::AcVoltage).value;
+    let dc_v = get_parameter(ParameterId::ObcDcv).value;
+    let dc_current_request_Ax10: u8 = if get_parameter(ParameterId::MainContactor).value > 0.5
+        && get_parameter(ParameterId::ActivateEvse).value > 0.5
+    {
+        let ac_request_DCA = ac_v / dc_v * user_current_request_ACA;
+        let obc_limit_DCA = 12.0;
+        // TODO: If the heater is operating, allow that much extra
+        //       charging current so that it's possible to heat the
+        //       battery using AC power
+        let bms_limit_DCA = get_parameter(ParameterId::BmsMaxChargeCurrent).value;
+        (ac_request_DCA
+            .min(obc_limit_DCA)
+            .min(bms_limit_DCA)
+            .max(0.0)
+            * 10.0) as u8
+    } else {
+        0
+    };
+
+    // Outlander OBC control
+    normal_frame(
+        0x286,
+        &[
+            (charge_voltage_setpoint_Vx10 >> 8) as u8,
+            (charge_voltage_setpoint_Vx10 & 0xff) as u8,
+            dc_current_request_Ax10, // DC current, 0.1A / bit
+            0,
+            0,
+            0,
+            0,
+            0,
+        ],
+    )
+}
+
+fn encode_pdm_status(hw: &mut dyn HardwareInterface) -> Option<bxcan::Frame> {
+    // This is an old PDM message, which we have inherited
+    // We use this to:
+    // * Request main contactor from the BMS for charging
+    //   and heating
+    // * Request the inverter to be disabled while charging
+    // * Provide a DC bus voltage reading to Foccci
+    // * Provide an OBC DC current reading to old SIM900 unit
+    // * Send AcObcState and enable parameters to Foccci so that it can
+    //   enable EVSE state C for AC charging
+
+    let ignition_input = hw.get_digital_input(DigitalInput::Ignition);
+
+    let request_main_contactor: bool =
+        get_parameter(ParameterId::ReqWakeupAndContactor).value > 0.5;
+
+    let request_inverter_disable: bool = get_parameter(ParameterId::FoccciPlugPresent).value >= 0.5;
+
+    let dc_link_voltage_Vx10: u16 = (get_parameter(ParameterId::ObcDcv).value * 10.0) as u16;
+
+    let obc_Ax10: u16 = (get_parameter(ParameterId::ObcDcc).value * 10.0) as u16;
+
+    let ac_obc_state = if get_parameter(ParameterId::ActivateObc).value > 0.5 {
+        2
+    } else {
+        0
+    };
+
+    let group1oc = hw.get_digital_input(DigitalInput::Group1OC);
+    let group2oc = hw.get_digital_input(DigitalInput::Group2OC);
+    let group3oc = hw.get_digital_input(DigitalInput::Group3OC);
+    let group4oc = hw.get_digital_input(DigitalInput::Group4OC);
+
+    normal_frame(
+        0x200,
+        &[
+            0x00 | if request_main_contactor { (1 << 0) } else { 0 }
+                | if request_inverter_disable {
+                    (1 << 3)
+                } else {
+                    0
+                }
+                | if ignition_input { (1 << 6) } else { 0 }
+                | (1 << 7), /* Foccci.enable (new) */
+            (dc_link_voltage_Vx10 >> 8) as u8,
+            (dc_link_voltage_Vx10 & 0xff) as u8,
+            (obc_Ax10 >> 8) as u8,
+            (obc_Ax10 & 0xff) as u8,
+            get_parameter(ParameterId::PcbT).value as u8,
+            ac_obc_state, /* Foccci.AcObcState (new) */
+            0x00 | if group1oc { (1 << 0) } else { 0 }
+                | if group2oc { (1 << 1) } else { 0 }
+                | if group3oc { (1 << 2) } else { 0 }
+                | if group4oc { (1 << 3) } else { 0 },
+        ],
+    )
+}
+
+fn encode_bms_charge_complete_voltage_setting(
+    _hw: &mut dyn HardwareInterface,
+) -> Option<bxcan::Frame> {
+    // Send charge completion voltage setting to BMS
+    let old_value: u16 = get_parameter(ParameterId::BmsChargeCompleteVoltageSetting).value as u16;
+    let new_value: u16 = get_parameter(ParameterId::ChargeCompleteVoltage).value as u16;
+    setting_frame(0x120, 0, old_value, new_value)
+}
+
+// Publish generic inputs for external monitoring
+
+fn encode_inputs_1(hw: &mut dyn HardwareInterface) -> Option<bxcan::Frame> {
+    let ignition = hw.get_digital_input(DigitalInput::Ignition);
+    let m7 = hw.get_digital_input(DigitalInput::M7);
+    let m8 = hw.get_digital_input(DigitalInput::M8);
+    let m9 = hw.get_digital_input(DigitalInput::M9);
+    let m10 = hw.get_digital_input(DigitalInput::M10);
+    let m11 = hw.get_digital_input(DigitalInput::M11);
+    let m12 = hw.get_digital_input(DigitalInput::M12);
+    let m13 = hw.get_digital_input(DigitalInput::M13);
+
+    let m1 = (hw.get_analog_input(AnalogInput::M1) * 128.0) as u16;
+    let m2 = (hw.get_analog_input(AnalogInput::M2) * 128.0) as u16;
+    let m3 = (hw.get_analog_input(AnalogInput::M3) * 128.0) as u16;
+    let m4 = (hw.get_analog_input(AnalogInput::M4) * 128.0) as u16;
+
+    let mut data = [0u8; 8];
+    let bits = data.view_bits_mut::<Msb0>();
+    bits[0..8].store_be(
+        if ignition { (1 << 0) } else { 0 }
+            | if m7 { (1 << 1) } else { 0 }
+            | if m8 { (1 << 2) } else { 0 }
+            | if m9 { (1 << 3) } else { 0 }
+            | if m10 { (1 << 4) } else { 0 }
+            | if m11 { (1 << 5) } else { 0 }
+            | if m12 { (1 << 6) } else { 0 }
+            | if m13 { (1 << 7) } else { 0 },
+    );
+    bits[8..16].store_be(0);
+    // 12 bits for each analog value (big endian)
+    bits[16..28].store_be(m1);
+    bits[28..40].store_be(m2);
+    bits[40..52].store_be(m3);
+    bits[52..64].store_be(m4);
+
+    normal_frame(0x204, &data)
+}
+
+// Byte 3 low nibble: Rolling counter, byte 4: CRC-8 SAE J1850 of the other
+// bytes
+const INPUTS_2_INTEGRITY: FrameIntegrity = FrameIntegrity {
+    counter: Some(CounterField {
+        byte: 3,
+        shift: 0,
+        bits: 4,
+    }),
+    checksum: Some(ChecksumField {
+        byte: 4,
+        kind: ChecksumKind::Crc8SaeJ1850,
+        seed: None,
+    }),
+};
+
+fn encode_inputs_2(hw: &mut dyn HardwareInterface) -> Option<bxcan::Frame> {
+    let m5 = (hw.get_analog_input(AnalogInput::M5) * 128.0) as u16;
+    let m6 = (hw.get_analog_input(AnalogInput::M6) * 128.0) as u16;
+
+    let vaux = (get_parameter(ParameterId::AuxVoltage).value * 128.0) as u16;
+
+    let mut data = [0u8; 8];
+    let bits = data.view_bits_mut::<Msb0>();
+    bits[0..12].store_be(m5);
+    bits[12..24].store_be(m6);
+    // Bytes 3-4: Counter and checksum (INPUTS_2_INTEGRITY)
+    bits[52..64].store_be(vaux);
+
+    normal_frame(0x205, &data)
+}
+
+fn encode_currents(hw: &mut dyn HardwareInterface) -> Option<bxcan::Frame> {
+    // Publish current measurements for external monitoring
+
+    let current1 = (hw.get_analog_input(AnalogInput::Current1) * 256.0) as u16;
+    let current2 = (hw.get_analog_input(AnalogInput::Current2) * 256.0) as u16;
+    let current3 = (hw.get_analog_input(AnalogInput::Current3) * 256.0) as u16;
+    let current4 = (hw.get_analog_input(AnalogInput::Current4) * 256.0) as u16;
+    let currentL = (hw.get_analog_input(AnalogInput::CurrentL) * (256.0 / 3.0)) as u16;
+
+    let mut data = [0u8; 8];
+    let bits = data.view_bits_mut::<Msb0>();
+    // 12 bits for each value (big endian)
+    bits[0..12].store_be(current1);
+    bits[12..24].store_be(current2);
+    bits[24..36].store_be(current3);
+    bits[36..48].store_be(current4);
+    bits[48..60].store_be(currentL);
+
+    normal_frame(0x206, &data)
+}
+
+fn encode_outputs(hw: &mut dyn HardwareInterface) -> Option<bxcan::Frame> {
+    // Publish output states for external monitoring
+
+    // Only the first 20 of DigitalOutput::ALL fit in the frame: Wakeup,
+    // HOUT1...12, LOUT1...6 and M1. The other M pins can be checked using the
+    // mpins console command.
+    let mut digital_outputs: u32 = 0;
+    for (i, output) in DigitalOutput::ALL.iter().take(20).enumerate() {
+        if hw.get_digital_output(*output) {
+            digital_outputs |= 1 << i;
+        }
+    }
+
+    let fuses_tripped = get_parameter(ParameterId::FusesTripped).value as u8;
+    let power_state = get_parameter(ParameterId::PowerState).value as u8;
+
+    let mut data = [0u8; 8];
+    let bits = data.view_bits_mut::<Msb0>();
+    // DigitalOutput bitmap, bit n = DigitalOutput::ALL[n] (big endian)
+    bits[0..20].store_be(digital_outputs);
+    // Smart fuse tripped or latched, bit n = group n+1
+    bits[20..24].store_be(fuses_tripped & 0x0f);
+    bits[24..27].store_be(power_state);
+    // 7 bits for each PwmOutput::ALL duty cycle in % (big endian)
+    for (i, output) in PwmOutput::ALL.iter().enumerate() {
+        let duty = (hw.get_pwm_output(*output).clamp(0.0, 1.0) * 100.0 + 0.5) as u8;
+        bits[29 + i * 7..36 + i * 7].store_be(duty);
+    }
+
+    normal_frame(0x207, &data)
+}
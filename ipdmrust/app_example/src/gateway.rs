@@ -0,0 +1,31 @@
+use crate::can_tx::setting_frame;
+use crate::parameters::*;
+use common::gateway::*;
+use common::*;
+
+pub fn register_rules<const N: usize>(gateway: &mut Gateway<N>) {
+    // For some reason inverter_controller isn't following the inverter
+    // disable request in 0x200, so we translate it into the 0x320 setting
+    // frame which it does follow
+    gateway.register(GatewayRule {
+        name: "0x200 -> 0x320",
+        direction: GatewayDirection::Tx,
+        id: CanMapId::Exact(standard_id(0x200)),
+        enable: Some(|| get_parameter(ParameterId::FoccciPlugPresent).value >= 0.5),
+        action: GatewayAction::Forward {
+            to_id: Some(standard_id(0x320)),
+            rewrite: &[],
+            function: Some(|data: &mut [u8]| {
+                // Only while 0x200 requests inverter disable. The setting
+                // frame is 8 bytes, so the copy has to be too.
+                if data.len() != 8 || data[0] & (1 << 3) == 0 {
+                    return false;
+                }
+                let setting = setting_frame(0x320, 1, 0, 1).unwrap();
+                data.copy_from_slice(setting.data().unwrap());
+                true
+            }),
+            min_interval_ms: 0,
+        },
+    });
+}
@@ -2,8 +2,12 @@
 
 use common::*;
 
+pub mod can_nodes;
 pub mod can_simulator;
+pub mod can_tx;
+pub mod gateway;
 pub mod parameters;
+use can_nodes::*;
 use parameters::*;
 
 pub extern crate bxcan;
@@ -12,7 +16,32 @@ pub extern crate profont;
 
 use arrayvec::ArrayString;
 use bitvec::prelude::*;
-use bxcan::StandardId;
+use common::can_integrity::{
+    ChecksumField, ChecksumKind, CounterField, FrameIntegrity, RxIntegrityCheck, RxIntegrityChecker,
+};
+use common::can_node::{
+    print_can_nodes, timeout_can_nodes, update_can_node_dtcs, update_can_nodes_on_can,
+};
+use common::can_scheduler::CanScheduler;
+use common::current_control::{CurrentControl, CurrentControlConfig};
+use common::dtc::DtcStore;
+use common::gateway::Gateway;
+use common::hbridge::{HBridges, HBridgesConfig};
+use common::j1939::{BamReceiver, J1939AddressClaimer, J1939Id, J1939_TP_MAX_LEN};
+use common::keypad::{KeyMapping, KeyMode, Keypad, KeypadConfig, LedColor};
+use common::load_diagnostics::{
+    Load, LoadConfig, LoadDiagnostics, LoadDiagnosticsConfig, StuckOnConfig,
+};
+use common::obd::{ObdConfig, ObdEncoding, ObdPid, ObdResponder};
+use common::output_layers::{OutputHw, OutputLayers};
+use common::output_manager::{OutputManager, OutputManagerConfig, ShedLoad};
+use common::pulse_capture::{CaptureChannelConfig, PulseCapture, PulseCaptureConfig};
+use common::pwm_control::{PwmChannelConfig, PwmControl, PwmControlConfig};
+use common::remote_io::{RemoteIo, RemoteIoConfig};
+use common::sdo::SdoServer;
+use common::smart_fuse::{FuseChannel, FuseConfig, FuseState, SmartFuse, SmartFuseConfig};
+use common::soft_pwm::{SoftPwm, SoftPwmConfig, SoftPwmOutput};
+use common::uds::{UdsConfig, UdsServer};
 use fixedstr::str_format;
 use int_enum::IntEnum;
 #[allow(unused_imports)]
@@ -71,6 +100,437 @@ const HeatLoopPump: DigitalOutput = DigitalOutput::LOUT5;
 
 const CpPwmToObc: PwmOutput = PwmOutput::SPWM1;
 
+// Why the app is keeping the vehicle awake. Published in the output status
+// frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PowerState {
+    Sleep = 0,
+    Ignition = 1,
+    // Charging without ignition
+    Charging = 2,
+    // HVAC or 12V battery charging without ignition
+    Remote = 3,
+}
+
+// Settings
+const VIN: &[u8; 17] = b"IPDM5600000000000";
+const CALIBRATION_ID: &[u8] = b"ipdmrust";
+
+// UDS diagnostics
+const UDS_CONFIG: UdsConfig = UdsConfig {
+    functional_id: Some(standard_id(0x7df)),
+    // Parameters are at 0x4000 + ParameterId
+    parameter_did_base: 0x4000,
+    static_dids: &[
+        (0xf190, VIN),
+        // systemNameOrEngineType
+        (0xf197, b"ipdm56"),
+    ],
+    ..UdsConfig::new(standard_id(0x7e2), standard_id(0x7ea))
+};
+
+// OBD-II mode 01 PIDs
+const OBD_PIDS: &[ObdPid] = &[
+    // Engine coolant temperature: Heating loop
+    ObdPid {
+        pid: 0x05,
+        parameter: ParameterId::HeaterT as usize,
+        encoding: ObdEncoding::U8 {
+            scale: 1.0,
+            offset: -40.0,
+        },
+    },
+    // Fuel tank level input: SoC, for simple gauges
+    ObdPid {
+        pid: 0x2f,
+        parameter: ParameterId::Soc as usize,
+        encoding: ObdEncoding::U8 {
+            scale: 100.0 / 255.0,
+            offset: 0.0,
+        },
+    },
+    // Control module voltage
+    ObdPid {
+        pid: 0x42,
+        parameter: ParameterId::AuxVoltage as usize,
+        encoding: ObdEncoding::U16 {
+            scale: 0.001,
+            offset: 0.0,
+        },
+    },
+    // Hybrid battery pack remaining life
+    ObdPid {
+        pid: 0x5b,
+        parameter: ParameterId::Soc as usize,
+        encoding: ObdEncoding::U8 {
+            scale: 100.0 / 255.0,
+            offset: 0.0,
+        },
+    },
+    // Engine oil temperature: Highest battery temperature
+    ObdPid {
+        pid: 0x5c,
+        parameter: ParameterId::BatteryTMax as usize,
+        encoding: ObdEncoding::U8 {
+            scale: 1.0,
+            offset: -40.0,
+        },
+    },
+];
+
+const OBD_CONFIG: ObdConfig = ObdConfig {
+    functional_id: standard_id(0x7df),
+    request_id: standard_id(0x7e0),
+    response_id: standard_id(0x7e8),
+    pids: OBD_PIDS,
+    vin: VIN,
+    calibration_id: CALIBRATION_ID,
+};
+
+// J1939 NAME: Arbitrary address capable, industry group 0 (global), function
+// 0x81 (not defined), manufacturer code 0x7FF (reserved for experimental use)
+const J1939_NAME: u64 = (1 << 63) | (0x81 << 40) | (0x7ff << 21);
+const J1939_PREFERRED_ADDRESS: u8 = 0x80;
+
+// Measure the cooling fan speed (CoolingFanSpeed) from a tachometer wired to
+// M12. This turns on the pull-up of M12, so only enable it if the fan has a
+// tachometer output.
+const COOLING_FAN_TACH: bool = false;
+
+// M pin modes. The hardware is configured according to this at startup.
+pub const M_PIN_CONFIG: &[MPinConfig] = &[
+    MPinConfig {
+        pin: MPin::M1,
+        mode: MPinMode::Analog,
+    },
+    MPinConfig {
+        pin: MPin::M2,
+        mode: MPinMode::Analog,
+    },
+    MPinConfig {
+        pin: MPin::M3,
+        mode: MPinMode::Analog,
+    },
+    MPinConfig {
+        pin: MPin::M4,
+        mode: MPinMode::Analog,
+    },
+    MPinConfig {
+        pin: MPin::M5,
+        mode: MPinMode::Analog,
+    },
+    MPinConfig {
+        pin: MPin::M6,
+        mode: MPinMode::Analog,
+    },
+    MPinConfig {
+        pin: MPin::M7,
+        mode: MPinMode::InputFloating,
+    },
+    MPinConfig {
+        pin: MPin::M8,
+        mode: MPinMode::InputFloating,
+    },
+    MPinConfig {
+        pin: MPin::M9,
+        mode: MPinMode::InputFloating,
+    },
+    MPinConfig {
+        pin: MPin::M10,
+        mode: MPinMode::InputFloating,
+    },
+    MPinConfig {
+        pin: MPin::M11,
+        mode: MPinMode::InputFloating,
+    },
+    // Cooling fan tachometer if COOLING_FAN_TACH
+    MPinConfig {
+        pin: MPin::M12,
+        mode: if COOLING_FAN_TACH {
+            MPinMode::Capture
+        } else {
+            MPinMode::InputFloating
+        },
+    },
+    MPinConfig {
+        pin: MPin::M13,
+        mode: MPinMode::InputFloating,
+    },
+];
+
+// The cooling fan tachometer gives two pulses per revolution. The fan is
+// considered stopped if there's no pulse within a second.
+const PULSE_CAPTURE_CONFIG: PulseCaptureConfig = PulseCaptureConfig {
+    channels: if COOLING_FAN_TACH {
+        &[CaptureChannelConfig {
+            input: CaptureInput::M12,
+            filter_ms: 500,
+            timeout_ms: 1000,
+        }]
+    } else {
+        &[]
+    },
+};
+
+// Remote I/O: Outputs that aren't used by the app can be commanded over CAN
+const REMOTE_IO_CONFIG: RemoteIoConfig = RemoteIoConfig {
+    command_id: standard_id(0x208),
+    status_id: standard_id(0x209),
+    status_period_ms: 500,
+    allowed_digital_outputs: &[
+        DigitalOutput::HOUT2,
+        DigitalOutput::HOUT3,
+        DigitalOutput::HOUT5,
+        DigitalOutput::HOUT7,
+        DigitalOutput::HOUT8,
+        DigitalOutput::HOUT9,
+        DigitalOutput::HOUT11,
+        DigitalOutput::HOUT12,
+        DigitalOutput::LOUT1,
+        DigitalOutput::LOUT6,
+    ],
+    allowed_pwm_outputs: &[PwmOutput::SPWM2, PwmOutput::LPWM2, PwmOutput::LPWM3],
+    max_lease_ms: 60000,
+};
+
+// Remote I/O commands control outputs, so senders have to protect them with a
+// rolling counter in the low nibble of byte 6 and a CRC-8 SAE J1850 in byte 7
+const REMOTE_IO_COMMAND_INTEGRITY: FrameIntegrity = FrameIntegrity {
+    counter: Some(CounterField {
+        byte: 6,
+        shift: 0,
+        bits: 4,
+    }),
+    checksum: Some(ChecksumField {
+        byte: 7,
+        kind: ChecksumKind::Crc8SaeJ1850,
+        seed: None,
+    }),
+};
+
+// Smart fuses for the HOUT groups. The hardware limits each group to about
+// 10 A.
+const fn group_fuse(group: OutputGroup) -> FuseConfig {
+    FuseConfig {
+        channel: FuseChannel::Group(group),
+        rated_current: 8.0,
+        i2t_limit: 50.0,
+        overcurrent_filter_ms: 100,
+        inrush_blanking_ms: 500,
+        retry_delay_ms: 1000,
+        max_retry_delay_ms: 30000,
+        max_trips: 5,
+        trip_memory_ms: 60000,
+    }
+}
+
+// Pumps and fans in the same group are turned on one at a time. When the 12V
+// battery sags, comfort loads are shed before the cooling fan.
+const OUTPUT_MANAGER_CONFIG: OutputManagerConfig = OutputManagerConfig {
+    stagger_ms: 300,
+    shed_loads: &[
+        ShedLoad {
+            output: HeatLoopPump,
+            priority: 0,
+            shed_below_v: 11.8,
+            restore_above_v: 12.4,
+        },
+        ShedLoad {
+            output: CoolingFan,
+            priority: 1,
+            shed_below_v: 11.5,
+            restore_above_v: 12.2,
+        },
+    ],
+    shed_delay_ms: 5000,
+    shed_step_ms: 2000,
+    status_id: standard_id(0x20b),
+    status_period_ms: 1000,
+};
+
+// Battery heating at partial duty cycle alternates between the heat and
+// neutral solenoids with a 120 s period, i.e. 60 s on and 60 s off at 50%
+const SOFT_PWM_CONFIG: SoftPwmConfig = SoftPwmConfig {
+    outputs: &[
+        SoftPwmOutput {
+            output: BatteryHeatSolenoid,
+            frequency_hz: 1.0 / 120.0,
+            inverted: false,
+        },
+        SoftPwmOutput {
+            output: BatteryNeutralSolenoid,
+            frequency_hz: 1.0 / 120.0,
+            inverted: true,
+        },
+    ],
+};
+
+// The CP signal to the OBC has to be at 1 kHz. It shares TIM4 with LCUR1 and
+// SPWM2, which don't care. The remotely controlled fans and pumps on the
+// other outputs are ramped.
+const PWM_CONTROL_CONFIG: PwmControlConfig = PwmControlConfig {
+    channels: &[
+        PwmChannelConfig {
+            output: CpPwmToObc,
+            frequency_hz: 1000.0,
+            ramp_up_ms: 0,
+            ramp_down_ms: 0,
+        },
+        PwmChannelConfig {
+            output: PwmOutput::SPWM2,
+            frequency_hz: f32::NAN,
+            ramp_up_ms: 3000,
+            ramp_down_ms: 1000,
+        },
+        PwmChannelConfig {
+            output: PwmOutput::LPWM2,
+            frequency_hz: 100.0,
+            ramp_up_ms: 3000,
+            ramp_down_ms: 1000,
+        },
+        PwmChannelConfig {
+            output: PwmOutput::LPWM3,
+            frequency_hz: 100.0,
+            ramp_up_ms: 3000,
+            ramp_down_ms: 1000,
+        },
+    ],
+};
+
+const SMART_FUSE_CONFIG: SmartFuseConfig = SmartFuseConfig {
+    channels: &[
+        group_fuse(OutputGroup::Group1),
+        group_fuse(OutputGroup::Group2),
+        group_fuse(OutputGroup::Group3),
+        group_fuse(OutputGroup::Group4),
+    ],
+    status_id: standard_id(0x20a),
+    status_period_ms: 1000,
+};
+
+// Open-load and stuck-on detection. DTCs are manufacturer specific B-codes:
+// B10xx-13 (circuit open) for HOUTxx and B110n-12 (short to battery) for
+// group n.
+const LOAD_DIAGNOSTICS_CONFIG: LoadDiagnosticsConfig = LoadDiagnosticsConfig {
+    loads: &[
+        LoadConfig {
+            load: Load::Digital(ObcDcdc12VSupply),
+            expected_current: f32::NAN,
+            open_load_dtc: 0x900113,
+        },
+        LoadConfig {
+            load: Load::Digital(BatteryPump),
+            expected_current: f32::NAN,
+            open_load_dtc: 0x900413,
+        },
+        LoadConfig {
+            load: Load::Digital(BrakeBooster),
+            expected_current: f32::NAN,
+            open_load_dtc: 0x901013,
+        },
+    ],
+    stuck_on: &[
+        StuckOnConfig {
+            channel: FuseChannel::Group(OutputGroup::Group1),
+            dtc: 0x910112,
+        },
+        StuckOnConfig {
+            channel: FuseChannel::Group(OutputGroup::Group2),
+            dtc: 0x910212,
+        },
+        StuckOnConfig {
+            channel: FuseChannel::Group(OutputGroup::Group3),
+            dtc: 0x910312,
+        },
+        StuckOnConfig {
+            channel: FuseChannel::Group(OutputGroup::Group4),
+            dtc: 0x910412,
+        },
+    ],
+    min_current: 0.2,
+    open_load_ratio: 0.3,
+    settle_ms: 200,
+    filter_ms: 1000,
+};
+
+// LCUR1 current regulation. Set peak_current for solenoids that need a higher
+// pull-in current. The DTC is B1020-92 (performance or incorrect operation).
+const LCUR1_CONTROL_CONFIG: CurrentControlConfig = CurrentControlConfig {
+    kp: 0.1,
+    ki: 2.0,
+    peak_current: f32::NAN,
+    peak_ms: 0,
+    max_error: 0.2,
+    unreachable_ms: 2000,
+    unreachable_dtc: 0x902092,
+};
+
+// Reversible loads driven by paired high and low side outputs. None are
+// fitted in this vehicle. Outputs used by a bridge can't be set directly or
+// through remote I/O.
+const HBRIDGES_CONFIG: HBridgesConfig = HBridgesConfig { bridges: &[] };
+
+// openinverter SDO parameter access (requests on 0x600 + node id)
+const SDO_NODE_ID: u8 = 9;
+// Fits the parameter list JSON (about 4 kB currently)
+const SDO_JSON_BUFFER_SIZE: usize = 6144;
+
+// CANopen keypad (Blink Marine PKP, factory default node id 0x15)
+const KEYPAD_CONFIG: KeypadConfig = KeypadConfig {
+    node_id: 0x15,
+    keys: &[
+        // Key 1: Run the cooling fan
+        KeyMapping {
+            key: 1,
+            parameter: ParameterId::KeypadCoolingFan as usize,
+            mode: KeyMode::Toggle,
+            led_on: LedColor::Blue,
+            led_off: LedColor::Off,
+        },
+    ],
+    heartbeat_timeout_ms: 2000,
+    led_period_ms: 1000,
+    led_brightness: 0x3f,
+    backlight_brightness: 0x10,
+    own_node_id: SDO_NODE_ID,
+    heartbeat_period_ms: 500,
+};
+
+// The output layers, from the app's side
+struct Outputs {
+    remote_io: RemoteIo,
+    hbridges: HBridges,
+    pwm_control: PwmControl,
+    soft_pwm: SoftPwm,
+    output_manager: OutputManager,
+    smart_fuse: SmartFuse,
+}
+
+impl Outputs {
+    fn new() -> Self {
+        Self {
+            remote_io: RemoteIo::new(REMOTE_IO_CONFIG),
+            hbridges: HBridges::new(HBRIDGES_CONFIG),
+            pwm_control: PwmControl::new(PWM_CONTROL_CONFIG),
+            soft_pwm: SoftPwm::new(SOFT_PWM_CONFIG),
+            output_manager: OutputManager::new(OUTPUT_MANAGER_CONFIG),
+            smart_fuse: SmartFuse::new(SMART_FUSE_CONFIG),
+        }
+    }
+
+    fn layers(&mut self) -> OutputLayers<'_> {
+        OutputLayers {
+            remote_io: Some(&mut self.remote_io),
+            hbridges: Some(&mut self.hbridges),
+            pwm_control: Some(&mut self.pwm_control),
+            soft_pwm: Some(&mut self.soft_pwm),
+            output_manager: Some(&mut self.output_manager),
+            smart_fuse: Some(&mut self.smart_fuse),
+        }
+    }
+}
+
 pub struct MainState {
     update_counter: u32,
     log_can: bool,
@@ -78,19 +538,49 @@ pub struct MainState {
     dt_ms: u64,
     last_test_print_ms: u64,
     last_solenoid_update_ms: u64,
-    last_can_30ms: u64,
-    last_can_200ms: u64,
-    last_can_500ms: u64,
+    // Battery temperature decision, updated with the solenoids
+    cool_battery_with_fan: bool,
+    last_log_parameters_ms: u64,
     last_heater_update_ms: u64,
     ignition_last_on_ms: u64,
     last_aux_low_ms: u64,
     last_logged_values: [f32; NUM_PARAMETERS],
     watch_filter: ArrayString<20>,
+    can_scheduler: CanScheduler<16>,
+    gateway: Gateway<8>,
+    rx_integrity: RxIntegrityChecker<8>,
+    dtcs: DtcStore<16>,
+    load_diagnostics: LoadDiagnostics,
+    lcur1_control: CurrentControl,
+    pulse_capture: PulseCapture,
+    uds: UdsServer<128>,
+    sdo: SdoServer<SDO_JSON_BUFFER_SIZE>,
+    obd: ObdResponder,
+    j1939_address: J1939AddressClaimer,
+    j1939_bam: BamReceiver<J1939_TP_MAX_LEN, 2>,
+    outputs: Outputs,
+    keypad: Keypad,
 }
 
 impl MainState {
     pub fn new() -> Self {
         init_parameters();
+        init_can_nodes();
+
+        let mut can_scheduler = CanScheduler::new(3);
+        can_tx::register_messages(&mut can_scheduler);
+
+        let mut gateway = Gateway::new();
+        gateway::register_rules(&mut gateway);
+
+        // Register an RxIntegrityCheck here for each received frame that
+        // carries an alive counter or a checksum
+        let mut rx_integrity = RxIntegrityChecker::new();
+        rx_integrity.register(RxIntegrityCheck {
+            id: REMOTE_IO_CONFIG.command_id,
+            integrity: REMOTE_IO_COMMAND_INTEGRITY,
+            max_counter_skip: 2,
+        });
 
         Self {
             update_counter: 0,
@@ -99,19 +589,37 @@ impl MainState {
             dt_ms: 0,
             last_test_print_ms: 0,
             last_solenoid_update_ms: 0,
-            last_can_30ms: 0,
-            last_can_200ms: 0,
-            last_can_500ms: 0,
+            cool_battery_with_fan: false,
+            last_log_parameters_ms: 0,
             last_heater_update_ms: 0,
             ignition_last_on_ms: 0,
             last_aux_low_ms: 0,
             last_logged_values: [f32::NAN; NUM_PARAMETERS],
             watch_filter: ArrayString::new(),
+            can_scheduler: can_scheduler,
+            gateway: gateway,
+            rx_integrity: rx_integrity,
+            dtcs: DtcStore::new(),
+            load_diagnostics: LoadDiagnostics::new(LOAD_DIAGNOSTICS_CONFIG),
+            lcur1_control: CurrentControl::new(LCUR1_CONTROL_CONFIG),
+            pulse_capture: PulseCapture::new(PULSE_CAPTURE_CONFIG),
+            uds: UdsServer::new(UDS_CONFIG),
+            sdo: SdoServer::new(SDO_NODE_ID),
+            obd: ObdResponder::new(OBD_CONFIG),
+            j1939_address: J1939AddressClaimer::new(J1939_NAME, J1939_PREFERRED_ADDRESS),
+            j1939_bam: BamReceiver::new(),
+            outputs: Outputs::new(),
+            keypad: Keypad::new(KEYPAD_CONFIG),
         }
     }
 
     // This should be called at 20ms interval
     pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        self.load_diagnostics.update(hw);
+        OutputHw::new(hw, self.outputs.layers()).update();
+        self.keypad.update(hw);
+        self.publish_fuse_states(hw);
+
         // Timekeeping
         let millis = hw.millis();
         self.dt_ms = if millis > self.last_millis {
@@ -120,6 +628,8 @@ impl MainState {
             0
         };
 
+        self.pulse_capture.update(hw);
+
         self.update_parameters(hw);
 
         self.read_inputs(hw);
@@ -135,21 +645,25 @@ impl MainState {
             self.update_heater(hw);
         }
 
-        if hw.millis() - self.last_can_500ms >= 500 {
-            self.last_can_500ms = hw.millis();
-            self.send_can_500ms(hw);
+        update_can_node_dtcs(&mut self.dtcs);
+        self.load_diagnostics.update_dtcs(&mut self.dtcs);
+        self.lcur1_control.update_dtcs(&mut self.dtcs);
 
-            self.log_parameters(hw);
-        }
+        self.uds.update(hw, &mut self.dtcs);
 
-        if hw.millis() - self.last_can_200ms >= 200 {
-            self.last_can_200ms = hw.millis();
-            self.send_can_200ms(hw);
-        }
+        self.sdo.update(hw);
+
+        self.obd.update(hw);
+
+        self.j1939_address.update(hw);
+        self.j1939_bam.run_timeout(hw.millis());
 
-        if hw.millis() - self.last_can_30ms >= 30 {
-            self.last_can_30ms = hw.millis();
-            self.send_can_30ms(hw);
+        self.can_scheduler.update(&mut self.gateway.wrap(hw));
+        self.gateway.update(hw);
+
+        if hw.millis() - self.last_log_parameters_ms >= 500 {
+            self.last_log_parameters_ms = hw.millis();
+            self.log_parameters(hw);
         }
 
         if hw.millis() - self.last_test_print_ms >= 15000 {
@@ -179,6 +693,7 @@ impl MainState {
             .set_value(hw.get_analog_input(AnalogInput::AuxVoltage), hw.millis());
         get_parameter(ParameterId::PcbT)
             .set_value(hw.get_analog_input(AnalogInput::PcbT), hw.millis());
+        self.pulse_capture.update_parameters(hw.millis());
 
         if !get_parameter(ParameterId::Soc).value.is_nan()
             && get_parameter(ParameterId::Soc).value >= 0.5
@@ -189,21 +704,12 @@ impl MainState {
         }
 
         self.timeout_parameters(hw);
+
+        timeout_can_nodes(hw.millis());
     }
 
     fn read_inputs(&mut self, hw: &mut dyn HardwareInterface) {
-        if hw.get_digital_input(DigitalInput::Group1OC) {
-            info!("-!- DigitalInput::Group1OC");
-        }
-        if hw.get_digital_input(DigitalInput::Group2OC) {
-            info!("-!- DigitalInput::Group2OC");
-        }
-        if hw.get_digital_input(DigitalInput::Group3OC) {
-            info!("-!- DigitalInput::Group3OC");
-        }
-        if hw.get_digital_input(DigitalInput::Group4OC) {
-            info!("-!- DigitalInput::Group4OC");
-        }
+        // Group overcurrent inputs are handled by the smart fuses
 
         if hw.get_digital_input(DigitalInput::Ignition) {
             self.ignition_last_on_ms = hw.millis();
@@ -245,6 +751,28 @@ impl MainState {
             },
             hw.millis(),
         );
+
+        let power_state = if ignition_input {
+            PowerState::Ignition
+        } else if get_parameter(ParameterId::ActivateEvse).value > 0.5 {
+            PowerState::Charging
+        } else if get_parameter(ParameterId::ReqWakeupAndContactor).value > 0.5 {
+            PowerState::Remote
+        } else {
+            PowerState::Sleep
+        };
+        get_parameter(ParameterId::PowerState).set_value(power_state as u8 as f32, hw.millis());
+    }
+
+    fn publish_fuse_states(&mut self, hw: &mut dyn HardwareInterface) {
+        let mut tripped = 0u8;
+        for (i, group) in OutputGroup::ALL.iter().enumerate() {
+            match self.outputs.smart_fuse.state(FuseChannel::Group(*group)) {
+                None | Some(FuseState::Ok) => {}
+                Some(_) => tripped |= 1 << i,
+            }
+        }
+        get_parameter(ParameterId::FusesTripped).set_value(tripped as f32, hw.millis());
     }
 
     fn update_charging(&mut self, hw: &mut dyn HardwareInterface) {
@@ -319,6 +847,9 @@ impl MainState {
     }
 
     fn update_outputs(&mut self, hw: &mut dyn HardwareInterface) {
+        // The app sets outputs through the output layers so that remote
+        // commands override the app and outputs of tripped groups stay off
+        let hw = &mut OutputHw::new(hw, self.outputs.layers());
         let ignition_input = hw.get_digital_input(DigitalInput::Ignition);
 
         // Require main contactor so that DC/DC can be operating
@@ -336,30 +867,31 @@ impl MainState {
             };
 
             // Update battery solenoids
-            let heat_battery = (get_parameter(ParameterId::BatteryTMin).value < heat_battery_to_t
-                && get_parameter(ParameterId::BatteryTMax).value < 30.0
-                && (
-                    // Only allow 100% duty cycle if battery < 3°C or
-                    // cabin > 15°C
-                    get_parameter(ParameterId::BatteryTMin).value < 3.0
-                        || get_parameter(ParameterId::CabinT).value > 15.0
-                        || hw.millis() % 120000 < 60000
-                    // 50% duty cycle
-                ));
+            let heat_battery = get_parameter(ParameterId::BatteryTMin).value < heat_battery_to_t
+                && get_parameter(ParameterId::BatteryTMax).value < 30.0;
+            let heat_duty = if !allow_solenoids || !heat_battery {
+                0.0
+            } else if get_parameter(ParameterId::BatteryTMin).value < 3.0
+                || get_parameter(ParameterId::CabinT).value > 15.0
+            {
+                // Only allow 100% duty cycle if battery < 3°C or cabin > 15°C
+                1.0
+            } else {
+                0.5
+            };
             let cool_battery = get_parameter(ParameterId::BatteryTMin).value > 23.0
                 && get_parameter(ParameterId::BatteryTMax).value > 30.0;
-            hw.set_digital_output(
-                BatteryNeutralSolenoid,
-                allow_solenoids && !cool_battery && !heat_battery,
-            );
-            hw.set_digital_output(BatteryHeatSolenoid, allow_solenoids && heat_battery);
+            // The neutral solenoid is open whenever the heat solenoid isn't
+            let neutral_duty = if allow_solenoids && !cool_battery {
+                1.0 - heat_duty
+            } else {
+                0.0
+            };
+            hw.soft_pwm().set_duty(BatteryNeutralSolenoid, neutral_duty);
+            hw.soft_pwm().set_duty(BatteryHeatSolenoid, heat_duty);
 
-            // Update cooling fan
             // TODO: Trigger on inverter, motor and OBC temperature also
-            hw.set_digital_output(
-                CoolingFan,
-                allow_solenoids && get_parameter(ParameterId::BatteryTMax).value > 35.0,
-            );
+            self.cool_battery_with_fan = get_parameter(ParameterId::BatteryTMax).value > 35.0;
 
             // Update heating loop pump
             hw.set_digital_output(
@@ -371,6 +903,14 @@ impl MainState {
             );
         }
 
+        // Update cooling fan
+        // The keypad is checked on every tick so that the fan follows the key
+        // and its LED immediately
+        hw.set_digital_output(
+            CoolingFan,
+            allow_solenoids && (self.cool_battery_with_fan || self.keypad.is_active(1)),
+        );
+
         // Wakeup line
         // This powers inverter_controller and BMS
         hw.set_digital_output(
@@ -445,286 +985,11 @@ impl MainState {
                 get_parameter(ParameterId::FoccciCPPWM).value * 0.01
             },
         );
-    }
 
-    fn send_can_500ms(&mut self, hw: &mut dyn HardwareInterface) {
-        {
-            // Send charge completion voltage setting to BMS
-            let old_value: u16 =
-                get_parameter(ParameterId::BmsChargeCompleteVoltageSetting).value as u16;
-            // TODO: Allow configuring this at runtime
-            let new_value: u16 = 4120;
-            if old_value != new_value {
-                self.send_setting_frame(hw, 0x120, 0, old_value, new_value);
-            }
-        }
-
-        {
-            // Publish generic inputs for external monitoring
-
-            let ignition = hw.get_digital_input(DigitalInput::Ignition);
-            let m7 = hw.get_digital_input(DigitalInput::M7);
-            let m8 = hw.get_digital_input(DigitalInput::M8);
-            let m9 = hw.get_digital_input(DigitalInput::M9);
-            let m10 = hw.get_digital_input(DigitalInput::M10);
-            let m11 = hw.get_digital_input(DigitalInput::M11);
-            let m12 = hw.get_digital_input(DigitalInput::M12);
-            let m13 = hw.get_digital_input(DigitalInput::M13);
-
-            let m1 = (hw.get_analog_input(AnalogInput::M1) * 128.0) as u16;
-            let m2 = (hw.get_analog_input(AnalogInput::M2) * 128.0) as u16;
-            let m3 = (hw.get_analog_input(AnalogInput::M3) * 128.0) as u16;
-            let m4 = (hw.get_analog_input(AnalogInput::M4) * 128.0) as u16;
-            let m5 = (hw.get_analog_input(AnalogInput::M5) * 128.0) as u16;
-            let m6 = (hw.get_analog_input(AnalogInput::M6) * 128.0) as u16;
-
-            let vaux = (get_parameter(ParameterId::AuxVoltage).value * 128.0) as u16;
-
-            let mut data = [0u8; 8];
-            let mut bits = data.view_bits_mut::<Msb0>();
-            bits[0..8].store_be(
-                if ignition { (1 << 0) } else { 0 }
-                    | if m7 { (1 << 1) } else { 0 }
-                    | if m8 { (1 << 2) } else { 0 }
-                    | if m9 { (1 << 3) } else { 0 }
-                    | if m10 { (1 << 4) } else { 0 }
-                    | if m11 { (1 << 5) } else { 0 }
-                    | if m12 { (1 << 6) } else { 0 }
-                    | if m13 { (1 << 7) } else { 0 },
-            );
-            bits[8..16].store_be(0);
-            // 12 bits for each analog value (big endian)
-            bits[16..28].store_be(m1);
-            bits[28..40].store_be(m2);
-            bits[40..52].store_be(m3);
-            bits[52..64].store_be(m4);
-
-            self.send_normal_frame(hw, 0x204, &data);
-
-            let mut data = [0u8; 8];
-            let mut bits = data.view_bits_mut::<Msb0>();
-            bits[0..12].store_be(m5);
-            bits[12..24].store_be(m6);
-            // ...
-            bits[52..64].store_be(vaux);
-
-            self.send_normal_frame(hw, 0x205, &data);
-        }
-
-        {
-            // Publish current measurements for external monitoring
-
-            let current1 = (hw.get_analog_input(AnalogInput::Current1) * 256.0) as u16;
-            let current2 = (hw.get_analog_input(AnalogInput::Current2) * 256.0) as u16;
-            let current3 = (hw.get_analog_input(AnalogInput::Current3) * 256.0) as u16;
-            let current4 = (hw.get_analog_input(AnalogInput::Current4) * 256.0) as u16;
-            let currentL = (hw.get_analog_input(AnalogInput::CurrentL) * (256.0 / 3.0)) as u16;
-
-            let mut data = [0u8; 8];
-            let mut bits = data.view_bits_mut::<Msb0>();
-            // 12 bits for each value (big endian)
-            bits[0..12].store_be(current1);
-            bits[12..24].store_be(current2);
-            bits[24..36].store_be(current3);
-            bits[36..48].store_be(current4);
-            bits[48..60].store_be(currentL);
-
-            self.send_normal_frame(hw, 0x206, &data);
-        }
-    }
-
-    fn send_can_200ms(&mut self, hw: &mut dyn HardwareInterface) {
-        let ignition_input = hw.get_digital_input(DigitalInput::Ignition);
-
-        {
-            // Outlander heater control
-            let requested_power_command =
-                if get_parameter(ParameterId::ReqHeaterPowerPercent).value > 70.0 {
-                    0xa2
-                } else if get_parameter(ParameterId::ReqHeaterPowerPercent).value > 30.0 {
-                    0x32
-                } else {
-                    0
-                };
-            self.send_normal_frame(
-                hw,
-                0x188,
-                &[
-                    0x03,
-                    0x50,
-                    requested_power_command,
-                    0x4D,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                ],
-            );
-        }
-
-        {
-            let charge_voltage_setpoint_Vx10: u16 = 3020;
-
-            // TODO: Make maximum AC charge current configurable (ui8d already
-            //       is capable of sending requests to change this)
-            let user_current_request_ACA: f32 = 10.0;
-
-            let ac_v = get_parameter(ParameterId::AcVoltage).value;
-            let dc_v = get_parameter(ParameterId::ObcDcv).value;
-            let dc_current_request_Ax10: u8 = if get_parameter(ParameterId::MainContactor).value
-                > 0.5
-                && get_parameter(ParameterId::ActivateEvse).value > 0.5
-            {
-                let ac_request_DCA = ac_v / dc_v * user_current_request_ACA;
-                let obc_limit_DCA = 12.0;
-                // TODO: If the heater is operating, allow that much extra
-                //       charging current so that it's possible to heat the
-                //       battery using AC power
-                let bms_limit_DCA = get_parameter(ParameterId::BmsMaxChargeCurrent).value;
-                (ac_request_DCA
-                    .min(obc_limit_DCA)
-                    .min(bms_limit_DCA)
-                    .max(0.0)
-                    * 10.0) as u8
-            } else {
-                0
-            };
-
-            // Outlander OBC control
-            self.send_normal_frame(
-                hw,
-                0x286,
-                &[
-                    (charge_voltage_setpoint_Vx10 >> 8) as u8,
-                    (charge_voltage_setpoint_Vx10 & 0xff) as u8,
-                    dc_current_request_Ax10, // DC current, 0.1A / bit
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                ],
-            );
-        }
-
-        {
-            // This is an old PDM message, which we have inherited
-            // We use this to:
-            // * Request main contactor from the BMS for charging
-            //   and heating
-            // * Request the inverter to be disabled while charging
-            // * Provide a DC bus voltage reading to Foccci
-            // * Provide an OBC DC current reading to old SIM900 unit
-            // * Send AcObcState and enable parameters to Foccci so that it can
-            //   enable EVSE state C for AC charging
-
-            let request_main_contactor: bool =
-                get_parameter(ParameterId::ReqWakeupAndContactor).value > 0.5;
-
-            let request_inverter_disable: bool =
-                get_parameter(ParameterId::FoccciPlugPresent).value >= 0.5;
-
-            let dc_link_voltage_Vx10: u16 =
-                (get_parameter(ParameterId::ObcDcv).value * 10.0) as u16;
-
-            let obc_Ax10: u16 = (get_parameter(ParameterId::ObcDcc).value * 10.0) as u16;
-
-            let ac_obc_state = if get_parameter(ParameterId::ActivateObc).value > 0.5 {
-                2
-            } else {
-                0
-            };
-
-            let group1oc = hw.get_digital_input(DigitalInput::Group1OC);
-            let group2oc = hw.get_digital_input(DigitalInput::Group2OC);
-            let group3oc = hw.get_digital_input(DigitalInput::Group3OC);
-            let group4oc = hw.get_digital_input(DigitalInput::Group4OC);
-
-            self.send_normal_frame(
-                hw,
-                0x200,
-                &[
-                    0x00 | if request_main_contactor { (1 << 0) } else { 0 }
-                        | if request_inverter_disable {
-                            (1 << 3)
-                        } else {
-                            0
-                        }
-                        | if ignition_input { (1 << 6) } else { 0 }
-                        | (1 << 7), /* Foccci.enable (new) */
-                    (dc_link_voltage_Vx10 >> 8) as u8,
-                    (dc_link_voltage_Vx10 & 0xff) as u8,
-                    (obc_Ax10 >> 8) as u8,
-                    (obc_Ax10 & 0xff) as u8,
-                    get_parameter(ParameterId::PcbT).value as u8,
-                    ac_obc_state, /* Foccci.AcObcState (new) */
-                    0x00 | if group1oc { (1 << 0) } else { 0 }
-                        | if group2oc { (1 << 1) } else { 0 }
-                        | if group3oc { (1 << 2) } else { 0 }
-                        | if group4oc { (1 << 3) } else { 0 },
-                ],
-            );
-
-            if request_inverter_disable {
-                // For some reason inverter_controller isn't following the value
-                // in 0x200, so we send this also which it does follow
-                self.send_setting_frame(hw, 0x320, 1, 0, 1);
-            }
-        }
-    }
-
-    fn send_can_30ms(&mut self, hw: &mut dyn HardwareInterface) {
-        if get_parameter(ParameterId::MainContactor).value > 0.5 {
-            // Outlander HV status message (for heater and OBC)
-            // 10...30ms is fine for this (EV-Omega uses 30ms)
-            let activate_evse = get_parameter(ParameterId::ActivateObc).value > 0.5;
-            self.send_normal_frame(
-                hw,
-                0x285,
-                &[
-                    0x00,
-                    0x00,
-                    0x14 | if activate_evse { 0xb6 } else { 0 }, // 0xb6 = Activate EVSE (OBC)
-                    0x21,
-                    0x90,
-                    0xfe,
-                    0x0c,
-                    0x10,
-                ],
-            );
-        }
-    }
-
-    fn send_normal_frame(&mut self, hw: &mut dyn HardwareInterface, frame_id: u16, data: &[u8]) {
-        if let Some(frame_data) = bxcan::Data::new(data) {
-            hw.send_can(bxcan::Frame::new_data(
-                bxcan::StandardId::new(frame_id).unwrap(),
-                frame_data,
-            ));
-        } else {
-            warn!(
-                "-!- send_normal_frame(): Invalid data for frame {:?}: {:?}",
-                frame_id, data
-            );
-        }
-    }
-
-    fn send_setting_frame(
-        &mut self,
-        hw: &mut dyn HardwareInterface,
-        frame_id: u16,
-        setting_id: u8,
-        old_value: u16,
-        new_value: u16,
-    ) {
-        let mut data: [u8; 8] = [0; 8];
-        data[0] = setting_id;
-        data[1..3].copy_from_slice(&old_value.to_be_bytes());
-        data[3..5].copy_from_slice(&new_value.to_be_bytes());
-        hw.send_can(bxcan::Frame::new_data(
-            bxcan::StandardId::new(frame_id).unwrap(),
-            bxcan::Data::new(&data).unwrap(),
-        ));
+        // Update LCUR1 current regulation
+        self.lcur1_control
+            .set_target(get_parameter(ParameterId::Lcur1TargetCurrent).value);
+        self.lcur1_control.update(hw);
     }
 
     fn log_parameters(&mut self, hw: &mut dyn HardwareInterface) {
@@ -788,6 +1053,58 @@ impl MainState {
                 if self.log_can { "enabled" } else { "disabled" }
             );
             true
+        } else if command == "can tx" {
+            self.can_scheduler.print_stats();
+            info!(
+                "Total missed deadlines: {}",
+                self.can_scheduler.total_missed_deadlines()
+            );
+            true
+        } else if command == "can rx" {
+            self.rx_integrity.print_stats();
+            true
+        } else if command == "gateway" {
+            self.gateway.print_stats();
+            true
+        } else if command == "nodes" {
+            print_can_nodes(self.last_millis);
+            true
+        } else if command == "outputs" {
+            print_outputs(hw);
+            true
+        } else if command == "mpins" {
+            print_m_pins(hw);
+            true
+        } else if command == "fuse" {
+            self.outputs.smart_fuse.print();
+            true
+        } else if command == "loads" {
+            self.load_diagnostics.print();
+            true
+        } else if command == "lcur1" {
+            self.lcur1_control.print();
+            true
+        } else if command == "pwm" {
+            self.outputs.pwm_control.print(hw);
+            true
+        } else if command == "hbridge" {
+            self.outputs.hbridges.print();
+            true
+        } else if command == "capture" {
+            self.pulse_capture.print();
+            true
+        } else if command == "shed" {
+            self.outputs.output_manager.print();
+            true
+        } else if command == "fuse reset" {
+            self.outputs.smart_fuse.reset(hw);
+            true
+        } else if command == "dtc" {
+            self.dtcs.print();
+            true
+        } else if command == "dtc clear" {
+            self.dtcs.clear(0xffffff);
+            true
         } else if command == "print" || command == "p" {
             self.print_parameters(hw);
             true
@@ -812,6 +1129,22 @@ impl MainState {
         info!("  dfu  - Activate DFU mode");
         info!("  panic  - Call panic!()");
         info!("  log can  - Enable logging of CAN messages on console");
+        info!("  can tx  - Print CAN transmit schedule statistics");
+        info!("  can rx  - Print CAN receive integrity check statistics");
+        info!("  gateway  - Print CAN gateway rule statistics");
+        info!("  nodes  - Print CAN node alive states");
+        info!("  outputs  - Print output states and diagnostics");
+        info!("  mpins  - Print M pin modes and values");
+        info!("  fuse  - Print smart fuse states");
+        info!("  fuse reset  - Reset tripped and latched smart fuses");
+        info!("  shed  - Print load shedding states");
+        info!("  pwm  - Print PWM frequencies and ramps");
+        info!("  lcur1  - Print LCUR1 current control state");
+        info!("  hbridge  - Print H-bridge states");
+        info!("  capture  - Print measured frequencies and duty cycles");
+        info!("  loads  - Print load diagnostics");
+        info!("  dtc  - Print diagnostic trouble codes");
+        info!("  dtc clear  - Clear diagnostic trouble codes");
         info!("  print | p - Print all parameter values");
         info!("  print | p <filter> - Print parameter values, filter by name");
         info!("  watch | w <filter> - Set watch filter");
@@ -824,9 +1157,45 @@ impl MainState {
                 if let Some(data) = frame.data() {
                     info!("on_can: {:?}: {:?}", id, data);
                 }
+            } else if let bxcan::Id::Extended(id) = frame.id() {
+                if let Some(data) = frame.data() {
+                    info!("on_can: {:?}: {:?}", id, data);
+                }
             }
         }
 
+        // Frames that fail their integrity check don't reach any consumer
+        if self.rx_integrity.check(&frame).is_err() {
+            invalidate_parameters_on_can_id(frame.id(), self.last_millis);
+            return;
+        }
+
+        // Functional diagnostic requests go to both UDS and OBD
+        let consumed = self.uds.on_can(&frame, self.last_millis)
+            | self.obd.on_can(&frame, self.last_millis)
+            | self.sdo.on_can(&frame, self.last_millis)
+            | self.outputs.remote_io.on_can(&frame)
+            | self.keypad.on_can(&frame, self.last_millis);
+        if consumed {
+            return;
+        }
+
+        update_can_nodes_on_can(&frame, self.last_millis);
+
+        self.gateway.on_can(&frame, self.last_millis);
+
+        self.j1939_address.on_can(&frame);
+        self.j1939_bam.on_can(&frame, self.last_millis);
+        if let Some(message) = self.j1939_bam.take_received() {
+            let id = J1939Id {
+                priority: 6,
+                pgn: message.pgn,
+                source_address: message.source_address,
+                destination_address: common::j1939::ADDRESS_GLOBAL,
+            };
+            update_parameters_on_data(id.to_id(), &message.data, self.last_millis);
+        }
+
         update_parameters_on_can(frame, self.last_millis);
     }
 }
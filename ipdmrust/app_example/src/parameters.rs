@@ -1,4 +1,3 @@
-use bxcan::{Id, StandardId};
 use common::*;
 
 define_parameters! {
@@ -17,7 +16,7 @@ define_parameters! {
         display_name: "Bat T min",
         unit: "degC",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x101).unwrap()),
+            id: CanMapId::Exact(standard_id(0x101)),
             bits: CanBitSelection::Int8(3),
             scale: 1.0,
         },
@@ -26,7 +25,7 @@ define_parameters! {
         display_name: "Bat T max",
         unit: "degC",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x101).unwrap()),
+            id: CanMapId::Exact(standard_id(0x101)),
             bits: CanBitSelection::Int8(4),
             scale: 1.0,
         },
@@ -36,7 +35,7 @@ define_parameters! {
         decimals: 2,
         unit: "V",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x101).unwrap()),
+            id: CanMapId::Exact(standard_id(0x101)),
             bits: CanBitSelection::Function(|data: &[u8]| -> Option<f32> {
                 Some((((data[0] as u16) << 4) | ((data[1] as u16) >> 4)) as f32)
             }),
@@ -49,7 +48,7 @@ define_parameters! {
         decimals: 2,
         unit: "V",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x101).unwrap()),
+            id: CanMapId::Exact(standard_id(0x101)),
             bits: CanBitSelection::Function(|data: &[u8]| -> Option<f32> {
                 Some(((((data[1] & 0x0f) as u16) << 8) | data[2] as u16) as f32)
             }),
@@ -61,7 +60,7 @@ define_parameters! {
         display_name: "SoC",
         unit: "%",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x102).unwrap()),
+            id: CanMapId::Exact(standard_id(0x102)),
             bits: CanBitSelection::Uint8(6),
             scale: 100.0 / 255.0,
         },
@@ -70,7 +69,7 @@ define_parameters! {
         display_name: "Heater T",
         unit: "degC",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x398).unwrap()),
+            id: CanMapId::Exact(standard_id(0x398)),
             bits: CanBitSelection::Function(|data: &[u8]| -> Option<f32> {
                 let t1 = data[3] as i8 - 40;
                 let t2 = data[4] as i8 - 40;
@@ -83,7 +82,7 @@ define_parameters! {
         display_name: "Heater heating",
         unit: "",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x398).unwrap()),
+            id: CanMapId::Exact(standard_id(0x398)),
             bits: CanBitSelection::Function(|data: &[u8]| -> Option<f32> {
                 if data[5] > 0 {
                     Some(1.0)
@@ -98,7 +97,7 @@ define_parameters! {
         display_name: "Heater power",
         unit: "%",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x398).unwrap()),
+            id: CanMapId::Exact(standard_id(0x398)),
             bits: CanBitSelection::Function(|data: &[u8]| -> Option<f32> {
                 // TODO: This accurate. The heater can be requested different
                 //       power levels in 0x188
@@ -115,7 +114,7 @@ define_parameters! {
         display_name: "Cabin T",
         unit: "degC",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x404).unwrap()),
+            id: CanMapId::Exact(standard_id(0x404)),
             bits: CanBitSelection::Int8(1),
             scale: 1.0,
         },
@@ -137,7 +136,7 @@ define_parameters! {
         display_name: "Main contactor",
         unit: "",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x100).unwrap()),
+            id: CanMapId::Exact(standard_id(0x100)),
             bits: CanBitSelection::Bit(2),
             scale: 1.0,
         },
@@ -146,7 +145,7 @@ define_parameters! {
         display_name: "Precharge failed",
         unit: "",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x100).unwrap()),
+            id: CanMapId::Exact(standard_id(0x100)),
             bits: CanBitSelection::Bit(6),
             scale: 1.0,
         },
@@ -155,7 +154,7 @@ define_parameters! {
         display_name: "Balancing",
         unit: "",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x101).unwrap()),
+            id: CanMapId::Exact(standard_id(0x101)),
             bits: CanBitSelection::Bit(5 * 8 + 0),
             scale: 1.0,
         },
@@ -164,7 +163,7 @@ define_parameters! {
         display_name: "OBC DC V",
         unit: "V",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x389).unwrap()),
+            id: CanMapId::Exact(standard_id(0x389)),
             bits: CanBitSelection::Uint8(0),
             scale: 2.0,
         },
@@ -174,7 +173,7 @@ define_parameters! {
         decimals: 1,
         unit: "A",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x389).unwrap()),
+            id: CanMapId::Exact(standard_id(0x389)),
             bits: CanBitSelection::Uint8(2),
             scale: 0.1,
         },
@@ -183,7 +182,7 @@ define_parameters! {
         display_name: "OBC AC V",
         unit: "V",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x389).unwrap()),
+            id: CanMapId::Exact(standard_id(0x389)),
             bits: CanBitSelection::Uint8(1),
             scale: 1.0,
         },
@@ -192,7 +191,7 @@ define_parameters! {
         display_name: "PdmState",
         unit: "",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x203).unwrap()),
+            id: CanMapId::Exact(standard_id(0x203)),
             bits: CanBitSelection::Function(|data: &[u8]| -> Option<f32> {
                 Some((data[0] >> 4) as f32)
             }),
@@ -203,7 +202,7 @@ define_parameters! {
         display_name: "OutlH T",
         unit: "degC",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x398).unwrap()),
+            id: CanMapId::Exact(standard_id(0x398)),
             bits: CanBitSelection::Function(|data: &[u8]| -> Option<f32> {
                 let t1 = data[3] as i8 - 40;
                 let t2 = data[4] as i8 - 40;
@@ -216,7 +215,7 @@ define_parameters! {
         display_name: "OutlH heating",
         unit: "",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x398).unwrap()),
+            id: CanMapId::Exact(standard_id(0x398)),
             bits: CanBitSelection::Function(|data: &[u8]| -> Option<f32> {
                 if data[5] > 0 {
                     Some(1.0)
@@ -231,7 +230,7 @@ define_parameters! {
         display_name: "OutlH power",
         unit: "%",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x398).unwrap()),
+            id: CanMapId::Exact(standard_id(0x398)),
             bits: CanBitSelection::Function(|data: &[u8]| -> Option<f32> {
                 // TODO: This accurate. The heater can be requested different
                 //       power levels in 0x188
@@ -248,7 +247,7 @@ define_parameters! {
         display_name: "Cruise active",
         unit: "",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x300).unwrap()),
+            id: CanMapId::Exact(standard_id(0x300)),
             bits: CanBitSelection::Bit(2),
             scale: 1.0,
         },
@@ -261,7 +260,7 @@ define_parameters! {
         display_name: "HVAC requested",
         unit: "",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x570).unwrap()),
+            id: CanMapId::Exact(standard_id(0x570)),
             bits: CanBitSelection::Function(|data: &[u8]| -> Option<f32> {
                 if data[0] == 2 {
                     if data[4] == 1 {
@@ -280,7 +279,7 @@ define_parameters! {
         display_name: "Foccci CP PWM",
         unit: "%",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x506).unwrap()),
+            id: CanMapId::Exact(standard_id(0x506)),
             bits: CanBitSelection::Uint8(1),
             scale: 1.0,
         },
@@ -298,7 +297,7 @@ define_parameters! {
         decimals: 1,
         unit: "A",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x102).unwrap()),
+            id: CanMapId::Exact(standard_id(0x102)),
             bits: CanBitSelection::Function(|data: &[u8]| -> Option<f32> {
                 Some((((data[2] as u16) << 8) | data[3] as u16) as f32)
             }),
@@ -310,7 +309,7 @@ define_parameters! {
         decimals: 1,
         unit: "A",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x102).unwrap()),
+            id: CanMapId::Exact(standard_id(0x102)),
             bits: CanBitSelection::Function(|data: &[u8]| -> Option<f32> {
                 Some((((data[4] as u16) << 8) | data[5] as u16) as f32)
             }),
@@ -321,7 +320,7 @@ define_parameters! {
         display_name: "CCS",
         unit: "A",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x506).unwrap()),
+            id: CanMapId::Exact(standard_id(0x506)),
             bits: CanBitSelection::Uint8(5),
             scale: 2.0,
         },
@@ -339,7 +338,7 @@ define_parameters! {
         display_name: "Precharging",
         unit: "",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x100).unwrap()),
+            id: CanMapId::Exact(standard_id(0x100)),
             bits: CanBitSelection::Bit(5),
             scale: 1.0,
         },
@@ -348,7 +347,7 @@ define_parameters! {
         display_name: "DCDC status",
         unit: "",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x377).unwrap()),
+            id: CanMapId::Exact(standard_id(0x377)),
             bits: CanBitSelection::Uint8(7),
             scale: 1.0,
         },
@@ -357,7 +356,7 @@ define_parameters! {
         display_name: "BmsChgCompV",
         unit: "mV",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x104).unwrap()),
+            id: CanMapId::Exact(standard_id(0x104)),
             bits: CanBitSelection::Function(|data: &[u8]| -> Option<f32> {
                 Some((((data[0] as u16) << 8) | data[1] as u16) as f32)
             }),
@@ -368,9 +367,71 @@ define_parameters! {
         display_name: "FoccciPlugPresent",
         unit: "",
         can_map: CanMap {
-            id: bxcan::Id::Standard(StandardId::new(0x506).unwrap()),
+            id: CanMapId::Exact(standard_id(0x506)),
             bits: CanBitSelection::Bit(2),
             scale: 1.0,
         },
     },
+    // Settings. These can be written using UDS WriteDataByIdentifier or SDO.
+    MaxAcChargeCurrent {
+        display_name: "MaxAcChargeCurrent",
+        decimals: 1,
+        unit: "A",
+        default_value: 10.0,
+        min_value: 0.0,
+        max_value: 32.0,
+        category: "Charging",
+        writable: true,
+    },
+    ChargeCompleteVoltage {
+        display_name: "ChargeCompleteV",
+        unit: "mV",
+        default_value: 4120.0,
+        min_value: 3000.0,
+        max_value: 4250.0,
+        category: "Charging",
+        writable: true,
+    },
+    // Keypad functions
+    KeypadCoolingFan {
+        display_name: "KeypadCoolingFan",
+        unit: "",
+        default_value: 0.0,
+    },
+    // Setpoint of the LCUR1 current controller. Written using UDS
+    // WriteDataByIdentifier or SDO like the settings above.
+    Lcur1TargetCurrent {
+        display_name: "LCUR1 target",
+        decimals: 2,
+        unit: "A",
+        default_value: 0.0,
+        min_value: 0.0,
+        max_value: 10.0,
+        category: "Outputs",
+        writable: true,
+    },
+    // See PowerState
+    PowerState {
+        display_name: "PowerState",
+        unit: "",
+        default_value: 0.0,
+    },
+    // Bit n is set while the smart fuse of output group n+1 has tripped or
+    // latched off
+    FusesTripped {
+        display_name: "FusesTripped",
+        unit: "",
+        default_value: 0.0,
+    },
+    // Only measured if COOLING_FAN_TACH is enabled
+    CoolingFanSpeed {
+        display_name: "Cooling fan",
+        unit: "rpm",
+        capture_map: CaptureMap {
+            input: CaptureInput::M12,
+            quantity: CaptureQuantity::Frequency,
+            scale: 30.0,
+        },
+        log_threshold: 100.0,
+    },
 }
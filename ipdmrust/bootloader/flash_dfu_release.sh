@@ -0,0 +1,7 @@
+#!/bin/sh
+set -euv
+dir="$( cd "$( dirname "$0" )" && pwd )"
+cd "$dir"
+
+# The bootloader goes at the start of flash, like a normal firmware build
+../embedded/flash_dfu_bin.sh ../target/thumbv7em-none-eabihf/release/bootloader.bin
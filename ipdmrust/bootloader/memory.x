@@ -0,0 +1,12 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* Flash sectors 0 and 1. See common/src/can_update.rs for the layout. */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 32K
+  RAM : ORIGIN = 0x20000000, LENGTH = 112K
+}
+
+/* This is where the call stack will be allocated. */
+/* The stack is of the full descending type. */
+/* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
+_stack_start = ORIGIN(RAM) + LENGTH(RAM);
@@ -0,0 +1,253 @@
+#![no_std]
+#![no_main]
+
+// CAN bootloader for iPDM56
+//
+// Lives in flash sectors 0-1 and starts the application at APP_ADDRESS if
+// the ImageRecord says there is a valid image and its CRC still matches.
+// Otherwise, or if the application has requested an update by setting
+// BOOTLOADER_HANDOFF_MAGIC in RTC backup register 0, it serves the update
+// protocol (common::can_update) on CAN1 until an image has been written and
+// verified.
+//
+// All outputs are left in their reset state (off) while in the bootloader.
+
+use common::can_update::*;
+use common::CanPort;
+use cortex_m::peripheral::{DWT, SCB};
+use cortex_m_rt::entry;
+use hal::flash::FlashExt;
+use hal::pac;
+use hal::prelude::*;
+use stm32f4xx_hal as hal;
+
+const SYSCLK_HZ: u32 = 42_000_000;
+
+// With a valid image, go back to it if no transfer is started within this
+// time after the application has handed off
+const FALLBACK_TIMEOUT_MS: u64 = 30000;
+
+// Maximum time to wait for a free CAN TX mailbox
+const CAN_TX_TIMEOUT_MS: u64 = 10;
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    // The update can be retried after a reset
+    SCB::sys_reset();
+}
+
+// CAN driver
+
+pub struct CAN1 {
+    _private: (),
+}
+unsafe impl bxcan::Instance for CAN1 {
+    const REGISTERS: *mut bxcan::RegisterBlock = 0x4000_6400 as *mut _;
+}
+unsafe impl bxcan::FilterOwner for CAN1 {
+    const NUM_FILTER_BANKS: u8 = 28;
+}
+
+// Application image
+
+fn app_image(size: u32) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(APP_ADDRESS as *const u8, size as usize) }
+}
+
+fn read_image_record() -> Option<ImageRecord> {
+    let bytes = unsafe { core::ptr::read_volatile(IMAGE_RECORD_ADDRESS as *const [u8; 16]) };
+    ImageRecord::from_bytes(&bytes)
+}
+
+// Returns the record if the image it describes is intact
+fn valid_image_record() -> Option<ImageRecord> {
+    let record = read_image_record()?;
+    if record.size == 0 || record.size > APP_MAX_SIZE {
+        return None;
+    }
+    if crc32(app_image(record.size)) != record.crc {
+        return None;
+    }
+    Some(record)
+}
+
+fn start_application() -> ! {
+    unsafe {
+        (*SCB::PTR).vtor.write(APP_ADDRESS);
+        cortex_m::asm::bootload(APP_ADDRESS as *const u32)
+    }
+}
+
+// Flash access
+
+struct Flash {
+    flash: pac::FLASH,
+}
+
+impl Flash {
+    fn offset(address: u32) -> usize {
+        (address - BOOTLOADER_ADDRESS) as usize
+    }
+}
+
+impl UpdateFlash for Flash {
+    fn erase(&mut self, size: u32) -> Result<(), UpdateFlashError> {
+        // The record goes first so that a partially erased image is never
+        // considered valid
+        let record_sector = self
+            .flash
+            .sector(Self::offset(IMAGE_RECORD_ADDRESS))
+            .ok_or(UpdateFlashError)?
+            .number;
+        let mut sectors = [0u8; 12];
+        let mut num_sectors = 0;
+        let end = Self::offset(APP_ADDRESS) + size as usize;
+        let mut offset = Self::offset(APP_ADDRESS);
+        while offset < end {
+            let sector = self.flash.sector(offset).ok_or(UpdateFlashError)?;
+            sectors[num_sectors] = sector.number;
+            num_sectors += 1;
+            offset = sector.offset + sector.size;
+        }
+
+        let mut unlocked = self.flash.unlocked();
+        unlocked
+            .erase(record_sector)
+            .map_err(|_| UpdateFlashError)?;
+        for &sector in &sectors[..num_sectors] {
+            unlocked.erase(sector).map_err(|_| UpdateFlashError)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), UpdateFlashError> {
+        self.flash
+            .unlocked()
+            .program(Self::offset(APP_ADDRESS + offset), data.iter())
+            .map_err(|_| UpdateFlashError)
+    }
+
+    fn read(&mut self, size: u32) -> &[u8] {
+        app_image(size)
+    }
+
+    fn mark_valid(&mut self, record: &ImageRecord) -> Result<(), UpdateFlashError> {
+        self.flash
+            .unlocked()
+            .program(Self::offset(IMAGE_RECORD_ADDRESS), record.to_bytes().iter())
+            .map_err(|_| UpdateFlashError)?;
+        if read_image_record() != Some(*record) {
+            return Err(UpdateFlashError);
+        }
+        Ok(())
+    }
+}
+
+// CAN port for UpdateTarget
+
+struct CanImplementation {
+    can: bxcan::Can<CAN1>,
+    last_cycles: u32,
+    cycles: u64,
+}
+
+impl CanPort for CanImplementation {
+    fn millis(&mut self) -> u64 {
+        let now = DWT::cycle_count();
+        self.cycles += now.wrapping_sub(self.last_cycles) as u64;
+        self.last_cycles = now;
+        self.cycles / (SYSCLK_HZ / 1000) as u64
+    }
+
+    fn send_can(&mut self, frame: bxcan::Frame) {
+        let start_ms = self.millis();
+        while self.can.transmit(&frame).is_err() {
+            if self.millis() - start_ms >= CAN_TX_TIMEOUT_MS {
+                return;
+            }
+        }
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    let dp = pac::Peripherals::take().unwrap();
+    let mut cp = cortex_m::Peripherals::take().unwrap();
+
+    // Check and clear the handoff flag. This needs access to the backup
+    // domain.
+    dp.RCC.apb1enr.modify(|_, w| w.pwren().set_bit());
+    dp.PWR.cr.modify(|_, w| w.dbp().set_bit());
+    let handoff = dp.RTC.bkpr[0].read().bits() == BOOTLOADER_HANDOFF_MAGIC;
+    if handoff {
+        dp.RTC.bkpr[0].write(|w| w.bits(0));
+    }
+
+    let record = valid_image_record();
+    if record.is_some() && !handoff {
+        // Nothing has been configured yet, so the application starts from a
+        // reset-like state
+        start_application();
+    }
+
+    // System clock
+
+    // Enable CAN1
+    dp.RCC.apb1enr.modify(|_, w| w.can1en().enabled());
+
+    let rcc = dp.RCC.constrain();
+    let _clocks = rcc
+        .cfgr
+        .use_hse(8.MHz())
+        .hclk(42.MHz())
+        .pclk1(42.MHz())
+        .pclk2(42.MHz())
+        .sysclk(SYSCLK_HZ.Hz())
+        .freeze();
+
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
+
+    // CAN
+
+    let gpiod = dp.GPIOD.split();
+    let _pins = (
+        gpiod.pd1.into_alternate::<9>(), // CAN1 TX
+        gpiod.pd0.into_alternate::<9>(), // CAN1 RX
+    );
+
+    let mut can = bxcan::Can::builder(CAN1 { _private: () })
+        .set_bit_timing(0x00090006) // 500kbps at 42MHz pclk1
+        .enable();
+
+    can.modify_filters()
+        .enable_bank(0, bxcan::Fifo::Fifo0, bxcan::filter::Mask32::accept_all());
+
+    let mut hw = CanImplementation {
+        can,
+        last_cycles: DWT::cycle_count(),
+        cycles: 0,
+    };
+    let mut flash = Flash { flash: dp.FLASH };
+
+    let mut update_target = UpdateTarget::new(UpdateTargetConfig {
+        mode: UpdateMode::Bootloader,
+        version: record.map_or(0, |r| r.version),
+        max_size: APP_MAX_SIZE,
+        fallback_timeout_ms: FALLBACK_TIMEOUT_MS,
+        image_valid: record.is_some(),
+    });
+
+    loop {
+        while let Ok(frame) = hw.can.receive() {
+            let millis = hw.millis();
+            update_target.on_can(&frame, millis);
+        }
+        if let Some(UpdateEvent::StartApplication) = update_target.update(&mut hw, Some(&mut flash))
+        {
+            // Start it through a reset so that it gets the peripherals in
+            // their reset state
+            SCB::sys_reset();
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// Console client for the iPDM56 CAN console
+//
+// Bridges stdin/stdout to the console tunneled over CAN. Lines typed on stdin
+// are sent as console input and log output is printed to stdout.
+//
+// Usage: can_console can0
+
+use cantools::bus::BusHw;
+use cantools::socketcan::SocketCan;
+use clap::Parser;
+use common::can_console::{KEEPALIVE, MAX_MESSAGE_LEN};
+use common::isotp::{IsoTp, IsoTpConfig};
+use common::CanPort;
+#[allow(unused_imports)]
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::sync::mpsc;
+
+const KEEPALIVE_INTERVAL_MS: u64 = 1000;
+
+fn parse_id(s: &str) -> Result<u16, String> {
+    let s = s.trim_start_matches("0x");
+    u16::from_str_radix(s, 16)
+        .ok()
+        .filter(|id| *id <= 0x7ff)
+        .ok_or_else(|| format!("Invalid standard CAN ID: {}", s))
+}
+
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[arg(help = "SocketCAN interface, e.g. can0")]
+    interface: String,
+    #[arg(long, default_value = "6f0", value_parser = parse_id,
+        help = "ID the console receives on (hex)")]
+    rx_id: u16,
+    #[arg(long, default_value = "6f8", value_parser = parse_id,
+        help = "ID the console sends on (hex)")]
+    tx_id: u16,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    stderrlog::new()
+        .verbosity(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    let mut can = match SocketCan::open(&cli.interface) {
+        Ok(can) => can,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", cli.interface, e);
+            std::process::exit(1);
+        }
+    };
+    let mut hw = BusHw::new(&mut can);
+
+    // Our tx is the console's rx
+    let mut isotp: IsoTp<MAX_MESSAGE_LEN> = IsoTp::new(IsoTpConfig::new(
+        common::standard_id(cli.rx_id),
+        common::standard_id(cli.tx_id),
+    ));
+
+    // stdin is read in its own thread so that the CAN loop never blocks on it
+    let (stdin_tx, stdin_rx) = mpsc::channel::<Option<String>>();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) => {
+                    if stdin_tx.send(Some(line)).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        let _ = stdin_tx.send(None);
+    });
+
+    let mut pending_input: VecDeque<u8> = VecDeque::new();
+    let mut stdin_closed = false;
+    let mut last_sent_ms: Option<u64> = None;
+    let mut stdout = std::io::stdout();
+
+    loop {
+        let mut timeout_ms = 5;
+        loop {
+            match hw.bus.receive(timeout_ms) {
+                Ok(Some(frame)) => {
+                    let millis = hw.millis();
+                    isotp.on_can(&frame, millis);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("CAN receive failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            timeout_ms = 0;
+        }
+
+        if let Some(error) = isotp.take_error() {
+            warn!("ISO-TP error: {:?}", error);
+        }
+        if let Some(message) = isotp.take_received() {
+            let _ = stdout.write_all(&message);
+            let _ = stdout.flush();
+        }
+
+        while let Ok(line) = stdin_rx.try_recv() {
+            match line {
+                Some(line) => {
+                    pending_input.extend(line.bytes());
+                    pending_input.push_back(b'\n');
+                }
+                None => stdin_closed = true,
+            }
+        }
+
+        let millis = hw.millis();
+        if isotp.is_tx_idle() {
+            if !pending_input.is_empty() {
+                let n = pending_input.len().min(MAX_MESSAGE_LEN);
+                let chunk: Vec<u8> = pending_input.drain(..n).collect();
+                let _ = isotp.send(&chunk);
+                last_sent_ms = Some(millis);
+            } else if stdin_closed {
+                break;
+            } else if last_sent_ms.is_none_or(|t| millis - t >= KEEPALIVE_INTERVAL_MS) {
+                let _ = isotp.send(&[KEEPALIVE]);
+                last_sent_ms = Some(millis);
+            }
+        }
+
+        isotp.update(&mut hw);
+    }
+}
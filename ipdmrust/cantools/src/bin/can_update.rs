@@ -0,0 +1,96 @@
+// Firmware update tool for iPDM56 units running the CAN bootloader
+//
+// Usage:
+//   can_update can0 --query
+//   can_update can0 ../target/thumbv7em-none-eabihf/release/embedded.bin
+
+use cantools::socketcan::SocketCan;
+use cantools::update::UpdateClient;
+use clap::Parser;
+use std::io::Write;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[arg(help = "SocketCAN interface, e.g. can0")]
+    interface: String,
+    #[arg(help = "Firmware image (.bin) built with the bootloader feature")]
+    image: Option<PathBuf>,
+    #[arg(long, help = "Only show the target's mode and version")]
+    query: bool,
+    #[arg(long, help = "Allow installing an older version")]
+    force: bool,
+}
+
+fn format_version(v: u32) -> String {
+    format!("{}.{}.{}", (v >> 16) & 0xff, (v >> 8) & 0xff, v & 0xff)
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    stderrlog::new()
+        .verbosity(log::LevelFilter::Info)
+        .module(module_path!())
+        .module("cantools")
+        .init()
+        .unwrap();
+
+    let mut can = match SocketCan::open(&cli.interface) {
+        Ok(can) => can,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", cli.interface, e);
+            std::process::exit(1);
+        }
+    };
+    let mut client = UpdateClient::new(&mut can);
+
+    if cli.query {
+        match client.query() {
+            Ok(info) => {
+                println!(
+                    "{:?}, version {}, max image size {} bytes",
+                    info.mode,
+                    format_version(info.version),
+                    info.max_size
+                );
+                return;
+            }
+            Err(e) => {
+                eprintln!("Query failed: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let Some(path) = cli.image else {
+        eprintln!("No image given");
+        std::process::exit(1);
+    };
+    let image = match std::fs::read(&path) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut last_percent = None;
+    let result = client.update(&image, cli.force, &mut |written, total| {
+        let percent = written as u64 * 100 / total as u64;
+        if last_percent != Some(percent) {
+            last_percent = Some(percent);
+            eprint!("\r{}% ({} / {} bytes)", percent, written, total);
+            let _ = std::io::stderr().flush();
+        }
+    });
+    eprintln!();
+    match result {
+        Ok(()) => println!("Update successful"),
+        Err(e) => {
+            eprintln!("Update failed: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use common::CanPort;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::io;
+
+// A CAN interface. Implemented by SocketCan, and by simulated targets in
+// tests.
+pub trait CanBus {
+    fn millis(&mut self) -> u64;
+    fn send(&mut self, frame: &bxcan::Frame) -> io::Result<()>;
+    // Waits up to timeout_ms for a frame
+    fn receive(&mut self, timeout_ms: u64) -> io::Result<Option<bxcan::Frame>>;
+}
+
+// CanPort on top of a CanBus, so that protocol implementations from common can
+// be used on the desktop
+pub struct BusHw<'a> {
+    pub bus: &'a mut dyn CanBus,
+}
+
+impl<'a> BusHw<'a> {
+    pub fn new(bus: &'a mut dyn CanBus) -> Self {
+        Self { bus }
+    }
+}
+
+impl CanPort for BusHw<'_> {
+    fn millis(&mut self) -> u64 {
+        self.bus.millis()
+    }
+
+    fn send_can(&mut self, frame: bxcan::Frame) {
+        if let Err(e) = self.bus.send(&frame) {
+            warn!("Failed to send CAN frame: {}", e);
+        }
+    }
+}
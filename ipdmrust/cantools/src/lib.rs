@@ -0,0 +1,5 @@
+// Desktop tools for talking to an iPDM56 over SocketCAN
+
+pub mod bus;
+pub mod socketcan;
+pub mod update;
@@ -0,0 +1,140 @@
+use crate::bus::CanBus;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::ffi::CString;
+use std::io;
+use std::time::Instant;
+
+// Minimal raw SocketCAN interface (Linux only)
+
+pub struct SocketCan {
+    fd: libc::c_int,
+    start: Instant,
+}
+
+impl CanBus for SocketCan {
+    fn millis(&mut self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn send(&mut self, frame: &bxcan::Frame) -> io::Result<()> {
+        SocketCan::send(self, frame)
+    }
+
+    fn receive(&mut self, timeout_ms: u64) -> io::Result<Option<bxcan::Frame>> {
+        SocketCan::receive(self, timeout_ms)
+    }
+}
+
+impl SocketCan {
+    // Opens a raw CAN socket bound to the given interface, e.g. "can0"
+    pub fn open(interface: &str) -> io::Result<Self> {
+        let name = CString::new(interface)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid interface name"))?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { libc::socket(libc::PF_CAN, libc::SOCK_RAW, libc::CAN_RAW) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = Self {
+            fd,
+            start: Instant::now(),
+        };
+        let mut addr: libc::sockaddr_can = unsafe { std::mem::zeroed() };
+        addr.can_family = libc::AF_CAN as libc::sa_family_t;
+        addr.can_ifindex = ifindex as libc::c_int;
+        let result = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_can as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_can>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    pub fn send(&self, frame: &bxcan::Frame) -> io::Result<()> {
+        let mut raw: libc::can_frame = unsafe { std::mem::zeroed() };
+        raw.can_id = match frame.id() {
+            bxcan::Id::Standard(id) => id.as_raw() as u32,
+            bxcan::Id::Extended(id) => id.as_raw() | libc::CAN_EFF_FLAG,
+        };
+        if frame.is_remote_frame() {
+            raw.can_id |= libc::CAN_RTR_FLAG;
+        }
+        raw.can_dlc = frame.dlc();
+        if let Some(data) = frame.data() {
+            raw.data[..data.len()].copy_from_slice(data);
+        }
+        let size = std::mem::size_of::<libc::can_frame>();
+        let n = unsafe {
+            libc::write(
+                self.fd,
+                &raw as *const libc::can_frame as *const libc::c_void,
+                size,
+            )
+        };
+        if n != size as isize {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Waits up to timeout for a frame. Error frames are skipped.
+    pub fn receive(&self, timeout_ms: u64) -> io::Result<Option<bxcan::Frame>> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let result = unsafe { libc::poll(&mut pollfd, 1, timeout_ms as libc::c_int) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if result == 0 {
+            return Ok(None);
+        }
+        let mut raw: libc::can_frame = unsafe { std::mem::zeroed() };
+        let size = std::mem::size_of::<libc::can_frame>();
+        let n = unsafe {
+            libc::read(
+                self.fd,
+                &mut raw as *mut libc::can_frame as *mut libc::c_void,
+                size,
+            )
+        };
+        if n != size as isize {
+            return Err(io::Error::last_os_error());
+        }
+        if raw.can_id & libc::CAN_ERR_FLAG != 0 {
+            return Ok(None);
+        }
+        let id = if raw.can_id & libc::CAN_EFF_FLAG != 0 {
+            bxcan::Id::Extended(bxcan::ExtendedId::new(raw.can_id & libc::CAN_EFF_MASK).unwrap())
+        } else {
+            bxcan::Id::Standard(
+                bxcan::StandardId::new((raw.can_id & libc::CAN_SFF_MASK) as u16).unwrap(),
+            )
+        };
+        let dlc = raw.can_dlc.min(8);
+        if raw.can_id & libc::CAN_RTR_FLAG != 0 {
+            return Ok(Some(bxcan::Frame::new_remote(id, dlc)));
+        }
+        let data = bxcan::Data::new(&raw.data[..dlc as usize]).unwrap();
+        Ok(Some(bxcan::Frame::new_data(id, data)))
+    }
+}
+
+impl Drop for SocketCan {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::bus::{BusHw, CanBus};
+use common::can_update::*;
+use common::isotp::{IsoTp, IsoTpConfig};
+use common::CanPort;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::io;
+
+// Host side of the firmware update protocol (see common::can_update)
+
+const QUERY_TIMEOUT_MS: u64 = 500;
+// Erasing flash in the bootloader can take several seconds
+const REQUEST_UPDATE_TIMEOUT_MS: u64 = 20000;
+const DATA_TIMEOUT_MS: u64 = 1000;
+const FINISH_TIMEOUT_MS: u64 = 5000;
+// Time given to the target to reset into the bootloader or the application
+const RESET_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Debug)]
+pub enum HostError {
+    Io(io::Error),
+    Timeout,
+    Rejected(UpdateError),
+    // The target sent something that doesn't follow the protocol
+    Protocol,
+    // The image has no firmware info block
+    NoFirmwareInfo,
+    // The target came back with a different version than was written
+    VersionMismatch { expected: u32, actual: u32 },
+}
+
+impl From<io::Error> for HostError {
+    fn from(e: io::Error) -> Self {
+        HostError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TargetInfo {
+    pub mode: UpdateMode,
+    pub version: u32,
+    pub max_size: u32,
+}
+
+pub struct UpdateClient<'a> {
+    hw: BusHw<'a>,
+    isotp: IsoTp<MAX_MESSAGE_LEN>,
+}
+
+impl<'a> UpdateClient<'a> {
+    pub fn new(bus: &'a mut dyn CanBus) -> Self {
+        Self {
+            hw: BusHw::new(bus),
+            isotp: IsoTp::new(IsoTpConfig::new(UPDATE_REQUEST_ID, UPDATE_RESPONSE_ID)),
+        }
+    }
+
+    // Sends a request and waits for its response
+    fn request(&mut self, request: &[u8], timeout_ms: u64) -> Result<Vec<u8>, HostError> {
+        // Drop anything left over from an earlier request
+        self.isotp.take_received();
+        self.isotp.take_error();
+        self.isotp.send(request).map_err(|_| HostError::Protocol)?;
+        let start_ms = self.hw.millis();
+        loop {
+            self.isotp.update(&mut self.hw);
+            if let Some(frame) = self.hw.bus.receive(1)? {
+                let millis = self.hw.millis();
+                self.isotp.on_can(&frame, millis);
+            }
+            if let Some(error) = self.isotp.take_error() {
+                debug!("ISO-TP error: {:?}", error);
+            }
+            if let Some(response) = self.isotp.take_received() {
+                return match response.first() {
+                    Some(&NEGATIVE_RESPONSE)
+                        if response.len() >= 3 && response[1] == request[0] =>
+                    {
+                        Err(UpdateError::from_u8(response[2])
+                            .map_or(HostError::Protocol, HostError::Rejected))
+                    }
+                    Some(&r) if r == request[0] + POSITIVE_RESPONSE_OFFSET => Ok(response.to_vec()),
+                    _ => Err(HostError::Protocol),
+                };
+            }
+            if self.hw.millis().saturating_sub(start_ms) >= timeout_ms {
+                return Err(HostError::Timeout);
+            }
+        }
+    }
+
+    pub fn query(&mut self) -> Result<TargetInfo, HostError> {
+        let r = self.request(&[CMD_QUERY], QUERY_TIMEOUT_MS)?;
+        if r.len() != 10 {
+            return Err(HostError::Protocol);
+        }
+        Ok(TargetInfo {
+            mode: UpdateMode::from_u8(r[1]).ok_or(HostError::Protocol)?,
+            version: u32::from_be_bytes([r[2], r[3], r[4], r[5]]),
+            max_size: u32::from_be_bytes([r[6], r[7], r[8], r[9]]),
+        })
+    }
+
+    // Queries until the target answers in the given mode
+    fn wait_for_mode(&mut self, mode: UpdateMode) -> Result<TargetInfo, HostError> {
+        let start_ms = self.hw.millis();
+        loop {
+            match self.query() {
+                Ok(info) if info.mode == mode => return Ok(info),
+                Ok(_) | Err(HostError::Timeout) => {}
+                Err(e) => return Err(e),
+            }
+            if self.hw.millis().saturating_sub(start_ms) >= RESET_TIMEOUT_MS {
+                return Err(HostError::Timeout);
+            }
+        }
+    }
+
+    fn request_update(
+        &mut self,
+        size: u32,
+        crc: u32,
+        version: u32,
+        force: bool,
+    ) -> Result<UpdateMode, HostError> {
+        let mut request = vec![CMD_REQUEST_UPDATE];
+        request.extend_from_slice(&size.to_be_bytes());
+        request.extend_from_slice(&crc.to_be_bytes());
+        request.extend_from_slice(&version.to_be_bytes());
+        request.push(if force { FLAG_FORCE } else { 0 });
+        let r = self.request(&request, REQUEST_UPDATE_TIMEOUT_MS)?;
+        r.get(1)
+            .and_then(|&m| UpdateMode::from_u8(m))
+            .ok_or(HostError::Protocol)
+    }
+
+    // Writes the image and waits for the target to start it. progress is
+    // called with (bytes written, total).
+    pub fn update(
+        &mut self,
+        image: &[u8],
+        force: bool,
+        progress: &mut dyn FnMut(u32, u32),
+    ) -> Result<(), HostError> {
+        let version = find_firmware_version(image).ok_or(HostError::NoFirmwareInfo)?;
+        let size = image.len() as u32;
+        let crc = crc32(image);
+
+        let info = self.query()?;
+        info!(
+            "Target: {:?}, version {:#08x}, max size {}",
+            info.mode, info.version, info.max_size
+        );
+        info!(
+            "Image: {} bytes, CRC {:#010x}, version {:#08x}",
+            size, crc, version
+        );
+
+        if self.request_update(size, crc, version, force)? == UpdateMode::Application {
+            info!("Waiting for the bootloader");
+            self.wait_for_mode(UpdateMode::Bootloader)?;
+            self.request_update(size, crc, version, force)?;
+        }
+
+        let mut offset = 0u32;
+        for chunk in image.chunks(DATA_CHUNK_SIZE) {
+            let mut request = vec![CMD_DATA];
+            request.extend_from_slice(&offset.to_be_bytes());
+            request.extend_from_slice(chunk);
+            let r = self.request(&request, DATA_TIMEOUT_MS)?;
+            let next = r
+                .get(1..5)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or(HostError::Protocol)?;
+            if next != offset + chunk.len() as u32 {
+                return Err(HostError::Protocol);
+            }
+            offset = next;
+            progress(offset, size);
+        }
+
+        self.request(&[CMD_FINISH], FINISH_TIMEOUT_MS)?;
+
+        info!("Image written. Waiting for the application");
+        let info = self.wait_for_mode(UpdateMode::Application)?;
+        if info.version != version {
+            return Err(HostError::VersionMismatch {
+                expected: version,
+                actual: info.version,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn abort(&mut self) -> Result<(), HostError> {
+        self.request(&[CMD_ABORT], QUERY_TIMEOUT_MS).map(|_| ())
+    }
+}
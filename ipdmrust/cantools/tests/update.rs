@@ -0,0 +1,402 @@
+// Firmware update host against a simulated target
+
+use cantools::bus::{BusHw, CanBus};
+use cantools::update::{HostError, UpdateClient};
+use common::can_update::*;
+use common::isotp::{IsoTp, IsoTpConfig};
+use common::*;
+use std::collections::VecDeque;
+use std::io;
+
+const APP_VERSION: u32 = version_from_parts(1, 2, 0);
+const MAX_SIZE: u32 = 16 * 1024;
+
+struct RamFlash {
+    data: Vec<u8>,
+    record: Option<ImageRecord>,
+}
+
+impl UpdateFlash for RamFlash {
+    fn erase(&mut self, size: u32) -> Result<(), UpdateFlashError> {
+        self.record = None;
+        self.data[..size as usize].fill(0xff);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), UpdateFlashError> {
+        self.data[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&mut self, size: u32) -> &[u8] {
+        &self.data[..size as usize]
+    }
+
+    fn mark_valid(&mut self, record: &ImageRecord) -> Result<(), UpdateFlashError> {
+        self.record = Some(*record);
+        Ok(())
+    }
+}
+
+struct SimHw {
+    millis: u64,
+    sent: VecDeque<bxcan::Frame>,
+}
+
+impl CanPort for SimHw {
+    fn millis(&mut self) -> u64 {
+        self.millis
+    }
+
+    fn send_can(&mut self, frame: bxcan::Frame) {
+        self.sent.push_back(frame);
+    }
+}
+
+// A target that runs either the application or the bootloader, like the real
+// one does after a reset
+struct SimTarget {
+    hw: SimHw,
+    target: UpdateTarget,
+    flash: RamFlash,
+    app_supports_update: bool,
+    handoff: bool,
+    resets: u32,
+    // Test hooks
+    corrupt_data_at: Option<u32>,
+    drop_after_data_frames: Option<u32>,
+    data_frames: u32,
+}
+
+impl SimTarget {
+    fn new(app_supports_update: bool) -> Self {
+        let mut sim = Self {
+            hw: SimHw {
+                millis: 0,
+                sent: VecDeque::new(),
+            },
+            target: UpdateTarget::new(app_config(APP_VERSION, true)),
+            flash: RamFlash {
+                data: vec![0xff; MAX_SIZE as usize],
+                record: Some(ImageRecord {
+                    size: 0,
+                    crc: 0,
+                    version: APP_VERSION,
+                }),
+            },
+            app_supports_update,
+            handoff: false,
+            resets: 0,
+            corrupt_data_at: None,
+            drop_after_data_frames: None,
+            data_frames: 0,
+        };
+        sim.reset();
+        sim
+    }
+
+    fn mode(&self) -> UpdateMode {
+        self.target.config.mode
+    }
+
+    // What the bootloader does on startup
+    fn reset(&mut self) {
+        self.resets += 1;
+        let record = self.flash.record;
+        if let (Some(record), false) = (record, self.handoff) {
+            self.target = UpdateTarget::new(app_config(record.version, self.app_supports_update));
+        } else {
+            self.handoff = false;
+            self.target = UpdateTarget::new(UpdateTargetConfig {
+                mode: UpdateMode::Bootloader,
+                version: record.map_or(0, |r| r.version),
+                max_size: MAX_SIZE,
+                fallback_timeout_ms: 10000,
+                image_valid: record.is_some(),
+            });
+        }
+    }
+
+    fn step(&mut self) {
+        self.hw.millis += 1;
+        let flash: Option<&mut dyn UpdateFlash> = match self.mode() {
+            UpdateMode::Bootloader => Some(&mut self.flash),
+            UpdateMode::Application => None,
+        };
+        match self.target.update(&mut self.hw, flash) {
+            Some(UpdateEvent::EnterBootloader) => {
+                self.handoff = true;
+                self.reset();
+            }
+            Some(UpdateEvent::StartApplication) => self.reset(),
+            None => {}
+        }
+    }
+}
+
+fn app_config(version: u32, supports_update: bool) -> UpdateTargetConfig {
+    UpdateTargetConfig {
+        mode: UpdateMode::Application,
+        version,
+        max_size: if supports_update { MAX_SIZE } else { 0 },
+        fallback_timeout_ms: 0,
+        image_valid: true,
+    }
+}
+
+impl CanBus for SimTarget {
+    fn millis(&mut self) -> u64 {
+        self.hw.millis
+    }
+
+    fn send(&mut self, frame: &bxcan::Frame) -> io::Result<()> {
+        let mut frame = frame.clone();
+        let data = frame.data().map_or(&[][..], |d| &d[..]).to_vec();
+        // Consecutive frames carry the image data. Single and first frames of
+        // Data requests are left alone.
+        if data.first().is_some_and(|b| b & 0xf0 == 0x20) {
+            self.data_frames += 1;
+            if self
+                .drop_after_data_frames
+                .is_some_and(|n| self.data_frames > n)
+            {
+                return Ok(());
+            }
+            if self.corrupt_data_at == Some(self.data_frames) {
+                let mut data = data.clone();
+                data[7] ^= 0x55;
+                frame = bxcan::Frame::new_data(frame.id(), bxcan::Data::new(&data).unwrap());
+            }
+        }
+        let millis = self.hw.millis;
+        self.target.on_can(&frame, millis);
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout_ms: u64) -> io::Result<Option<bxcan::Frame>> {
+        let end_ms = self.hw.millis + timeout_ms.max(1);
+        while self.hw.millis < end_ms {
+            if let Some(frame) = self.hw.sent.pop_front() {
+                return Ok(Some(frame));
+            }
+            self.step();
+        }
+        Ok(self.hw.sent.pop_front())
+    }
+}
+
+fn make_image(size: usize, version: u32) -> Vec<u8> {
+    let mut image: Vec<u8> = (0..size).map(|i| (i * 7 + i / 256) as u8).collect();
+    image[100..112].copy_from_slice(&firmware_info(version));
+    image
+}
+
+fn no_progress(_: u32, _: u32) {}
+
+fn request_update(image: &[u8], version: u32) -> Vec<u8> {
+    let mut request = vec![CMD_REQUEST_UPDATE];
+    request.extend_from_slice(&(image.len() as u32).to_be_bytes());
+    request.extend_from_slice(&crc32(image).to_be_bytes());
+    request.extend_from_slice(&version.to_be_bytes());
+    request.push(0);
+    request
+}
+
+// Sends a request without the checks done by UpdateClient and returns the
+// response
+fn raw_request(sim: &mut SimTarget, request: &[u8]) -> Vec<u8> {
+    let mut isotp: IsoTp<MAX_MESSAGE_LEN> =
+        IsoTp::new(IsoTpConfig::new(UPDATE_REQUEST_ID, UPDATE_RESPONSE_ID));
+    isotp.send(request).unwrap();
+    let mut hw = BusHw::new(sim);
+    for _ in 0..1000 {
+        isotp.update(&mut hw);
+        if let Some(frame) = hw.bus.receive(1).unwrap() {
+            let millis = hw.millis();
+            isotp.on_can(&frame, millis);
+        }
+        if let Some(response) = isotp.take_received() {
+            return response.to_vec();
+        }
+    }
+    panic!("No response");
+}
+
+#[test]
+fn update_through_bootloader_handoff() {
+    let mut sim = SimTarget::new(true);
+    let image = make_image(5000, version_from_parts(1, 3, 0));
+    let mut progress = Vec::new();
+    let result =
+        UpdateClient::new(&mut sim).update(&image, false, &mut |w, t| progress.push((w, t)));
+    assert!(result.is_ok(), "{:?}", result);
+    assert_eq!(sim.mode(), UpdateMode::Application);
+    assert_eq!(sim.target.config.version, version_from_parts(1, 3, 0));
+    assert_eq!(&sim.flash.data[..image.len()], &image[..]);
+    assert_eq!(
+        sim.flash.record,
+        Some(ImageRecord {
+            size: 5000,
+            crc: crc32(&image),
+            version: version_from_parts(1, 3, 0),
+        })
+    );
+    assert_eq!(progress.last(), Some(&(5000, 5000)));
+}
+
+#[test]
+fn query_reports_mode_and_version() {
+    let mut sim = SimTarget::new(true);
+    let info = UpdateClient::new(&mut sim).query().unwrap();
+    assert_eq!(info.mode, UpdateMode::Application);
+    assert_eq!(info.version, APP_VERSION);
+    assert_eq!(info.max_size, MAX_SIZE);
+}
+
+#[test]
+fn older_version_needs_force() {
+    let mut sim = SimTarget::new(true);
+    let image = make_image(1000, version_from_parts(1, 1, 0));
+    let result = UpdateClient::new(&mut sim).update(&image, false, &mut no_progress);
+    assert!(matches!(
+        result,
+        Err(HostError::Rejected(UpdateError::OldVersion))
+    ));
+    // Rejected by the application, so nothing was touched
+    assert_eq!(sim.mode(), UpdateMode::Application);
+    assert_eq!(sim.resets, 1);
+
+    let result = UpdateClient::new(&mut sim).update(&image, true, &mut no_progress);
+    assert!(result.is_ok(), "{:?}", result);
+    assert_eq!(sim.target.config.version, version_from_parts(1, 1, 0));
+}
+
+#[test]
+fn too_large_image_is_rejected() {
+    let mut sim = SimTarget::new(true);
+    let image = make_image(MAX_SIZE as usize + 1, version_from_parts(2, 0, 0));
+    let result = UpdateClient::new(&mut sim).update(&image, false, &mut no_progress);
+    assert!(matches!(
+        result,
+        Err(HostError::Rejected(UpdateError::TooLarge))
+    ));
+    assert_eq!(sim.mode(), UpdateMode::Application);
+}
+
+#[test]
+fn image_without_firmware_info_is_refused() {
+    let mut sim = SimTarget::new(true);
+    let image = vec![0u8; 1000];
+    let result = UpdateClient::new(&mut sim).update(&image, false, &mut no_progress);
+    assert!(matches!(result, Err(HostError::NoFirmwareInfo)));
+}
+
+#[test]
+fn application_without_bootloader_refuses() {
+    let mut sim = SimTarget::new(false);
+    let image = make_image(1000, version_from_parts(2, 0, 0));
+    let result = UpdateClient::new(&mut sim).update(&image, false, &mut no_progress);
+    assert!(matches!(
+        result,
+        Err(HostError::Rejected(UpdateError::NotSupported))
+    ));
+}
+
+#[test]
+fn corrupted_transfer_stays_in_bootloader_until_retried() {
+    let mut sim = SimTarget::new(true);
+    let image = make_image(3000, version_from_parts(1, 3, 0));
+    sim.corrupt_data_at = Some(20);
+    let result = UpdateClient::new(&mut sim).update(&image, false, &mut no_progress);
+    assert!(matches!(
+        result,
+        Err(HostError::Rejected(UpdateError::CrcMismatch))
+    ));
+    assert_eq!(sim.flash.record, None);
+
+    // No valid image, so the bootloader never gives up waiting
+    for _ in 0..30000 {
+        sim.step();
+    }
+    assert_eq!(sim.mode(), UpdateMode::Bootloader);
+
+    sim.corrupt_data_at = None;
+    let result = UpdateClient::new(&mut sim).update(&image, false, &mut no_progress);
+    assert!(result.is_ok(), "{:?}", result);
+    assert_eq!(sim.mode(), UpdateMode::Application);
+    assert_eq!(&sim.flash.data[..image.len()], &image[..]);
+}
+
+#[test]
+fn interrupted_transfer_can_be_restarted() {
+    let mut sim = SimTarget::new(true);
+    let image = make_image(4000, version_from_parts(1, 3, 0));
+    sim.drop_after_data_frames = Some(100);
+    let result = UpdateClient::new(&mut sim).update(&image, false, &mut no_progress);
+    assert!(matches!(result, Err(HostError::Timeout)));
+    assert_eq!(sim.mode(), UpdateMode::Bootloader);
+    assert_eq!(sim.flash.record, None);
+
+    sim.drop_after_data_frames = None;
+    let result = UpdateClient::new(&mut sim).update(&image, false, &mut no_progress);
+    assert!(result.is_ok(), "{:?}", result);
+    assert_eq!(sim.target.config.version, version_from_parts(1, 3, 0));
+}
+
+#[test]
+fn bootloader_returns_to_application_if_host_goes_away() {
+    let mut sim = SimTarget::new(true);
+    // The application accepts the request and hands off, but the host never
+    // continues
+    let image = make_image(1000, version_from_parts(1, 3, 0));
+    let request = request_update(&image, version_from_parts(1, 3, 0));
+    assert_eq!(
+        raw_request(&mut sim, &request),
+        vec![0x42, UpdateMode::Application as u8]
+    );
+    for _ in 0..500 {
+        sim.step();
+    }
+    assert_eq!(sim.mode(), UpdateMode::Bootloader);
+
+    for _ in 0..11000 {
+        sim.step();
+    }
+    assert_eq!(sim.mode(), UpdateMode::Application);
+    assert_eq!(sim.target.config.version, APP_VERSION);
+}
+
+#[test]
+fn bootloader_checks_firmware_info_version() {
+    let mut sim = SimTarget::new(true);
+    sim.handoff = true;
+    sim.reset();
+    assert_eq!(sim.mode(), UpdateMode::Bootloader);
+
+    // Requested as 1.4.0, but the image says 1.3.0
+    let image = make_image(1000, version_from_parts(1, 3, 0));
+    let request = request_update(&image, version_from_parts(1, 4, 0));
+    assert_eq!(
+        raw_request(&mut sim, &request),
+        vec![0x42, UpdateMode::Bootloader as u8]
+    );
+    for (i, chunk) in image.chunks(DATA_CHUNK_SIZE).enumerate() {
+        let mut data = vec![CMD_DATA];
+        data.extend_from_slice(&((i * DATA_CHUNK_SIZE) as u32).to_be_bytes());
+        data.extend_from_slice(chunk);
+        assert_eq!(raw_request(&mut sim, &data)[0], 0x43);
+    }
+    assert_eq!(
+        raw_request(&mut sim, &[CMD_FINISH]),
+        vec![
+            NEGATIVE_RESPONSE,
+            CMD_FINISH,
+            UpdateError::VersionMismatch as u8
+        ]
+    );
+    assert_eq!(sim.flash.record, None);
+    for _ in 0..11000 {
+        sim.step();
+    }
+    assert_eq!(sim.mode(), UpdateMode::Bootloader);
+}
@@ -0,0 +1,141 @@
+use crate::isotp::{IsoTp, IsoTpConfig};
+use crate::HardwareInterface;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
+
+// Text console tunneled over CAN
+//
+// Console input and output are carried in ISO-TP messages on a pair of CAN
+// IDs. The client sends console input to rx_id and receives log output from
+// tx_id.
+//
+// A client is considered connected for session_timeout_ms after any message
+// from it. Log output is only sent while a client is connected. Clients send
+// KEEPALIVE (a message consisting of a single zero byte) when there is no
+// input to send.
+//
+// N is the size of the output buffer. Output that doesn't fit is dropped and
+// replaced with a marker.
+
+pub const KEEPALIVE: u8 = 0x00;
+
+// Maximum length of a message in either direction
+pub const MAX_MESSAGE_LEN: usize = 128;
+const INPUT_BUF_SIZE: usize = MAX_MESSAGE_LEN;
+
+const OVERFLOW_MARKER: &str = " | CAN CONSOLE BUFFER FULL\r\n";
+
+#[derive(Debug, Clone, Copy)]
+pub struct CanConsoleConfig {
+    // Client to us
+    pub rx_id: bxcan::Id,
+    // Us to client
+    pub tx_id: bxcan::Id,
+    pub session_timeout_ms: u64,
+}
+
+pub struct CanConsole<const N: usize> {
+    pub config: CanConsoleConfig,
+    isotp: IsoTp<MAX_MESSAGE_LEN>,
+    output: ConstGenericRingBuffer<u8, N>,
+    overflowed: bool,
+    input: ConstGenericRingBuffer<u8, INPUT_BUF_SIZE>,
+    last_client_ms: Option<u64>,
+}
+
+impl<const N: usize> CanConsole<N> {
+    pub fn new(config: CanConsoleConfig) -> Self {
+        Self {
+            config,
+            isotp: IsoTp::new(IsoTpConfig::new(config.tx_id, config.rx_id)),
+            output: ConstGenericRingBuffer::new(),
+            overflowed: false,
+            input: ConstGenericRingBuffer::new(),
+            last_client_ms: None,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.last_client_ms.is_some()
+    }
+
+    // Returns true if the frame was consumed
+    pub fn on_can(&mut self, frame: &bxcan::Frame, millis: u64) -> bool {
+        self.isotp.on_can(frame, millis)
+    }
+
+    // Queues output for the client. Does nothing if no client is connected.
+    pub fn write_output(&mut self, s: &str) {
+        if !self.is_connected() {
+            return;
+        }
+        if self.overflowed {
+            if self.output.capacity() - self.output.len() < OVERFLOW_MARKER.len() + s.len() {
+                return;
+            }
+            self.output.extend(OVERFLOW_MARKER.bytes());
+            self.overflowed = false;
+        }
+        if self.output.capacity() - self.output.len() < s.len() {
+            self.overflowed = true;
+            return;
+        }
+        self.output.extend(s.bytes());
+    }
+
+    // Returns the next byte of console input from the client
+    pub fn read_input(&mut self) -> Option<u8> {
+        self.input.dequeue()
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        let millis = hw.millis();
+
+        if let Some(error) = self.isotp.take_error() {
+            debug!("CAN console: ISO-TP error: {:?}", error);
+        }
+
+        if let Some(message) = self.isotp.take_received() {
+            if !self.is_connected() {
+                info!("-!- CAN console: Client connected");
+            }
+            self.last_client_ms = Some(millis);
+            if message[..] != [KEEPALIVE] {
+                for &b in message.iter() {
+                    if self.input.is_full() {
+                        warn!("-!- CAN console: Input buffer full");
+                        break;
+                    }
+                    self.input.push(b);
+                }
+            }
+        }
+
+        if self
+            .last_client_ms
+            .is_some_and(|t| millis.saturating_sub(t) >= self.config.session_timeout_ms)
+        {
+            self.last_client_ms = None;
+            self.output.clear();
+            self.overflowed = false;
+            info!("-!- CAN console: Client disconnected");
+        }
+
+        if self.isotp.is_tx_idle() && !self.output.is_empty() {
+            let mut chunk = [0u8; MAX_MESSAGE_LEN];
+            let mut len = 0;
+            while len < MAX_MESSAGE_LEN {
+                let Some(b) = self.output.dequeue() else {
+                    break;
+                };
+                chunk[len] = b;
+                len += 1;
+            }
+            let _ = self.isotp.send(&chunk[..len]);
+        }
+
+        self.isotp.update(hw);
+    }
+}
@@ -0,0 +1,251 @@
+use arrayvec::ArrayVec;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Rolling counters and checksums ("alive counters") in CAN frames
+//
+// On TX, FrameIntegrity::apply() writes the counter and then the checksum into
+// the frame data. On RX, RxIntegrityChecker validates both and detects senders
+// that are stuck repeating the same counter value.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumKind {
+    // XOR of all other bytes
+    Xor,
+    // Sum of all other bytes, truncated to 8 bits
+    Sum,
+    // CRC-8 SAE J1850 (poly 0x1D, init 0xFF, xorout 0xFF)
+    Crc8SaeJ1850,
+    // CRC-8 AUTOSAR / CRC-8H2F (poly 0x2F, init 0xFF, xorout 0xFF)
+    Crc8Autosar,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CounterField {
+    pub byte: u8,
+    // Position of the least significant bit within the byte
+    pub shift: u8,
+    // Usually 2 or 4
+    pub bits: u8,
+}
+
+impl CounterField {
+    pub const fn modulo(&self) -> u16 {
+        1u16 << self.bits
+    }
+
+    fn mask(&self) -> u8 {
+        (((1u16 << self.bits) - 1) as u8) << self.shift
+    }
+
+    pub fn read(&self, data: &[u8]) -> u8 {
+        (data[self.byte as usize] & self.mask()) >> self.shift
+    }
+
+    pub fn write(&self, data: &mut [u8], value: u8) {
+        let byte = &mut data[self.byte as usize];
+        *byte = (*byte & !self.mask()) | ((value << self.shift) & self.mask());
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChecksumField {
+    pub byte: u8,
+    pub kind: ChecksumKind,
+    // Fed into the calculation before the data. Some OEMs put (part of) the
+    // CAN ID here.
+    pub seed: Option<u8>,
+}
+
+impl ChecksumField {
+    pub fn calculate(&self, data: &[u8]) -> u8 {
+        let skip = self.byte as usize;
+        let bytes = self.seed.into_iter().chain(
+            data.iter()
+                .enumerate()
+                .filter(|(i, _)| *i != skip)
+                .map(|(_, b)| *b),
+        );
+        match self.kind {
+            ChecksumKind::Xor => bytes.fold(0, |acc, b| acc ^ b),
+            ChecksumKind::Sum => bytes.fold(0, |acc, b| acc.wrapping_add(b)),
+            ChecksumKind::Crc8SaeJ1850 => crc8(0x1d, 0xff, 0xff, bytes),
+            ChecksumKind::Crc8Autosar => crc8(0x2f, 0xff, 0xff, bytes),
+        }
+    }
+}
+
+pub fn crc8(poly: u8, init: u8, xorout: u8, bytes: impl Iterator<Item = u8>) -> u8 {
+    let mut crc = init;
+    for b in bytes {
+        crc ^= b;
+        for _ in 0..8 {
+            if crc & 0x80 != 0 {
+                crc = (crc << 1) ^ poly;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc ^ xorout
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameIntegrity {
+    pub counter: Option<CounterField>,
+    pub checksum: Option<ChecksumField>,
+}
+
+impl FrameIntegrity {
+    // Writes the counter and the checksum into data
+    pub fn apply(&self, data: &mut [u8], counter: u8) {
+        if let Some(counter_field) = &self.counter {
+            counter_field.write(data, (counter as u16 % counter_field.modulo()) as u8);
+        }
+        if let Some(checksum_field) = &self.checksum {
+            data[checksum_field.byte as usize] = checksum_field.calculate(data);
+        }
+    }
+
+    // Returns a copy of the frame with the counter and checksum applied
+    pub fn apply_to_frame(&self, frame: &bxcan::Frame, counter: u8) -> Option<bxcan::Frame> {
+        let data = frame.data()?;
+        let mut buf = [0u8; 8];
+        let buf = &mut buf[..data.len()];
+        buf.copy_from_slice(data);
+        if !self.fits(buf.len()) {
+            warn!(
+                "-!- FrameIntegrity: Frame {:?} is too short ({} bytes)",
+                frame.id(),
+                buf.len()
+            );
+            return None;
+        }
+        self.apply(buf, counter);
+        Some(bxcan::Frame::new_data(frame.id(), bxcan::Data::new(buf)?))
+    }
+
+    fn fits(&self, len: usize) -> bool {
+        self.counter.is_none_or(|c| (c.byte as usize) < len)
+            && self.checksum.is_none_or(|c| (c.byte as usize) < len)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityError {
+    TooShort,
+    BadChecksum,
+    // The counter didn't change from the previous frame
+    CounterStuck,
+    // The counter skipped more values than allowed
+    CounterJump,
+}
+
+pub struct RxIntegrityCheck {
+    pub id: bxcan::Id,
+    pub integrity: FrameIntegrity,
+    // How many counter values may be skipped (lost frames) before the frame is
+    // considered invalid
+    pub max_counter_skip: u8,
+}
+
+struct RxIntegrityState {
+    check: RxIntegrityCheck,
+    last_counter: Option<u8>,
+    error_count: u32,
+    last_error: Option<IntegrityError>,
+}
+
+pub struct RxIntegrityChecker<const N: usize> {
+    checks: ArrayVec<RxIntegrityState, N>,
+}
+
+impl<const N: usize> Default for RxIntegrityChecker<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> RxIntegrityChecker<N> {
+    pub fn new() -> Self {
+        Self {
+            checks: ArrayVec::new(),
+        }
+    }
+
+    pub fn register(&mut self, check: RxIntegrityCheck) {
+        if self.checks.is_full() {
+            error!(
+                "-!- RxIntegrityChecker::register(): {:?}: Too many checks (max {})",
+                check.id, N
+            );
+            return;
+        }
+        self.checks.push(RxIntegrityState {
+            check,
+            last_counter: None,
+            error_count: 0,
+            last_error: None,
+        });
+    }
+
+    // Returns Err if the frame has a registered check and it fails. Frames
+    // without a registered check are always Ok.
+    pub fn check(&mut self, frame: &bxcan::Frame) -> Result<(), IntegrityError> {
+        let Some(state) = self.checks.iter_mut().find(|s| s.check.id == frame.id()) else {
+            return Ok(());
+        };
+        let result = Self::check_data(state, frame.data().map_or(&[], |d| &d[..]));
+        if let Err(e) = result {
+            if state.last_error != Some(e) {
+                warn!(
+                    "-!- CAN RX {:?}: Integrity check failed: {:?}",
+                    frame.id(),
+                    e
+                );
+            }
+            state.error_count += 1;
+            state.last_error = Some(e);
+        } else if state.last_error.is_some() {
+            info!("-!- CAN RX {:?}: Integrity check OK again", frame.id());
+            state.last_error = None;
+        }
+        result
+    }
+
+    fn check_data(state: &mut RxIntegrityState, data: &[u8]) -> Result<(), IntegrityError> {
+        let integrity = &state.check.integrity;
+        if !integrity.fits(data.len()) {
+            return Err(IntegrityError::TooShort);
+        }
+        if let Some(checksum_field) = &integrity.checksum {
+            if data[checksum_field.byte as usize] != checksum_field.calculate(data) {
+                return Err(IntegrityError::BadChecksum);
+            }
+        }
+        if let Some(counter_field) = &integrity.counter {
+            let counter = counter_field.read(data);
+            let last_counter = state.last_counter.replace(counter);
+            if let Some(last_counter) = last_counter {
+                let modulo = counter_field.modulo();
+                let delta = (counter as u16 + modulo - last_counter as u16) % modulo;
+                if delta == 0 {
+                    return Err(IntegrityError::CounterStuck);
+                }
+                if delta - 1 > state.check.max_counter_skip as u16 {
+                    return Err(IntegrityError::CounterJump);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn print_stats(&self) {
+        for state in &self.checks {
+            info!(
+                "* {:?}: errors {}, last error {:?}",
+                state.check.id, state.error_count, state.last_error
+            );
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::dtc::DtcStore;
+use core::ptr::addr_of_mut;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// CAN node alive supervision
+//
+// Each node is marked alive by receiving any of its CAN IDs and marked dead
+// when none of them have been received within the node's timeout. This is the
+// equivalent of MODULE_DEF(name, timeout_ms) in ipdmsw.
+//
+// A node can have a lost communication DTC, which fails when a node that has
+// been seen times out.
+
+pub struct CanNode<'a> {
+    pub id: usize,
+    pub name: &'a str,
+    pub can_ids: &'a [bxcan::Id],
+    pub timeout_ms: u64,
+    pub last_seen_ms: u64,
+    pub alive: bool,
+    pub seen: bool,
+    pub lost_dtc: Option<u32>,
+}
+
+impl<'a> CanNode<'a> {
+    pub fn is_alive(&self) -> bool {
+        self.alive
+    }
+
+    // The node has been seen but isn't alive anymore
+    pub fn is_lost(&self) -> bool {
+        self.seen && !self.alive
+    }
+
+    pub fn on_can(&mut self, id: bxcan::Id, millis: u64) {
+        if !self.can_ids.contains(&id) {
+            return;
+        }
+        self.last_seen_ms = millis;
+        self.seen = true;
+        if !self.alive {
+            self.alive = true;
+            info!("-!- {} alive", self.name);
+        }
+    }
+
+    pub fn run_timeout(&mut self, millis: u64) {
+        if self.timeout_ms == 0 || !self.alive {
+            return;
+        }
+        if millis.saturating_sub(self.last_seen_ms) >= self.timeout_ms {
+            self.alive = false;
+            warn!("-!- {} timed out", self.name);
+        }
+    }
+}
+
+pub static mut CAN_NODES: Option<&'static mut [CanNode<'static>]> = None;
+
+pub fn set_can_nodes(nodes: &'static mut [CanNode<'static>]) {
+    unsafe {
+        CAN_NODES = Some(nodes);
+    }
+}
+
+pub fn get_can_nodes() -> &'static mut [CanNode<'static>] {
+    unsafe {
+        (*addr_of_mut!(CAN_NODES))
+            .as_mut()
+            .expect("CAN nodes not initialized")
+    }
+}
+
+pub fn get_can_node_id(id: usize) -> &'static mut CanNode<'static> {
+    &mut get_can_nodes()[id]
+}
+
+pub fn update_can_nodes_on_can(frame: &bxcan::Frame, millis: u64) {
+    for node in get_can_nodes().iter_mut() {
+        node.on_can(frame.id(), millis);
+    }
+}
+
+// This should be called regularly
+pub fn timeout_can_nodes(millis: u64) {
+    for node in get_can_nodes().iter_mut() {
+        node.run_timeout(millis);
+    }
+}
+
+pub fn update_can_node_dtcs<const N: usize>(dtcs: &mut DtcStore<N>) {
+    for node in get_can_nodes().iter() {
+        if let Some(code) = node.lost_dtc {
+            dtcs.set(code, node.is_lost());
+        }
+    }
+}
+
+pub fn print_can_nodes(millis: u64) {
+    for node in get_can_nodes().iter() {
+        if node.alive {
+            info!(
+                "* {:>18}: alive (last seen {} ms ago)",
+                node.name,
+                millis.saturating_sub(node.last_seen_ms)
+            );
+        } else {
+            info!("* {:>18}: dead", node.name);
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! define_can_nodes {
+    ($($name:ident {
+        name: $display_name:expr,
+        can_ids: $can_ids:expr,
+        timeout_ms: $timeout_ms:expr,
+        $(lost_dtc: $lost_dtc:expr,)?
+    }),* $(,)?) => {
+        pub const NUM_CAN_NODES: usize = {
+            let mut count = 0;
+            $(let _ = stringify!($name); count += 1;)*
+            count
+        };
+
+        #[repr(usize)]
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub enum CanNodeId {
+            $($name),*
+        }
+
+        pub static mut CAN_NODES: [$crate::can_node::CanNode; NUM_CAN_NODES] = [
+            $(
+                $crate::can_node::CanNode {
+                    id: CanNodeId::$name as usize,
+                    name: $display_name,
+                    can_ids: $can_ids,
+                    timeout_ms: $timeout_ms,
+                    last_seen_ms: 0,
+                    alive: false,
+                    seen: false,
+                    lost_dtc: {
+                        #[allow(unused_variables)]
+                        let lost_dtc: Option<u32> = None;
+                        $(let lost_dtc = Some($lost_dtc);)?
+                        lost_dtc
+                    },
+                }
+            ),*
+        ];
+
+        // Accessor using CanNodeId enum
+        pub fn get_can_node(id: CanNodeId) -> &'static mut $crate::can_node::CanNode<'static> {
+            $crate::can_node::get_can_node_id(id as usize)
+        }
+
+        // Initialization function: Call this at start of main() or whatever
+        pub fn init_can_nodes() {
+            unsafe {
+                $crate::can_node::set_can_nodes(&mut CAN_NODES);
+            }
+        }
+    };
+}
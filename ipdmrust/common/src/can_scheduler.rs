@@ -0,0 +1,173 @@
+use crate::can_integrity::FrameIntegrity;
+use crate::HardwareInterface;
+use arrayvec::ArrayVec;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Periodic CAN transmit scheduler
+//
+// Each message is registered with a period and a phase offset. Transmissions
+// happen at offset_ms + N * period_ms. If more messages are due on the same
+// tick than max_frames_per_tick allows, the rest are deferred to the following
+// ticks. A message that is not sent before its next instance is due counts as
+// a missed deadline.
+
+pub struct PeriodicMessage {
+    pub name: &'static str,
+    pub period_ms: u64,
+    pub offset_ms: u64,
+    // The message is skipped while this returns false
+    pub enable: Option<fn() -> bool>,
+    // Returning None skips this instance without it counting as missed
+    pub encode: fn(&mut dyn HardwareInterface) -> Option<bxcan::Frame>,
+    // Rolling counter and checksum written into the encoded frame
+    pub integrity: Option<FrameIntegrity>,
+}
+
+struct ScheduledMessage {
+    message: PeriodicMessage,
+    next_due_ms: u64,
+    aligned: bool,
+    counter: u8,
+    sent_count: u32,
+    missed_deadlines: u32,
+}
+
+pub struct CanScheduler<const N: usize> {
+    messages: ArrayVec<ScheduledMessage, N>,
+    max_frames_per_tick: usize,
+    total_missed_deadlines: u32,
+}
+
+impl<const N: usize> CanScheduler<N> {
+    pub fn new(max_frames_per_tick: usize) -> Self {
+        Self {
+            messages: ArrayVec::new(),
+            max_frames_per_tick,
+            total_missed_deadlines: 0,
+        }
+    }
+
+    pub fn register(&mut self, message: PeriodicMessage) {
+        if message.period_ms == 0 {
+            error!(
+                "-!- CanScheduler::register(): {}: Period can't be 0",
+                message.name
+            );
+            return;
+        }
+        if self.messages.is_full() {
+            error!(
+                "-!- CanScheduler::register(): {}: Too many messages (max {})",
+                message.name, N
+            );
+            return;
+        }
+        self.messages.push(ScheduledMessage {
+            next_due_ms: message.offset_ms,
+            message,
+            aligned: false,
+            counter: 0,
+            sent_count: 0,
+            missed_deadlines: 0,
+        });
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        let millis = hw.millis();
+        let mut frames_sent = 0;
+
+        for m in self.messages.iter_mut() {
+            if !m.aligned {
+                // Start from the first slot at or after the current time
+                // instead of counting the time before the first update as
+                // missed deadlines
+                if m.next_due_ms < millis {
+                    m.next_due_ms = next_slot_after(m.next_due_ms, m.message.period_ms, millis - 1);
+                }
+                m.aligned = true;
+            }
+            if m.next_due_ms > millis {
+                continue;
+            }
+            if let Some(enable) = m.message.enable {
+                if !enable() {
+                    // Keep the phase while disabled so that re-enabling
+                    // doesn't cause a burst or missed deadlines
+                    m.next_due_ms = next_slot_after(m.next_due_ms, m.message.period_ms, millis);
+                }
+            }
+        }
+
+        // Serve the most overdue messages first
+        while frames_sent < self.max_frames_per_tick {
+            let Some(i) = self.earliest_due(millis) else {
+                break;
+            };
+
+            let m = &mut self.messages[i];
+            let late_ms = millis - m.next_due_ms;
+            if late_ms >= m.message.period_ms {
+                let missed = (late_ms / m.message.period_ms) as u32;
+                m.missed_deadlines += missed;
+                self.total_missed_deadlines += missed;
+                warn!(
+                    "-!- CAN TX {}: Missed {} deadline(s), {} ms late",
+                    m.message.name, missed, late_ms
+                );
+            }
+            m.next_due_ms = next_slot_after(m.next_due_ms, m.message.period_ms, millis);
+
+            let mut frame = (m.message.encode)(hw);
+            if let (Some(integrity), Some(f)) = (&m.message.integrity, &frame) {
+                frame = integrity.apply_to_frame(f, m.counter);
+                m.counter = m.counter.wrapping_add(1);
+            }
+            if let Some(frame) = frame {
+                hw.send_can(frame);
+                m.sent_count += 1;
+                frames_sent += 1;
+            }
+        }
+    }
+
+    fn earliest_due(&self, millis: u64) -> Option<usize> {
+        let mut earliest: Option<usize> = None;
+        for (i, m) in self.messages.iter().enumerate() {
+            if m.next_due_ms > millis {
+                continue;
+            }
+            match earliest {
+                Some(j) if self.messages[j].next_due_ms <= m.next_due_ms => {}
+                _ => earliest = Some(i),
+            }
+        }
+        earliest
+    }
+
+    pub fn total_missed_deadlines(&self) -> u32 {
+        self.total_missed_deadlines
+    }
+
+    pub fn print_stats(&self) {
+        for m in &self.messages {
+            info!(
+                "* {:>18}: {:>5} ms +{:>4} ms: sent {}, missed {}",
+                m.message.name,
+                m.message.period_ms,
+                m.message.offset_ms,
+                m.sent_count,
+                m.missed_deadlines
+            );
+        }
+    }
+}
+
+// Returns the first slot (base + N * period) that is later than millis
+fn next_slot_after(base_ms: u64, period_ms: u64, millis: u64) -> u64 {
+    if base_ms > millis {
+        return base_ms;
+    }
+    base_ms + ((millis - base_ms) / period_ms + 1) * period_ms
+}
@@ -0,0 +1,504 @@
+use crate::isotp::{IsoTp, IsoTpConfig};
+use crate::{standard_id, CanPort};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Firmware update over CAN
+//
+// The host talks to an UpdateTarget using ISO-TP on UPDATE_REQUEST_ID and
+// UPDATE_RESPONSE_ID. The same protocol is served by the application and by
+// the bootloader:
+// * The application only answers Query and RequestUpdate. An accepted
+//   RequestUpdate makes it hand off to the bootloader (UpdateEvent::
+//   EnterBootloader).
+// * The bootloader erases the application area on RequestUpdate, writes Data
+//   chunks in order, and on Finish checks the size, the CRC and the firmware
+//   info version of what was written before marking the image valid
+//   (UpdateEvent::StartApplication). It never starts an image that isn't
+//   marked valid, so an interrupted or corrupted transfer leaves the device
+//   waiting in the bootloader for another attempt.
+//
+// So the host sends RequestUpdate, and if the response says it came from the
+// application, waits until Query is answered by the bootloader and sends
+// RequestUpdate again.
+//
+// Messages (multi-byte values are big endian):
+// * Query: [0x01]
+//   -> [0x41, mode, version (4), max_size (4)]
+// * RequestUpdate: [0x02, size (4), crc32 (4), version (4), flags]
+//   -> [0x42, mode]
+// * Data: [0x03, offset (4), data (1..=DATA_CHUNK_SIZE)]
+//   -> [0x43, next offset (4)]
+// * Finish: [0x04]
+//   -> [0x44]
+// * Abort: [0x05]
+//   -> [0x45]
+// * Any request can be answered with [0x7F, request, UpdateError]
+//
+// Versions are major << 16 | minor << 8 | patch. An image older than the
+// running one is rejected unless FLAG_FORCE is set. The version of an image is
+// found from its firmware info block (see firmware_info()), and the bootloader
+// only accepts an image whose block matches the version it was requested with.
+
+pub const UPDATE_REQUEST_ID: bxcan::Id = standard_id(0x6e0);
+pub const UPDATE_RESPONSE_ID: bxcan::Id = standard_id(0x6e8);
+
+pub const DATA_CHUNK_SIZE: usize = 256;
+pub const MAX_MESSAGE_LEN: usize = 5 + DATA_CHUNK_SIZE;
+
+pub const CMD_QUERY: u8 = 0x01;
+pub const CMD_REQUEST_UPDATE: u8 = 0x02;
+pub const CMD_DATA: u8 = 0x03;
+pub const CMD_FINISH: u8 = 0x04;
+pub const CMD_ABORT: u8 = 0x05;
+pub const POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+pub const NEGATIVE_RESPONSE: u8 = 0x7f;
+
+// RequestUpdate flags
+pub const FLAG_FORCE: u8 = 0x01;
+
+// iPDM56 flash layout when using the bootloader:
+// * Sectors 0-1 (0x08000000, 32K): Bootloader
+// * Sector 2 (0x08008000, 16K): ImageRecord of the application
+// * Sector 3 (0x0800C000, 16K): Unused
+// * Sectors 4-7 (0x08010000, 448K): Application
+pub const BOOTLOADER_ADDRESS: u32 = 0x0800_0000;
+pub const IMAGE_RECORD_ADDRESS: u32 = 0x0800_8000;
+pub const APP_ADDRESS: u32 = 0x0801_0000;
+pub const APP_MAX_SIZE: u32 = 448 * 1024;
+
+// The application writes this to RTC backup register 0 before resetting to
+// make the bootloader wait for an update instead of starting the application
+pub const BOOTLOADER_HANDOFF_MAGIC: u32 = 0xB007_CA4E;
+
+// How long the response is given to go out before an event is reported
+const EVENT_DELAY_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum UpdateMode {
+    Application = 1,
+    Bootloader = 2,
+}
+
+impl UpdateMode {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Self::Application),
+            2 => Some(Self::Bootloader),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum UpdateError {
+    // Firmware update isn't possible (e.g. built without bootloader support)
+    NotSupported = 1,
+    TooLarge = 2,
+    OldVersion = 3,
+    WrongState = 4,
+    WrongOffset = 5,
+    FlashError = 6,
+    CrcMismatch = 7,
+    Invalid = 8,
+    SizeMismatch = 9,
+    // The image's firmware info block is missing or has another version
+    VersionMismatch = 10,
+}
+
+impl UpdateError {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Self::NotSupported),
+            2 => Some(Self::TooLarge),
+            3 => Some(Self::OldVersion),
+            4 => Some(Self::WrongState),
+            5 => Some(Self::WrongOffset),
+            6 => Some(Self::FlashError),
+            7 => Some(Self::CrcMismatch),
+            8 => Some(Self::Invalid),
+            9 => Some(Self::SizeMismatch),
+            10 => Some(Self::VersionMismatch),
+            _ => None,
+        }
+    }
+}
+
+pub const fn version_from_parts(major: u8, minor: u8, patch: u8) -> u32 {
+    ((major as u32) << 16) | ((minor as u32) << 8) | patch as u32
+}
+
+// Parses "major.minor.patch", e.g. env!("CARGO_PKG_VERSION"). Anything after
+// the patch number is ignored.
+pub const fn parse_version(s: &str) -> u32 {
+    let b = s.as_bytes();
+    let mut parts = [0u32; 3];
+    let mut part = 0;
+    let mut i = 0;
+    while i < b.len() && part < 3 {
+        if b[i] == b'.' {
+            part += 1;
+        } else if b[i].is_ascii_digit() {
+            parts[part] = parts[part] * 10 + (b[i] - b'0') as u32;
+        } else {
+            break;
+        }
+        i += 1;
+    }
+    ((parts[0] & 0xff) << 16) | ((parts[1] & 0xff) << 8) | (parts[2] & 0xff)
+}
+
+// Firmware info block
+//
+// Images carry their version in a 12 byte block: FIRMWARE_INFO_MAGIC followed
+// by the version (little endian). The application puts it in a static and the
+// host and the bootloader find it by searching the image.
+
+pub const FIRMWARE_INFO_MAGIC: [u8; 8] = *b"iPDMfwV1";
+
+pub const fn firmware_info(version: u32) -> [u8; 12] {
+    let m = FIRMWARE_INFO_MAGIC;
+    let v = version.to_le_bytes();
+    [
+        m[0], m[1], m[2], m[3], m[4], m[5], m[6], m[7], v[0], v[1], v[2], v[3],
+    ]
+}
+
+pub fn find_firmware_version(image: &[u8]) -> Option<u32> {
+    image
+        .windows(12)
+        .find(|w| w[..8] == FIRMWARE_INFO_MAGIC)
+        .map(|w| u32::from_le_bytes([w[8], w[9], w[10], w[11]]))
+}
+
+// CRC-32 (IEEE 802.3, as used by zlib)
+
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+// Record stored at IMAGE_RECORD_ADDRESS by the bootloader once an image has
+// been verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageRecord {
+    pub size: u32,
+    pub crc: u32,
+    pub version: u32,
+}
+
+impl ImageRecord {
+    pub const MAGIC: u32 = 0x1D_A6E5;
+    pub const LEN: usize = 16;
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut b = [0u8; Self::LEN];
+        b[0..4].copy_from_slice(&Self::MAGIC.to_le_bytes());
+        b[4..8].copy_from_slice(&self.size.to_le_bytes());
+        b[8..12].copy_from_slice(&self.crc.to_le_bytes());
+        b[12..16].copy_from_slice(&self.version.to_le_bytes());
+        b
+    }
+
+    pub fn from_bytes(b: &[u8; Self::LEN]) -> Option<Self> {
+        let word = |i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        if word(0) != Self::MAGIC {
+            return None;
+        }
+        Some(Self {
+            size: word(4),
+            crc: word(8),
+            version: word(12),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpdateFlashError;
+
+// Storage for the application image, implemented by the bootloader
+pub trait UpdateFlash {
+    // Invalidates the current image and erases space for size bytes
+    fn erase(&mut self, size: u32) -> Result<(), UpdateFlashError>;
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), UpdateFlashError>;
+    // The first size bytes of the written image
+    fn read(&mut self, size: u32) -> &[u8];
+    // Marks the written image valid so that it will be started
+    fn mark_valid(&mut self, record: &ImageRecord) -> Result<(), UpdateFlashError>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UpdateTargetConfig {
+    pub mode: UpdateMode,
+    // Version of the running application, or of the valid image in the
+    // bootloader (0 if there is none)
+    pub version: u32,
+    // 0 = updates not supported
+    pub max_size: u32,
+    // Bootloader only: If the image is valid and the host doesn't start a
+    // transfer within this time, the application is started again
+    pub fallback_timeout_ms: u64,
+    pub image_valid: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateEvent {
+    // Reset into the bootloader with the handoff flag set
+    EnterBootloader,
+    // Start the (now valid) application
+    StartApplication,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Receiving {
+        size: u32,
+        crc: u32,
+        version: u32,
+        received: u32,
+    },
+}
+
+pub struct UpdateTarget {
+    pub config: UpdateTargetConfig,
+    isotp: IsoTp<MAX_MESSAGE_LEN>,
+    state: State,
+    pending_event: Option<(UpdateEvent, Option<u64>)>,
+    start_ms: Option<u64>,
+    transfer_started: bool,
+}
+
+impl UpdateTarget {
+    pub fn new(config: UpdateTargetConfig) -> Self {
+        Self {
+            config,
+            isotp: IsoTp::new(IsoTpConfig::new(UPDATE_RESPONSE_ID, UPDATE_REQUEST_ID)),
+            state: State::Idle,
+            pending_event: None,
+            start_ms: None,
+            transfer_started: false,
+        }
+    }
+
+    // Returns true if the frame was consumed
+    pub fn on_can(&mut self, frame: &bxcan::Frame, millis: u64) -> bool {
+        self.isotp.on_can(frame, millis)
+    }
+
+    // This should be called regularly. flash is only needed in the bootloader.
+    pub fn update(
+        &mut self,
+        hw: &mut dyn CanPort,
+        flash: Option<&mut dyn UpdateFlash>,
+    ) -> Option<UpdateEvent> {
+        let millis = hw.millis();
+        let start_ms = *self.start_ms.get_or_insert(millis);
+
+        if let Some(error) = self.isotp.take_error() {
+            debug!("Update: ISO-TP error: {:?}", error);
+        }
+        if let Some(request) = self.isotp.take_received() {
+            let mut response = [0u8; 10];
+            let len = match self.handle_request(&request, flash, &mut response) {
+                Ok(len) => len,
+                Err(e) => {
+                    warn!("-!- Update: Request {:?} failed: {:?}", request.first(), e);
+                    response[0] = NEGATIVE_RESPONSE;
+                    response[1] = request.first().copied().unwrap_or(0);
+                    response[2] = e as u8;
+                    3
+                }
+            };
+            if let Err(e) = self.isotp.send(&response[..len]) {
+                debug!("Update: Failed to send response: {:?}", e);
+            }
+        }
+
+        if self.config.mode == UpdateMode::Bootloader
+            && self.config.image_valid
+            && !self.transfer_started
+            && self.pending_event.is_none()
+            && millis.saturating_sub(start_ms) >= self.config.fallback_timeout_ms
+        {
+            info!("-!- Update: No transfer started, returning to application");
+            self.pending_event = Some((UpdateEvent::StartApplication, Some(millis)));
+        }
+
+        self.isotp.update(hw);
+
+        // Events are reported once the response has had time to go out
+        if let Some((event, at_ms)) = self.pending_event {
+            match at_ms {
+                None => {
+                    if self.isotp.is_tx_idle() {
+                        self.pending_event = Some((event, Some(millis)));
+                    }
+                }
+                Some(at_ms) => {
+                    if millis.saturating_sub(at_ms) >= EVENT_DELAY_MS {
+                        self.pending_event = None;
+                        return Some(event);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // Writes the positive response and returns its length
+    fn handle_request(
+        &mut self,
+        request: &[u8],
+        flash: Option<&mut dyn UpdateFlash>,
+        response: &mut [u8; 10],
+    ) -> Result<usize, UpdateError> {
+        let Some(&command) = request.first() else {
+            return Err(UpdateError::Invalid);
+        };
+        let word = |i: usize| -> Result<u32, UpdateError> {
+            request
+                .get(i..i + 4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or(UpdateError::Invalid)
+        };
+        response[0] = command.wrapping_add(POSITIVE_RESPONSE_OFFSET);
+        match command {
+            CMD_QUERY => {
+                response[1] = self.config.mode as u8;
+                response[2..6].copy_from_slice(&self.config.version.to_be_bytes());
+                response[6..10].copy_from_slice(&self.config.max_size.to_be_bytes());
+                Ok(10)
+            }
+            CMD_REQUEST_UPDATE => {
+                if request.len() != 14 {
+                    return Err(UpdateError::Invalid);
+                }
+                let (size, crc, version) = (word(1)?, word(5)?, word(9)?);
+                let force = request[13] & FLAG_FORCE != 0;
+                if self.config.max_size == 0 {
+                    return Err(UpdateError::NotSupported);
+                }
+                if size == 0 {
+                    return Err(UpdateError::Invalid);
+                }
+                if size > self.config.max_size {
+                    return Err(UpdateError::TooLarge);
+                }
+                if version < self.config.version && !force {
+                    return Err(UpdateError::OldVersion);
+                }
+                info!(
+                    "-!- Update: Requested: {} bytes, CRC {:#010x}, version {:#08x}",
+                    size, crc, version
+                );
+                match self.config.mode {
+                    UpdateMode::Application => {
+                        self.pending_event = Some((UpdateEvent::EnterBootloader, None));
+                    }
+                    UpdateMode::Bootloader => {
+                        let flash = flash.ok_or(UpdateError::NotSupported)?;
+                        self.transfer_started = true;
+                        self.state = State::Idle;
+                        self.config.image_valid = false;
+                        flash.erase(size).map_err(|_| UpdateError::FlashError)?;
+                        self.state = State::Receiving {
+                            size,
+                            crc,
+                            version,
+                            received: 0,
+                        };
+                    }
+                }
+                response[1] = self.config.mode as u8;
+                Ok(2)
+            }
+            CMD_DATA => {
+                let State::Receiving { size, received, .. } = &mut self.state else {
+                    return Err(UpdateError::WrongState);
+                };
+                let flash = flash.ok_or(UpdateError::WrongState)?;
+                let offset = word(1)?;
+                let data = &request[5..];
+                if data.is_empty() {
+                    return Err(UpdateError::Invalid);
+                }
+                if offset != *received {
+                    return Err(UpdateError::WrongOffset);
+                }
+                if offset + data.len() as u32 > *size {
+                    return Err(UpdateError::SizeMismatch);
+                }
+                if flash.write(offset, data).is_err() {
+                    self.state = State::Idle;
+                    return Err(UpdateError::FlashError);
+                }
+                *received += data.len() as u32;
+                response[1..5].copy_from_slice(&received.to_be_bytes());
+                Ok(5)
+            }
+            CMD_FINISH => {
+                let State::Receiving {
+                    size,
+                    crc,
+                    version,
+                    received,
+                } = self.state
+                else {
+                    return Err(UpdateError::WrongState);
+                };
+                let flash = flash.ok_or(UpdateError::WrongState)?;
+                if received != size {
+                    return Err(UpdateError::SizeMismatch);
+                }
+                self.state = State::Idle;
+                let image = flash.read(size);
+                let actual_crc = crc32(image);
+                if actual_crc != crc {
+                    warn!(
+                        "-!- Update: CRC mismatch: expected {:#010x}, got {:#010x}",
+                        crc, actual_crc
+                    );
+                    return Err(UpdateError::CrcMismatch);
+                }
+                let image_version = find_firmware_version(image);
+                if image_version != Some(version) {
+                    warn!(
+                        "-!- Update: Version mismatch: requested {:#08x}, image has {:?}",
+                        version, image_version
+                    );
+                    return Err(UpdateError::VersionMismatch);
+                }
+                flash
+                    .mark_valid(&ImageRecord { size, crc, version })
+                    .map_err(|_| UpdateError::FlashError)?;
+                info!("-!- Update: Image verified and marked valid");
+                self.config.image_valid = true;
+                self.config.version = version;
+                self.pending_event = Some((UpdateEvent::StartApplication, None));
+                Ok(1)
+            }
+            CMD_ABORT => {
+                if self.state != State::Idle {
+                    info!("-!- Update: Aborted");
+                }
+                self.state = State::Idle;
+                Ok(1)
+            }
+            _ => Err(UpdateError::Invalid),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use crate::dtc::DtcStore;
+use crate::{AnalogInput, HardwareInterface, PwmOutput};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Current control: Closed-loop current regulation of LCUR1
+//
+// A PI controller adjusts LCUR1's duty cycle so that CurrentL follows the
+// target set using set_target(). The integrator is limited so that the duty
+// cycle stays within 0...1 (no windup), and it is reset when the target is 0.
+//
+// Peak-and-hold: When the target turns on from 0 and peak_current isn't NaN,
+// the controller first regulates to peak_current for peak_ms (e.g. to pull in
+// a solenoid) and then to the target.
+//
+// The setpoint is unreachable if the current stays further than max_error
+// from it with the duty cycle at its limit for unreachable_ms, e.g. because of
+// an open load or low supply voltage. This is logged and reported as
+// unreachable_dtc.
+
+#[derive(Debug, Clone, Copy)]
+pub struct CurrentControlConfig {
+    // Duty cycle per A
+    pub kp: f32,
+    // Duty cycle per A·s
+    pub ki: f32,
+    // NaN = no peak phase
+    pub peak_current: f32,
+    pub peak_ms: u64,
+    pub max_error: f32,
+    pub unreachable_ms: u64,
+    pub unreachable_dtc: u32,
+}
+
+pub struct CurrentControl {
+    pub config: CurrentControlConfig,
+    target: f32,
+    peak_pending: bool,
+    peak_until_ms: Option<u64>,
+    integral: f32,
+    duty: f32,
+    current: f32,
+    last_update_ms: Option<u64>,
+    saturated_since_ms: Option<u64>,
+    unreachable: bool,
+}
+
+impl CurrentControl {
+    pub fn new(config: CurrentControlConfig) -> Self {
+        Self {
+            config,
+            target: 0.0,
+            peak_pending: false,
+            peak_until_ms: None,
+            integral: 0.0,
+            duty: 0.0,
+            current: f32::NAN,
+            last_update_ms: None,
+            saturated_since_ms: None,
+            unreachable: false,
+        }
+    }
+
+    // Target current (A). 0 turns the output off.
+    pub fn set_target(&mut self, current: f32) {
+        let current = if current.is_nan() {
+            0.0
+        } else {
+            current.max(0.0)
+        };
+        if self.target == 0.0 && current > 0.0 && !self.config.peak_current.is_nan() {
+            // Started on the next update
+            self.peak_pending = true;
+        }
+        if current == 0.0 {
+            self.peak_pending = false;
+            self.peak_until_ms = None;
+        }
+        self.target = current;
+    }
+
+    pub fn get_target(&self) -> f32 {
+        self.target
+    }
+
+    // The current regulated to right now, taking the peak phase into account
+    pub fn get_setpoint(&self) -> f32 {
+        if self.peak_pending || self.peak_until_ms.is_some() {
+            self.config.peak_current.max(self.target)
+        } else {
+            self.target
+        }
+    }
+
+    pub fn get_duty(&self) -> f32 {
+        self.duty
+    }
+
+    pub fn is_unreachable(&self) -> bool {
+        self.unreachable
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        let millis = hw.millis();
+        let dt = self
+            .last_update_ms
+            .map_or(0.0, |t| millis.saturating_sub(t) as f32 / 1000.0);
+        self.last_update_ms = Some(millis);
+        self.current = hw.get_analog_input(AnalogInput::CurrentL);
+
+        if self.peak_pending {
+            self.peak_pending = false;
+            self.peak_until_ms = Some(millis + self.config.peak_ms);
+        } else if self.peak_until_ms.is_some_and(|t| millis >= t) {
+            self.peak_until_ms = None;
+        }
+        let setpoint = self.get_setpoint();
+
+        if setpoint == 0.0 || self.current.is_nan() {
+            self.integral = 0.0;
+            self.duty = 0.0;
+            self.saturated_since_ms = None;
+            self.set_unreachable(false, setpoint);
+            hw.set_pwm_output(PwmOutput::LCUR1, 0.0);
+            return;
+        }
+
+        let error = setpoint - self.current;
+        let proportional = self.config.kp * error;
+        self.integral = (self.integral + self.config.ki * error * dt)
+            .clamp(-proportional, 1.0 - proportional)
+            .clamp(0.0, 1.0);
+        self.duty = (proportional + self.integral).clamp(0.0, 1.0);
+        hw.set_pwm_output(PwmOutput::LCUR1, self.duty);
+
+        let saturated = (self.duty >= 1.0 && error > self.config.max_error)
+            || (self.duty <= 0.0 && error < -self.config.max_error);
+        if saturated {
+            let since = *self.saturated_since_ms.get_or_insert(millis);
+            if millis - since >= self.config.unreachable_ms {
+                self.set_unreachable(true, setpoint);
+            }
+        } else {
+            self.saturated_since_ms = None;
+            if error.abs() <= self.config.max_error {
+                self.set_unreachable(false, setpoint);
+            }
+        }
+    }
+
+    fn set_unreachable(&mut self, unreachable: bool, setpoint: f32) {
+        if unreachable && !self.unreachable {
+            warn!(
+                "-!- LCUR1: Setpoint {:.2} A unreachable, at {:.2} A with {:.0}% duty",
+                setpoint,
+                self.current,
+                self.duty * 100.0
+            );
+        }
+        self.unreachable = unreachable;
+    }
+
+    pub fn update_dtcs<const N: usize>(&self, dtcs: &mut DtcStore<N>) {
+        dtcs.set(self.config.unreachable_dtc, self.unreachable);
+    }
+
+    pub fn print(&self) {
+        info!(
+            "LCUR1: target {:.2} A, setpoint {:.2} A, measured {:.2} A, duty {:.1}%{}",
+            self.target,
+            self.get_setpoint(),
+            self.current,
+            self.duty * 100.0,
+            if self.unreachable {
+                ", UNREACHABLE"
+            } else {
+                ""
+            }
+        );
+    }
+}
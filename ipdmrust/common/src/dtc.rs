@@ -0,0 +1,140 @@
+use arrayvec::ArrayVec;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Diagnostic trouble codes
+//
+// DTCs are stored as 3-byte UDS codes (ISO 14229-1 DTC format, i.e. the 2-byte
+// SAE J2012 code followed by a failure type byte). A DTC is added to the store
+// the first time it fails and stays there, confirmed, until it is cleared.
+
+// Status bits (ISO 14229-1 DTCStatusMask)
+pub const DTC_STATUS_TEST_FAILED: u8 = 0x01;
+pub const DTC_STATUS_TEST_FAILED_THIS_OPERATION_CYCLE: u8 = 0x02;
+pub const DTC_STATUS_CONFIRMED: u8 = 0x08;
+
+// The status bits this implementation supports
+pub const DTC_STATUS_AVAILABILITY_MASK: u8 =
+    DTC_STATUS_TEST_FAILED | DTC_STATUS_TEST_FAILED_THIS_OPERATION_CYCLE | DTC_STATUS_CONFIRMED;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Dtc {
+    pub code: u32,
+    pub status: u8,
+    pub fail_count: u16,
+}
+
+impl Dtc {
+    pub fn is_failed(&self) -> bool {
+        self.status & DTC_STATUS_TEST_FAILED != 0
+    }
+}
+
+// Writes the code in the SAE J2012 form, e.g. 0xC11100 -> "U0111-00"
+pub fn format_dtc_code(code: u32, f: &mut dyn core::fmt::Write) -> core::fmt::Result {
+    let letter = match (code >> 22) & 0x03 {
+        0 => 'P',
+        1 => 'C',
+        2 => 'B',
+        _ => 'U',
+    };
+    write!(
+        f,
+        "{}{:04X}-{:02X}",
+        letter,
+        (code >> 8) & 0x3fff,
+        code & 0xff
+    )
+}
+
+pub struct DtcStore<const N: usize> {
+    dtcs: ArrayVec<Dtc, N>,
+}
+
+impl<const N: usize> Default for DtcStore<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> DtcStore<N> {
+    pub fn new() -> Self {
+        Self {
+            dtcs: ArrayVec::new(),
+        }
+    }
+
+    // Reports the result of a test. This can be called on every tick; only
+    // transitions are logged.
+    pub fn set(&mut self, code: u32, failed: bool) {
+        let index = self.dtcs.iter().position(|d| d.code == code);
+        let dtc = match index {
+            Some(i) => &mut self.dtcs[i],
+            None => {
+                if !failed {
+                    return;
+                }
+                if self.dtcs.is_full() {
+                    error!(
+                        "-!- DtcStore::set(): {:06X}: Too many DTCs (max {})",
+                        code, N
+                    );
+                    return;
+                }
+                self.dtcs.push(Dtc {
+                    code,
+                    status: 0,
+                    fail_count: 0,
+                });
+                self.dtcs.last_mut().unwrap()
+            }
+        };
+        if failed && !dtc.is_failed() {
+            warn!("-!- DTC {:06X} failed", code);
+            dtc.status |= DTC_STATUS_TEST_FAILED
+                | DTC_STATUS_TEST_FAILED_THIS_OPERATION_CYCLE
+                | DTC_STATUS_CONFIRMED;
+            dtc.fail_count = dtc.fail_count.saturating_add(1);
+        } else if !failed && dtc.is_failed() {
+            info!("-!- DTC {:06X} passed", code);
+            dtc.status &= !DTC_STATUS_TEST_FAILED;
+        }
+    }
+
+    pub fn is_failed(&self, code: u32) -> bool {
+        self.dtcs.iter().any(|d| d.code == code && d.is_failed())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Dtc> {
+        self.dtcs.iter()
+    }
+
+    // Clears the given DTC, or all of them if code is 0xFFFFFF
+    pub fn clear(&mut self, code: u32) {
+        if code == 0xffffff {
+            info!("-!- DTCs cleared");
+            self.dtcs.clear();
+        } else {
+            info!("-!- DTC {:06X} cleared", code);
+            self.dtcs.retain(|d| d.code != code);
+        }
+    }
+
+    pub fn print(&self) {
+        if self.dtcs.is_empty() {
+            info!("No DTCs");
+        }
+        for dtc in &self.dtcs {
+            let mut code = arrayvec::ArrayString::<12>::new();
+            let _ = format_dtc_code(dtc.code, &mut code);
+            info!(
+                "* {} ({:06X}): status {:02X}, failed {} times{}",
+                code,
+                dtc.code,
+                dtc.status,
+                dtc.fail_count,
+                if dtc.is_failed() { " (active)" } else { "" }
+            );
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use crate::{
+    AnalogInput, CanMapId, CanPort, CaptureInput, DigitalInput, DigitalOutput, HardwareInterface,
+    MPin, MPinMode, PulseMeasurement, PwmOutput, PwmTimer,
+};
+use arrayvec::ArrayVec;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
+
+// CAN gateway: ID translation, frame rewriting, rate limiting and blocking
+//
+// The iPDM56 has a single CAN bus, so rules work in two directions:
+// * Rx rules match received frames and can forward a (remapped, rewritten)
+//   copy back onto the bus. This is used for translating between an OEM
+//   component and the rest of the car.
+// * Tx rules match frames sent by the app through GatewayTx. They can send an
+//   additional translated copy, rate limit or block the frame.
+//
+// Rules are declared by the app. Each rule can have an enable function, which
+// usually looks at a parameter, so that rules can be switched on and off at
+// runtime.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GatewayDirection {
+    Rx,
+    Tx,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ByteRewrite {
+    pub byte: u8,
+    // Only bits set in the mask are replaced
+    pub mask: u8,
+    pub value: u8,
+}
+
+pub enum GatewayAction {
+    // Send a copy of the frame, optionally with a different ID and rewritten
+    // data. The function can rewrite signals and returns false to drop the
+    // copy.
+    Forward {
+        to_id: Option<bxcan::Id>,
+        rewrite: &'static [ByteRewrite],
+        function: Option<fn(&mut [u8]) -> bool>,
+        // 0 = not limited
+        min_interval_ms: u64,
+    },
+    // Drop the frame (Tx only)
+    Block,
+    // Let the frame through at most once per interval (Tx only)
+    RateLimit {
+        min_interval_ms: u64,
+    },
+}
+
+pub struct GatewayRule {
+    pub name: &'static str,
+    pub direction: GatewayDirection,
+    pub id: CanMapId,
+    pub enable: Option<fn() -> bool>,
+    pub action: GatewayAction,
+}
+
+struct GatewayRuleState {
+    rule: GatewayRule,
+    last_pass_ms: Option<u64>,
+    passed_count: u32,
+    dropped_count: u32,
+}
+
+impl GatewayRuleState {
+    fn enabled(&self) -> bool {
+        self.rule.enable.is_none_or(|enable| enable())
+    }
+
+    // Returns true if the rate limit allows passing a frame now
+    fn rate_ok(&self, min_interval_ms: u64, millis: u64) -> bool {
+        self.last_pass_ms
+            .is_none_or(|last| millis.saturating_sub(last) >= min_interval_ms)
+    }
+}
+
+pub struct Gateway<const N: usize> {
+    rules: ArrayVec<GatewayRuleState, N>,
+    out_frames: ConstGenericRingBuffer<bxcan::Frame, 8>,
+}
+
+impl<const N: usize> Default for Gateway<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Gateway<N> {
+    pub fn new() -> Self {
+        Self {
+            rules: ArrayVec::new(),
+            out_frames: ConstGenericRingBuffer::new(),
+        }
+    }
+
+    pub fn register(&mut self, rule: GatewayRule) {
+        if self.rules.is_full() {
+            error!(
+                "-!- Gateway::register(): {}: Too many rules (max {})",
+                rule.name, N
+            );
+            return;
+        }
+        self.rules.push(GatewayRuleState {
+            rule,
+            last_pass_ms: None,
+            passed_count: 0,
+            dropped_count: 0,
+        });
+    }
+
+    // Applies Rx rules to a received frame. Forwarded frames are sent on the
+    // next update().
+    pub fn on_can(&mut self, frame: &bxcan::Frame, millis: u64) {
+        self.apply(GatewayDirection::Rx, frame, millis);
+    }
+
+    // Applies Tx rules to a frame sent by the app. Returns false if the frame
+    // should not be sent.
+    pub fn filter_tx(&mut self, frame: &bxcan::Frame, millis: u64) -> bool {
+        self.apply(GatewayDirection::Tx, frame, millis)
+    }
+
+    // Wraps the hardware so that frames sent through it go through Tx rules
+    pub fn wrap<'a>(&'a mut self, hw: &'a mut dyn HardwareInterface) -> GatewayTx<'a, N> {
+        GatewayTx { gateway: self, hw }
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        while let Some(frame) = self.out_frames.dequeue() {
+            hw.send_can(frame);
+        }
+    }
+
+    fn apply(&mut self, direction: GatewayDirection, frame: &bxcan::Frame, millis: u64) -> bool {
+        let mut pass = true;
+        for state in self.rules.iter_mut() {
+            if state.rule.direction != direction
+                || !state.rule.id.matches(frame.id())
+                || !state.enabled()
+            {
+                continue;
+            }
+            match &state.rule.action {
+                GatewayAction::Forward {
+                    to_id,
+                    rewrite,
+                    function,
+                    min_interval_ms,
+                } => {
+                    if !state.rate_ok(*min_interval_ms, millis) {
+                        state.dropped_count += 1;
+                        continue;
+                    }
+                    let Some(data) = frame.data() else {
+                        continue;
+                    };
+                    let mut buf = [0u8; 8];
+                    let buf = &mut buf[..data.len()];
+                    buf.copy_from_slice(data);
+                    for r in rewrite.iter() {
+                        if let Some(b) = buf.get_mut(r.byte as usize) {
+                            *b = (*b & !r.mask) | (r.value & r.mask);
+                        }
+                    }
+                    if let Some(function) = function {
+                        if !function(buf) {
+                            state.dropped_count += 1;
+                            continue;
+                        }
+                    }
+                    let id = to_id.unwrap_or(frame.id());
+                    self.out_frames
+                        .push(bxcan::Frame::new_data(id, bxcan::Data::new(buf).unwrap()));
+                    state.last_pass_ms = Some(millis);
+                    state.passed_count += 1;
+                }
+                GatewayAction::Block => {
+                    state.dropped_count += 1;
+                    pass = false;
+                }
+                GatewayAction::RateLimit { min_interval_ms } => {
+                    if state.rate_ok(*min_interval_ms, millis) {
+                        state.last_pass_ms = Some(millis);
+                        state.passed_count += 1;
+                    } else {
+                        state.dropped_count += 1;
+                        pass = false;
+                    }
+                }
+            }
+        }
+        pass
+    }
+
+    pub fn print_stats(&self) {
+        for state in &self.rules {
+            info!(
+                "* {:>18}: {:?} {}, passed {}, dropped {}",
+                state.rule.name,
+                state.rule.direction,
+                if state.enabled() {
+                    "enabled"
+                } else {
+                    "disabled"
+                },
+                state.passed_count,
+                state.dropped_count
+            );
+        }
+    }
+}
+
+// HardwareInterface that passes frames sent by the app through the gateway's
+// Tx rules. Everything else goes directly to the hardware.
+pub struct GatewayTx<'a, const N: usize> {
+    gateway: &'a mut Gateway<N>,
+    hw: &'a mut dyn HardwareInterface,
+}
+
+impl<const N: usize> CanPort for GatewayTx<'_, N> {
+    fn millis(&mut self) -> u64 {
+        self.hw.millis()
+    }
+
+    fn send_can(&mut self, frame: bxcan::Frame) {
+        let millis = self.hw.millis();
+        let pass = self.gateway.filter_tx(&frame, millis);
+        if pass {
+            self.hw.send_can(frame);
+        }
+        // Translated copies are sent right after the original
+        self.gateway.update(self.hw);
+    }
+}
+
+impl<const N: usize> HardwareInterface for GatewayTx<'_, N> {
+    fn reboot(&mut self) {
+        self.hw.reboot()
+    }
+
+    fn activate_dfu(&mut self) {
+        self.hw.activate_dfu()
+    }
+
+    fn get_analog_input(&mut self, input: AnalogInput) -> f32 {
+        self.hw.get_analog_input(input)
+    }
+
+    fn get_digital_input(&mut self, input: DigitalInput) -> bool {
+        self.hw.get_digital_input(input)
+    }
+
+    fn set_digital_output(&mut self, output: DigitalOutput, value: bool) {
+        self.hw.set_digital_output(output, value)
+    }
+
+    fn get_digital_output(&mut self, output: DigitalOutput) -> bool {
+        self.hw.get_digital_output(output)
+    }
+
+    fn set_pwm_output(&mut self, output: PwmOutput, value: f32) {
+        self.hw.set_pwm_output(output, value)
+    }
+
+    fn get_pwm_output(&mut self, output: PwmOutput) -> f32 {
+        self.hw.get_pwm_output(output)
+    }
+
+    fn set_pwm_frequency(&mut self, timer: PwmTimer, frequency_hz: f32) {
+        self.hw.set_pwm_frequency(timer, frequency_hz)
+    }
+
+    fn get_pwm_frequency(&mut self, timer: PwmTimer) -> f32 {
+        self.hw.get_pwm_frequency(timer)
+    }
+
+    fn get_m_pin_mode(&mut self, pin: MPin) -> MPinMode {
+        self.hw.get_m_pin_mode(pin)
+    }
+
+    fn get_pulse_measurement(&mut self, input: CaptureInput) -> PulseMeasurement {
+        self.hw.get_pulse_measurement(input)
+    }
+}
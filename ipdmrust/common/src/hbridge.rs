@@ -0,0 +1,351 @@
+use crate::output_layers::OutputLayer;
+use crate::{DigitalOutput, HardwareInterface};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// H-bridges: Reversible motors driven by HOUT+LOUT pairs
+//
+// A half bridge is a high side output (HOUT) and a low side output (LOUT)
+// connected to the same motor terminal. A full H-bridge has a half bridge on
+// both terminals. With only one half bridge the motor's other terminal goes
+// to ground, and the motor can't be reversed.
+//
+// Commands:
+// * Forward: a.high and b.low on
+// * Reverse: b.high and a.low on
+// * Brake: Both low sides on (the motor is shorted)
+// * Coast: Everything off
+//
+// Interlocks: The high and low side of a half bridge are never on at the same
+// time. When the command changes, everything is first turned off for
+// dead_time_ms. Outputs of a bridge can't be set directly through the output layer.
+//
+// Forward and Reverse are limited to max_pulse_ms, after which the bridge
+// brakes. If stall_current isn't NaN, the motor is also braked when the
+// current of the driving high side's group stays above stall_current for
+// stall_ms, ignoring the first stall_blanking_ms (inrush) of the pulse. This
+// is also how end of travel of locks and flaps is detected.
+
+pub const MAX_BRIDGES: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HalfBridge {
+    pub high: DigitalOutput,
+    pub low: DigitalOutput,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HBridgeConfig {
+    pub name: &'static str,
+    pub a: HalfBridge,
+    // None = the motor's other terminal is at ground
+    pub b: Option<HalfBridge>,
+    pub dead_time_ms: u64,
+    pub max_pulse_ms: u64,
+    // A, NaN = no stall detection
+    pub stall_current: f32,
+    pub stall_ms: u64,
+    pub stall_blanking_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HBridgesConfig {
+    pub bridges: &'static [HBridgeConfig],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HBridgeCommand {
+    Coast,
+    Brake,
+    Forward,
+    Reverse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HBridgeStop {
+    // Stopped by a command
+    Command,
+    PulseLimit,
+    Stall,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bridge {
+    // What the outputs are driven to, Coast during dead time
+    applied: HBridgeCommand,
+    // Set by pulse(), started on the next update
+    requested: Option<(HBridgeCommand, u64)>,
+    pending: Option<HBridgeCommand>,
+    dead_time_until_ms: u64,
+    pulse_start_ms: u64,
+    pulse_ms: u64,
+    over_current_since_ms: Option<u64>,
+    last_stop: Option<HBridgeStop>,
+}
+
+impl Bridge {
+    fn new() -> Self {
+        Self {
+            applied: HBridgeCommand::Coast,
+            requested: None,
+            pending: None,
+            dead_time_until_ms: 0,
+            pulse_start_ms: 0,
+            pulse_ms: 0,
+            over_current_since_ms: None,
+            last_stop: None,
+        }
+    }
+}
+
+pub struct HBridges {
+    pub config: HBridgesConfig,
+    bridges: [Bridge; MAX_BRIDGES],
+}
+
+impl HBridges {
+    pub fn new(config: HBridgesConfig) -> Self {
+        if config.bridges.len() > MAX_BRIDGES {
+            error!(
+                "-!- HBridges::new(): Too many bridges (max {})",
+                MAX_BRIDGES
+            );
+        }
+        for bridge in config.bridges {
+            let halves = [Some(bridge.a), bridge.b];
+            for half in halves.iter().flatten() {
+                if half.high.group().is_none() || half.low.group().is_some() {
+                    error!(
+                        "-!- HBridges::new(): {}: {:?} must be a high side and {:?} a low side output",
+                        bridge.name, half.high, half.low
+                    );
+                }
+            }
+        }
+        Self {
+            config,
+            bridges: [Bridge::new(); MAX_BRIDGES],
+        }
+    }
+
+    fn configs(&self) -> &'static [HBridgeConfig] {
+        let n = self.config.bridges.len().min(MAX_BRIDGES);
+        &self.config.bridges[..n]
+    }
+
+    // Whether the output is part of a bridge
+    pub fn is_bridge_output(&self, output: DigitalOutput) -> bool {
+        self.configs().iter().any(|bridge| {
+            [Some(bridge.a), bridge.b]
+                .iter()
+                .flatten()
+                .any(|half| half.high == output || half.low == output)
+        })
+    }
+
+    // The command the bridge is executing or going to execute after the dead
+    // time
+    pub fn get_command(&self, i: usize) -> HBridgeCommand {
+        let bridge = &self.bridges[i];
+        bridge
+            .requested
+            .map(|(command, _)| command)
+            .or(bridge.pending)
+            .unwrap_or(bridge.applied)
+    }
+
+    // Why the bridge last stopped driving the motor
+    pub fn last_stop(&self, i: usize) -> Option<HBridgeStop> {
+        self.bridges[i].last_stop
+    }
+
+    pub fn set_command(&mut self, i: usize, command: HBridgeCommand) {
+        let Some(config) = self.configs().get(i) else {
+            error!("-!- HBridges::set_command(): No bridge {}", i);
+            return;
+        };
+        self.pulse(i, command, config.max_pulse_ms);
+    }
+
+    // Drives the motor for duration_ms (limited to max_pulse_ms), then
+    // brakes. For Brake and Coast the duration doesn't matter. Takes effect on
+    // the next update().
+    pub fn pulse(&mut self, i: usize, command: HBridgeCommand, duration_ms: u64) {
+        let Some(config) = self.configs().get(i) else {
+            error!("-!- HBridges::pulse(): No bridge {}", i);
+            return;
+        };
+        if config.b.is_none() && command == HBridgeCommand::Reverse {
+            error!("-!- {}: Can't reverse with one half bridge", config.name);
+            return;
+        }
+        self.bridges[i].requested = Some((command, duration_ms.min(config.max_pulse_ms)));
+    }
+
+    fn start(
+        &mut self,
+        hw: &mut dyn HardwareInterface,
+        i: usize,
+        command: HBridgeCommand,
+        pulse_ms: u64,
+        millis: u64,
+    ) {
+        let config = self.configs()[i];
+        let bridge = &mut self.bridges[i];
+        if bridge.pending.is_none() && bridge.applied == command {
+            // Restarts the pulse
+            bridge.pulse_start_ms = millis;
+            bridge.pulse_ms = pulse_ms;
+            return;
+        }
+        if is_driving(command) {
+            bridge.last_stop = None;
+        } else if is_driving(bridge.applied) || bridge.pending.is_some_and(is_driving) {
+            bridge.last_stop = Some(HBridgeStop::Command);
+        }
+        bridge.pulse_ms = pulse_ms;
+        bridge.pending = Some(command);
+        if bridge.applied != HBridgeCommand::Coast {
+            // Everything off for the dead time first
+            bridge.dead_time_until_ms = millis + config.dead_time_ms;
+            self.apply(hw, i, HBridgeCommand::Coast, millis);
+        }
+    }
+
+    fn apply(
+        &mut self,
+        hw: &mut dyn HardwareInterface,
+        i: usize,
+        command: HBridgeCommand,
+        millis: u64,
+    ) {
+        let config = self.configs()[i];
+        let (a_high, a_low, b_high, b_low) = match command {
+            HBridgeCommand::Coast => (false, false, false, false),
+            HBridgeCommand::Brake => (false, true, false, true),
+            HBridgeCommand::Forward => (true, false, false, true),
+            HBridgeCommand::Reverse => (false, true, true, false),
+        };
+        let mut outputs = [
+            Some((config.a.high, a_high)),
+            Some((config.a.low, a_low)),
+            None,
+            None,
+        ];
+        if let Some(b) = config.b {
+            outputs[2] = Some((b.high, b_high));
+            outputs[3] = Some((b.low, b_low));
+        }
+        // Turn-offs first
+        for turn_on in [false, true] {
+            for &(output, value) in outputs.iter().flatten() {
+                if value == turn_on {
+                    hw.set_digital_output(output, value);
+                }
+            }
+        }
+        let bridge = &mut self.bridges[i];
+        bridge.applied = command;
+        bridge.pulse_start_ms = millis;
+        bridge.over_current_since_ms = None;
+    }
+
+    fn stop(&mut self, hw: &mut dyn HardwareInterface, i: usize, reason: HBridgeStop, millis: u64) {
+        let config = self.configs()[i];
+        match reason {
+            HBridgeStop::Stall => info!("-!- {}: Stalled, braking", config.name),
+            _ => debug!("{}: {:?}, braking", config.name, reason),
+        }
+        self.bridges[i].dead_time_until_ms = millis + config.dead_time_ms;
+        self.apply(hw, i, HBridgeCommand::Coast, millis);
+        let bridge = &mut self.bridges[i];
+        bridge.pending = Some(HBridgeCommand::Brake);
+        bridge.last_stop = Some(reason);
+    }
+
+    fn update_bridge(&mut self, hw: &mut dyn HardwareInterface, i: usize, millis: u64) {
+        if let Some((command, pulse_ms)) = self.bridges[i].requested.take() {
+            self.start(hw, i, command, pulse_ms, millis);
+        }
+        let config = self.configs()[i];
+        let bridge = &self.bridges[i];
+        if let Some(command) = bridge.pending {
+            if millis >= bridge.dead_time_until_ms {
+                self.bridges[i].pending = None;
+                self.apply(hw, i, command, millis);
+            }
+            return;
+        }
+        if !is_driving(bridge.applied) {
+            return;
+        }
+
+        let elapsed_ms = millis - bridge.pulse_start_ms;
+        if elapsed_ms >= bridge.pulse_ms {
+            self.stop(hw, i, HBridgeStop::PulseLimit, millis);
+            return;
+        }
+
+        if config.stall_current.is_nan() || elapsed_ms < config.stall_blanking_ms {
+            return;
+        }
+        let high = match bridge.applied {
+            HBridgeCommand::Forward => config.a.high,
+            _ => config.b.map_or(config.a.high, |b| b.high),
+        };
+        let Some(group) = high.group() else {
+            return;
+        };
+        let current = hw.get_analog_input(group.current_input());
+        let bridge = &mut self.bridges[i];
+        if current > config.stall_current {
+            let since = *bridge.over_current_since_ms.get_or_insert(millis);
+            if millis - since >= config.stall_ms {
+                self.stop(hw, i, HBridgeStop::Stall, millis);
+            }
+        } else {
+            bridge.over_current_since_ms = None;
+        }
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        let millis = hw.millis();
+        for i in 0..self.configs().len() {
+            self.update_bridge(hw, i, millis);
+        }
+    }
+
+    pub fn print(&self) {
+        for (i, config) in self.configs().iter().enumerate() {
+            info!(
+                "{}: {:?}, last stop: {:?}",
+                config.name,
+                self.get_command(i),
+                self.last_stop(i)
+            );
+        }
+    }
+}
+
+fn is_driving(command: HBridgeCommand) -> bool {
+    matches!(command, HBridgeCommand::Forward | HBridgeCommand::Reverse)
+}
+
+impl OutputLayer for HBridges {
+    // Outputs of bridges can only be controlled using HBridges
+    fn set_digital_output(
+        &mut self,
+        next: &mut dyn HardwareInterface,
+        output: DigitalOutput,
+        value: bool,
+    ) {
+        if !self.is_bridge_output(output) {
+            next.set_digital_output(output, value)
+        }
+    }
+
+    fn update(&mut self, next: &mut dyn HardwareInterface) {
+        HBridges::update(self, next)
+    }
+}
@@ -0,0 +1,430 @@
+use crate::CanPort;
+use arrayvec::ArrayVec;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
+
+// ISO-TP (ISO 15765-2) transport layer for classic CAN
+//
+// Received frames are fed in using on_can(), which doesn't have access to the
+// hardware. Any frames that need to be sent as a result (flow control, and
+// consecutive frames of an outgoing message) are sent from update(), which
+// should be called on every logic tick.
+//
+// N is the maximum message length. ISO-TP on classic CAN supports up to 4095
+// bytes.
+
+pub const ISOTP_MAX_LEN: usize = 4095;
+
+const PCI_SINGLE_FRAME: u8 = 0x00;
+const PCI_FIRST_FRAME: u8 = 0x10;
+const PCI_CONSECUTIVE_FRAME: u8 = 0x20;
+const PCI_FLOW_CONTROL: u8 = 0x30;
+
+const FLOW_STATUS_CONTINUE: u8 = 0;
+const FLOW_STATUS_WAIT: u8 = 1;
+const FLOW_STATUS_OVERFLOW: u8 = 2;
+
+// How many FC(WAIT) frames are accepted in a row before giving up
+const MAX_FLOW_CONTROL_WAITS: u8 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsoTpError {
+    // A transmission is already in progress
+    Busy,
+    // The message doesn't fit in the buffer or in the protocol
+    TooLong,
+    // Flow control (N_Bs) or consecutive frame (N_Cr) timeout
+    Timeout,
+    // The receiver reported that the message doesn't fit in its buffer
+    Overflow,
+    // A consecutive frame arrived with the wrong sequence number
+    WrongSequenceNumber,
+    // Too many FC(WAIT) frames from the receiver
+    TooManyWaits,
+    // Flow control frame with an invalid flow status
+    InvalidFlowControl,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IsoTpConfig {
+    pub tx_id: bxcan::Id,
+    pub rx_id: bxcan::Id,
+    // Block size we request from the sender when receiving. 0 = no limit.
+    pub block_size: u8,
+    // Minimum separation time we request from the sender when receiving
+    pub st_min_ms: u8,
+    // If set, all frames are padded to 8 bytes using this value
+    pub padding: Option<u8>,
+    // N_Bs and N_Cr
+    pub timeout_ms: u64,
+    // Upper limit for frames sent per update() call when the receiver allows
+    // back-to-back consecutive frames
+    pub max_frames_per_update: usize,
+}
+
+impl IsoTpConfig {
+    pub const fn new(tx_id: bxcan::Id, rx_id: bxcan::Id) -> Self {
+        Self {
+            tx_id,
+            rx_id,
+            block_size: 0,
+            st_min_ms: 0,
+            padding: Some(0xAA),
+            timeout_ms: 1000,
+            max_frames_per_update: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TxState {
+    Idle,
+    WaitFlowControl,
+    SendConsecutive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RxState {
+    Idle,
+    ReceiveConsecutive,
+    Complete,
+}
+
+pub struct IsoTp<const N: usize> {
+    pub config: IsoTpConfig,
+    // Frames waiting to be sent on the next update()
+    out_frames: ConstGenericRingBuffer<bxcan::Frame, 4>,
+    last_error: Option<IsoTpError>,
+
+    tx_state: TxState,
+    tx_buf: ArrayVec<u8, N>,
+    tx_pos: usize,
+    tx_sn: u8,
+    tx_block_remaining: u8,
+    tx_block_size: u8,
+    tx_st_min_ms: u64,
+    tx_waits: u8,
+    tx_last_ms: u64,
+    tx_last_cf_ms: Option<u64>,
+    tx_wait_started: bool,
+
+    rx_state: RxState,
+    rx_buf: ArrayVec<u8, N>,
+    rx_len: usize,
+    rx_sn: u8,
+    rx_block_count: u8,
+    rx_last_ms: u64,
+}
+
+impl<const N: usize> IsoTp<N> {
+    pub fn new(config: IsoTpConfig) -> Self {
+        Self {
+            config,
+            out_frames: ConstGenericRingBuffer::new(),
+            last_error: None,
+            tx_state: TxState::Idle,
+            tx_buf: ArrayVec::new(),
+            tx_pos: 0,
+            tx_sn: 0,
+            tx_block_remaining: 0,
+            tx_block_size: 0,
+            tx_st_min_ms: 0,
+            tx_waits: 0,
+            tx_last_ms: 0,
+            tx_last_cf_ms: None,
+            tx_wait_started: false,
+            rx_state: RxState::Idle,
+            rx_buf: ArrayVec::new(),
+            rx_len: 0,
+            rx_sn: 0,
+            rx_block_count: 0,
+            rx_last_ms: 0,
+        }
+    }
+
+    pub fn is_tx_idle(&self) -> bool {
+        self.tx_state == TxState::Idle
+    }
+
+    // Starts sending a message. Single frame messages go out on the next
+    // update(); longer ones are segmented and follow the receiver's flow
+    // control.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), IsoTpError> {
+        if self.tx_state != TxState::Idle {
+            return Err(IsoTpError::Busy);
+        }
+        if data.len() > N || data.len() > ISOTP_MAX_LEN {
+            return Err(IsoTpError::TooLong);
+        }
+        self.tx_buf.clear();
+        self.tx_buf.try_extend_from_slice(data).unwrap();
+
+        if data.len() <= 7 {
+            let mut buf = [0u8; 8];
+            buf[0] = PCI_SINGLE_FRAME | data.len() as u8;
+            buf[1..1 + data.len()].copy_from_slice(data);
+            self.queue_frame(&buf[..1 + data.len()]);
+        } else {
+            let mut buf = [0u8; 8];
+            buf[0] = PCI_FIRST_FRAME | ((data.len() >> 8) as u8 & 0x0f);
+            buf[1] = (data.len() & 0xff) as u8;
+            buf[2..8].copy_from_slice(&data[..6]);
+            self.queue_frame(&buf);
+            self.tx_pos = 6;
+            self.tx_sn = 1;
+            self.tx_waits = 0;
+            self.tx_last_cf_ms = None;
+            self.tx_state = TxState::WaitFlowControl;
+            // The timeout starts when the first frame is actually sent
+            self.tx_wait_started = false;
+        }
+        Ok(())
+    }
+
+    // Returns true if the frame was addressed to this endpoint
+    pub fn on_can(&mut self, frame: &bxcan::Frame, millis: u64) -> bool {
+        if frame.id() != self.config.rx_id {
+            return false;
+        }
+        let Some(data) = frame.data() else {
+            return true;
+        };
+        if data.is_empty() {
+            return true;
+        }
+        match data[0] & 0xf0 {
+            PCI_SINGLE_FRAME => self.on_single_frame(data),
+            PCI_FIRST_FRAME => self.on_first_frame(data, millis),
+            PCI_CONSECUTIVE_FRAME => self.on_consecutive_frame(data, millis),
+            PCI_FLOW_CONTROL => self.on_flow_control(data, millis),
+            _ => {}
+        }
+        true
+    }
+
+    fn on_single_frame(&mut self, data: &[u8]) {
+        let len = (data[0] & 0x0f) as usize;
+        if len == 0 || len > 7 || len + 1 > data.len() || len > N {
+            return;
+        }
+        if self.rx_state == RxState::ReceiveConsecutive {
+            debug!("IsoTp: Single frame interrupted reception");
+        }
+        self.rx_buf.clear();
+        self.rx_buf
+            .try_extend_from_slice(&data[1..1 + len])
+            .unwrap();
+        self.rx_state = RxState::Complete;
+    }
+
+    fn on_first_frame(&mut self, data: &[u8], millis: u64) {
+        if data.len() < 8 {
+            return;
+        }
+        let len = (((data[0] & 0x0f) as usize) << 8) | data[1] as usize;
+        if len <= 7 {
+            return;
+        }
+        if len > N {
+            warn!("-!- IsoTp: Incoming message too long: {} bytes", len);
+            self.queue_flow_control(FLOW_STATUS_OVERFLOW);
+            self.rx_state = RxState::Idle;
+            return;
+        }
+        self.rx_buf.clear();
+        self.rx_buf.try_extend_from_slice(&data[2..8]).unwrap();
+        self.rx_len = len;
+        self.rx_sn = 1;
+        self.rx_block_count = 0;
+        self.rx_last_ms = millis;
+        self.rx_state = RxState::ReceiveConsecutive;
+        self.queue_flow_control(FLOW_STATUS_CONTINUE);
+    }
+
+    fn on_consecutive_frame(&mut self, data: &[u8], millis: u64) {
+        if self.rx_state != RxState::ReceiveConsecutive {
+            return;
+        }
+        let sn = data[0] & 0x0f;
+        if sn != self.rx_sn {
+            self.set_error(IsoTpError::WrongSequenceNumber);
+            self.rx_state = RxState::Idle;
+            return;
+        }
+        self.rx_sn = (self.rx_sn + 1) & 0x0f;
+        self.rx_last_ms = millis;
+        let remaining = self.rx_len - self.rx_buf.len();
+        let n = remaining.min(7).min(data.len() - 1);
+        self.rx_buf.try_extend_from_slice(&data[1..1 + n]).unwrap();
+        if self.rx_buf.len() >= self.rx_len {
+            self.rx_state = RxState::Complete;
+            return;
+        }
+        if self.config.block_size != 0 {
+            self.rx_block_count += 1;
+            if self.rx_block_count >= self.config.block_size {
+                self.rx_block_count = 0;
+                self.queue_flow_control(FLOW_STATUS_CONTINUE);
+            }
+        }
+    }
+
+    fn on_flow_control(&mut self, data: &[u8], millis: u64) {
+        if self.tx_state != TxState::WaitFlowControl || data.len() < 3 {
+            return;
+        }
+        match data[0] & 0x0f {
+            FLOW_STATUS_CONTINUE => {
+                self.tx_block_size = data[1];
+                self.tx_block_remaining = data[1];
+                self.tx_st_min_ms = match data[2] {
+                    0x00..=0x7f => data[2] as u64,
+                    // 100...900us; we can't time that finely
+                    0xf1..=0xf9 => 1,
+                    // Reserved values are to be interpreted as the maximum
+                    _ => 0x7f,
+                };
+                self.tx_waits = 0;
+                self.tx_state = TxState::SendConsecutive;
+            }
+            FLOW_STATUS_WAIT => {
+                self.tx_waits += 1;
+                self.tx_last_ms = millis;
+                if self.tx_waits > MAX_FLOW_CONTROL_WAITS {
+                    self.abort_tx(IsoTpError::TooManyWaits);
+                }
+            }
+            FLOW_STATUS_OVERFLOW => {
+                self.abort_tx(IsoTpError::Overflow);
+            }
+            _ => {
+                self.abort_tx(IsoTpError::InvalidFlowControl);
+            }
+        }
+    }
+
+    // Sends queued frames and consecutive frames, and handles timeouts
+    pub fn update(&mut self, hw: &mut dyn CanPort) {
+        let millis = hw.millis();
+        let mut frames_sent = 0;
+
+        while let Some(frame) = self.out_frames.dequeue() {
+            hw.send_can(frame);
+            frames_sent += 1;
+        }
+
+        match self.tx_state {
+            TxState::Idle => {}
+            TxState::WaitFlowControl => {
+                if !self.tx_wait_started {
+                    self.tx_wait_started = true;
+                    self.tx_last_ms = millis;
+                } else if millis - self.tx_last_ms >= self.config.timeout_ms {
+                    self.abort_tx(IsoTpError::Timeout);
+                }
+            }
+            TxState::SendConsecutive => {
+                while frames_sent < self.config.max_frames_per_update
+                    && self.tx_state == TxState::SendConsecutive
+                    && self
+                        .tx_last_cf_ms
+                        .is_none_or(|t| millis - t >= self.tx_st_min_ms)
+                {
+                    self.send_consecutive_frame(hw, millis);
+                    frames_sent += 1;
+                    // Frames sent within one update go out back-to-back, so
+                    // only one is allowed if the receiver wants a gap
+                    if self.tx_st_min_ms > 0 {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if self.rx_state == RxState::ReceiveConsecutive
+            && millis.saturating_sub(self.rx_last_ms) >= self.config.timeout_ms
+        {
+            self.set_error(IsoTpError::Timeout);
+            self.rx_state = RxState::Idle;
+        }
+    }
+
+    fn send_consecutive_frame(&mut self, hw: &mut dyn CanPort, millis: u64) {
+        let n = (self.tx_buf.len() - self.tx_pos).min(7);
+        let mut buf = [0u8; 8];
+        buf[0] = PCI_CONSECUTIVE_FRAME | self.tx_sn;
+        buf[1..1 + n].copy_from_slice(&self.tx_buf[self.tx_pos..self.tx_pos + n]);
+        if let Some(frame) = self.make_frame(&buf[..1 + n]) {
+            hw.send_can(frame);
+        }
+        self.tx_pos += n;
+        self.tx_sn = (self.tx_sn + 1) & 0x0f;
+        self.tx_last_ms = millis;
+        self.tx_last_cf_ms = Some(millis);
+
+        if self.tx_pos >= self.tx_buf.len() {
+            self.tx_state = TxState::Idle;
+        } else if self.tx_block_size != 0 {
+            self.tx_block_remaining -= 1;
+            if self.tx_block_remaining == 0 {
+                self.tx_state = TxState::WaitFlowControl;
+                self.tx_wait_started = true;
+            }
+        }
+    }
+
+    // Returns a completely received message
+    pub fn take_received(&mut self) -> Option<ArrayVec<u8, N>> {
+        if self.rx_state != RxState::Complete {
+            return None;
+        }
+        self.rx_state = RxState::Idle;
+        Some(core::mem::take(&mut self.rx_buf))
+    }
+
+    // Returns the last error from a transmission or reception that failed
+    // after it was started
+    pub fn take_error(&mut self) -> Option<IsoTpError> {
+        self.last_error.take()
+    }
+
+    fn abort_tx(&mut self, error: IsoTpError) {
+        self.set_error(error);
+        self.tx_state = TxState::Idle;
+    }
+
+    fn set_error(&mut self, error: IsoTpError) {
+        warn!("-!- IsoTp {:?}: {:?}", self.config.tx_id, error);
+        self.last_error = Some(error);
+    }
+
+    fn queue_flow_control(&mut self, flow_status: u8) {
+        self.queue_frame(&[
+            PCI_FLOW_CONTROL | flow_status,
+            self.config.block_size,
+            self.config.st_min_ms.min(0x7f),
+        ]);
+    }
+
+    fn queue_frame(&mut self, data: &[u8]) {
+        if let Some(frame) = self.make_frame(data) {
+            self.out_frames.push(frame);
+        }
+    }
+
+    fn make_frame(&self, data: &[u8]) -> Option<bxcan::Frame> {
+        let mut buf = [0u8; 8];
+        buf[..data.len()].copy_from_slice(data);
+        let len = if let Some(padding) = self.config.padding {
+            buf[data.len()..].fill(padding);
+            8
+        } else {
+            data.len()
+        };
+        Some(bxcan::Frame::new_data(
+            self.config.tx_id,
+            bxcan::Data::new(&buf[..len])?,
+        ))
+    }
+}
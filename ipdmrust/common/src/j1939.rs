@@ -0,0 +1,347 @@
+use crate::HardwareInterface;
+use arrayvec::ArrayVec;
+use bxcan::ExtendedId;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// SAE J1939
+//
+// * J1939Id: Decoding and encoding of 29-bit identifiers (priority, PGN,
+//   source and destination address)
+// * j1939_frame(): Building frames to transmit
+// * J1939AddressClaimer: Address claiming (J1939-81)
+// * BamReceiver: Transport protocol broadcast (BAM) receive (J1939-21)
+
+pub const PGN_REQUEST: u32 = 0xEA00;
+pub const PGN_ADDRESS_CLAIMED: u32 = 0xEE00;
+pub const PGN_TP_CM: u32 = 0xEC00;
+pub const PGN_TP_DT: u32 = 0xEB00;
+
+pub const ADDRESS_GLOBAL: u8 = 0xFF;
+pub const ADDRESS_NULL: u8 = 0xFE;
+
+// Maximum length of a transport protocol message
+pub const J1939_TP_MAX_LEN: usize = 1785;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct J1939Id {
+    pub priority: u8,
+    // For PDU1 (PF < 240) PGNs the PS byte is the destination address and is
+    // zero in the PGN
+    pub pgn: u32,
+    pub source_address: u8,
+    // ADDRESS_GLOBAL for PDU2 PGNs
+    pub destination_address: u8,
+}
+
+impl J1939Id {
+    pub fn from_id(id: bxcan::Id) -> Option<Self> {
+        let bxcan::Id::Extended(id) = id else {
+            return None;
+        };
+        let raw = id.as_raw();
+        let pf = (raw >> 16) & 0xff;
+        let ps = (raw >> 8) & 0xff;
+        let (pgn, destination_address) = if pf < 240 {
+            ((raw >> 8) & 0x3ff00, ps as u8)
+        } else {
+            ((raw >> 8) & 0x3ffff, ADDRESS_GLOBAL)
+        };
+        Some(Self {
+            priority: ((raw >> 26) & 0x07) as u8,
+            pgn,
+            source_address: (raw & 0xff) as u8,
+            destination_address,
+        })
+    }
+
+    pub fn to_id(&self) -> bxcan::Id {
+        let pf = (self.pgn >> 8) & 0xff;
+        let mut raw = ((self.priority as u32 & 0x07) << 26)
+            | ((self.pgn & 0x3ffff) << 8)
+            | self.source_address as u32;
+        if pf < 240 {
+            raw = (raw & !0xff00) | ((self.destination_address as u32) << 8);
+        }
+        bxcan::Id::Extended(ExtendedId::new(raw).unwrap())
+    }
+
+    pub fn is_pdu1(&self) -> bool {
+        (self.pgn >> 8) & 0xff < 240
+    }
+}
+
+// Builds a J1939 frame. destination_address is ignored for PDU2 PGNs.
+pub fn j1939_frame(id: J1939Id, data: &[u8]) -> Option<bxcan::Frame> {
+    Some(bxcan::Frame::new_data(id.to_id(), bxcan::Data::new(data)?))
+}
+
+// Address claiming
+
+// How long to wait for contending claims before using the address
+const ADDRESS_CLAIM_WAIT_MS: u64 = 250;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressClaimState {
+    // A claim is queued to be sent
+    Claiming,
+    WaitingForContention,
+    Claimed,
+    CannotClaim,
+}
+
+pub struct J1939AddressClaimer {
+    // The 64-bit J1939 NAME. Lower value wins contention.
+    pub name: u64,
+    // If the NAME has the arbitrary address capable bit set, addresses from
+    // this range are tried when the preferred address is lost
+    pub preferred_address: u8,
+    pub address_range: core::ops::RangeInclusive<u8>,
+    address: u8,
+    state: AddressClaimState,
+    claim_sent_ms: u64,
+    send_claim: bool,
+}
+
+impl J1939AddressClaimer {
+    pub fn new(name: u64, preferred_address: u8) -> Self {
+        Self {
+            name,
+            preferred_address,
+            address_range: 128..=247,
+            address: preferred_address,
+            state: AddressClaimState::Claiming,
+            claim_sent_ms: 0,
+            send_claim: true,
+        }
+    }
+
+    fn arbitrary_address_capable(&self) -> bool {
+        self.name & (1 << 63) != 0
+    }
+
+    pub fn state(&self) -> AddressClaimState {
+        self.state
+    }
+
+    // The claimed address, or None if it can't be used yet
+    pub fn address(&self) -> Option<u8> {
+        if self.state == AddressClaimState::Claimed {
+            Some(self.address)
+        } else {
+            None
+        }
+    }
+
+    pub fn on_can(&mut self, frame: &bxcan::Frame) {
+        let Some(id) = J1939Id::from_id(frame.id()) else {
+            return;
+        };
+        let Some(data) = frame.data() else {
+            return;
+        };
+        if id.pgn == PGN_REQUEST && data.len() >= 3 {
+            let requested_pgn = u32::from_le_bytes([data[0], data[1], data[2], 0]);
+            if requested_pgn == PGN_ADDRESS_CLAIMED
+                && (id.destination_address == ADDRESS_GLOBAL
+                    || id.destination_address == self.address)
+            {
+                self.send_claim = true;
+            }
+        } else if id.pgn == PGN_ADDRESS_CLAIMED
+            && data.len() == 8
+            && id.source_address == self.address
+            && self.state != AddressClaimState::CannotClaim
+        {
+            let their_name = u64::from_le_bytes(data[..].try_into().unwrap());
+            if their_name == self.name {
+                return;
+            }
+            if self.name < their_name {
+                // We win. Defend the address.
+                self.send_claim = true;
+            } else {
+                self.lose_address();
+            }
+        }
+    }
+
+    fn lose_address(&mut self) {
+        warn!("-!- J1939: Lost address {}", self.address);
+        // Try the next address in the range
+        let next = if !self.arbitrary_address_capable() {
+            None
+        } else if self.address_range.contains(&self.address) {
+            self.address
+                .checked_add(1)
+                .filter(|a| self.address_range.contains(a))
+        } else {
+            Some(*self.address_range.start())
+        };
+        if let Some(address) = next {
+            self.address = address;
+            self.state = AddressClaimState::Claiming;
+        } else {
+            warn!("-!- J1939: Cannot claim an address");
+            self.state = AddressClaimState::CannotClaim;
+        }
+        self.send_claim = true;
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        if self.send_claim {
+            self.send_claim = false;
+            let source_address = if self.state == AddressClaimState::CannotClaim {
+                ADDRESS_NULL
+            } else {
+                self.address
+            };
+            let id = J1939Id {
+                priority: 6,
+                pgn: PGN_ADDRESS_CLAIMED,
+                source_address,
+                destination_address: ADDRESS_GLOBAL,
+            };
+            if let Some(frame) = j1939_frame(id, &self.name.to_le_bytes()) {
+                hw.send_can(frame);
+            }
+            if self.state == AddressClaimState::Claiming {
+                self.state = AddressClaimState::WaitingForContention;
+                self.claim_sent_ms = hw.millis();
+            }
+        }
+        if self.state == AddressClaimState::WaitingForContention
+            && hw.millis().saturating_sub(self.claim_sent_ms) >= ADDRESS_CLAIM_WAIT_MS
+        {
+            info!("-!- J1939: Claimed address {}", self.address);
+            self.state = AddressClaimState::Claimed;
+        }
+    }
+}
+
+// Transport protocol BAM receive
+
+const TP_CM_BAM: u8 = 32;
+// T1: Maximum time between data packets
+const BAM_TIMEOUT_MS: u64 = 750;
+
+pub struct J1939Message<const N: usize> {
+    pub pgn: u32,
+    pub source_address: u8,
+    pub data: ArrayVec<u8, N>,
+}
+
+struct BamSession<const N: usize> {
+    message: J1939Message<N>,
+    size: usize,
+    packets: u8,
+    next_sequence: u8,
+    last_ms: u64,
+}
+
+// N is the maximum message length, S is the number of concurrent sessions
+// (one per source address)
+pub struct BamReceiver<const N: usize, const S: usize> {
+    sessions: ArrayVec<BamSession<N>, S>,
+    received: Option<J1939Message<N>>,
+}
+
+impl<const N: usize, const S: usize> Default for BamReceiver<N, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const S: usize> BamReceiver<N, S> {
+    pub fn new() -> Self {
+        Self {
+            sessions: ArrayVec::new(),
+            received: None,
+        }
+    }
+
+    pub fn on_can(&mut self, frame: &bxcan::Frame, millis: u64) {
+        let Some(id) = J1939Id::from_id(frame.id()) else {
+            return;
+        };
+        let Some(data) = frame.data() else {
+            return;
+        };
+        if data.len() != 8 || id.destination_address != ADDRESS_GLOBAL {
+            return;
+        }
+        let source_address = id.source_address;
+        if id.pgn == PGN_TP_CM && data[0] == TP_CM_BAM {
+            let size = u16::from_le_bytes([data[1], data[2]]) as usize;
+            let packets = data[3];
+            let pgn = u32::from_le_bytes([data[5], data[6], data[7], 0]);
+            // A new announcement aborts any previous session from the sender
+            self.sessions
+                .retain(|s| s.message.source_address != source_address);
+            if size > N || size < 9 || packets as usize != size.div_ceil(7) {
+                debug!(
+                    "J1939: Ignoring BAM of {} bytes from {}",
+                    size, source_address
+                );
+                return;
+            }
+            if self.sessions.is_full() {
+                warn!("-!- J1939: Too many BAM sessions (max {})", S);
+                return;
+            }
+            self.sessions.push(BamSession {
+                message: J1939Message {
+                    pgn,
+                    source_address,
+                    data: ArrayVec::new(),
+                },
+                size,
+                packets,
+                next_sequence: 1,
+                last_ms: millis,
+            });
+        } else if id.pgn == PGN_TP_DT {
+            let Some(i) = self
+                .sessions
+                .iter()
+                .position(|s| s.message.source_address == source_address)
+            else {
+                return;
+            };
+            let session = &mut self.sessions[i];
+            if data[0] != session.next_sequence {
+                warn!(
+                    "-!- J1939: BAM from {}: Wrong sequence number {} (expected {})",
+                    source_address, data[0], session.next_sequence
+                );
+                self.sessions.remove(i);
+                return;
+            }
+            let n = (session.size - session.message.data.len()).min(7);
+            session.message.data.extend(data[1..1 + n].iter().copied());
+            session.last_ms = millis;
+            if session.next_sequence == session.packets {
+                let session = self.sessions.remove(i);
+                self.received = Some(session.message);
+            } else {
+                session.next_sequence += 1;
+            }
+        }
+    }
+
+    // This should be called regularly
+    pub fn run_timeout(&mut self, millis: u64) {
+        self.sessions.retain(|s| {
+            let timed_out = millis.saturating_sub(s.last_ms) >= BAM_TIMEOUT_MS;
+            if timed_out {
+                warn!("-!- J1939: BAM from {} timed out", s.message.source_address);
+            }
+            !timed_out
+        });
+    }
+
+    pub fn take_received(&mut self) -> Option<J1939Message<N>> {
+        self.received.take()
+    }
+}
@@ -0,0 +1,348 @@
+use crate::{get_parameter_id, standard_id, HardwareInterface};
+use arrayvec::ArrayVec;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// CANopen keypad driver (Blink Marine PKP style)
+//
+// * Key states are received in TPDO1 (0x180 + node id) as a bitmap, key 1 =
+//   bit 0 of byte 0
+// * LEDs are set using RPDO1 (0x200 + node id): bytes 0-1 red, 2-3 green, 4-5
+//   blue, each a bitmap like the key states
+// * LED brightness is set using RPDO3 (0x400 + node id) and backlight
+//   brightness using RPDO4 (0x500 + node id), byte 0 = 0..0x3F
+// * The keypad's heartbeat (0x700 + node id) is supervised. When the keypad
+//   boots or is in pre-operational state, it is started using NMT.
+// * Our own heartbeat is sent on 0x700 + own_node_id. Keypads can be
+//   configured to turn their LEDs off when it goes missing.
+//
+// Keys are mapped to parameters. Momentary and hold keys set the parameter to
+// 1.0 while active, toggle keys flip it on each press. Each key's LED shows
+// the state of its parameter. The app can also read key states directly using
+// is_active(key), like it reads DigitalInputs.
+
+const NMT_ID: u16 = 0x000;
+const NMT_START_REMOTE_NODE: u8 = 0x01;
+
+const HEARTBEAT_BOOTUP: u8 = 0x00;
+const HEARTBEAT_OPERATIONAL: u8 = 0x05;
+
+const MAX_KEYS: usize = 16;
+
+pub const MAX_BRIGHTNESS: u8 = 0x3f;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyMode {
+    // Active while pressed
+    Momentary,
+    // Active after being held for the given time, until released
+    Hold { hold_ms: u64 },
+    // Each press flips the state
+    Toggle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LedColor {
+    Off = 0b000,
+    Red = 0b001,
+    Green = 0b010,
+    Blue = 0b100,
+    Yellow = 0b011,
+    Magenta = 0b101,
+    Cyan = 0b110,
+    White = 0b111,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeyMapping {
+    // 1-based, as printed on the keypad
+    pub key: u8,
+    // Parameter id
+    pub parameter: usize,
+    pub mode: KeyMode,
+    pub led_on: LedColor,
+    pub led_off: LedColor,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeypadConfig {
+    pub node_id: u8,
+    pub keys: &'static [KeyMapping],
+    // Key parameters are released if no heartbeat is received within this
+    // time
+    pub heartbeat_timeout_ms: u64,
+    pub led_period_ms: u64,
+    // 0..MAX_BRIGHTNESS
+    pub led_brightness: u8,
+    pub backlight_brightness: u8,
+    // Our node id for the heartbeat
+    pub own_node_id: u8,
+    pub heartbeat_period_ms: u64,
+}
+
+pub struct Keypad {
+    pub config: KeypadConfig,
+    // The valid mappings of config.keys
+    keys: ArrayVec<KeyMapping, MAX_KEYS>,
+    pressed: u16,
+    pressed_since_ms: [u64; MAX_KEYS],
+    alive: bool,
+    last_heartbeat_ms: u64,
+    send_nmt_start: bool,
+    last_led_ms: u64,
+    last_leds: Option<[u8; 6]>,
+    send_brightness: bool,
+    last_own_heartbeat_ms: u64,
+}
+
+impl Keypad {
+    pub fn new(config: KeypadConfig) -> Self {
+        let mut keys = ArrayVec::new();
+        for mapping in config.keys {
+            if !(1..=MAX_KEYS as u8).contains(&mapping.key) {
+                error!(
+                    "-!- Keypad::new(): Key {} out of range (1..{}), ignoring",
+                    mapping.key, MAX_KEYS
+                );
+            } else if keys.try_push(*mapping).is_err() {
+                error!("-!- Keypad::new(): Too many keys (max {})", MAX_KEYS);
+                break;
+            }
+        }
+        Self {
+            config,
+            keys,
+            pressed: 0,
+            pressed_since_ms: [0; MAX_KEYS],
+            alive: false,
+            last_heartbeat_ms: 0,
+            send_nmt_start: false,
+            last_led_ms: 0,
+            last_leds: None,
+            send_brightness: true,
+            last_own_heartbeat_ms: 0,
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.alive
+    }
+
+    // Whether the key is physically pressed. key is 1-based.
+    pub fn is_pressed(&self, key: u8) -> bool {
+        (1..=MAX_KEYS as u8).contains(&key) && self.pressed & (1 << (key - 1)) != 0
+    }
+
+    // Whether the key's mapped function is active (pressed, held or toggled
+    // on). Unmapped keys are active while pressed.
+    pub fn is_active(&self, key: u8) -> bool {
+        match self.keys.iter().find(|m| m.key == key) {
+            Some(mapping) => get_parameter_id(mapping.parameter).value > 0.5,
+            None => self.is_pressed(key),
+        }
+    }
+
+    pub fn set_brightness(&mut self, led_brightness: u8, backlight_brightness: u8) {
+        self.config.led_brightness = led_brightness.min(MAX_BRIGHTNESS);
+        self.config.backlight_brightness = backlight_brightness.min(MAX_BRIGHTNESS);
+        self.send_brightness = true;
+    }
+
+    fn tpdo1_id(&self) -> bxcan::Id {
+        standard_id(0x180 + self.config.node_id as u16)
+    }
+
+    fn rpdo1_id(&self) -> bxcan::Id {
+        standard_id(0x200 + self.config.node_id as u16)
+    }
+
+    fn rpdo3_id(&self) -> bxcan::Id {
+        standard_id(0x400 + self.config.node_id as u16)
+    }
+
+    fn rpdo4_id(&self) -> bxcan::Id {
+        standard_id(0x500 + self.config.node_id as u16)
+    }
+
+    fn heartbeat_id(&self) -> bxcan::Id {
+        standard_id(0x700 + self.config.node_id as u16)
+    }
+
+    // Returns true if the frame was consumed
+    pub fn on_can(&mut self, frame: &bxcan::Frame, millis: u64) -> bool {
+        let Some(data) = frame.data() else {
+            return false;
+        };
+        if frame.id() == self.heartbeat_id() {
+            if let Some(&state) = data.first() {
+                if !self.alive {
+                    info!("-!- Keypad alive");
+                    self.alive = true;
+                }
+                self.last_heartbeat_ms = millis;
+                if state == HEARTBEAT_BOOTUP {
+                    // Re-send the LED state after a reboot
+                    self.last_leds = None;
+                    self.send_brightness = true;
+                }
+                if state != HEARTBEAT_OPERATIONAL {
+                    self.send_nmt_start = true;
+                }
+            }
+            true
+        } else if frame.id() == self.tpdo1_id() {
+            let pressed = u16::from_le_bytes([
+                data.first().copied().unwrap_or(0),
+                data.get(1).copied().unwrap_or(0),
+            ]);
+            self.on_keys(pressed, millis);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn on_keys(&mut self, pressed: u16, millis: u64) {
+        let changed = pressed ^ self.pressed;
+        self.pressed = pressed;
+        for mapping in &self.keys {
+            let bit = 1u16 << (mapping.key - 1);
+            if changed & bit == 0 {
+                continue;
+            }
+            let is_pressed = pressed & bit != 0;
+            if is_pressed {
+                self.pressed_since_ms[(mapping.key - 1) as usize] = millis;
+            }
+            let param = get_parameter_id(mapping.parameter);
+            match mapping.mode {
+                KeyMode::Momentary => {
+                    param.set_value(if is_pressed { 1.0 } else { 0.0 }, millis);
+                }
+                KeyMode::Hold { .. } => {
+                    if !is_pressed {
+                        param.set_value(0.0, millis);
+                    }
+                }
+                KeyMode::Toggle => {
+                    if is_pressed {
+                        let value = if param.value > 0.5 { 0.0 } else { 1.0 };
+                        param.set_value(value, millis);
+                    }
+                }
+            }
+        }
+    }
+
+    fn release_keys(&mut self, millis: u64) {
+        self.pressed = 0;
+        for mapping in &self.keys {
+            if mapping.mode != KeyMode::Toggle {
+                get_parameter_id(mapping.parameter).set_value(0.0, millis);
+            }
+        }
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        let millis = hw.millis();
+
+        if millis.saturating_sub(self.last_own_heartbeat_ms) >= self.config.heartbeat_period_ms {
+            self.last_own_heartbeat_ms = millis;
+            hw.send_can(bxcan::Frame::new_data(
+                standard_id(0x700 + self.config.own_node_id as u16),
+                bxcan::Data::new(&[HEARTBEAT_OPERATIONAL]).unwrap(),
+            ));
+        }
+
+        if self.alive
+            && millis.saturating_sub(self.last_heartbeat_ms) >= self.config.heartbeat_timeout_ms
+        {
+            warn!("-!- Keypad timed out");
+            self.alive = false;
+            self.last_leds = None;
+            self.send_brightness = true;
+            self.release_keys(millis);
+        }
+        if !self.alive {
+            return;
+        }
+
+        for mapping in &self.keys {
+            if let KeyMode::Hold { hold_ms } = mapping.mode {
+                let bit = 1u16 << (mapping.key - 1);
+                let since = self.pressed_since_ms[(mapping.key - 1) as usize];
+                if self.pressed & bit != 0 && millis.saturating_sub(since) >= hold_ms {
+                    let param = get_parameter_id(mapping.parameter);
+                    if param.value < 0.5 {
+                        param.set_value(1.0, millis);
+                    }
+                }
+            }
+        }
+
+        if self.send_nmt_start {
+            self.send_nmt_start = false;
+            info!("-!- Keypad: Sending NMT start");
+            hw.send_can(bxcan::Frame::new_data(
+                standard_id(NMT_ID),
+                bxcan::Data::new(&[NMT_START_REMOTE_NODE, self.config.node_id]).unwrap(),
+            ));
+        }
+
+        if self.send_brightness {
+            self.send_brightness = false;
+            hw.send_can(bxcan::Frame::new_data(
+                self.rpdo3_id(),
+                bxcan::Data::new(&[self.config.led_brightness]).unwrap(),
+            ));
+            hw.send_can(bxcan::Frame::new_data(
+                self.rpdo4_id(),
+                bxcan::Data::new(&[self.config.backlight_brightness]).unwrap(),
+            ));
+        }
+
+        // LEDs are sent when they change and periodically
+        let leds = self.led_data();
+        if self.last_leds != Some(leds)
+            || millis.saturating_sub(self.last_led_ms) >= self.config.led_period_ms
+        {
+            self.last_leds = Some(leds);
+            self.last_led_ms = millis;
+            hw.send_can(bxcan::Frame::new_data(
+                self.rpdo1_id(),
+                bxcan::Data::new(&leds).unwrap(),
+            ));
+        }
+    }
+
+    fn led_data(&self) -> [u8; 6] {
+        let mut red: u16 = 0;
+        let mut green: u16 = 0;
+        let mut blue: u16 = 0;
+        for mapping in &self.keys {
+            let active = get_parameter_id(mapping.parameter).value > 0.5;
+            let color = if active {
+                mapping.led_on
+            } else {
+                mapping.led_off
+            } as u8;
+            let bit = 1u16 << (mapping.key - 1);
+            if color & LedColor::Red as u8 != 0 {
+                red |= bit;
+            }
+            if color & LedColor::Green as u8 != 0 {
+                green |= bit;
+            }
+            if color & LedColor::Blue as u8 != 0 {
+                blue |= bit;
+            }
+        }
+        let mut data = [0u8; 6];
+        data[0..2].copy_from_slice(&red.to_le_bytes());
+        data[2..4].copy_from_slice(&green.to_le_bytes());
+        data[4..6].copy_from_slice(&blue.to_le_bytes());
+        data
+    }
+}
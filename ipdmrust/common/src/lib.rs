@@ -1,20 +1,43 @@
 #![no_std]
 
+pub mod can_console;
+pub mod can_integrity;
+pub mod can_node;
+pub mod can_scheduler;
+pub mod can_update;
 pub mod command_accumulator;
+pub mod current_control;
+pub mod dtc;
+pub mod gateway;
+pub mod hbridge;
+pub mod isotp;
+pub mod j1939;
+pub mod keypad;
+pub mod load_diagnostics;
+pub mod obd;
+pub mod output_layers;
+pub mod output_manager;
+pub mod pulse_capture;
+pub mod pwm_control;
+pub mod remote_io;
+pub mod sdo;
+pub mod smart_fuse;
+pub mod soft_pwm;
+pub mod uds;
 
 pub extern crate bxcan;
 pub extern crate log;
 
 use arrayvec::ArrayString;
 use bitvec::prelude::*;
-use bxcan::StandardId;
+use bxcan::{ExtendedId, StandardId};
 use fixedstr::str_format;
 use int_enum::IntEnum;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use ringbuffer::RingBuffer;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum AnalogInput {
     AuxVoltage,
     PcbT,
@@ -38,6 +61,12 @@ pub enum DigitalInput {
     Group3OC, // HOUT7..10
     Group4OC, // HOUT11,HOUT12,WAKEUP
     Ignition,
+    M1,
+    M2,
+    M3,
+    M4,
+    M5,
+    M6,
     M7,
     M8,
     M9,
@@ -68,7 +97,20 @@ pub enum DigitalOutput {
     LOUT4,
     LOUT5,
     LOUT6,
-    // TODO: M* pins
+    // Only driven when the pin is configured as MPinMode::Output
+    M1,
+    M2,
+    M3,
+    M4,
+    M5,
+    M6,
+    M7,
+    M8,
+    M9,
+    M10,
+    M11,
+    M12,
+    M13,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -81,21 +123,508 @@ pub enum PwmOutput {
     // TODO: LPWM1 (not supported in ipdmhw2.0 due to a hardware bug
 }
 
-pub trait HardwareInterface {
+impl DigitalOutput {
+    pub const ALL: [DigitalOutput; 32] = [
+        DigitalOutput::Wakeup,
+        DigitalOutput::HOUT1,
+        DigitalOutput::HOUT2,
+        DigitalOutput::HOUT3,
+        DigitalOutput::HOUT4,
+        DigitalOutput::HOUT5,
+        DigitalOutput::HOUT6,
+        DigitalOutput::HOUT7,
+        DigitalOutput::HOUT8,
+        DigitalOutput::HOUT9,
+        DigitalOutput::HOUT10,
+        DigitalOutput::HOUT11,
+        DigitalOutput::HOUT12,
+        DigitalOutput::LOUT1,
+        DigitalOutput::LOUT2,
+        DigitalOutput::LOUT3,
+        DigitalOutput::LOUT4,
+        DigitalOutput::LOUT5,
+        DigitalOutput::LOUT6,
+        DigitalOutput::M1,
+        DigitalOutput::M2,
+        DigitalOutput::M3,
+        DigitalOutput::M4,
+        DigitalOutput::M5,
+        DigitalOutput::M6,
+        DigitalOutput::M7,
+        DigitalOutput::M8,
+        DigitalOutput::M9,
+        DigitalOutput::M10,
+        DigitalOutput::M11,
+        DigitalOutput::M12,
+        DigitalOutput::M13,
+    ];
+}
+
+impl PwmOutput {
+    pub const ALL: [PwmOutput; 5] = [
+        PwmOutput::LCUR1,
+        PwmOutput::SPWM1,
+        PwmOutput::SPWM2,
+        PwmOutput::LPWM2,
+        PwmOutput::LPWM3,
+    ];
+}
+
+// PWM outputs on the same timer share a frequency
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PwmTimer {
+    Tim3,
+    Tim4,
+}
+
+impl PwmTimer {
+    pub const ALL: [PwmTimer; 2] = [PwmTimer::Tim3, PwmTimer::Tim4];
+
+    pub fn outputs(self) -> &'static [PwmOutput] {
+        match self {
+            PwmTimer::Tim3 => &[PwmOutput::LPWM2, PwmOutput::LPWM3],
+            PwmTimer::Tim4 => &[PwmOutput::LCUR1, PwmOutput::SPWM1, PwmOutput::SPWM2],
+        }
+    }
+}
+
+impl PwmOutput {
+    pub fn timer(self) -> PwmTimer {
+        match self {
+            PwmOutput::LPWM2 | PwmOutput::LPWM3 => PwmTimer::Tim3,
+            PwmOutput::LCUR1 | PwmOutput::SPWM1 | PwmOutput::SPWM2 => PwmTimer::Tim4,
+        }
+    }
+}
+
+// High side outputs share a current limiter, an overcurrent signal and a
+// current measurement per group
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputGroup {
+    Group1,
+    Group2,
+    Group3,
+    Group4,
+}
+
+impl OutputGroup {
+    pub const ALL: [OutputGroup; 4] = [
+        OutputGroup::Group1,
+        OutputGroup::Group2,
+        OutputGroup::Group3,
+        OutputGroup::Group4,
+    ];
+
+    pub fn outputs(self) -> &'static [DigitalOutput] {
+        match self {
+            OutputGroup::Group1 => &[
+                DigitalOutput::HOUT1,
+                DigitalOutput::HOUT2,
+                DigitalOutput::HOUT3,
+            ],
+            OutputGroup::Group2 => &[
+                DigitalOutput::HOUT4,
+                DigitalOutput::HOUT5,
+                DigitalOutput::HOUT6,
+            ],
+            OutputGroup::Group3 => &[
+                DigitalOutput::HOUT7,
+                DigitalOutput::HOUT8,
+                DigitalOutput::HOUT9,
+                DigitalOutput::HOUT10,
+            ],
+            OutputGroup::Group4 => &[
+                DigitalOutput::HOUT11,
+                DigitalOutput::HOUT12,
+                DigitalOutput::Wakeup,
+            ],
+        }
+    }
+
+    pub fn overcurrent_input(self) -> DigitalInput {
+        match self {
+            OutputGroup::Group1 => DigitalInput::Group1OC,
+            OutputGroup::Group2 => DigitalInput::Group2OC,
+            OutputGroup::Group3 => DigitalInput::Group3OC,
+            OutputGroup::Group4 => DigitalInput::Group4OC,
+        }
+    }
+
+    pub fn current_input(self) -> AnalogInput {
+        match self {
+            OutputGroup::Group1 => AnalogInput::Current1,
+            OutputGroup::Group2 => AnalogInput::Current2,
+            OutputGroup::Group3 => AnalogInput::Current3,
+            OutputGroup::Group4 => AnalogInput::Current4,
+        }
+    }
+}
+
+impl DigitalOutput {
+    // None for the low side outputs and M pins
+    pub fn group(self) -> Option<OutputGroup> {
+        OutputGroup::ALL
+            .into_iter()
+            .find(|group| group.outputs().contains(&self))
+    }
+
+    pub fn m_pin(self) -> Option<MPin> {
+        MPin::ALL
+            .into_iter()
+            .find(|pin| pin.digital_output() == self)
+    }
+}
+
+// General purpose M pins. Each pin is configured at startup as one of
+// MPinMode using the app's MPinConfig list, and HardwareInterface
+// implementations follow that configuration:
+// * Analog: Read using AnalogInput. Only M1...M6 are connected to the ADC.
+// * Input*: Read using DigitalInput
+// * Output: Push-pull, set using DigitalOutput
+// * Capture: Input with a pull-up whose frequency and duty cycle are measured
+//   (see CaptureInput). Also readable using DigitalInput.
+// Reading a pin in another mode returns NaN or false, and setting a pin that
+// isn't an output does nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MPin {
+    M1,
+    M2,
+    M3,
+    M4,
+    M5,
+    M6,
+    M7,
+    M8,
+    M9,
+    M10,
+    M11,
+    M12,
+    M13,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MPinMode {
+    Analog,
+    InputFloating,
+    InputPullUp,
+    InputPullDown,
+    Output,
+    Capture,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MPinConfig {
+    pub pin: MPin,
+    pub mode: MPinMode,
+}
+
+impl MPin {
+    pub const ALL: [MPin; 13] = [
+        MPin::M1,
+        MPin::M2,
+        MPin::M3,
+        MPin::M4,
+        MPin::M5,
+        MPin::M6,
+        MPin::M7,
+        MPin::M8,
+        MPin::M9,
+        MPin::M10,
+        MPin::M11,
+        MPin::M12,
+        MPin::M13,
+    ];
+
+    // None for pins that aren't connected to the ADC
+    pub fn analog_input(self) -> Option<AnalogInput> {
+        match self {
+            MPin::M1 => Some(AnalogInput::M1),
+            MPin::M2 => Some(AnalogInput::M2),
+            MPin::M3 => Some(AnalogInput::M3),
+            MPin::M4 => Some(AnalogInput::M4),
+            MPin::M5 => Some(AnalogInput::M5),
+            MPin::M6 => Some(AnalogInput::M6),
+            _ => None,
+        }
+    }
+
+    pub fn digital_input(self) -> DigitalInput {
+        match self {
+            MPin::M1 => DigitalInput::M1,
+            MPin::M2 => DigitalInput::M2,
+            MPin::M3 => DigitalInput::M3,
+            MPin::M4 => DigitalInput::M4,
+            MPin::M5 => DigitalInput::M5,
+            MPin::M6 => DigitalInput::M6,
+            MPin::M7 => DigitalInput::M7,
+            MPin::M8 => DigitalInput::M8,
+            MPin::M9 => DigitalInput::M9,
+            MPin::M10 => DigitalInput::M10,
+            MPin::M11 => DigitalInput::M11,
+            MPin::M12 => DigitalInput::M12,
+            MPin::M13 => DigitalInput::M13,
+        }
+    }
+
+    pub fn digital_output(self) -> DigitalOutput {
+        match self {
+            MPin::M1 => DigitalOutput::M1,
+            MPin::M2 => DigitalOutput::M2,
+            MPin::M3 => DigitalOutput::M3,
+            MPin::M4 => DigitalOutput::M4,
+            MPin::M5 => DigitalOutput::M5,
+            MPin::M6 => DigitalOutput::M6,
+            MPin::M7 => DigitalOutput::M7,
+            MPin::M8 => DigitalOutput::M8,
+            MPin::M9 => DigitalOutput::M9,
+            MPin::M10 => DigitalOutput::M10,
+            MPin::M11 => DigitalOutput::M11,
+            MPin::M12 => DigitalOutput::M12,
+            MPin::M13 => DigitalOutput::M13,
+        }
+    }
+
+    // The mode used when the pin isn't configured. This is how the pins
+    // worked before they were configurable.
+    pub fn default_mode(self) -> MPinMode {
+        if self.analog_input().is_some() {
+            MPinMode::Analog
+        } else {
+            MPinMode::InputFloating
+        }
+    }
+
+    // The mode of the pin according to config. Falls back to the default
+    // mode if the configured mode isn't supported by the pin.
+    pub fn mode(self, config: &[MPinConfig]) -> MPinMode {
+        let Some(c) = config.iter().find(|c| c.pin == self) else {
+            return self.default_mode();
+        };
+        if c.mode == MPinMode::Analog && self.analog_input().is_none() {
+            error!(
+                "-!- {:?} can't be an analog input, using {:?}",
+                self,
+                self.default_mode()
+            );
+            return self.default_mode();
+        }
+        if c.mode == MPinMode::Capture && self.capture_input().is_none() {
+            error!(
+                "-!- {:?} can't be a capture input, using {:?}",
+                self,
+                self.default_mode()
+            );
+            return self.default_mode();
+        }
+        c.mode
+    }
+
+    // None for pins that don't support capture
+    pub fn capture_input(self) -> Option<CaptureInput> {
+        CaptureInput::ALL
+            .into_iter()
+            .find(|input| input.m_pin() == self)
+    }
+}
+
+// M pins that support frequency and pulse width measurement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CaptureInput {
+    M11, // PE9
+    M12, // PB3
+    M13, // PB4
+}
+
+impl CaptureInput {
+    pub const ALL: [CaptureInput; 3] = [CaptureInput::M11, CaptureInput::M12, CaptureInput::M13];
+
+    pub fn m_pin(self) -> MPin {
+        match self {
+            CaptureInput::M11 => MPin::M11,
+            CaptureInput::M12 => MPin::M12,
+            CaptureInput::M13 => MPin::M13,
+        }
+    }
+}
+
+// The most recent raw measurement of a capture input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PulseMeasurement {
+    // Number of periods measured so far. Wraps around.
+    pub count: u32,
+    // Last period, from rising edge to rising edge
+    pub period_us: f32,
+    // High time within the last period
+    pub high_us: f32,
+}
+
+impl PulseMeasurement {
+    pub const NONE: PulseMeasurement = PulseMeasurement {
+        count: 0,
+        period_us: f32::NAN,
+        high_us: f32::NAN,
+    };
+}
+
+impl AnalogInput {
+    pub fn m_pin(self) -> Option<MPin> {
+        MPin::ALL
+            .into_iter()
+            .find(|pin| pin.analog_input() == Some(self))
+    }
+}
+
+impl DigitalInput {
+    pub fn m_pin(self) -> Option<MPin> {
+        MPin::ALL
+            .into_iter()
+            .find(|pin| pin.digital_input() == self)
+    }
+}
+
+impl MPinMode {
+    pub fn is_input(self) -> bool {
+        matches!(
+            self,
+            MPinMode::InputFloating
+                | MPinMode::InputPullUp
+                | MPinMode::InputPullDown
+                | MPinMode::Capture
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputDiagnostics {
+    // State the output is driven to
+    pub commanded: bool,
+    pub group: Option<OutputGroup>,
+    // The group's overcurrent signal is active
+    pub group_tripped: bool,
+    // Measured current of the whole group (A). NaN for outputs without a
+    // group.
+    pub group_current: f32,
+}
+
+// The part of the hardware that CAN protocols need. The bootloader and the
+// host tools only have this.
+pub trait CanPort {
     fn millis(&mut self) -> u64;
 
+    fn send_can(&mut self, frame: bxcan::Frame);
+}
+
+pub trait HardwareInterface: CanPort {
     fn reboot(&mut self);
     fn activate_dfu(&mut self);
 
-    fn send_can(&mut self, frame: bxcan::Frame);
-
     fn get_analog_input(&mut self, input: AnalogInput) -> f32;
 
     fn get_digital_input(&mut self, input: DigitalInput) -> bool;
 
     fn set_digital_output(&mut self, output: DigitalOutput, value: bool);
+    // Returns the state the output is currently driven to
+    fn get_digital_output(&mut self, output: DigitalOutput) -> bool;
 
     fn set_pwm_output(&mut self, output: PwmOutput, value: f32);
+    // Returns the duty cycle the output is currently driven at (0.0...1.0)
+    fn get_pwm_output(&mut self, output: PwmOutput) -> f32;
+
+    // Sets the frequency of all outputs of the timer. Duty cycles are kept.
+    fn set_pwm_frequency(&mut self, timer: PwmTimer, frequency_hz: f32);
+    fn get_pwm_frequency(&mut self, timer: PwmTimer) -> f32;
+
+    // The mode the M pin was configured to at startup
+    fn get_m_pin_mode(&mut self, pin: MPin) -> MPinMode;
+
+    // PulseMeasurement::NONE if the pin isn't configured as MPinMode::Capture
+    fn get_pulse_measurement(&mut self, input: CaptureInput) -> PulseMeasurement;
+
+    fn get_output_diagnostics(&mut self, output: DigitalOutput) -> OutputDiagnostics {
+        let group = output.group();
+        OutputDiagnostics {
+            commanded: self.get_digital_output(output),
+            group,
+            group_tripped: group.is_some_and(|g| self.get_digital_input(g.overcurrent_input())),
+            group_current: group.map_or(f32::NAN, |g| self.get_analog_input(g.current_input())),
+        }
+    }
+}
+
+pub fn print_outputs(hw: &mut dyn HardwareInterface) {
+    for output in DigitalOutput::ALL {
+        if output
+            .m_pin()
+            .is_some_and(|pin| hw.get_m_pin_mode(pin) != MPinMode::Output)
+        {
+            continue;
+        }
+        let diag = hw.get_output_diagnostics(output);
+        if let Some(group) = diag.group {
+            info!(
+                "{:?}: {} ({:?}: {:.2} A{})",
+                output,
+                if diag.commanded { "on" } else { "off" },
+                group,
+                diag.group_current,
+                if diag.group_tripped { ", TRIPPED" } else { "" }
+            );
+        } else {
+            info!(
+                "{:?}: {}",
+                output,
+                if diag.commanded { "on" } else { "off" }
+            );
+        }
+    }
+    for output in PwmOutput::ALL {
+        info!(
+            "{:?}: {:.1}% ({:?}: {:.0} Hz)",
+            output,
+            hw.get_pwm_output(output) * 100.0,
+            output.timer(),
+            hw.get_pwm_frequency(output.timer())
+        );
+    }
+}
+
+pub fn print_m_pins(hw: &mut dyn HardwareInterface) {
+    for pin in MPin::ALL {
+        let mode = hw.get_m_pin_mode(pin);
+        match mode {
+            MPinMode::Analog => {
+                let input = pin.analog_input().unwrap();
+                info!("{:?}: {:?}: {:.2} V", pin, mode, hw.get_analog_input(input));
+            }
+            MPinMode::Output => {
+                let value = hw.get_digital_output(pin.digital_output());
+                info!(
+                    "{:?}: {:?}: {}",
+                    pin,
+                    mode,
+                    if value { "on" } else { "off" }
+                );
+            }
+            _ => {
+                let value = hw.get_digital_input(pin.digital_input());
+                info!(
+                    "{:?}: {:?}: {}",
+                    pin,
+                    mode,
+                    if value { "high" } else { "low" }
+                );
+            }
+        }
+    }
+}
+
+// Shorthand for use in static definitions
+pub const fn standard_id(id: u16) -> bxcan::Id {
+    bxcan::Id::Standard(StandardId::new(id).unwrap())
+}
+
+pub const fn extended_id(id: u32) -> bxcan::Id {
+    bxcan::Id::Extended(ExtendedId::new(id).unwrap())
 }
 
 // Parameter definitions
@@ -111,12 +640,55 @@ pub enum CanBitSelection {
     Function(fn(&[u8]) -> Option<f32>),
 }
 
+pub enum CanMapId {
+    // Matches the exact CAN ID
+    Exact(bxcan::Id),
+    // Matches a J1939 PGN regardless of priority and destination address,
+    // optionally only from the given source address
+    J1939 {
+        pgn: u32,
+        source_address: Option<u8>,
+    },
+}
+
+impl CanMapId {
+    pub fn matches(&self, id: bxcan::Id) -> bool {
+        match *self {
+            CanMapId::Exact(exact_id) => exact_id == id,
+            CanMapId::J1939 {
+                pgn,
+                source_address,
+            } => j1939::J1939Id::from_id(id).is_some_and(|j1939_id| {
+                j1939_id.pgn == pgn && source_address.is_none_or(|sa| sa == j1939_id.source_address)
+            }),
+        }
+    }
+}
+
 pub struct CanMap {
-    pub id: bxcan::Id,
+    pub id: CanMapId,
     pub bits: CanBitSelection,
     pub scale: f32,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureQuantity {
+    // Hz
+    Frequency,
+    // ms
+    Period,
+    // 0.0...1.0
+    Duty,
+}
+
+// Maps a filtered capture input measurement to a parameter (see
+// pulse_capture)
+pub struct CaptureMap {
+    pub input: CaptureInput,
+    pub quantity: CaptureQuantity,
+    pub scale: f32,
+}
+
 pub struct ReportMap<'a> {
     pub name: &'a str,
     pub decimals: u8,
@@ -130,9 +702,18 @@ pub struct Parameter<'a> {
     pub decimals: u8,
     pub unit: &'a str,
     pub can_map: Option<CanMap>,
+    pub capture_map: Option<CaptureMap>,
     pub report_map: Option<ReportMap<'a>>,
     pub log_threshold: f32,
     pub update_timestamp: u64,
+    // Writable parameters are settings that can be changed over diagnostic
+    // protocols. Written values have to be within min_value...max_value.
+    pub writable: bool,
+    pub default_value: f32,
+    pub min_value: f32,
+    pub max_value: f32,
+    // Grouping for the openinverter web interface
+    pub category: &'a str,
 }
 
 impl<'a> Parameter<'a> {
@@ -153,15 +734,25 @@ impl<'a> Parameter<'a> {
             decimals: decimals,
             unit: unit,
             can_map: can_map,
+            capture_map: None,
             report_map: report_map,
             log_threshold: log_threshold,
             update_timestamp: 0,
+            writable: false,
+            default_value: value,
+            min_value: f32::NEG_INFINITY,
+            max_value: f32::INFINITY,
+            category: "",
         }
     }
     pub fn set_value(&mut self, value: f32, millis: u64) {
         self.value = value;
         self.update_timestamp = millis;
     }
+    // Whether the value can be written to a writable parameter
+    pub fn in_range(&self, value: f32) -> bool {
+        value.is_finite() && value >= self.min_value && value <= self.max_value
+    }
 }
 
 pub static mut PARAMETERS: Option<&'static mut [Parameter<'static>]> = None;
@@ -179,8 +770,14 @@ macro_rules! define_parameters {
         $(decimals: $decimals:expr,)?
         unit: $unit:expr,
         $(can_map: $can_map:expr,)?
+        $(capture_map: $capture_map:expr,)?
         $(report_map: $report_map:expr,)?
         $(log_threshold: $log_threshold:expr,)?
+        $(default_value: $default_value:expr,)?
+        $(min_value: $min_value:expr,)?
+        $(max_value: $max_value:expr,)?
+        $(category: $category:expr,)?
+        $(writable: $writable:expr,)?
     }),* $(,)?) => {
         pub const NUM_PARAMETERS: usize = {
             let mut count = 0;
@@ -213,7 +810,12 @@ macro_rules! define_parameters {
                 Parameter {
                     id: ParameterId::$name as usize,
                     display_name: $display_name,
-                    value: f32::NAN,
+                    value: {
+                        #[allow(unused_variables)]
+                        let value: f32 = f32::NAN;
+                        $(let value = $default_value;)?
+                        value
+                    },
                     decimals: {
                         #[allow(unused_variables)]
                         let decimals: u8 = 0;
@@ -227,6 +829,12 @@ macro_rules! define_parameters {
                         $(let can_map = Some($can_map);)?
                         can_map
                     },
+                    capture_map: {
+                        #[allow(unused_variables)]
+                        let capture_map: Option<CaptureMap> = None;
+                        $(let capture_map = Some($capture_map);)?
+                        capture_map
+                    },
                     report_map: {
                         #[allow(unused_variables)]
                         let report_map: Option<ReportMap> = None;
@@ -240,6 +848,36 @@ macro_rules! define_parameters {
                         log_threshold
                     },
                     update_timestamp: 0,
+                    writable: {
+                        #[allow(unused_variables)]
+                        let writable: bool = false;
+                        $(let writable = $writable;)?
+                        writable
+                    },
+                    default_value: {
+                        #[allow(unused_variables)]
+                        let value: f32 = f32::NAN;
+                        $(let value = $default_value;)?
+                        value
+                    },
+                    min_value: {
+                        #[allow(unused_variables)]
+                        let min_value: f32 = f32::NEG_INFINITY;
+                        $(let min_value = $min_value;)?
+                        min_value
+                    },
+                    max_value: {
+                        #[allow(unused_variables)]
+                        let max_value: f32 = f32::INFINITY;
+                        $(let max_value = $max_value;)?
+                        max_value
+                    },
+                    category: {
+                        #[allow(unused_variables)]
+                        let category: &str = "";
+                        $(let category = $category;)?
+                        category
+                    },
                 }
             ),*
         ];
@@ -269,56 +907,62 @@ pub fn get_parameter_id(id: usize) -> &'static mut Parameter<'static> {
 }
 
 pub fn update_parameters_on_can(frame: bxcan::Frame, millis: u64) {
+    if let Some(data) = frame.data() {
+        update_parameters_on_data(frame.id(), data, millis);
+    }
+}
+
+// Like update_parameters_on_can(), but for data that didn't arrive in a single
+// frame (e.g. a J1939 transport protocol message)
+pub fn update_parameters_on_data(id: bxcan::Id, data: &[u8], millis: u64) {
     for (i, param) in get_parameters().iter_mut().enumerate() {
         if let Some(can_map) = &param.can_map {
-            if let Some(data) = frame.data() {
-                if can_map.id == frame.id() {
-                    match can_map.bits {
-                        CanBitSelection::Bit(bit_i) => {
-                            let byte = data[(bit_i as usize) / 8];
-                            let bit_in_byte = bit_i % 8;
-                            let mask = 1 << bit_in_byte;
-                            param.set_value(
-                                ((byte & mask) >> bit_in_byte) as f32 * can_map.scale,
-                                millis,
-                            );
-                        }
-                        CanBitSelection::BeUnsigned(i0, len) => {
-                            let bits = data.view_bits::<Msb0>();
-                            let raw = bits[i0 as usize..(i0 + len) as usize].load_be::<u64>();
-                            param.set_value(raw as f32 * can_map.scale, millis);
-                        }
-                        CanBitSelection::LeUnsigned(i0, len) => {
-                            let bits = data.view_bits::<Lsb0>();
-                            let raw = bits[i0 as usize..(i0 + len) as usize].load_le::<u64>();
-                            param.set_value(raw as f32 * can_map.scale, millis);
-                        }
-                        CanBitSelection::BeSigned(i0, len) => {
-                            let bits = data.view_bits::<Msb0>();
-                            let raw = bits[i0 as usize..(i0 + len) as usize].load_be::<i64>();
-                            param.set_value(raw as f32 * can_map.scale, millis);
-                        }
-                        CanBitSelection::LeSigned(i0, len) => {
-                            let bits = data.view_bits::<Lsb0>();
-                            let raw = bits[i0 as usize..(i0 + len) as usize].load_le::<i64>();
-                            param.set_value(raw as f32 * can_map.scale, millis);
-                        }
-                        CanBitSelection::Uint8(byte_i) => {
-                            param.set_value(
-                                (data[byte_i as usize] as u8) as f32 * can_map.scale,
-                                millis,
-                            );
-                        }
-                        CanBitSelection::Int8(byte_i) => {
-                            param.set_value(
-                                (data[byte_i as usize] as i8) as f32 * can_map.scale,
-                                millis,
-                            );
-                        }
-                        CanBitSelection::Function(function) => {
-                            if let Some(value) = function(data) {
-                                param.set_value(value * can_map.scale, millis);
-                            }
+            if can_map.id.matches(id) {
+                match can_map.bits {
+                    CanBitSelection::Bit(bit_i) => {
+                        let byte = data[(bit_i as usize) / 8];
+                        let bit_in_byte = bit_i % 8;
+                        let mask = 1 << bit_in_byte;
+                        param.set_value(
+                            ((byte & mask) >> bit_in_byte) as f32 * can_map.scale,
+                            millis,
+                        );
+                    }
+                    CanBitSelection::BeUnsigned(i0, len) => {
+                        let bits = data.view_bits::<Msb0>();
+                        let raw = bits[i0 as usize..(i0 + len) as usize].load_be::<u64>();
+                        param.set_value(raw as f32 * can_map.scale, millis);
+                    }
+                    CanBitSelection::LeUnsigned(i0, len) => {
+                        let bits = data.view_bits::<Lsb0>();
+                        let raw = bits[i0 as usize..(i0 + len) as usize].load_le::<u64>();
+                        param.set_value(raw as f32 * can_map.scale, millis);
+                    }
+                    CanBitSelection::BeSigned(i0, len) => {
+                        let bits = data.view_bits::<Msb0>();
+                        let raw = bits[i0 as usize..(i0 + len) as usize].load_be::<i64>();
+                        param.set_value(raw as f32 * can_map.scale, millis);
+                    }
+                    CanBitSelection::LeSigned(i0, len) => {
+                        let bits = data.view_bits::<Lsb0>();
+                        let raw = bits[i0 as usize..(i0 + len) as usize].load_le::<i64>();
+                        param.set_value(raw as f32 * can_map.scale, millis);
+                    }
+                    CanBitSelection::Uint8(byte_i) => {
+                        param.set_value(
+                            (data[byte_i as usize] as u8) as f32 * can_map.scale,
+                            millis,
+                        );
+                    }
+                    CanBitSelection::Int8(byte_i) => {
+                        param.set_value(
+                            (data[byte_i as usize] as i8) as f32 * can_map.scale,
+                            millis,
+                        );
+                    }
+                    CanBitSelection::Function(function) => {
+                        if let Some(value) = function(data) {
+                            param.set_value(value * can_map.scale, millis);
                         }
                     }
                 }
@@ -326,3 +970,15 @@ pub fn update_parameters_on_can(frame: bxcan::Frame, millis: u64) {
         }
     }
 }
+
+// Marks parameters mapped to the given CAN ID as invalid. This is used when a
+// received frame fails its integrity check.
+pub fn invalidate_parameters_on_can_id(id: bxcan::Id, millis: u64) {
+    for param in get_parameters().iter_mut() {
+        if let Some(can_map) = &param.can_map {
+            if can_map.id.matches(id) {
+                param.set_value(f32::NAN, millis);
+            }
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use crate::dtc::DtcStore;
+use crate::smart_fuse::FuseChannel;
+use crate::{DigitalOutput, HardwareInterface, OutputGroup, PwmOutput};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Load diagnostics: Open-load and stuck-on detection from the current
+// measurements
+//
+// Currents are only measured per channel (a HOUT group or LCUR1), so a load's
+// own current is known
+// * when it turns on: the channel current settle_ms after the turn-on minus
+//   the channel current just before it. This is skipped if anything else on
+//   the channel changes in the meantime.
+// * while it is the only output on in its channel.
+// A load is open if its current is below min_current or below
+// open_load_ratio times its expected current. The expected current is either
+// configured or, if NaN, learned from turn-ons that pass. LCUR1's expected
+// current is scaled by its duty cycle.
+//
+// A channel is stuck on (or shorted to battery) if all of its outputs have
+// been off for settle_ms and it still draws more than min_current for
+// filter_ms.
+//
+// Results are reported as DTCs using update_dtcs().
+
+const NUM_DIGITAL: usize = DigitalOutput::ALL.len();
+const NUM_CHANNELS: usize = OutputGroup::ALL.len() + 1;
+pub const MAX_LOADS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Load {
+    // A HOUT or Wakeup
+    Digital(DigitalOutput),
+    Lcur1,
+}
+
+impl Load {
+    pub fn channel(self) -> Option<FuseChannel> {
+        match self {
+            Load::Digital(output) => output.group().map(FuseChannel::Group),
+            Load::Lcur1 => Some(FuseChannel::Lcur1),
+        }
+    }
+
+    // 0.0...1.0
+    fn level(self, hw: &mut dyn HardwareInterface) -> f32 {
+        match self {
+            Load::Digital(output) => {
+                if hw.get_digital_output(output) {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Load::Lcur1 => hw.get_pwm_output(PwmOutput::LCUR1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoadConfig {
+    pub load: Load,
+    // When fully on (A). NaN = learn.
+    pub expected_current: f32,
+    pub open_load_dtc: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StuckOnConfig {
+    pub channel: FuseChannel,
+    pub dtc: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoadDiagnosticsConfig {
+    pub loads: &'static [LoadConfig],
+    pub stuck_on: &'static [StuckOnConfig],
+    // Anything below this counts as no current (A)
+    pub min_current: f32,
+    pub open_load_ratio: f32,
+    pub settle_ms: u64,
+    pub filter_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LoadState {
+    level: f32,
+    on_since_ms: Option<u64>,
+    current_before: f32,
+    learned_current: f32,
+    low_since_ms: Option<u64>,
+    open: bool,
+}
+
+impl LoadState {
+    fn new() -> Self {
+        Self {
+            level: 0.0,
+            on_since_ms: None,
+            current_before: f32::NAN,
+            learned_current: f32::NAN,
+            low_since_ms: None,
+            open: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ChannelState {
+    current: f32,
+    last_change_ms: u64,
+    outputs_on: u8,
+    high_since_ms: Option<u64>,
+    stuck_on: bool,
+}
+
+impl ChannelState {
+    fn new() -> Self {
+        Self {
+            current: f32::NAN,
+            last_change_ms: 0,
+            outputs_on: 0,
+            high_since_ms: None,
+            stuck_on: false,
+        }
+    }
+}
+
+fn channel_index(channel: FuseChannel) -> usize {
+    match channel {
+        FuseChannel::Group(group) => group as usize,
+        FuseChannel::Lcur1 => OutputGroup::ALL.len(),
+    }
+}
+
+pub struct LoadDiagnostics {
+    pub config: LoadDiagnosticsConfig,
+    loads: [LoadState; MAX_LOADS],
+    channels: [ChannelState; NUM_CHANNELS],
+    last_digital: [bool; NUM_DIGITAL],
+    last_lcur1: f32,
+}
+
+impl LoadDiagnostics {
+    pub fn new(config: LoadDiagnosticsConfig) -> Self {
+        if config.loads.len() > MAX_LOADS {
+            error!(
+                "-!- LoadDiagnostics::new(): Too many loads (max {})",
+                MAX_LOADS
+            );
+        }
+        Self {
+            config,
+            loads: [LoadState::new(); MAX_LOADS],
+            channels: [ChannelState::new(); NUM_CHANNELS],
+            last_digital: [false; NUM_DIGITAL],
+            last_lcur1: 0.0,
+        }
+    }
+
+    fn load_configs(&self) -> &'static [LoadConfig] {
+        let n = self.config.loads.len().min(MAX_LOADS);
+        &self.config.loads[..n]
+    }
+
+    // The configured or learned current of load i when fully on
+    fn expected_current(&self, i: usize) -> f32 {
+        let configured = self.config.loads[i].expected_current;
+        if configured.is_nan() {
+            self.loads[i].learned_current
+        } else {
+            configured
+        }
+    }
+
+    // Below this, load i at the given level is considered open
+    fn open_threshold(&self, i: usize, level: f32) -> f32 {
+        let expected = self.expected_current(i) * level * self.config.open_load_ratio;
+        if expected.is_nan() {
+            self.config.min_current
+        } else {
+            expected.max(self.config.min_current)
+        }
+    }
+
+    pub fn is_open(&self, load: Load) -> bool {
+        self.load_configs()
+            .iter()
+            .position(|c| c.load == load)
+            .is_some_and(|i| self.loads[i].open)
+    }
+
+    pub fn is_stuck_on(&self, channel: FuseChannel) -> bool {
+        self.channels[channel_index(channel)].stuck_on
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        let millis = hw.millis();
+
+        // Track output changes per channel
+        let mut outputs_on = [0u8; NUM_CHANNELS];
+        for output in DigitalOutput::ALL {
+            let Some(group) = output.group() else {
+                continue;
+            };
+            let on = hw.get_digital_output(output);
+            let c = group as usize;
+            if on != self.last_digital[output as usize] {
+                self.channels[c].last_change_ms = millis;
+            }
+            self.last_digital[output as usize] = on;
+            outputs_on[c] += on as u8;
+        }
+        let lcur1 = hw.get_pwm_output(PwmOutput::LCUR1);
+        let c = channel_index(FuseChannel::Lcur1);
+        if lcur1 != self.last_lcur1 {
+            self.channels[c].last_change_ms = millis;
+        }
+        self.last_lcur1 = lcur1;
+        outputs_on[c] = (lcur1 > 0.0) as u8;
+
+        let mut currents = [f32::NAN; NUM_CHANNELS];
+        for group in OutputGroup::ALL {
+            currents[group as usize] = hw.get_analog_input(group.current_input());
+        }
+        currents[c] = hw.get_analog_input(FuseChannel::Lcur1.current_input());
+
+        for (i, config) in self.load_configs().iter().enumerate() {
+            let Some(channel) = config.load.channel() else {
+                continue;
+            };
+            let c = channel_index(channel);
+            let level = config.load.level(hw);
+            self.update_load(i, level, currents[c], outputs_on[c], millis);
+        }
+
+        for check in self.config.stuck_on {
+            let c = channel_index(check.channel);
+            self.update_stuck_on(check.channel, currents[c], outputs_on[c], millis);
+        }
+
+        for c in 0..NUM_CHANNELS {
+            self.channels[c].current = currents[c];
+            self.channels[c].outputs_on = outputs_on[c];
+        }
+    }
+
+    fn update_load(&mut self, i: usize, level: f32, current: f32, outputs_on: u8, millis: u64) {
+        let config = self.config.loads[i];
+        let channel = &self.channels[channel_index(config.load.channel().unwrap())];
+        let last_change_ms = channel.last_change_ms;
+        let previous_current = channel.current;
+        let threshold = self.open_threshold(i, level);
+        let filter_ms = self.config.filter_ms;
+        let settle_ms = self.config.settle_ms;
+        let load = &mut self.loads[i];
+        let was_on = load.level > 0.0;
+        load.level = level;
+
+        if level <= 0.0 {
+            load.on_since_ms = None;
+            load.low_since_ms = None;
+            return;
+        }
+        if !was_on {
+            // The previous tick's current is from before the turn-on
+            load.on_since_ms = Some(millis);
+            load.current_before = previous_current;
+            return;
+        }
+        if current.is_nan() {
+            return;
+        }
+
+        // Turn-on check
+        if let Some(on_since) = load.on_since_ms {
+            if millis - on_since < settle_ms {
+                return;
+            }
+            load.on_since_ms = None;
+            if last_change_ms > on_since || load.current_before.is_nan() {
+                // Inconclusive
+            } else {
+                let step = current - load.current_before;
+                let open = step < threshold;
+                if !open && config.expected_current.is_nan() {
+                    let full = step / level;
+                    load.learned_current = if load.learned_current.is_nan() {
+                        full
+                    } else {
+                        load.learned_current + (full - load.learned_current) * 0.25
+                    };
+                }
+                self.set_open(i, open);
+                return;
+            }
+        }
+
+        // Continuous check while alone in the channel
+        if outputs_on != 1 || millis - last_change_ms < settle_ms {
+            self.loads[i].low_since_ms = None;
+            return;
+        }
+        let load = &mut self.loads[i];
+        if current < threshold {
+            let since = *load.low_since_ms.get_or_insert(millis);
+            if millis - since >= filter_ms {
+                self.set_open(i, true);
+            }
+        } else {
+            load.low_since_ms = None;
+            self.set_open(i, false);
+        }
+    }
+
+    fn set_open(&mut self, i: usize, open: bool) {
+        let load = &mut self.loads[i];
+        if open && !load.open {
+            warn!(
+                "-!- Load diagnostics: {:?}: open load",
+                self.config.loads[i].load
+            );
+        }
+        load.open = open;
+    }
+
+    fn update_stuck_on(&mut self, channel: FuseChannel, current: f32, outputs_on: u8, millis: u64) {
+        let min_current = self.config.min_current;
+        let settle_ms = self.config.settle_ms;
+        let filter_ms = self.config.filter_ms;
+        let ch = &mut self.channels[channel_index(channel)];
+        if outputs_on != 0 || millis - ch.last_change_ms < settle_ms || current.is_nan() {
+            ch.high_since_ms = None;
+            return;
+        }
+        if current > min_current {
+            let since = *ch.high_since_ms.get_or_insert(millis);
+            if millis - since >= filter_ms && !ch.stuck_on {
+                warn!(
+                    "-!- Load diagnostics: {:?}: {:.2} A while off, stuck on",
+                    channel, current
+                );
+                ch.stuck_on = true;
+            }
+        } else {
+            ch.high_since_ms = None;
+            ch.stuck_on = false;
+        }
+    }
+
+    pub fn update_dtcs<const N: usize>(&self, dtcs: &mut DtcStore<N>) {
+        for (i, config) in self.load_configs().iter().enumerate() {
+            dtcs.set(config.open_load_dtc, self.loads[i].open);
+        }
+        for check in self.config.stuck_on {
+            dtcs.set(check.dtc, self.is_stuck_on(check.channel));
+        }
+    }
+
+    pub fn print(&self) {
+        for (i, config) in self.load_configs().iter().enumerate() {
+            info!(
+                "{:?}: expected {:.2} A{}{}",
+                config.load,
+                self.expected_current(i),
+                if config.expected_current.is_nan() {
+                    " (learned)"
+                } else {
+                    ""
+                },
+                if self.loads[i].open {
+                    ", open load"
+                } else {
+                    ""
+                }
+            );
+        }
+        for check in self.config.stuck_on {
+            let ch = &self.channels[channel_index(check.channel)];
+            info!(
+                "{:?}: {:.2} A, {} outputs on{}",
+                check.channel,
+                ch.current,
+                ch.outputs_on,
+                if ch.stuck_on { ", stuck on" } else { "" }
+            );
+        }
+    }
+}
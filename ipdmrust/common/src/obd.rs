@@ -0,0 +1,196 @@
+use crate::isotp::{IsoTp, IsoTpConfig};
+use crate::{get_parameter_id, HardwareInterface};
+use arrayvec::ArrayVec;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// OBD-II (SAE J1979) responder
+//
+// Answers mode 01 (current data) from parameters using a PID table declared by
+// the app, and mode 09 (vehicle information) with the VIN and calibration ID.
+// Requests are accepted on the functional ID (0x7DF) and on the physical
+// request ID. Unsupported PIDs are not answered, like scan tools expect.
+
+pub const MODE_CURRENT_DATA: u8 = 0x01;
+pub const MODE_VEHICLE_INFORMATION: u8 = 0x09;
+
+const POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+
+const PID_VIN: u8 = 0x02;
+const PID_CALIBRATION_ID: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ObdEncoding {
+    // raw = (value - offset) / scale, in 1 or 2 bytes (big endian)
+    U8 { scale: f32, offset: f32 },
+    U16 { scale: f32, offset: f32 },
+}
+
+impl ObdEncoding {
+    fn encode(&self, value: f32, out: &mut ArrayVec<u8, 64>) -> Option<()> {
+        match *self {
+            ObdEncoding::U8 { scale, offset } => {
+                let raw = (((value - offset) / scale) + 0.5).clamp(0.0, 255.0) as u8;
+                out.try_push(raw).ok()
+            }
+            ObdEncoding::U16 { scale, offset } => {
+                let raw = (((value - offset) / scale) + 0.5).clamp(0.0, 65535.0) as u16;
+                out.try_extend_from_slice(&raw.to_be_bytes()).ok()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ObdPid {
+    pub pid: u8,
+    // Parameter id
+    pub parameter: usize,
+    pub encoding: ObdEncoding,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ObdConfig {
+    pub functional_id: bxcan::Id,
+    pub request_id: bxcan::Id,
+    pub response_id: bxcan::Id,
+    pub pids: &'static [ObdPid],
+    pub vin: &'static [u8; 17],
+    // Up to 16 characters. Padded with zeros.
+    pub calibration_id: &'static [u8],
+}
+
+pub struct ObdResponder {
+    pub config: ObdConfig,
+    isotp: IsoTp<64>,
+    functional_request: Option<ArrayVec<u8, 7>>,
+}
+
+impl ObdResponder {
+    pub fn new(config: ObdConfig) -> Self {
+        Self {
+            config,
+            isotp: IsoTp::new(IsoTpConfig::new(config.response_id, config.request_id)),
+            functional_request: None,
+        }
+    }
+
+    // Returns true if the frame was consumed. Functional requests are never
+    // consumed, as other diagnostic servers may want to see them too.
+    pub fn on_can(&mut self, frame: &bxcan::Frame, millis: u64) -> bool {
+        if frame.id() == self.config.functional_id {
+            if let Some(data) = frame.data() {
+                // Single frame only
+                let len = data.first().copied().unwrap_or(0xff) as usize;
+                if (1..=7).contains(&len) && len < data.len() {
+                    self.functional_request = ArrayVec::try_from(&data[1..1 + len]).ok();
+                }
+            }
+            return false;
+        }
+        self.isotp.on_can(frame, millis)
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        if let Some(error) = self.isotp.take_error() {
+            debug!("OBD: ISO-TP error: {:?}", error);
+        }
+        if let Some(request) = self.isotp.take_received() {
+            self.handle_request(&request);
+        }
+        if let Some(request) = self.functional_request.take() {
+            self.handle_request(&request);
+        }
+        self.isotp.update(hw);
+    }
+
+    fn handle_request(&mut self, request: &[u8]) {
+        let Some(&mode) = request.first() else {
+            return;
+        };
+        type Handler = fn(&ObdResponder, &[u8], &mut ArrayVec<u8, 64>) -> Option<()>;
+        let handler: Handler = match mode {
+            MODE_CURRENT_DATA => Self::current_data,
+            MODE_VEHICLE_INFORMATION => Self::vehicle_information,
+            // Unsupported modes are not answered
+            _ => return,
+        };
+        let mut response: ArrayVec<u8, 64> = ArrayVec::new();
+        response.push(mode + POSITIVE_RESPONSE_OFFSET);
+        let ok = handler(self, &request[1..], &mut response);
+        // Nothing is sent if none of the requested PIDs are supported
+        if ok.is_some() && response.len() > 1 {
+            if let Err(e) = self.isotp.send(&response) {
+                debug!("OBD: Failed to send response: {:?}", e);
+            }
+        }
+    }
+
+    fn current_data(&self, pids: &[u8], response: &mut ArrayVec<u8, 64>) -> Option<()> {
+        // Up to 6 PIDs per request
+        if pids.is_empty() || pids.len() > 6 {
+            return None;
+        }
+        for &pid in pids {
+            if pid % 0x20 == 0 {
+                if pid != 0 && !self.config.pids.iter().any(|p| p.pid > pid) {
+                    continue;
+                }
+                response.try_push(pid).ok()?;
+                response
+                    .try_extend_from_slice(&self.supported_pids(pid).to_be_bytes())
+                    .ok()?;
+            } else if let Some(obd_pid) = self.config.pids.iter().find(|p| p.pid == pid) {
+                let value = get_parameter_id(obd_pid.parameter).value;
+                if value.is_nan() {
+                    continue;
+                }
+                response.try_push(pid).ok()?;
+                obd_pid.encoding.encode(value, response)?;
+            }
+        }
+        Some(())
+    }
+
+    // Bitmap of supported PIDs base + 1 ..= base + 0x20. The last bit tells
+    // whether the next range has any supported PIDs.
+    fn supported_pids(&self, base: u8) -> u32 {
+        let mut bitmap = 0u32;
+        for obd_pid in self.config.pids {
+            if obd_pid.pid > base && obd_pid.pid as u16 <= base as u16 + 0x20 {
+                bitmap |= 1 << (0x20 - (obd_pid.pid - base));
+            } else if obd_pid.pid as u16 > base as u16 + 0x20 {
+                bitmap |= 1;
+            }
+        }
+        bitmap
+    }
+
+    fn vehicle_information(&self, pids: &[u8], response: &mut ArrayVec<u8, 64>) -> Option<()> {
+        // Only one PID per request
+        let &[pid] = pids else {
+            return None;
+        };
+        response.try_push(pid).ok()?;
+        match pid {
+            0x00 => {
+                let bitmap: u32 = (1 << (0x20 - PID_VIN)) | (1 << (0x20 - PID_CALIBRATION_ID));
+                response.try_extend_from_slice(&bitmap.to_be_bytes()).ok()
+            }
+            PID_VIN => {
+                // Number of data items
+                response.try_push(1).ok()?;
+                response.try_extend_from_slice(self.config.vin).ok()
+            }
+            PID_CALIBRATION_ID => {
+                response.try_push(1).ok()?;
+                let mut calibration_id = [0u8; 16];
+                let len = self.config.calibration_id.len().min(16);
+                calibration_id[..len].copy_from_slice(&self.config.calibration_id[..len]);
+                response.try_extend_from_slice(&calibration_id).ok()
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use crate::hbridge::HBridges;
+use crate::output_manager::OutputManager;
+use crate::pwm_control::PwmControl;
+use crate::remote_io::RemoteIo;
+use crate::smart_fuse::SmartFuse;
+use crate::soft_pwm::SoftPwm;
+use crate::{
+    AnalogInput, CanPort, CaptureInput, DigitalInput, DigitalOutput, HardwareInterface, MPin,
+    MPinMode, PulseMeasurement, PwmOutput, PwmTimer,
+};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Output layers: The modules between the app and the outputs
+//
+// From the app's side:
+// * RemoteIo: Remote commands override the app
+// * HBridges: Outputs of bridges can only be controlled using HBridges
+// * PwmControl: Duty cycle changes are ramped
+// * SoftPwm: Setting a software PWM output sets its duty cycle to 0 or 1
+// * OutputManager: Turn-ons are staggered and loads are shed
+// * SmartFuse: Outputs of tripped channels stay off
+//
+// The app owns the modules and passes references to the ones it uses to
+// OutputHw, which is a HardwareInterface. Outputs set through it pass through
+// the layers in the order above. Each layer is updated with the layers below
+// it, so that outputs it sets pass through those. Everything other than
+// setting outputs goes directly to the hardware.
+
+pub trait OutputLayer {
+    // The defaults pass the value on to the next layer
+    fn set_digital_output(
+        &mut self,
+        next: &mut dyn HardwareInterface,
+        output: DigitalOutput,
+        value: bool,
+    ) {
+        next.set_digital_output(output, value)
+    }
+
+    fn set_pwm_output(&mut self, next: &mut dyn HardwareInterface, output: PwmOutput, value: f32) {
+        next.set_pwm_output(output, value)
+    }
+
+    // This is called on every logic tick
+    fn update(&mut self, next: &mut dyn HardwareInterface);
+}
+
+#[derive(Default)]
+pub struct OutputLayers<'a> {
+    pub remote_io: Option<&'a mut RemoteIo>,
+    pub hbridges: Option<&'a mut HBridges>,
+    pub pwm_control: Option<&'a mut PwmControl>,
+    pub soft_pwm: Option<&'a mut SoftPwm>,
+    pub output_manager: Option<&'a mut OutputManager>,
+    pub smart_fuse: Option<&'a mut SmartFuse>,
+}
+
+pub struct OutputHw<'a> {
+    hw: &'a mut dyn HardwareInterface,
+    layers: OutputLayers<'a>,
+}
+
+// Splits off the layer if there's no outermost layer yet, otherwise returns it
+// to be included in the layers below
+fn split_layer<'b, L: OutputLayer>(
+    layer: &'b mut Option<&mut L>,
+    outermost: &mut Option<&'b mut dyn OutputLayer>,
+) -> Option<&'b mut L> {
+    let layer = layer.as_deref_mut()?;
+    if outermost.is_none() {
+        *outermost = Some(layer);
+        None
+    } else {
+        Some(layer)
+    }
+}
+
+impl<'a> OutputHw<'a> {
+    pub fn new(hw: &'a mut dyn HardwareInterface, layers: OutputLayers<'a>) -> Self {
+        Self { hw, layers }
+    }
+
+    // Updates the layers, innermost first
+    pub fn update(&mut self) {
+        if let (Some(layer), mut below) = self.split() {
+            below.update();
+            layer.update(&mut below);
+        }
+    }
+
+    // Returns the outermost layer and the hardware below it
+    fn split(&mut self) -> (Option<&mut dyn OutputLayer>, OutputHw<'_>) {
+        let OutputLayers {
+            remote_io,
+            hbridges,
+            pwm_control,
+            soft_pwm,
+            output_manager,
+            smart_fuse,
+        } = &mut self.layers;
+        let mut outermost = None;
+        let below = OutputLayers {
+            remote_io: split_layer(remote_io, &mut outermost),
+            hbridges: split_layer(hbridges, &mut outermost),
+            pwm_control: split_layer(pwm_control, &mut outermost),
+            soft_pwm: split_layer(soft_pwm, &mut outermost),
+            output_manager: split_layer(output_manager, &mut outermost),
+            smart_fuse: split_layer(smart_fuse, &mut outermost),
+        };
+        (outermost, OutputHw::new(&mut *self.hw, below))
+    }
+
+    pub fn remote_io(&mut self) -> &mut RemoteIo {
+        self.layers
+            .remote_io
+            .as_deref_mut()
+            .expect("No remote I/O layer")
+    }
+
+    pub fn hbridges(&mut self) -> &mut HBridges {
+        self.layers
+            .hbridges
+            .as_deref_mut()
+            .expect("No H-bridge layer")
+    }
+
+    pub fn pwm_control(&mut self) -> &mut PwmControl {
+        self.layers
+            .pwm_control
+            .as_deref_mut()
+            .expect("No PWM control layer")
+    }
+
+    pub fn soft_pwm(&mut self) -> &mut SoftPwm {
+        self.layers
+            .soft_pwm
+            .as_deref_mut()
+            .expect("No software PWM layer")
+    }
+
+    pub fn output_manager(&mut self) -> &mut OutputManager {
+        self.layers
+            .output_manager
+            .as_deref_mut()
+            .expect("No output manager layer")
+    }
+
+    pub fn smart_fuse(&mut self) -> &mut SmartFuse {
+        self.layers
+            .smart_fuse
+            .as_deref_mut()
+            .expect("No smart fuse layer")
+    }
+}
+
+impl CanPort for OutputHw<'_> {
+    fn millis(&mut self) -> u64 {
+        self.hw.millis()
+    }
+
+    fn send_can(&mut self, frame: bxcan::Frame) {
+        self.hw.send_can(frame)
+    }
+}
+
+impl HardwareInterface for OutputHw<'_> {
+    fn reboot(&mut self) {
+        self.hw.reboot()
+    }
+
+    fn activate_dfu(&mut self) {
+        self.hw.activate_dfu()
+    }
+
+    fn get_analog_input(&mut self, input: AnalogInput) -> f32 {
+        self.hw.get_analog_input(input)
+    }
+
+    fn get_digital_input(&mut self, input: DigitalInput) -> bool {
+        self.hw.get_digital_input(input)
+    }
+
+    fn set_digital_output(&mut self, output: DigitalOutput, value: bool) {
+        if let (Some(layer), mut below) = self.split() {
+            layer.set_digital_output(&mut below, output, value)
+        } else {
+            self.hw.set_digital_output(output, value)
+        }
+    }
+
+    fn get_digital_output(&mut self, output: DigitalOutput) -> bool {
+        self.hw.get_digital_output(output)
+    }
+
+    fn set_pwm_output(&mut self, output: PwmOutput, value: f32) {
+        if let (Some(layer), mut below) = self.split() {
+            layer.set_pwm_output(&mut below, output, value)
+        } else {
+            self.hw.set_pwm_output(output, value)
+        }
+    }
+
+    fn get_pwm_output(&mut self, output: PwmOutput) -> f32 {
+        self.hw.get_pwm_output(output)
+    }
+
+    fn set_pwm_frequency(&mut self, timer: PwmTimer, frequency_hz: f32) {
+        self.hw.set_pwm_frequency(timer, frequency_hz)
+    }
+
+    fn get_pwm_frequency(&mut self, timer: PwmTimer) -> f32 {
+        self.hw.get_pwm_frequency(timer)
+    }
+
+    fn get_m_pin_mode(&mut self, pin: MPin) -> MPinMode {
+        self.hw.get_m_pin_mode(pin)
+    }
+
+    fn get_pulse_measurement(&mut self, input: CaptureInput) -> PulseMeasurement {
+        self.hw.get_pulse_measurement(input)
+    }
+}
@@ -0,0 +1,296 @@
+use crate::output_layers::OutputLayer;
+use crate::{AnalogInput, DigitalOutput, HardwareInterface, OutputGroup};
+use arrayvec::ArrayVec;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Output manager: Staggered turn-on of high side outputs
+//
+// Outputs of a group share a current limiter, so turning several of them on
+// at the same time adds up their inrush currents. The output manager queues
+// turn-on requests per group and lets them through one at a time, at least
+// stagger_ms apart. Turning outputs off, outputs without a group and PWM
+// outputs are not delayed.
+//
+// Load shedding: Each of shed_loads is turned off when the aux voltage has
+// stayed below its shed_below_v for shed_delay_ms, and restored when the
+// voltage has stayed above restore_above_v for as long. Loads are shed lowest
+// priority first and restored highest priority first, one at a time at least
+// shed_step_ms apart. A shed output remembers the app's value and gets it back
+// when restored.
+//
+// Turn-ons of grouped outputs set through the output layer are staggered.
+// Readback returns the actual
+// output state, which is off while a turn-on is queued or the load is shed.
+//
+// Status frame (status_id), 8 bytes, sent periodically and on changes:
+// * Bytes 0-3: Shed outputs, bit n = DigitalOutput n (little endian)
+// * Bytes 4-5: Aux voltage in 10 mV (little endian)
+
+const NUM_DIGITAL: usize = DigitalOutput::ALL.len();
+const NUM_GROUPS: usize = OutputGroup::ALL.len();
+const MAX_GROUP_OUTPUTS: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShedLoad {
+    pub output: DigitalOutput,
+    // Lower priorities are shed first and restored last
+    pub priority: u8,
+    pub shed_below_v: f32,
+    pub restore_above_v: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OutputManagerConfig {
+    pub stagger_ms: u64,
+    pub shed_loads: &'static [ShedLoad],
+    // How long the voltage has to stay past a threshold
+    pub shed_delay_ms: u64,
+    // Minimum time between shedding or restoring two loads
+    pub shed_step_ms: u64,
+    pub status_id: bxcan::Id,
+    pub status_period_ms: u64,
+}
+
+pub struct OutputManager {
+    pub config: OutputManagerConfig,
+    requested: [bool; NUM_DIGITAL],
+    applied: [bool; NUM_DIGITAL],
+    queues: [ArrayVec<DigitalOutput, MAX_GROUP_OUTPUTS>; NUM_GROUPS],
+    last_turn_on_ms: [Option<u64>; NUM_GROUPS],
+    shed: [bool; NUM_DIGITAL],
+    // Since when each of shed_loads has been past its shed or restore
+    // threshold
+    past_threshold_since_ms: [Option<u64>; NUM_DIGITAL],
+    last_shed_step_ms: Option<u64>,
+    aux_voltage: f32,
+    last_status_ms: u64,
+    send_status: bool,
+}
+
+impl OutputManager {
+    pub fn new(config: OutputManagerConfig) -> Self {
+        Self {
+            config,
+            requested: [false; NUM_DIGITAL],
+            applied: [false; NUM_DIGITAL],
+            queues: Default::default(),
+            last_turn_on_ms: [None; NUM_GROUPS],
+            shed: [false; NUM_DIGITAL],
+            past_threshold_since_ms: [None; NUM_DIGITAL],
+            last_shed_step_ms: None,
+            aux_voltage: f32::NAN,
+            last_status_ms: 0,
+            send_status: true,
+        }
+    }
+
+    // Whether a turn-on of the output is waiting for its turn
+    pub fn is_pending(&self, output: DigitalOutput) -> bool {
+        self.requested[output as usize] && !self.applied[output as usize]
+    }
+
+    pub fn is_shed(&self, output: DigitalOutput) -> bool {
+        self.shed[output as usize]
+    }
+
+    pub fn set_digital_output(
+        &mut self,
+        hw: &mut dyn HardwareInterface,
+        output: DigitalOutput,
+        value: bool,
+    ) {
+        let i = output as usize;
+        self.requested[i] = value;
+        if self.shed[i] {
+            return;
+        }
+        let Some(group) = output.group() else {
+            self.applied[i] = value;
+            hw.set_digital_output(output, value);
+            return;
+        };
+        let queue = &mut self.queues[group as usize];
+        if !value {
+            queue.retain(|o| *o != output);
+            self.applied[i] = false;
+            hw.set_digital_output(output, false);
+        } else if self.applied[i] {
+            hw.set_digital_output(output, true);
+        } else {
+            if !queue.contains(&output) {
+                queue.push(output);
+            }
+            self.process_group(hw, group);
+        }
+    }
+
+    fn process_group(&mut self, hw: &mut dyn HardwareInterface, group: OutputGroup) {
+        let millis = hw.millis();
+        let g = group as usize;
+        if self.queues[g].is_empty() {
+            return;
+        }
+        if self.last_turn_on_ms[g].is_some_and(|t| millis - t < self.config.stagger_ms) {
+            return;
+        }
+        let output = self.queues[g].remove(0);
+        self.applied[output as usize] = true;
+        self.last_turn_on_ms[g] = Some(millis);
+        hw.set_digital_output(output, true);
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        let millis = hw.millis();
+        self.update_load_shedding(hw, millis);
+        for group in OutputGroup::ALL {
+            self.process_group(hw, group);
+        }
+
+        if self.send_status
+            || millis.saturating_sub(self.last_status_ms) >= self.config.status_period_ms
+        {
+            self.send_status = false;
+            self.last_status_ms = millis;
+            hw.send_can(self.status_frame());
+        }
+    }
+
+    fn update_load_shedding(&mut self, hw: &mut dyn HardwareInterface, millis: u64) {
+        let voltage = hw.get_analog_input(AnalogInput::AuxVoltage);
+        self.aux_voltage = voltage;
+        if voltage.is_nan() {
+            return;
+        }
+
+        let mut shed_candidate: Option<ShedLoad> = None;
+        let mut restore_candidate: Option<ShedLoad> = None;
+        for &load in self.config.shed_loads {
+            let i = load.output as usize;
+            let past_threshold = if self.shed[i] {
+                voltage > load.restore_above_v
+            } else {
+                voltage < load.shed_below_v
+            };
+            if !past_threshold {
+                self.past_threshold_since_ms[i] = None;
+                continue;
+            }
+            let since = *self.past_threshold_since_ms[i].get_or_insert(millis);
+            if millis - since < self.config.shed_delay_ms {
+                continue;
+            }
+            if self.shed[i] {
+                if restore_candidate.is_none_or(|c| load.priority > c.priority) {
+                    restore_candidate = Some(load);
+                }
+            } else if shed_candidate.is_none_or(|c| load.priority < c.priority) {
+                shed_candidate = Some(load);
+            }
+        }
+
+        if self
+            .last_shed_step_ms
+            .is_some_and(|t| millis - t < self.config.shed_step_ms)
+        {
+            return;
+        }
+        if let Some(load) = shed_candidate {
+            self.shed_load(hw, load.output, voltage);
+            self.last_shed_step_ms = Some(millis);
+        } else if let Some(load) = restore_candidate {
+            self.restore_load(hw, load.output, voltage);
+            self.last_shed_step_ms = Some(millis);
+        }
+    }
+
+    fn shed_load(&mut self, hw: &mut dyn HardwareInterface, output: DigitalOutput, voltage: f32) {
+        let i = output as usize;
+        warn!(
+            "-!- Load shedding: {:?} off, aux voltage {:.2} V",
+            output, voltage
+        );
+        self.shed[i] = true;
+        self.past_threshold_since_ms[i] = None;
+        self.applied[i] = false;
+        if let Some(group) = output.group() {
+            self.queues[group as usize].retain(|o| *o != output);
+        }
+        hw.set_digital_output(output, false);
+        self.send_status = true;
+    }
+
+    fn restore_load(
+        &mut self,
+        hw: &mut dyn HardwareInterface,
+        output: DigitalOutput,
+        voltage: f32,
+    ) {
+        let i = output as usize;
+        info!(
+            "-!- Load shedding: {:?} restored, aux voltage {:.2} V",
+            output, voltage
+        );
+        self.shed[i] = false;
+        self.past_threshold_since_ms[i] = None;
+        self.set_digital_output(hw, output, self.requested[i]);
+        self.send_status = true;
+    }
+
+    fn status_frame(&self) -> bxcan::Frame {
+        let mut shed: u32 = 0;
+        for output in DigitalOutput::ALL {
+            if self.shed[output as usize] {
+                shed |= 1 << output as usize;
+            }
+        }
+        let voltage = if self.aux_voltage.is_nan() {
+            0xffff
+        } else {
+            (self.aux_voltage * 100.0).clamp(0.0, 65534.0) as u16
+        };
+        let mut data = [0u8; 8];
+        data[0..4].copy_from_slice(&shed.to_le_bytes());
+        data[4..6].copy_from_slice(&voltage.to_le_bytes());
+        bxcan::Frame::new_data(self.config.status_id, bxcan::Data::new(&data).unwrap())
+    }
+
+    pub fn print(&self) {
+        info!("Aux voltage: {:.2} V", self.aux_voltage);
+        for load in self.config.shed_loads {
+            info!(
+                "{:?}: priority {}, shed below {:.2} V, restore above {:.2} V: {}",
+                load.output,
+                load.priority,
+                load.shed_below_v,
+                load.restore_above_v,
+                if self.shed[load.output as usize] {
+                    "shed"
+                } else {
+                    "not shed"
+                }
+            );
+        }
+        for output in DigitalOutput::ALL {
+            if self.is_pending(output) && !self.is_shed(output) {
+                info!("{:?}: turn-on pending", output);
+            }
+        }
+    }
+}
+
+impl OutputLayer for OutputManager {
+    fn set_digital_output(
+        &mut self,
+        next: &mut dyn HardwareInterface,
+        output: DigitalOutput,
+        value: bool,
+    ) {
+        OutputManager::set_digital_output(self, next, output, value)
+    }
+
+    fn update(&mut self, next: &mut dyn HardwareInterface) {
+        OutputManager::update(self, next)
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{get_parameters, CaptureInput, CaptureQuantity, HardwareInterface};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Pulse capture: Frequency, period and duty cycle of capture inputs
+//
+// The hardware timestamps the edges of M pins configured as MPinMode::Capture
+// and reports the most recent period and high time (see PulseMeasurement).
+// Each new period is fed into a first order lowpass filter with a time
+// constant of filter_ms (0 = no filtering).
+//
+// If no new period is measured for timeout_ms, the signal has stopped: The
+// frequency reads 0, the period NaN and the duty cycle follows the level of
+// the pin, so that a PWM signal at 0% or 100% reads correctly. The first
+// period after a timeout (or startup) spans the pause and is discarded.
+//
+// Parameters with a capture_map are set from the filtered values using
+// update_parameters(). Inputs that aren't configured read NaN.
+
+const NUM_INPUTS: usize = CaptureInput::ALL.len();
+
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureChannelConfig {
+    pub input: CaptureInput,
+    pub filter_ms: u64,
+    pub timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PulseCaptureConfig {
+    pub channels: &'static [CaptureChannelConfig],
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    last_count: u32,
+    stop_count: u32,
+    last_period_ms: u64,
+    stopped: bool,
+    frequency: f32,
+    duty: f32,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            last_count: 0,
+            stop_count: 0,
+            last_period_ms: 0,
+            stopped: true,
+            frequency: f32::NAN,
+            duty: f32::NAN,
+        }
+    }
+}
+
+pub struct PulseCapture {
+    pub config: PulseCaptureConfig,
+    channels: [Channel; NUM_INPUTS],
+    last_update_ms: Option<u64>,
+}
+
+impl PulseCapture {
+    pub fn new(config: PulseCaptureConfig) -> Self {
+        Self {
+            config,
+            channels: [Channel::new(); NUM_INPUTS],
+            last_update_ms: None,
+        }
+    }
+
+    // Hz
+    pub fn get_frequency(&self, input: CaptureInput) -> f32 {
+        self.channels[input as usize].frequency
+    }
+
+    // ms, NaN while the signal is stopped
+    pub fn get_period(&self, input: CaptureInput) -> f32 {
+        let frequency = self.get_frequency(input);
+        if frequency > 0.0 {
+            1000.0 / frequency
+        } else {
+            f32::NAN
+        }
+    }
+
+    // 0.0...1.0
+    pub fn get_duty(&self, input: CaptureInput) -> f32 {
+        self.channels[input as usize].duty
+    }
+
+    pub fn is_stopped(&self, input: CaptureInput) -> bool {
+        self.channels[input as usize].stopped
+    }
+
+    pub fn get(&self, input: CaptureInput, quantity: CaptureQuantity) -> f32 {
+        match quantity {
+            CaptureQuantity::Frequency => self.get_frequency(input),
+            CaptureQuantity::Period => self.get_period(input),
+            CaptureQuantity::Duty => self.get_duty(input),
+        }
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        let millis = hw.millis();
+        let dt_ms = self.last_update_ms.map_or(0, |t| millis.saturating_sub(t));
+        self.last_update_ms = Some(millis);
+
+        for config in self.config.channels {
+            let measurement = hw.get_pulse_measurement(config.input);
+            let channel = &mut self.channels[config.input as usize];
+
+            if measurement.count != channel.last_count {
+                // The first period after a stop spans the pause
+                let periods_since_stop = measurement.count.wrapping_sub(channel.stop_count);
+                let valid =
+                    (!channel.stopped || periods_since_stop > 1) && measurement.period_us > 0.0;
+                channel.last_count = measurement.count;
+                channel.last_period_ms = millis;
+                if valid {
+                    let frequency = 1_000_000.0 / measurement.period_us;
+                    let duty = (measurement.high_us / measurement.period_us).clamp(0.0, 1.0);
+                    let a = dt_ms as f32 / (config.filter_ms + dt_ms).max(1) as f32;
+                    channel.frequency = if channel.stopped {
+                        frequency
+                    } else {
+                        channel.frequency + (frequency - channel.frequency) * a
+                    };
+                    // The duty cycle is unknown if the falling edge was missed
+                    if channel.stopped || channel.duty.is_nan() {
+                        channel.duty = duty;
+                    } else if !duty.is_nan() {
+                        channel.duty += (duty - channel.duty) * a;
+                    }
+                    channel.stopped = false;
+                }
+            } else if !channel.stopped && millis - channel.last_period_ms >= config.timeout_ms {
+                debug!("{:?}: Signal stopped", config.input);
+                channel.stopped = true;
+                channel.stop_count = measurement.count;
+            }
+
+            if channel.stopped {
+                let level = hw.get_digital_input(config.input.m_pin().digital_input());
+                channel.frequency = 0.0;
+                channel.duty = if level { 1.0 } else { 0.0 };
+            }
+        }
+    }
+
+    // Sets parameters that have a capture_map
+    pub fn update_parameters(&self, millis: u64) {
+        for param in get_parameters().iter_mut() {
+            let Some(capture_map) = &param.capture_map else {
+                continue;
+            };
+            if !self
+                .config
+                .channels
+                .iter()
+                .any(|c| c.input == capture_map.input)
+            {
+                continue;
+            }
+            let value = self.get(capture_map.input, capture_map.quantity);
+            param.set_value(value * capture_map.scale, millis);
+        }
+    }
+
+    pub fn print(&self) {
+        for config in self.config.channels {
+            let input = config.input;
+            info!(
+                "{:?}: {:.2} Hz, {:.2} ms, {:.1}% duty{}",
+                input,
+                self.get_frequency(input),
+                self.get_period(input),
+                self.get_duty(input) * 100.0,
+                if self.is_stopped(input) {
+                    ", STOPPED"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use crate::output_layers::OutputLayer;
+use crate::{HardwareInterface, PwmOutput, PwmTimer};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// PWM control: Frequencies per timer and duty cycle ramps
+//
+// Each configured output can ask for a frequency. Outputs on the same timer
+// share the frequency, so if they ask for different ones, the timer is
+// reported as conflicting and runs at the frequency of the first one in the
+// config. The frequencies are set on the first update().
+//
+// Duty cycle changes of a configured output are ramped: A full 0 -> 100%
+// change takes ramp_up_ms and 100% -> 0 takes ramp_down_ms. A ramp time of 0
+// applies changes in that direction immediately.
+//
+// Outputs set through the output layer are ramped. Readback returns the duty cycle
+// the output is currently at, not the target.
+
+const NUM_PWM: usize = PwmOutput::ALL.len();
+const NUM_TIMERS: usize = PwmTimer::ALL.len();
+
+#[derive(Debug, Clone, Copy)]
+pub struct PwmChannelConfig {
+    pub output: PwmOutput,
+    // NaN = don't care
+    pub frequency_hz: f32,
+    pub ramp_up_ms: u64,
+    pub ramp_down_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PwmControlConfig {
+    pub channels: &'static [PwmChannelConfig],
+}
+
+pub struct PwmControl {
+    pub config: PwmControlConfig,
+    frequency_hz: [f32; NUM_TIMERS],
+    conflict: [bool; NUM_TIMERS],
+    frequencies_applied: bool,
+    target: [f32; NUM_PWM],
+    value: [f32; NUM_PWM],
+    last_update_ms: Option<u64>,
+}
+
+impl PwmControl {
+    pub fn new(config: PwmControlConfig) -> Self {
+        let mut frequency_hz = [f32::NAN; NUM_TIMERS];
+        let mut conflict = [false; NUM_TIMERS];
+        for channel in config.channels {
+            if channel.frequency_hz.is_nan() {
+                continue;
+            }
+            let t = channel.output.timer() as usize;
+            if frequency_hz[t].is_nan() {
+                frequency_hz[t] = channel.frequency_hz;
+            } else if frequency_hz[t] != channel.frequency_hz {
+                error!(
+                    "-!- PWM frequency conflict: {:?} wants {} Hz but {:?} runs at {} Hz",
+                    channel.output,
+                    channel.frequency_hz,
+                    channel.output.timer(),
+                    frequency_hz[t]
+                );
+                conflict[t] = true;
+            }
+        }
+        Self {
+            config,
+            frequency_hz,
+            conflict,
+            frequencies_applied: false,
+            target: [0.0; NUM_PWM],
+            value: [0.0; NUM_PWM],
+            last_update_ms: None,
+        }
+    }
+
+    // The frequency chosen for the timer. NaN if none of its outputs care.
+    pub fn timer_frequency(&self, timer: PwmTimer) -> f32 {
+        self.frequency_hz[timer as usize]
+    }
+
+    // Whether outputs of the timer ask for different frequencies
+    pub fn has_conflict(&self, timer: PwmTimer) -> bool {
+        self.conflict[timer as usize]
+    }
+
+    pub fn get_target(&self, output: PwmOutput) -> f32 {
+        self.target[output as usize]
+    }
+
+    fn channel(&self, output: PwmOutput) -> Option<&PwmChannelConfig> {
+        self.config.channels.iter().find(|c| c.output == output)
+    }
+
+    pub fn set_pwm_output(
+        &mut self,
+        hw: &mut dyn HardwareInterface,
+        output: PwmOutput,
+        value: f32,
+    ) {
+        let i = output as usize;
+        self.target[i] = value;
+        let ramp_ms = match self.channel(output) {
+            Some(channel) if value > self.value[i] => channel.ramp_up_ms,
+            Some(channel) => channel.ramp_down_ms,
+            None => 0,
+        };
+        if ramp_ms == 0 {
+            self.value[i] = value;
+            hw.set_pwm_output(output, value);
+        }
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        if !self.frequencies_applied {
+            self.frequencies_applied = true;
+            for timer in PwmTimer::ALL {
+                let frequency_hz = self.frequency_hz[timer as usize];
+                if !frequency_hz.is_nan() {
+                    hw.set_pwm_frequency(timer, frequency_hz);
+                }
+            }
+        }
+
+        let millis = hw.millis();
+        let dt_ms = self.last_update_ms.map_or(0, |t| millis.saturating_sub(t));
+        self.last_update_ms = Some(millis);
+
+        for channel in self.config.channels {
+            let i = channel.output as usize;
+            let (value, target) = (self.value[i], self.target[i]);
+            if value == target {
+                continue;
+            }
+            let new_value = if target > value {
+                if channel.ramp_up_ms == 0 {
+                    target
+                } else {
+                    (value + dt_ms as f32 / channel.ramp_up_ms as f32).min(target)
+                }
+            } else if channel.ramp_down_ms == 0 {
+                target
+            } else {
+                (value - dt_ms as f32 / channel.ramp_down_ms as f32).max(target)
+            };
+            self.value[i] = new_value;
+            hw.set_pwm_output(channel.output, new_value);
+        }
+    }
+
+    pub fn print(&self, hw: &mut dyn HardwareInterface) {
+        for timer in PwmTimer::ALL {
+            info!(
+                "{:?}: {:.0} Hz (requested {:.0} Hz){}",
+                timer,
+                hw.get_pwm_frequency(timer),
+                self.timer_frequency(timer),
+                if self.has_conflict(timer) {
+                    ", CONFLICT"
+                } else {
+                    ""
+                }
+            );
+        }
+        for channel in self.config.channels {
+            info!(
+                "{:?}: {:.1}% -> {:.1}%",
+                channel.output,
+                self.value[channel.output as usize] * 100.0,
+                self.target[channel.output as usize] * 100.0
+            );
+        }
+    }
+}
+
+impl OutputLayer for PwmControl {
+    fn set_pwm_output(&mut self, next: &mut dyn HardwareInterface, output: PwmOutput, value: f32) {
+        PwmControl::set_pwm_output(self, next, output, value)
+    }
+
+    fn update(&mut self, next: &mut dyn HardwareInterface) {
+        PwmControl::update(self, next)
+    }
+}
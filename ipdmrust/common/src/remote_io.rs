@@ -0,0 +1,286 @@
+use crate::output_layers::OutputLayer;
+use crate::{DigitalOutput, HardwareInterface, PwmOutput};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
+
+// Remote I/O: Other controllers can command outputs over CAN
+//
+// A remote command holds the output at the commanded value for the duration
+// of a lease. When the lease ends, the output reverts to the value most
+// recently set by the app. The app declares which outputs can be remote
+// controlled.
+//
+// As an output layer, RemoteIo records the app's values and doesn't let them
+// through to outputs that are under remote control.
+//
+// Command frame (command_id), 6 or 8 bytes:
+// * Byte 0: Output type: 1 = DigitalOutput, 2 = PwmOutput
+// * Byte 1: Output index
+//   * DigitalOutput: 0 = Wakeup, 1..12 = HOUT1..12, 13..18 = LOUT1..6,
+//     19..31 = M1..13
+//   * PwmOutput: 0 = LCUR1, 1 = SPWM1, 2 = SPWM2, 3 = LPWM2, 4 = LPWM3
+// * Bytes 2-3: Value, big endian
+//   * DigitalOutput: 0 = off, 1 = on
+//   * PwmOutput: Duty cycle in 0.01% (0..10000)
+// * Bytes 4-5: Lease in ms, big endian. 0 releases the output immediately.
+// * Bytes 6-7: Unused. The app can require a rolling counter and a checksum
+//   here (see can_integrity).
+//
+// Status frame (status_id), 8 bytes, sent periodically and after each command:
+// * Bytes 0-2: DigitalOutput states as currently driven by the hardware, bit
+//   n = output index n (little endian)
+// * Bytes 3-5: DigitalOutputs under remote control (little endian)
+//   Only the first 24 outputs (Wakeup, HOUT1-12, LOUT1-6 and M1-5) fit; M6-13
+//   aren't reported.
+// * Byte 6: PwmOutputs under remote control, bit n = output index n
+// * Byte 7: Result of the last command (see CommandResult)
+
+const NUM_DIGITAL: usize = DigitalOutput::ALL.len();
+const NUM_PWM: usize = PwmOutput::ALL.len();
+
+const OUTPUT_TYPE_DIGITAL: u8 = 1;
+const OUTPUT_TYPE_PWM: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CommandResult {
+    Ok = 0,
+    NotAllowed = 1,
+    Invalid = 2,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteIoConfig {
+    pub command_id: bxcan::Id,
+    pub status_id: bxcan::Id,
+    pub status_period_ms: u64,
+    pub allowed_digital_outputs: &'static [DigitalOutput],
+    pub allowed_pwm_outputs: &'static [PwmOutput],
+    // Longer leases are shortened to this
+    pub max_lease_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Lease {
+    expires_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Command {
+    Digital(usize, bool, u64),
+    Pwm(usize, f32, u64),
+}
+
+pub struct RemoteIo {
+    pub config: RemoteIoConfig,
+    digital_app: [bool; NUM_DIGITAL],
+    digital_lease: [Option<Lease>; NUM_DIGITAL],
+    pwm_app: [f32; NUM_PWM],
+    pwm_lease: [Option<Lease>; NUM_PWM],
+    commands: ConstGenericRingBuffer<Command, 4>,
+    last_result: CommandResult,
+    last_status_ms: u64,
+    send_status: bool,
+}
+
+impl RemoteIo {
+    pub fn new(config: RemoteIoConfig) -> Self {
+        Self {
+            config,
+            digital_app: [false; NUM_DIGITAL],
+            digital_lease: [None; NUM_DIGITAL],
+            pwm_app: [0.0; NUM_PWM],
+            pwm_lease: [None; NUM_PWM],
+            commands: ConstGenericRingBuffer::new(),
+            last_result: CommandResult::Ok,
+            last_status_ms: 0,
+            send_status: false,
+        }
+    }
+
+    pub fn is_remote_controlled(&self, output: DigitalOutput) -> bool {
+        self.digital_lease[output as usize].is_some()
+    }
+
+    pub fn is_pwm_remote_controlled(&self, output: PwmOutput) -> bool {
+        self.pwm_lease[output as usize].is_some()
+    }
+
+    // Returns true if the frame was consumed
+    pub fn on_can(&mut self, frame: &bxcan::Frame) -> bool {
+        if frame.id() != self.config.command_id {
+            return false;
+        }
+        let data = frame.data().map_or(&[][..], |d| &d[..]);
+        self.last_result = self.parse_command(data);
+        if self.last_result != CommandResult::Ok {
+            warn!(
+                "-!- Remote I/O: Command {:?} rejected: {:?}",
+                data, self.last_result
+            );
+        }
+        self.send_status = true;
+        true
+    }
+
+    fn parse_command(&mut self, data: &[u8]) -> CommandResult {
+        if data.len() < 6 {
+            return CommandResult::Invalid;
+        }
+        let index = data[1] as usize;
+        let value = u16::from_be_bytes([data[2], data[3]]);
+        let lease_ms =
+            (u16::from_be_bytes([data[4], data[5]]) as u64).min(self.config.max_lease_ms);
+        let command = match data[0] {
+            OUTPUT_TYPE_DIGITAL => {
+                let Some(&output) = DigitalOutput::ALL.get(index) else {
+                    return CommandResult::Invalid;
+                };
+                if value > 1 {
+                    return CommandResult::Invalid;
+                }
+                if !self.config.allowed_digital_outputs.contains(&output) {
+                    return CommandResult::NotAllowed;
+                }
+                Command::Digital(index, value != 0, lease_ms)
+            }
+            OUTPUT_TYPE_PWM => {
+                let Some(&output) = PwmOutput::ALL.get(index) else {
+                    return CommandResult::Invalid;
+                };
+                if value > 10000 {
+                    return CommandResult::Invalid;
+                }
+                if !self.config.allowed_pwm_outputs.contains(&output) {
+                    return CommandResult::NotAllowed;
+                }
+                Command::Pwm(index, value as f32 / 10000.0, lease_ms)
+            }
+            _ => return CommandResult::Invalid,
+        };
+        self.commands.push(command);
+        CommandResult::Ok
+    }
+
+    // This should be called on every logic tick, with the actual hardware
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        let millis = hw.millis();
+
+        while let Some(command) = self.commands.dequeue() {
+            match command {
+                Command::Digital(i, value, lease_ms) => {
+                    let output = DigitalOutput::ALL[i];
+                    if lease_ms == 0 {
+                        self.digital_lease[i] = None;
+                        info!("-!- Remote I/O: {:?} released", output);
+                        hw.set_digital_output(output, self.digital_app[i]);
+                    } else {
+                        if self.digital_lease[i].is_none() {
+                            info!("-!- Remote I/O: {:?} under remote control", output);
+                        }
+                        self.digital_lease[i] = Some(Lease {
+                            expires_ms: millis + lease_ms,
+                        });
+                        hw.set_digital_output(output, value);
+                    }
+                }
+                Command::Pwm(i, value, lease_ms) => {
+                    let output = PwmOutput::ALL[i];
+                    if lease_ms == 0 {
+                        self.pwm_lease[i] = None;
+                        info!("-!- Remote I/O: {:?} released", output);
+                        hw.set_pwm_output(output, self.pwm_app[i]);
+                    } else {
+                        if self.pwm_lease[i].is_none() {
+                            info!("-!- Remote I/O: {:?} under remote control", output);
+                        }
+                        self.pwm_lease[i] = Some(Lease {
+                            expires_ms: millis + lease_ms,
+                        });
+                        hw.set_pwm_output(output, value);
+                    }
+                }
+            }
+        }
+
+        for (i, lease) in self.digital_lease.iter_mut().enumerate() {
+            if lease.is_some_and(|l| millis >= l.expires_ms) {
+                *lease = None;
+                let output = DigitalOutput::ALL[i];
+                info!("-!- Remote I/O: {:?} lease expired", output);
+                hw.set_digital_output(output, self.digital_app[i]);
+                self.send_status = true;
+            }
+        }
+        for (i, lease) in self.pwm_lease.iter_mut().enumerate() {
+            if lease.is_some_and(|l| millis >= l.expires_ms) {
+                *lease = None;
+                let output = PwmOutput::ALL[i];
+                info!("-!- Remote I/O: {:?} lease expired", output);
+                hw.set_pwm_output(output, self.pwm_app[i]);
+                self.send_status = true;
+            }
+        }
+
+        if self.send_status
+            || millis.saturating_sub(self.last_status_ms) >= self.config.status_period_ms
+        {
+            self.send_status = false;
+            self.last_status_ms = millis;
+            let frame = self.status_frame(hw);
+            hw.send_can(frame);
+        }
+    }
+
+    fn status_frame(&self, hw: &mut dyn HardwareInterface) -> bxcan::Frame {
+        let mut states: u32 = 0;
+        let mut remote: u32 = 0;
+        for (i, &output) in DigitalOutput::ALL.iter().take(24).enumerate() {
+            if hw.get_digital_output(output) {
+                states |= 1 << i;
+            }
+            if self.digital_lease[i].is_some() {
+                remote |= 1 << i;
+            }
+        }
+        let mut pwm_remote: u8 = 0;
+        for i in 0..NUM_PWM {
+            if self.pwm_lease[i].is_some() {
+                pwm_remote |= 1 << i;
+            }
+        }
+        let mut data = [0u8; 8];
+        data[0..3].copy_from_slice(&states.to_le_bytes()[0..3]);
+        data[3..6].copy_from_slice(&remote.to_le_bytes()[0..3]);
+        data[6] = pwm_remote;
+        data[7] = self.last_result as u8;
+        bxcan::Frame::new_data(self.config.status_id, bxcan::Data::new(&data).unwrap())
+    }
+}
+
+impl OutputLayer for RemoteIo {
+    // Values for outputs under remote control are recorded but not applied
+    fn set_digital_output(
+        &mut self,
+        next: &mut dyn HardwareInterface,
+        output: DigitalOutput,
+        value: bool,
+    ) {
+        self.digital_app[output as usize] = value;
+        if !self.is_remote_controlled(output) {
+            next.set_digital_output(output, value);
+        }
+    }
+
+    fn set_pwm_output(&mut self, next: &mut dyn HardwareInterface, output: PwmOutput, value: f32) {
+        self.pwm_app[output as usize] = value;
+        if !self.is_pwm_remote_controlled(output) {
+            next.set_pwm_output(output, value);
+        }
+    }
+
+    fn update(&mut self, next: &mut dyn HardwareInterface) {
+        RemoteIo::update(self, next)
+    }
+}
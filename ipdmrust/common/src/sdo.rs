@@ -0,0 +1,357 @@
+use crate::{get_parameters, HardwareInterface, Parameter};
+use arrayvec::ArrayVec;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
+
+// CANopen SDO server compatible with the openinverter parameter protocol
+//
+// Requests are received on 0x600 + node_id and responses are sent on 0x580 +
+// node_id. Parameters are accessed by their numeric id through object
+// 0x2100 + (id >> 8), sub-index id & 0xff (or 0x2000 + ... which is the same
+// thing here, as ids are indices). Values are signed 32-bit fixed point with 5
+// fractional bits, like in openinverter.
+//
+// The parameter list can be uploaded as JSON from object 0x5001, sub-index 0
+// using a segmented upload. The JSON is generated into a buffer of N bytes when
+// the upload starts, so that the values don't change in the middle of it.
+// Writes are checked against the parameter's min_value and max_value.
+
+pub const SDO_INDEX_PARAMS: u16 = 0x2000;
+pub const SDO_INDEX_PARAM_UID: u16 = 0x2100;
+pub const SDO_INDEX_STRINGS: u16 = 0x5001;
+
+const SDO_REQUEST_BASE_ID: u16 = 0x600;
+const SDO_RESPONSE_BASE_ID: u16 = 0x580;
+
+// Client command specifiers
+const CCS_DOWNLOAD_INITIATE: u8 = 1;
+const CCS_UPLOAD_INITIATE: u8 = 2;
+const CCS_UPLOAD_SEGMENT: u8 = 3;
+const CCS_ABORT: u8 = 4;
+
+// Server responses
+const SDO_RESPONSE_UPLOAD_EXPEDITED_4: u8 = 0x43;
+const SDO_RESPONSE_UPLOAD_SEGMENTED: u8 = 0x41;
+const SDO_RESPONSE_DOWNLOAD: u8 = 0x60;
+const SDO_RESPONSE_ABORT: u8 = 0x80;
+
+// Abort codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SdoAbort {
+    ToggleBit = 0x0503_0000,
+    Timeout = 0x0504_0000,
+    InvalidCommand = 0x0504_0001,
+    OutOfMemory = 0x0504_0005,
+    ReadOnly = 0x0601_0002,
+    ObjectDoesNotExist = 0x0602_0000,
+    LengthMismatch = 0x0607_0010,
+    SubIndexDoesNotExist = 0x0609_0011,
+    ValueRange = 0x0609_0030,
+}
+
+const SEGMENT_TIMEOUT_MS: u64 = 1000;
+
+pub fn parameter_to_fixed(value: f32) -> i32 {
+    if value.is_nan() {
+        0
+    } else {
+        (value * 32.0) as i32
+    }
+}
+
+pub fn fixed_to_parameter(value: i32) -> f32 {
+    value as f32 / 32.0
+}
+
+// The fixed point range
+const FIXED_MIN: f32 = i32::MIN as f32 / 32.0;
+const FIXED_MAX: f32 = i32::MAX as f32 / 32.0;
+
+struct SegmentedUpload {
+    index: u16,
+    sub_index: u8,
+    offset: usize,
+    toggle: bool,
+    last_ms: u64,
+}
+
+pub struct SdoServer<const N: usize> {
+    pub node_id: u8,
+    out_frames: ConstGenericRingBuffer<bxcan::Frame, 4>,
+    upload: Option<SegmentedUpload>,
+    json: JsonBuffer<N>,
+}
+
+impl<const N: usize> SdoServer<N> {
+    pub fn new(node_id: u8) -> Self {
+        Self {
+            node_id,
+            out_frames: ConstGenericRingBuffer::new(),
+            upload: None,
+            json: JsonBuffer::new(),
+        }
+    }
+
+    pub fn request_id(&self) -> bxcan::Id {
+        crate::standard_id(SDO_REQUEST_BASE_ID + self.node_id as u16)
+    }
+
+    pub fn response_id(&self) -> bxcan::Id {
+        crate::standard_id(SDO_RESPONSE_BASE_ID + self.node_id as u16)
+    }
+
+    // Returns true if the frame was consumed
+    pub fn on_can(&mut self, frame: &bxcan::Frame, millis: u64) -> bool {
+        if frame.id() != self.request_id() {
+            return false;
+        }
+        let Some(data) = frame.data() else {
+            return true;
+        };
+        if data.len() != 8 {
+            return true;
+        }
+        let ccs = data[0] >> 5;
+        let index = u16::from_le_bytes([data[1], data[2]]);
+        let sub_index = data[3];
+        let value = [data[4], data[5], data[6], data[7]];
+
+        match ccs {
+            CCS_UPLOAD_INITIATE => {
+                self.upload = None;
+                self.upload_initiate(index, sub_index, millis);
+            }
+            CCS_UPLOAD_SEGMENT => self.upload_segment(data[0], millis),
+            CCS_DOWNLOAD_INITIATE => {
+                self.upload = None;
+                self.download(data[0], index, sub_index, value, millis);
+            }
+            CCS_ABORT => {
+                self.upload = None;
+            }
+            _ => self.abort(index, sub_index, SdoAbort::InvalidCommand),
+        }
+        true
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        if let Some(upload) = &self.upload {
+            if hw.millis().saturating_sub(upload.last_ms) >= SEGMENT_TIMEOUT_MS {
+                let (index, sub_index) = (upload.index, upload.sub_index);
+                self.upload = None;
+                self.abort(index, sub_index, SdoAbort::Timeout);
+            }
+        }
+        while let Some(frame) = self.out_frames.dequeue() {
+            hw.send_can(frame);
+        }
+    }
+
+    fn upload_initiate(&mut self, index: u16, sub_index: u8, millis: u64) {
+        if index == SDO_INDEX_STRINGS {
+            if sub_index != 0 {
+                self.abort(index, sub_index, SdoAbort::SubIndexDoesNotExist);
+                return;
+            }
+            self.json.buf.clear();
+            self.json.overflow = false;
+            write_parameter_json(&mut self.json);
+            if self.json.overflow {
+                error!("SDO: Parameter JSON doesn't fit in {} bytes", N);
+                self.abort(index, sub_index, SdoAbort::OutOfMemory);
+                return;
+            }
+            let size = self.json.buf.len();
+            self.upload = Some(SegmentedUpload {
+                index,
+                sub_index,
+                offset: 0,
+                toggle: false,
+                last_ms: millis,
+            });
+            let mut data = [0u8; 8];
+            data[0] = SDO_RESPONSE_UPLOAD_SEGMENTED;
+            data[1..3].copy_from_slice(&index.to_le_bytes());
+            data[3] = sub_index;
+            data[4..8].copy_from_slice(&(size as u32).to_le_bytes());
+            self.queue(&data);
+            return;
+        }
+        match self.lookup_parameter(index, sub_index) {
+            Ok(param) => {
+                let value = parameter_to_fixed(param.value);
+                self.respond(SDO_RESPONSE_UPLOAD_EXPEDITED_4, index, sub_index, value);
+            }
+            Err(abort) => self.abort(index, sub_index, abort),
+        }
+    }
+
+    fn upload_segment(&mut self, command: u8, millis: u64) {
+        let Some(upload) = &mut self.upload else {
+            self.abort(0, 0, SdoAbort::InvalidCommand);
+            return;
+        };
+        let toggle = command & 0x10 != 0;
+        if toggle != upload.toggle {
+            let (index, sub_index) = (upload.index, upload.sub_index);
+            self.upload = None;
+            self.abort(index, sub_index, SdoAbort::ToggleBit);
+            return;
+        }
+
+        let segment = &self.json.buf[upload.offset..];
+        let n = segment.len().min(7);
+        upload.offset += n;
+        upload.toggle = !upload.toggle;
+        upload.last_ms = millis;
+        let last = upload.offset >= self.json.buf.len();
+
+        let mut data = [0u8; 8];
+        data[0] = if toggle { 0x10 } else { 0 } | (((7 - n) as u8) << 1) | last as u8;
+        data[1..1 + n].copy_from_slice(&segment[..n]);
+        if last {
+            self.upload = None;
+        }
+        self.queue(&data);
+    }
+
+    fn download(&mut self, command: u8, index: u16, sub_index: u8, value: [u8; 4], millis: u64) {
+        // Only expedited downloads are supported
+        let expedited = command & 0x02 != 0;
+        if !expedited {
+            self.abort(index, sub_index, SdoAbort::InvalidCommand);
+            return;
+        }
+        let param = match self.lookup_parameter(index, sub_index) {
+            Ok(param) => param,
+            Err(abort) => {
+                self.abort(index, sub_index, abort);
+                return;
+            }
+        };
+        if !param.writable {
+            self.abort(index, sub_index, SdoAbort::ReadOnly);
+            return;
+        }
+        // If the size is indicated, it has to be 4 bytes
+        let size_indicated = command & 0x01 != 0;
+        if size_indicated && (command >> 2) & 0x03 != 0 {
+            self.abort(index, sub_index, SdoAbort::LengthMismatch);
+            return;
+        }
+        let value = fixed_to_parameter(i32::from_le_bytes(value));
+        if !param.in_range(value) {
+            self.abort(index, sub_index, SdoAbort::ValueRange);
+            return;
+        }
+        info!(
+            "-!- SDO: {} set to {} {}",
+            param.display_name, value, param.unit
+        );
+        param.set_value(value, millis);
+        self.respond(SDO_RESPONSE_DOWNLOAD, index, sub_index, 0);
+    }
+
+    fn lookup_parameter(
+        &self,
+        index: u16,
+        sub_index: u8,
+    ) -> Result<&'static mut Parameter<'static>, SdoAbort> {
+        let id = match index & 0xff00 {
+            SDO_INDEX_PARAMS | SDO_INDEX_PARAM_UID => ((index & 0xff) << 8) | sub_index as u16,
+            _ => return Err(SdoAbort::ObjectDoesNotExist),
+        };
+        get_parameters()
+            .get_mut(id as usize)
+            .ok_or(SdoAbort::ObjectDoesNotExist)
+    }
+
+    fn abort(&mut self, index: u16, sub_index: u8, abort: SdoAbort) {
+        debug!("SDO: Abort {:04X}:{:02X}: {:?}", index, sub_index, abort);
+        self.respond(SDO_RESPONSE_ABORT, index, sub_index, abort as u32 as i32);
+    }
+
+    fn respond(&mut self, command: u8, index: u16, sub_index: u8, value: i32) {
+        let mut data = [0u8; 8];
+        data[0] = command;
+        data[1..3].copy_from_slice(&index.to_le_bytes());
+        data[3] = sub_index;
+        data[4..8].copy_from_slice(&value.to_le_bytes());
+        self.queue(&data);
+    }
+
+    fn queue(&mut self, data: &[u8; 8]) {
+        self.out_frames.push(bxcan::Frame::new_data(
+            self.response_id(),
+            bxcan::Data::new(data).unwrap(),
+        ));
+    }
+}
+
+// fmt::Write sink that remembers whether it ran out of space
+struct JsonBuffer<const N: usize> {
+    buf: ArrayVec<u8, N>,
+    overflow: bool,
+}
+
+impl<const N: usize> JsonBuffer<N> {
+    fn new() -> Self {
+        Self {
+            buf: ArrayVec::new(),
+            overflow: false,
+        }
+    }
+}
+
+impl<const N: usize> core::fmt::Write for JsonBuffer<N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if self.buf.try_extend_from_slice(s.as_bytes()).is_err() {
+            self.overflow = true;
+            return Err(core::fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+fn write_json_number(w: &mut dyn core::fmt::Write, value: f32, decimals: u8) {
+    if value.is_nan() {
+        let _ = w.write_str("null");
+    } else {
+        let _ = write!(w, "{:.*}", decimals as usize, value);
+    }
+}
+
+// Writes the parameter list in the openinverter JSON format. Writable
+// parameters also get their range, default value and category.
+fn write_parameter_json(w: &mut dyn core::fmt::Write) {
+    let _ = w.write_str("{");
+    for (i, param) in get_parameters().iter().enumerate() {
+        if i != 0 {
+            let _ = w.write_str(",");
+        }
+        let _ = write!(
+            w,
+            "\"{}\":{{\"unit\":\"{}\",\"value\":",
+            param.display_name, param.unit
+        );
+        write_json_number(w, param.value, param.decimals);
+        if param.writable {
+            let _ = w.write_str(",\"minimum\":");
+            write_json_number(w, param.min_value.max(FIXED_MIN), param.decimals);
+            let _ = w.write_str(",\"maximum\":");
+            write_json_number(w, param.max_value.min(FIXED_MAX), param.decimals);
+            let _ = w.write_str(",\"default\":");
+            write_json_number(w, param.default_value, param.decimals);
+            let _ = write!(w, ",\"category\":\"{}\"", param.category);
+        }
+        let _ = write!(
+            w,
+            ",\"isparam\":{},\"id\":{},\"i\":{}}}",
+            param.writable, param.id, i
+        );
+    }
+    let _ = w.write_str("}");
+}
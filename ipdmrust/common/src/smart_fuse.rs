@@ -0,0 +1,439 @@
+use crate::output_layers::OutputLayer;
+use crate::{AnalogInput, DigitalInput, DigitalOutput, HardwareInterface, OutputGroup, PwmOutput};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Smart fuses: Software protection for output groups
+//
+// Each protected channel trips when
+// * the group's hardware overcurrent signal stays active for
+//   overcurrent_filter_ms while any of its outputs is on, or
+// * the i²t accumulated above rated_current exceeds i2t_limit. The
+//   accumulator drains when the current is below rated_current. For
+//   inrush_blanking_ms after any of the channel's outputs turns on, the
+//   current is ignored.
+//
+// A tripped channel forces its outputs off and retries after retry_delay_ms,
+// doubling the delay for each consecutive trip up to max_retry_delay_ms. After
+// max_trips trips the channel latches off until it's reset using reset() (the
+// app's "fuse reset" console command) or an ignition off -> on cycle. The trip
+// count is forgotten after trip_memory_ms without trips.
+//
+// As an output layer, SmartFuse records the app's values and doesn't let them
+// through to outputs of a tripped channel. When the channel
+// is restored, the recorded values are applied.
+//
+// Status frame (status_id), 8 bytes, sent periodically and on changes:
+// * Byte 0: Tripped channels, bit n = channel n in SmartFuseConfig::channels
+// * Byte 1: Latched channels
+// * Bytes 2-6: Trip count of each channel
+
+const NUM_DIGITAL: usize = DigitalOutput::ALL.len();
+const NUM_PWM: usize = PwmOutput::ALL.len();
+
+pub const MAX_CHANNELS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FuseChannel {
+    // HOUTs (and Wakeup) of a group, measured using Current1..4
+    Group(OutputGroup),
+    // LCUR1, measured using CurrentL. There is no overcurrent signal.
+    Lcur1,
+}
+
+impl FuseChannel {
+    pub fn current_input(self) -> AnalogInput {
+        match self {
+            FuseChannel::Group(group) => group.current_input(),
+            FuseChannel::Lcur1 => AnalogInput::CurrentL,
+        }
+    }
+
+    pub fn overcurrent_input(self) -> Option<DigitalInput> {
+        match self {
+            FuseChannel::Group(group) => Some(group.overcurrent_input()),
+            FuseChannel::Lcur1 => None,
+        }
+    }
+
+    pub fn digital_outputs(self) -> &'static [DigitalOutput] {
+        match self {
+            FuseChannel::Group(group) => group.outputs(),
+            FuseChannel::Lcur1 => &[],
+        }
+    }
+
+    pub fn pwm_outputs(self) -> &'static [PwmOutput] {
+        match self {
+            FuseChannel::Group(_) => &[],
+            FuseChannel::Lcur1 => &[PwmOutput::LCUR1],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FuseConfig {
+    pub channel: FuseChannel,
+    // A
+    pub rated_current: f32,
+    // A²s above rated_current
+    pub i2t_limit: f32,
+    pub overcurrent_filter_ms: u64,
+    // 0 = no blanking
+    pub inrush_blanking_ms: u64,
+    pub retry_delay_ms: u64,
+    pub max_retry_delay_ms: u64,
+    // Latch off on this many consecutive trips
+    pub max_trips: u8,
+    pub trip_memory_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SmartFuseConfig {
+    pub channels: &'static [FuseConfig],
+    pub status_id: bxcan::Id,
+    pub status_period_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TripReason {
+    Overcurrent,
+    I2t,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FuseState {
+    Ok,
+    Tripped { retry_ms: u64 },
+    Latched,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    state: FuseState,
+    trips: u8,
+    last_trip_ms: u64,
+    last_reason: Option<TripReason>,
+    overcurrent_since_ms: Option<u64>,
+    i2t: f32,
+    blanking_until_ms: u64,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            state: FuseState::Ok,
+            trips: 0,
+            last_trip_ms: 0,
+            last_reason: None,
+            overcurrent_since_ms: None,
+            i2t: 0.0,
+            blanking_until_ms: 0,
+        }
+    }
+}
+
+pub struct SmartFuse {
+    pub config: SmartFuseConfig,
+    channels: [Channel; MAX_CHANNELS],
+    digital_app: [bool; NUM_DIGITAL],
+    pwm_app: [f32; NUM_PWM],
+    last_update_ms: Option<u64>,
+    last_ignition: bool,
+    last_status_ms: u64,
+    send_status: bool,
+}
+
+impl SmartFuse {
+    pub fn new(config: SmartFuseConfig) -> Self {
+        assert!(config.channels.len() <= MAX_CHANNELS);
+        Self {
+            config,
+            channels: [Channel::new(); MAX_CHANNELS],
+            digital_app: [false; NUM_DIGITAL],
+            pwm_app: [0.0; NUM_PWM],
+            last_update_ms: None,
+            last_ignition: false,
+            last_status_ms: 0,
+            send_status: true,
+        }
+    }
+
+    pub fn state(&self, channel: FuseChannel) -> Option<FuseState> {
+        self.index(channel).map(|i| self.channels[i].state)
+    }
+
+    pub fn trips(&self, channel: FuseChannel) -> u8 {
+        self.index(channel).map_or(0, |i| self.channels[i].trips)
+    }
+
+    fn index(&self, channel: FuseChannel) -> Option<usize> {
+        self.config
+            .channels
+            .iter()
+            .position(|c| c.channel == channel)
+    }
+
+    fn is_blocked(&self, i: usize) -> bool {
+        self.channels[i].state != FuseState::Ok
+    }
+
+    fn digital_channel(&self, output: DigitalOutput) -> Option<usize> {
+        self.config
+            .channels
+            .iter()
+            .position(|c| c.channel.digital_outputs().contains(&output))
+    }
+
+    fn pwm_channel(&self, output: PwmOutput) -> Option<usize> {
+        self.config
+            .channels
+            .iter()
+            .position(|c| c.channel.pwm_outputs().contains(&output))
+    }
+
+    pub fn set_digital_output(
+        &mut self,
+        hw: &mut dyn HardwareInterface,
+        output: DigitalOutput,
+        value: bool,
+    ) {
+        self.digital_app[output as usize] = value;
+        match self.digital_channel(output) {
+            Some(i) => self.drive_digital(hw, i, output),
+            None => hw.set_digital_output(output, value),
+        }
+    }
+
+    pub fn set_pwm_output(
+        &mut self,
+        hw: &mut dyn HardwareInterface,
+        output: PwmOutput,
+        value: f32,
+    ) {
+        self.pwm_app[output as usize] = value;
+        match self.pwm_channel(output) {
+            Some(i) => self.drive_pwm(hw, i, output),
+            None => hw.set_pwm_output(output, value),
+        }
+    }
+
+    // Sets an output of channel i to the app's value unless the channel is
+    // blocked. Turning an output on starts the inrush blanking window.
+    fn drive_digital(&mut self, hw: &mut dyn HardwareInterface, i: usize, output: DigitalOutput) {
+        let value = self.digital_app[output as usize] && !self.is_blocked(i);
+        if value && !hw.get_digital_output(output) {
+            self.start_blanking(hw, i);
+        }
+        hw.set_digital_output(output, value);
+    }
+
+    fn drive_pwm(&mut self, hw: &mut dyn HardwareInterface, i: usize, output: PwmOutput) {
+        let value = if self.is_blocked(i) {
+            0.0
+        } else {
+            self.pwm_app[output as usize]
+        };
+        if value > 0.0 && hw.get_pwm_output(output) <= 0.0 {
+            self.start_blanking(hw, i);
+        }
+        hw.set_pwm_output(output, value);
+    }
+
+    fn start_blanking(&mut self, hw: &mut dyn HardwareInterface, i: usize) {
+        let blanking_ms = self.config.channels[i].inrush_blanking_ms;
+        if blanking_ms > 0 {
+            self.channels[i].blanking_until_ms = hw.millis() + blanking_ms;
+        }
+    }
+
+    // Clears latched and tripped channels and trip counts
+    pub fn reset(&mut self, hw: &mut dyn HardwareInterface) {
+        for i in 0..self.config.channels.len() {
+            if self.channels[i].state != FuseState::Ok || self.channels[i].trips != 0 {
+                info!(
+                    "-!- Smart fuse: {:?} reset",
+                    self.config.channels[i].channel
+                );
+            }
+            self.channels[i] = Channel::new();
+            self.apply(hw, i);
+        }
+        self.send_status = true;
+    }
+
+    // Applies the app's values to a channel's outputs
+    fn apply(&mut self, hw: &mut dyn HardwareInterface, i: usize) {
+        let channel = self.config.channels[i].channel;
+        for &output in channel.digital_outputs() {
+            self.drive_digital(hw, i, output);
+        }
+        for &output in channel.pwm_outputs() {
+            self.drive_pwm(hw, i, output);
+        }
+    }
+
+    fn is_on(&self, i: usize) -> bool {
+        let channel = self.config.channels[i].channel;
+        channel
+            .digital_outputs()
+            .iter()
+            .any(|&o| self.digital_app[o as usize])
+            || channel
+                .pwm_outputs()
+                .iter()
+                .any(|&o| self.pwm_app[o as usize] > 0.0)
+    }
+
+    fn trip(&mut self, hw: &mut dyn HardwareInterface, i: usize, reason: TripReason, millis: u64) {
+        let config = self.config.channels[i];
+        let ch = &mut self.channels[i];
+        ch.trips = ch.trips.saturating_add(1);
+        ch.last_trip_ms = millis;
+        ch.last_reason = Some(reason);
+        ch.overcurrent_since_ms = None;
+        ch.i2t = 0.0;
+        if ch.trips >= config.max_trips {
+            ch.state = FuseState::Latched;
+            warn!(
+                "-!- Smart fuse: {:?} tripped ({:?}), latched off after {} trips",
+                config.channel, reason, ch.trips
+            );
+        } else {
+            let delay_ms = config
+                .retry_delay_ms
+                .saturating_mul(1 << (ch.trips - 1).min(16))
+                .min(config.max_retry_delay_ms);
+            ch.state = FuseState::Tripped {
+                retry_ms: millis + delay_ms,
+            };
+            warn!(
+                "-!- Smart fuse: {:?} tripped ({:?}), trip {}/{}, retrying in {} ms",
+                config.channel, reason, ch.trips, config.max_trips, delay_ms
+            );
+        }
+        self.apply(hw, i);
+        self.send_status = true;
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        let millis = hw.millis();
+        let dt = self
+            .last_update_ms
+            .map_or(0.0, |t| millis.saturating_sub(t) as f32 / 1000.0);
+        self.last_update_ms = Some(millis);
+
+        // An ignition cycle clears latched faults
+        let ignition = hw.get_digital_input(DigitalInput::Ignition);
+        if ignition
+            && !self.last_ignition
+            && self.channels.iter().any(|c| c.state == FuseState::Latched)
+        {
+            info!("-!- Smart fuse: Ignition cycle, resetting");
+            self.reset(hw);
+        }
+        self.last_ignition = ignition;
+
+        for i in 0..self.config.channels.len() {
+            let config = self.config.channels[i];
+            match self.channels[i].state {
+                FuseState::Ok => {
+                    let on = self.is_on(i);
+                    let overcurrent = config
+                        .channel
+                        .overcurrent_input()
+                        .is_some_and(|input| hw.get_digital_input(input));
+                    let ch = &mut self.channels[i];
+                    if overcurrent && on {
+                        let since = *ch.overcurrent_since_ms.get_or_insert(millis);
+                        if millis - since >= config.overcurrent_filter_ms {
+                            self.trip(hw, i, TripReason::Overcurrent, millis);
+                            continue;
+                        }
+                    } else {
+                        ch.overcurrent_since_ms = None;
+                    }
+
+                    let current = hw.get_analog_input(config.channel.current_input());
+                    let ch = &mut self.channels[i];
+                    if !current.is_nan() && millis >= ch.blanking_until_ms {
+                        let excess =
+                            current * current - config.rated_current * config.rated_current;
+                        ch.i2t = (ch.i2t + excess * dt).max(0.0);
+                    }
+                    if ch.i2t >= config.i2t_limit {
+                        self.trip(hw, i, TripReason::I2t, millis);
+                        continue;
+                    }
+
+                    let ch = &mut self.channels[i];
+                    if ch.trips > 0 && millis - ch.last_trip_ms >= config.trip_memory_ms {
+                        ch.trips = 0;
+                        self.send_status = true;
+                    }
+                }
+                FuseState::Tripped { retry_ms } => {
+                    if millis >= retry_ms {
+                        info!("-!- Smart fuse: {:?} retrying", config.channel);
+                        self.channels[i].state = FuseState::Ok;
+                        self.apply(hw, i);
+                        self.send_status = true;
+                    }
+                }
+                FuseState::Latched => {}
+            }
+        }
+
+        if self.send_status
+            || millis.saturating_sub(self.last_status_ms) >= self.config.status_period_ms
+        {
+            self.send_status = false;
+            self.last_status_ms = millis;
+            hw.send_can(self.status_frame());
+        }
+    }
+
+    fn status_frame(&self) -> bxcan::Frame {
+        let mut data = [0u8; 8];
+        for i in 0..self.config.channels.len() {
+            match self.channels[i].state {
+                FuseState::Ok => {}
+                FuseState::Tripped { .. } => data[0] |= 1 << i,
+                FuseState::Latched => data[1] |= 1 << i,
+            }
+            data[2 + i] = self.channels[i].trips;
+        }
+        bxcan::Frame::new_data(self.config.status_id, bxcan::Data::new(&data).unwrap())
+    }
+
+    pub fn print(&self) {
+        for i in 0..self.config.channels.len() {
+            let config = self.config.channels[i];
+            let ch = &self.channels[i];
+            info!(
+                "{:?}: {:?}, {} trips, last: {:?}, i2t {:.1}/{:.1} A²s",
+                config.channel, ch.state, ch.trips, ch.last_reason, ch.i2t, config.i2t_limit
+            );
+        }
+    }
+}
+
+impl OutputLayer for SmartFuse {
+    fn set_digital_output(
+        &mut self,
+        next: &mut dyn HardwareInterface,
+        output: DigitalOutput,
+        value: bool,
+    ) {
+        SmartFuse::set_digital_output(self, next, output, value)
+    }
+
+    fn set_pwm_output(&mut self, next: &mut dyn HardwareInterface, output: PwmOutput, value: f32) {
+        SmartFuse::set_pwm_output(self, next, output, value)
+    }
+
+    fn update(&mut self, next: &mut dyn HardwareInterface) {
+        SmartFuse::update(self, next)
+    }
+}
@@ -0,0 +1,139 @@
+use crate::output_layers::OutputLayer;
+use crate::{DigitalOutput, HardwareInterface};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Software PWM: Time-proportioning on any DigitalOutput
+//
+// Each configured output is on for duty * period at the start of each period
+// (at the end if inverted). Periods are aligned to millis(), so an inverted
+// output at duty 1 - d is on exactly when a normal one of the same frequency at
+// duty d is off. The output is evaluated on every logic tick, so the on time
+// has the resolution of the tick interval (20 ms).
+//
+// Frequencies are limited to MIN_FREQUENCY_HZ...MAX_FREQUENCY_HZ. The low end
+// allows periods of minutes for slow loads like solenoid valves. Outputs are
+// set through the output layers below, so turn-ons are staggered and tripped
+// groups stay off.
+//
+// The app sets duty cycles using set_duty(), which take effect on the next
+// update(). Setting a software PWM output through the output layer is the
+// same as a duty of 0 or 1, but takes effect immediately.
+
+pub const MIN_FREQUENCY_HZ: f32 = 0.001;
+pub const MAX_FREQUENCY_HZ: f32 = 10.0;
+pub const MAX_OUTPUTS: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SoftPwmOutput {
+    pub output: DigitalOutput,
+    pub frequency_hz: f32,
+    pub inverted: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SoftPwmConfig {
+    pub outputs: &'static [SoftPwmOutput],
+}
+
+pub struct SoftPwm {
+    pub config: SoftPwmConfig,
+    period_ms: [u64; MAX_OUTPUTS],
+    duty: [f32; MAX_OUTPUTS],
+}
+
+impl SoftPwm {
+    pub fn new(config: SoftPwmConfig) -> Self {
+        if config.outputs.len() > MAX_OUTPUTS {
+            error!("-!- SoftPwm::new(): Too many outputs (max {})", MAX_OUTPUTS);
+        }
+        let mut period_ms = [0; MAX_OUTPUTS];
+        for (i, output) in config.outputs.iter().take(MAX_OUTPUTS).enumerate() {
+            let frequency_hz = output
+                .frequency_hz
+                .clamp(MIN_FREQUENCY_HZ, MAX_FREQUENCY_HZ);
+            if frequency_hz != output.frequency_hz {
+                error!(
+                    "-!- SoftPwm::new(): {:?}: {} Hz out of range, using {} Hz",
+                    output.output, output.frequency_hz, frequency_hz
+                );
+            }
+            period_ms[i] = (1000.0 / frequency_hz + 0.5) as u64;
+        }
+        Self {
+            config,
+            period_ms,
+            duty: [0.0; MAX_OUTPUTS],
+        }
+    }
+
+    fn index(&self, output: DigitalOutput) -> Option<usize> {
+        self.config
+            .outputs
+            .iter()
+            .take(MAX_OUTPUTS)
+            .position(|o| o.output == output)
+    }
+
+    pub fn is_soft_pwm(&self, output: DigitalOutput) -> bool {
+        self.index(output).is_some()
+    }
+
+    pub fn get_duty(&self, output: DigitalOutput) -> Option<f32> {
+        self.index(output).map(|i| self.duty[i])
+    }
+
+    // duty: 0.0...1.0
+    pub fn set_duty(&mut self, output: DigitalOutput, duty: f32) {
+        let Some(i) = self.index(output) else {
+            error!("-!- SoftPwm::set_duty(): {:?} is not configured", output);
+            return;
+        };
+        self.duty[i] = if duty.is_nan() {
+            0.0
+        } else {
+            duty.clamp(0.0, 1.0)
+        };
+    }
+
+    fn apply(&self, hw: &mut dyn HardwareInterface, i: usize) {
+        let config = self.config.outputs[i];
+        let period_ms = self.period_ms[i];
+        let on_ms = (self.duty[i] * period_ms as f32) as u64;
+        let phase_ms = hw.millis() % period_ms;
+        let on = if config.inverted {
+            phase_ms >= period_ms - on_ms
+        } else {
+            phase_ms < on_ms
+        };
+        hw.set_digital_output(config.output, on);
+    }
+
+    // This should be called on every logic tick
+    pub fn update(&mut self, hw: &mut dyn HardwareInterface) {
+        for i in 0..self.config.outputs.len().min(MAX_OUTPUTS) {
+            self.apply(hw, i);
+        }
+    }
+}
+
+impl OutputLayer for SoftPwm {
+    // Setting a software PWM output sets its duty cycle to 0 or 1
+    fn set_digital_output(
+        &mut self,
+        next: &mut dyn HardwareInterface,
+        output: DigitalOutput,
+        value: bool,
+    ) {
+        if let Some(i) = self.index(output) {
+            self.set_duty(output, if value { 1.0 } else { 0.0 });
+            self.apply(next, i);
+        } else {
+            next.set_digital_output(output, value);
+        }
+    }
+
+    fn update(&mut self, next: &mut dyn HardwareInterface) {
+        SoftPwm::update(self, next)
+    }
+}
@@ -0,0 +1,431 @@
+use crate::dtc::{DtcStore, DTC_STATUS_AVAILABILITY_MASK};
+use crate::isotp::{IsoTp, IsoTpConfig};
+use crate::{get_parameters, HardwareInterface};
+use arrayvec::ArrayVec;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// UDS (ISO 14229) diagnostic server on top of ISO-TP
+//
+// Parameters can be read as data identifiers (DIDs) parameter_did_base +
+// parameter id. The value is an IEEE 754 f32 in big endian. Parameters marked
+// writable can also be written in the extended session. Written values live in
+// RAM only.
+//
+// Supported services:
+// * 0x10 DiagnosticSessionControl (default and extended)
+// * 0x11 ECUReset (hard and soft reset, both reboot)
+// * 0x14 ClearDiagnosticInformation
+// * 0x19 ReadDTCInformation (sub-functions 0x01, 0x02, 0x0A)
+// * 0x22 ReadDataByIdentifier
+// * 0x2E WriteDataByIdentifier
+// * 0x3E TesterPresent
+
+pub const SID_DIAGNOSTIC_SESSION_CONTROL: u8 = 0x10;
+pub const SID_ECU_RESET: u8 = 0x11;
+pub const SID_CLEAR_DIAGNOSTIC_INFORMATION: u8 = 0x14;
+pub const SID_READ_DTC_INFORMATION: u8 = 0x19;
+pub const SID_READ_DATA_BY_IDENTIFIER: u8 = 0x22;
+pub const SID_WRITE_DATA_BY_IDENTIFIER: u8 = 0x2E;
+pub const SID_TESTER_PRESENT: u8 = 0x3E;
+
+const NEGATIVE_RESPONSE: u8 = 0x7F;
+const POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+const SUPPRESS_POSITIVE_RESPONSE: u8 = 0x80;
+
+// Active diagnostic session
+pub const DID_ACTIVE_SESSION: u16 = 0xF186;
+
+// P2 and P2* server timings reported in the session control response
+const P2_SERVER_MAX_MS: u16 = 50;
+const P2_STAR_SERVER_MAX_MS: u16 = 5000;
+
+// Time between the ECUReset response and the actual reboot, so that the
+// response makes it out of the CAN peripheral
+const RESET_DELAY_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Nrc {
+    GeneralReject = 0x10,
+    ServiceNotSupported = 0x11,
+    SubFunctionNotSupported = 0x12,
+    IncorrectMessageLength = 0x13,
+    ResponseTooLong = 0x14,
+    ConditionsNotCorrect = 0x22,
+    RequestOutOfRange = 0x31,
+    SubFunctionNotSupportedInActiveSession = 0x7E,
+    ServiceNotSupportedInActiveSession = 0x7F,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Session {
+    Default = 0x01,
+    Extended = 0x03,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UdsConfig {
+    // Physical request and response IDs
+    pub request_id: bxcan::Id,
+    pub response_id: bxcan::Id,
+    // Functional (broadcast) request ID, e.g. 0x7DF. Only single frame
+    // requests are accepted on this ID.
+    pub functional_id: Option<bxcan::Id>,
+    // DID of parameter id 0
+    pub parameter_did_base: u16,
+    // Constant data identifiers, e.g. (0xF190, VIN)
+    pub static_dids: &'static [(u16, &'static [u8])],
+    // A non-default session falls back to the default session if no request
+    // is received within this time (S3)
+    pub session_timeout_ms: u64,
+}
+
+impl UdsConfig {
+    pub const fn new(request_id: bxcan::Id, response_id: bxcan::Id) -> Self {
+        Self {
+            request_id,
+            response_id,
+            functional_id: None,
+            parameter_did_base: 0x4000,
+            static_dids: &[],
+            session_timeout_ms: 5000,
+        }
+    }
+}
+
+type Response<const N: usize> = ArrayVec<u8, N>;
+
+pub struct UdsServer<const N: usize> {
+    pub config: UdsConfig,
+    isotp: IsoTp<N>,
+    functional_request: Option<ArrayVec<u8, 7>>,
+    session: Session,
+    last_request_ms: u64,
+    reset_at_ms: Option<u64>,
+}
+
+impl<const N: usize> UdsServer<N> {
+    pub fn new(config: UdsConfig) -> Self {
+        Self {
+            config,
+            isotp: IsoTp::new(IsoTpConfig::new(config.response_id, config.request_id)),
+            functional_request: None,
+            session: Session::Default,
+            last_request_ms: 0,
+            reset_at_ms: None,
+        }
+    }
+
+    pub fn session(&self) -> Session {
+        self.session
+    }
+
+    // Returns true if the frame was consumed. Functional requests are never
+    // consumed, as other diagnostic servers may want to see them too.
+    pub fn on_can(&mut self, frame: &bxcan::Frame, millis: u64) -> bool {
+        if Some(frame.id()) == self.config.functional_id {
+            if let Some(data) = frame.data() {
+                // Single frame only
+                let len = (data.first().copied().unwrap_or(0xff)) as usize;
+                if (1..=7).contains(&len) && len < data.len() {
+                    self.functional_request = ArrayVec::try_from(&data[1..1 + len]).ok();
+                }
+            }
+            return false;
+        }
+        self.isotp.on_can(frame, millis)
+    }
+
+    // This should be called on every logic tick
+    pub fn update<const D: usize>(
+        &mut self,
+        hw: &mut dyn HardwareInterface,
+        dtcs: &mut DtcStore<D>,
+    ) {
+        let millis = hw.millis();
+
+        if let Some(error) = self.isotp.take_error() {
+            warn!("-!- UDS: ISO-TP error: {:?}", error);
+        }
+
+        if let Some(request) = self.isotp.take_received() {
+            self.handle_request(&request, false, millis, dtcs);
+        }
+        if let Some(request) = self.functional_request.take() {
+            self.handle_request(&request, true, millis, dtcs);
+        }
+
+        if self.session != Session::Default
+            && millis.saturating_sub(self.last_request_ms) >= self.config.session_timeout_ms
+        {
+            info!("-!- UDS: Session timed out");
+            self.session = Session::Default;
+        }
+
+        self.isotp.update(hw);
+
+        if let Some(reset_at_ms) = self.reset_at_ms {
+            if millis >= reset_at_ms && self.isotp.is_tx_idle() {
+                info!("-!- UDS: ECU reset");
+                self.reset_at_ms = None;
+                hw.reboot();
+            }
+        }
+    }
+
+    fn handle_request<const D: usize>(
+        &mut self,
+        request: &[u8],
+        functional: bool,
+        millis: u64,
+        dtcs: &mut DtcStore<D>,
+    ) {
+        let Some(&sid) = request.first() else {
+            return;
+        };
+        self.last_request_ms = millis;
+
+        // The first byte becomes the positive response SID once the service
+        // is known to be supported. Unsupported SIDs can be 0xC0 and above.
+        let mut response: Response<N> = ArrayVec::new();
+        let _ = response.try_push(0);
+
+        let result = match sid {
+            SID_DIAGNOSTIC_SESSION_CONTROL => {
+                self.diagnostic_session_control(request, &mut response)
+            }
+            SID_ECU_RESET => self.ecu_reset(request, &mut response, millis),
+            SID_CLEAR_DIAGNOSTIC_INFORMATION => Self::clear_diagnostic_information(request, dtcs),
+            SID_READ_DTC_INFORMATION => Self::read_dtc_information(request, &mut response, dtcs),
+            SID_READ_DATA_BY_IDENTIFIER => self.read_data_by_identifier(request, &mut response),
+            SID_WRITE_DATA_BY_IDENTIFIER => {
+                self.write_data_by_identifier(request, &mut response, millis)
+            }
+            SID_TESTER_PRESENT => Self::tester_present(request, &mut response),
+            _ => Err(Nrc::ServiceNotSupported),
+        };
+
+        match result {
+            Ok(()) => {
+                response[0] = sid + POSITIVE_RESPONSE_OFFSET;
+                let suppress = matches!(
+                    sid,
+                    SID_DIAGNOSTIC_SESSION_CONTROL | SID_ECU_RESET | SID_TESTER_PRESENT
+                ) && request
+                    .get(1)
+                    .is_some_and(|b| b & SUPPRESS_POSITIVE_RESPONSE != 0);
+                if !suppress {
+                    self.respond(&response);
+                }
+            }
+            Err(nrc) => {
+                // ISO 14229-1: These are not sent in response to functional
+                // requests
+                let suppress = functional
+                    && matches!(
+                        nrc,
+                        Nrc::ServiceNotSupported
+                            | Nrc::SubFunctionNotSupported
+                            | Nrc::RequestOutOfRange
+                            | Nrc::SubFunctionNotSupportedInActiveSession
+                            | Nrc::ServiceNotSupportedInActiveSession
+                    );
+                if !suppress {
+                    self.respond(&[NEGATIVE_RESPONSE, sid, nrc as u8]);
+                }
+            }
+        }
+    }
+
+    fn respond(&mut self, response: &[u8]) {
+        if let Err(e) = self.isotp.send(response) {
+            warn!("-!- UDS: Failed to send response: {:?}", e);
+        }
+    }
+
+    fn diagnostic_session_control(
+        &mut self,
+        request: &[u8],
+        response: &mut Response<N>,
+    ) -> Result<(), Nrc> {
+        if request.len() != 2 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let sub_function = request[1] & !SUPPRESS_POSITIVE_RESPONSE;
+        self.session = match sub_function {
+            0x01 => Session::Default,
+            0x03 => Session::Extended,
+            _ => return Err(Nrc::SubFunctionNotSupported),
+        };
+        info!("-!- UDS: Session {:?}", self.session);
+        push_bytes(response, &[sub_function])?;
+        push_bytes(response, &P2_SERVER_MAX_MS.to_be_bytes())?;
+        // P2* is in units of 10ms
+        push_bytes(response, &(P2_STAR_SERVER_MAX_MS / 10).to_be_bytes())
+    }
+
+    fn ecu_reset(
+        &mut self,
+        request: &[u8],
+        response: &mut Response<N>,
+        millis: u64,
+    ) -> Result<(), Nrc> {
+        if request.len() != 2 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let sub_function = request[1] & !SUPPRESS_POSITIVE_RESPONSE;
+        match sub_function {
+            // Hard reset and soft reset
+            0x01 | 0x03 => {}
+            _ => return Err(Nrc::SubFunctionNotSupported),
+        }
+        self.reset_at_ms = Some(millis + RESET_DELAY_MS);
+        push_bytes(response, &[sub_function])
+    }
+
+    fn clear_diagnostic_information<const D: usize>(
+        request: &[u8],
+        dtcs: &mut DtcStore<D>,
+    ) -> Result<(), Nrc> {
+        if request.len() != 4 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let code = u32::from_be_bytes([0, request[1], request[2], request[3]]);
+        if code != 0xffffff && !dtcs.iter().any(|d| d.code == code) {
+            return Err(Nrc::RequestOutOfRange);
+        }
+        dtcs.clear(code);
+        Ok(())
+    }
+
+    fn read_dtc_information<const D: usize>(
+        request: &[u8],
+        response: &mut Response<N>,
+        dtcs: &DtcStore<D>,
+    ) -> Result<(), Nrc> {
+        if request.len() < 2 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let sub_function = request[1];
+        let expected_len = match sub_function {
+            // reportNumberOfDTCByStatusMask, reportDTCByStatusMask
+            0x01 | 0x02 => 3,
+            // reportSupportedDTC
+            0x0A => 2,
+            _ => return Err(Nrc::SubFunctionNotSupported),
+        };
+        if request.len() != expected_len {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let status_mask = if sub_function == 0x0A {
+            0xff
+        } else {
+            request[2]
+        };
+        let matching = dtcs
+            .iter()
+            .filter(move |d| sub_function == 0x0A || d.status & status_mask != 0);
+
+        push_bytes(response, &[sub_function, DTC_STATUS_AVAILABILITY_MASK])?;
+        if sub_function == 0x01 {
+            // DTCFormatIdentifier: ISO 14229-1
+            push_bytes(response, &[0x01])?;
+            push_bytes(response, &(matching.count() as u16).to_be_bytes())
+        } else {
+            for dtc in matching {
+                push_bytes(response, &dtc.code.to_be_bytes()[1..])?;
+                push_bytes(response, &[dtc.status])?;
+            }
+            Ok(())
+        }
+    }
+
+    fn read_data_by_identifier(
+        &mut self,
+        request: &[u8],
+        response: &mut Response<N>,
+    ) -> Result<(), Nrc> {
+        if request.len() < 3 || !(request.len() - 1).is_multiple_of(2) {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        for did_bytes in request[1..].chunks(2) {
+            let did = u16::from_be_bytes([did_bytes[0], did_bytes[1]]);
+            push_bytes(response, did_bytes)?;
+            if did == DID_ACTIVE_SESSION {
+                push_bytes(response, &[self.session as u8])?;
+            } else if let Some((_, data)) =
+                self.config.static_dids.iter().find(|(id, _)| *id == did)
+            {
+                push_bytes(response, data)?;
+            } else if let Some(param) = self.did_to_parameter(did) {
+                push_bytes(response, &get_parameters()[param].value.to_be_bytes())?;
+            } else {
+                return Err(Nrc::RequestOutOfRange);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_data_by_identifier(
+        &mut self,
+        request: &[u8],
+        response: &mut Response<N>,
+        millis: u64,
+    ) -> Result<(), Nrc> {
+        if request.len() < 3 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let did = u16::from_be_bytes([request[1], request[2]]);
+        let Some(param) = self.did_to_parameter(did) else {
+            return Err(Nrc::RequestOutOfRange);
+        };
+        let param = &mut get_parameters()[param];
+        if !param.writable {
+            return Err(Nrc::RequestOutOfRange);
+        }
+        if self.session != Session::Extended {
+            return Err(Nrc::ServiceNotSupportedInActiveSession);
+        }
+        if request.len() != 7 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let value = f32::from_be_bytes([request[3], request[4], request[5], request[6]]);
+        if !param.in_range(value) {
+            return Err(Nrc::RequestOutOfRange);
+        }
+        info!(
+            "-!- UDS: {} set to {} {}",
+            param.display_name, value, param.unit
+        );
+        param.set_value(value, millis);
+        push_bytes(response, &request[1..3])
+    }
+
+    fn tester_present(request: &[u8], response: &mut Response<N>) -> Result<(), Nrc> {
+        if request.len() != 2 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let sub_function = request[1] & !SUPPRESS_POSITIVE_RESPONSE;
+        if sub_function != 0x00 {
+            return Err(Nrc::SubFunctionNotSupported);
+        }
+        push_bytes(response, &[sub_function])
+    }
+
+    fn did_to_parameter(&self, did: u16) -> Option<usize> {
+        let index = did.checked_sub(self.config.parameter_did_base)? as usize;
+        if index < get_parameters().len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
+fn push_bytes<const N: usize>(response: &mut Response<N>, bytes: &[u8]) -> Result<(), Nrc> {
+    response
+        .try_extend_from_slice(bytes)
+        .map_err(|_| Nrc::ResponseTooLong)
+}
@@ -0,0 +1,174 @@
+// CAN console tests: A client ISO-TP endpoint talking to the console
+
+mod util;
+
+use common::can_console::*;
+use common::isotp::{IsoTp, IsoTpConfig};
+use common::*;
+use util::*;
+
+const CONSOLE_CONFIG: CanConsoleConfig = CanConsoleConfig {
+    rx_id: standard_id(0x6f0),
+    tx_id: standard_id(0x6f8),
+    session_timeout_ms: 5000,
+};
+
+struct Session<const N: usize> {
+    console: CanConsole<N>,
+    hw: TestHardware,
+    client: IsoTp<MAX_MESSAGE_LEN>,
+    client_hw: TestHardware,
+}
+
+impl<const N: usize> Session<N> {
+    fn new() -> Self {
+        Self {
+            console: CanConsole::new(CONSOLE_CONFIG),
+            hw: TestHardware::new(),
+            client: IsoTp::new(IsoTpConfig::new(CONSOLE_CONFIG.rx_id, CONSOLE_CONFIG.tx_id)),
+            client_hw: TestHardware::new(),
+        }
+    }
+
+    // Runs the console and the client for the given time, delivering frames
+    // between them. Returns the messages received by the client.
+    fn run(&mut self, ms: u64) -> Vec<Vec<u8>> {
+        let mut received = Vec::new();
+        let end = self.hw.millis + ms;
+        while self.hw.millis < end {
+            self.hw.millis += TICK_MS;
+            self.client_hw.millis += TICK_MS;
+            self.console.update(&mut self.hw);
+            self.client.update(&mut self.client_hw);
+            for frame in self.hw.take_sent() {
+                self.client.on_can(&frame, self.client_hw.millis);
+            }
+            for frame in self.client_hw.take_sent() {
+                assert!(self.console.on_can(&frame, self.hw.millis));
+            }
+            if let Some(message) = self.client.take_received() {
+                received.push(message.to_vec());
+            }
+        }
+        received
+    }
+
+    fn connect(&mut self) {
+        self.client.send(&[KEEPALIVE]).unwrap();
+        self.run(100);
+        assert!(self.console.is_connected());
+    }
+
+    fn take_input(&mut self) -> Vec<u8> {
+        std::iter::from_fn(|| self.console.read_input()).collect()
+    }
+}
+
+#[test]
+fn command_round_trip() {
+    let mut session = Session::<256>::new();
+    assert!(!session.console.is_connected());
+
+    session.client.send(b"help\r").unwrap();
+    assert!(session.run(100).is_empty());
+    assert!(session.console.is_connected());
+    assert_eq!(session.take_input(), b"help\r");
+
+    // Output written by the console, like the mirrored log, goes to the client
+    session.console.write_output("Commands:\r\n");
+    session.console.write_output("  help\r\n");
+    assert_eq!(session.run(100), vec![b"Commands:\r\n  help\r\n".to_vec()]);
+
+    // Frames on other IDs aren't consumed
+    let other = bxcan::Frame::new_data(
+        bxcan::StandardId::new(0x100).unwrap(),
+        bxcan::Data::new(&[0x01]).unwrap(),
+    );
+    assert!(!session.console.on_can(&other, session.hw.millis));
+}
+
+#[test]
+fn keepalive_is_not_input() {
+    let mut session = Session::<256>::new();
+    session.connect();
+    assert!(session.take_input().is_empty());
+}
+
+#[test]
+fn output_is_dropped_without_client() {
+    let mut session = Session::<256>::new();
+    session.console.write_output("Boot\r\n");
+    assert!(session.run(100).is_empty());
+    assert!(session.hw.sent.is_empty());
+
+    // Only output written after connecting is sent
+    session.connect();
+    session.console.write_output("Log\r\n");
+    assert_eq!(session.run(100), vec![b"Log\r\n".to_vec()]);
+}
+
+#[test]
+fn session_expires_without_messages() {
+    let mut session = Session::<256>::new();
+    session.connect();
+
+    // KEEPALIVE extends the session
+    session.run(4000);
+    session.client.send(&[KEEPALIVE]).unwrap();
+    session.run(4000);
+    assert!(session.console.is_connected());
+
+    // The session ends session_timeout_ms after the keepalive was received,
+    // a tick after it was sent
+    session.run(1000);
+    assert!(session.console.is_connected());
+    session.run(100);
+    assert!(!session.console.is_connected());
+    session.console.write_output("Log\r\n");
+    assert!(session.run(100).is_empty());
+}
+
+#[test]
+fn long_output_is_chunked() {
+    let mut session = Session::<512>::new();
+    session.connect();
+    let output: String = (0..300).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+    session.console.write_output(&output);
+    let received = session.run(1000);
+    assert_eq!(
+        received.iter().map(|m| m.len()).collect::<Vec<_>>(),
+        vec![MAX_MESSAGE_LEN, MAX_MESSAGE_LEN, 300 - 2 * MAX_MESSAGE_LEN]
+    );
+    assert_eq!(received.concat(), output.as_bytes());
+}
+
+#[test]
+fn overflow_is_marked() {
+    let mut session = Session::<64>::new();
+    session.connect();
+    session.console.write_output(&"a".repeat(40));
+    // Doesn't fit and is dropped
+    session.console.write_output(&"b".repeat(40));
+    assert_eq!(session.run(100), vec!["a".repeat(40).into_bytes()]);
+
+    // The marker comes before the next output that fits
+    session.console.write_output("c\r\n");
+    assert_eq!(
+        session.run(100),
+        vec![b" | CAN CONSOLE BUFFER FULL\r\nc\r\n".to_vec()]
+    );
+}
+
+#[test]
+fn input_is_limited_to_buffer() {
+    let mut session = Session::<256>::new();
+    session.client.send(&[b'x'; 100]).unwrap();
+    session.run(200);
+    session.client.send(&[b'y'; 100]).unwrap();
+    session.run(200);
+    // Input not read by the console is kept until the buffer is full
+    let input = session.take_input();
+    assert_eq!(input.len(), MAX_MESSAGE_LEN);
+    assert_eq!(&input[..100], &[b'x'; 100]);
+    assert_eq!(&input[100..], &[b'y'; MAX_MESSAGE_LEN - 100]);
+}
@@ -0,0 +1,145 @@
+// Rolling counter and checksum tests
+
+use common::can_integrity::*;
+use common::*;
+
+// Check values and the test vectors of the AUTOSAR CRC library specification
+const VECTORS: &[&[u8]] = &[
+    b"123456789",
+    &[0x00, 0x00, 0x00, 0x00],
+    &[0xf2, 0x01, 0x83],
+    &[0x0f, 0xaa, 0x00, 0x55],
+    &[0x00, 0xff, 0x55, 0x11],
+    &[0x33, 0x22, 0x55, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff],
+    &[0x92, 0x6b, 0x55],
+    &[0xff, 0xff, 0xff, 0xff],
+];
+
+fn checksum(kind: ChecksumKind, data: &[u8]) -> u8 {
+    // The checksum byte is outside the data so that all of it is included
+    let mut buf = data.to_vec();
+    buf.push(0);
+    ChecksumField {
+        byte: data.len() as u8,
+        kind,
+        seed: None,
+    }
+    .calculate(&buf)
+}
+
+#[test]
+fn crc8_sae_j1850_known_answers() {
+    let expected = [0x4b, 0x59, 0x37, 0x79, 0xb8, 0xcb, 0x8c, 0x74];
+    for (data, expected) in VECTORS.iter().zip(expected) {
+        assert_eq!(
+            checksum(ChecksumKind::Crc8SaeJ1850, data),
+            expected,
+            "{:02x?}",
+            data
+        );
+    }
+}
+
+#[test]
+fn crc8_autosar_known_answers() {
+    let expected = [0xdf, 0x12, 0xc2, 0xc6, 0x77, 0x11, 0x33, 0x6c];
+    for (data, expected) in VECTORS.iter().zip(expected) {
+        assert_eq!(
+            checksum(ChecksumKind::Crc8Autosar, data),
+            expected,
+            "{:02x?}",
+            data
+        );
+    }
+}
+
+#[test]
+fn simple_checksums_skip_their_own_byte() {
+    let field = |kind| ChecksumField {
+        byte: 1,
+        kind,
+        seed: None,
+    };
+    let data = [0x01, 0xaa, 0x02, 0xff];
+    assert_eq!(
+        field(ChecksumKind::Xor).calculate(&data),
+        0x01 ^ 0x02 ^ 0xff
+    );
+    assert_eq!(field(ChecksumKind::Sum).calculate(&data), 0x02);
+    let seeded = ChecksumField {
+        seed: Some(0x10),
+        ..field(ChecksumKind::Sum)
+    };
+    assert_eq!(seeded.calculate(&data), 0x12);
+}
+
+const INTEGRITY: FrameIntegrity = FrameIntegrity {
+    counter: Some(CounterField {
+        byte: 6,
+        shift: 0,
+        bits: 4,
+    }),
+    checksum: Some(ChecksumField {
+        byte: 7,
+        kind: ChecksumKind::Crc8SaeJ1850,
+        seed: None,
+    }),
+};
+
+fn frame(counter: u8) -> bxcan::Frame {
+    let frame = bxcan::Frame::new_data(
+        bxcan::StandardId::new(0x208).unwrap(),
+        bxcan::Data::new(&[1, 2, 0, 1, 0, 100, 0, 0]).unwrap(),
+    );
+    INTEGRITY.apply_to_frame(&frame, counter).unwrap()
+}
+
+fn checker() -> RxIntegrityChecker<4> {
+    let mut checker = RxIntegrityChecker::new();
+    checker.register(RxIntegrityCheck {
+        id: standard_id(0x208),
+        integrity: INTEGRITY,
+        max_counter_skip: 1,
+    });
+    checker
+}
+
+#[test]
+fn applied_integrity_passes_check() {
+    let mut checker = checker();
+    // The counter wraps at 16
+    for counter in 0..40 {
+        assert_eq!(checker.check(&frame(counter)), Ok(()), "{}", counter);
+    }
+    // Frames without a check always pass
+    let other = bxcan::Frame::new_data(
+        bxcan::StandardId::new(0x209).unwrap(),
+        bxcan::Data::new(&[0; 8]).unwrap(),
+    );
+    assert_eq!(checker.check(&other), Ok(()));
+}
+
+#[test]
+fn check_detects_errors() {
+    let mut checker = checker();
+    assert_eq!(checker.check(&frame(0)), Ok(()));
+    assert_eq!(checker.check(&frame(0)), Err(IntegrityError::CounterStuck));
+    // One lost frame is allowed, two aren't
+    assert_eq!(checker.check(&frame(2)), Ok(()));
+    assert_eq!(checker.check(&frame(5)), Err(IntegrityError::CounterJump));
+
+    let mut data = [0u8; 8];
+    data.copy_from_slice(frame(6).data().unwrap());
+    data[2] ^= 0x01;
+    let corrupted = bxcan::Frame::new_data(
+        bxcan::StandardId::new(0x208).unwrap(),
+        bxcan::Data::new(&data).unwrap(),
+    );
+    assert_eq!(checker.check(&corrupted), Err(IntegrityError::BadChecksum));
+
+    let short = bxcan::Frame::new_data(
+        bxcan::StandardId::new(0x208).unwrap(),
+        bxcan::Data::new(&[1, 2, 0, 1, 0, 100]).unwrap(),
+    );
+    assert_eq!(checker.check(&short), Err(IntegrityError::TooShort));
+}
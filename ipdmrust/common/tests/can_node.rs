@@ -0,0 +1,105 @@
+// CAN node alive supervision tests
+
+use common::can_node::*;
+use common::dtc::DtcStore;
+use common::*;
+
+const IDS: &[bxcan::Id] = &[standard_id(0x100), standard_id(0x101)];
+
+fn node() -> CanNode<'static> {
+    CanNode {
+        id: 0,
+        name: "Test",
+        can_ids: IDS,
+        timeout_ms: 1000,
+        last_seen_ms: 0,
+        alive: false,
+        seen: false,
+        lost_dtc: None,
+    }
+}
+
+#[test]
+fn node_times_out_and_recovers() {
+    let mut node = node();
+    assert!(!node.is_alive());
+    assert!(!node.is_lost());
+
+    // Other IDs don't count
+    node.on_can(standard_id(0x102), 100);
+    assert!(!node.is_alive());
+
+    node.on_can(standard_id(0x101), 100);
+    assert!(node.is_alive());
+    node.run_timeout(1099);
+    assert!(node.is_alive());
+    // Any of the IDs keeps the node alive
+    node.on_can(standard_id(0x100), 1000);
+    node.run_timeout(1999);
+    assert!(node.is_alive());
+    node.run_timeout(2000);
+    assert!(!node.is_alive());
+    assert!(node.is_lost());
+
+    node.on_can(standard_id(0x100), 5000);
+    assert!(node.is_alive());
+    assert!(!node.is_lost());
+}
+
+#[test]
+fn zero_timeout_never_times_out() {
+    let mut node = CanNode {
+        timeout_ms: 0,
+        ..node()
+    };
+    node.on_can(standard_id(0x100), 0);
+    node.run_timeout(1_000_000);
+    assert!(node.is_alive());
+}
+
+define_can_nodes! {
+    Bms {
+        name: "BMS",
+        can_ids: IDS,
+        timeout_ms: 1000,
+        lost_dtc: 0xC11100,
+    },
+    Charger {
+        name: "Charger",
+        can_ids: &[standard_id(0x389)],
+        timeout_ms: 1000,
+    },
+}
+
+fn frame(id: u16) -> bxcan::Frame {
+    bxcan::Frame::new_data(
+        bxcan::StandardId::new(id).unwrap(),
+        bxcan::Data::new(&[0; 8]).unwrap(),
+    )
+}
+
+#[test]
+fn lost_node_sets_dtc() {
+    init_can_nodes();
+    let mut dtcs: DtcStore<4> = DtcStore::new();
+
+    // A node that has never been seen isn't lost
+    timeout_can_nodes(5000);
+    update_can_node_dtcs(&mut dtcs);
+    assert!(!dtcs.is_failed(0xC11100));
+
+    update_can_nodes_on_can(&frame(0x101), 5000);
+    update_can_nodes_on_can(&frame(0x389), 5000);
+    assert!(get_can_node(CanNodeId::Bms).is_alive());
+    assert!(get_can_node(CanNodeId::Charger).is_alive());
+
+    timeout_can_nodes(6000);
+    update_can_node_dtcs(&mut dtcs);
+    assert!(dtcs.is_failed(0xC11100));
+    assert!(!get_can_node(CanNodeId::Charger).is_alive());
+
+    update_can_nodes_on_can(&frame(0x100), 7000);
+    timeout_can_nodes(7000);
+    update_can_node_dtcs(&mut dtcs);
+    assert!(!dtcs.is_failed(0xC11100));
+}
@@ -0,0 +1,118 @@
+// Periodic CAN transmit scheduler tests
+
+mod util;
+
+use common::can_scheduler::*;
+use common::*;
+use util::*;
+
+fn frame(id: u16) -> Option<bxcan::Frame> {
+    Some(bxcan::Frame::new_data(
+        bxcan::StandardId::new(id).unwrap(),
+        bxcan::Data::new(&[0; 8]).unwrap(),
+    ))
+}
+
+fn message(name: &'static str, period_ms: u64, offset_ms: u64) -> PeriodicMessage {
+    PeriodicMessage {
+        name,
+        period_ms,
+        offset_ms,
+        enable: None,
+        encode: |_| frame(0x100),
+        integrity: None,
+    }
+}
+
+// Runs the scheduler every tick_ms and returns the times frames were sent at
+fn send_times<const N: usize>(
+    scheduler: &mut CanScheduler<N>,
+    hw: &mut TestHardware,
+    tick_ms: u64,
+    ms: u64,
+) -> Vec<u64> {
+    let mut times = Vec::new();
+    let end = hw.millis + ms;
+    while hw.millis < end {
+        hw.millis += tick_ms;
+        scheduler.update(hw);
+        for _ in hw.take_sent() {
+            times.push(hw.millis);
+        }
+    }
+    times
+}
+
+#[test]
+fn messages_follow_period_and_offset() {
+    let mut hw = TestHardware::new();
+    let mut scheduler: CanScheduler<4> = CanScheduler::new(3);
+    scheduler.register(message("A", 100, 30));
+    let times = send_times(&mut scheduler, &mut hw, 10, 400);
+    assert_eq!(times, vec![30, 130, 230, 330]);
+    assert_eq!(scheduler.total_missed_deadlines(), 0);
+}
+
+#[test]
+fn first_update_late_doesnt_count_as_missed() {
+    let mut hw = TestHardware::new();
+    hw.millis = 10_000;
+    let mut scheduler: CanScheduler<4> = CanScheduler::new(3);
+    scheduler.register(message("A", 100, 30));
+    let times = send_times(&mut scheduler, &mut hw, 10, 200);
+    assert_eq!(times, vec![10_030, 10_130]);
+    assert_eq!(scheduler.total_missed_deadlines(), 0);
+}
+
+#[test]
+fn frames_over_limit_are_deferred() {
+    let mut hw = TestHardware::new();
+    let mut scheduler: CanScheduler<4> = CanScheduler::new(2);
+    scheduler.register(message("A", 100, 0));
+    scheduler.register(message("B", 100, 0));
+    scheduler.register(message("C", 100, 0));
+    let times = send_times(&mut scheduler, &mut hw, 10, 200);
+    assert_eq!(times, vec![100, 100, 110, 200, 200]);
+    assert_eq!(scheduler.total_missed_deadlines(), 0);
+}
+
+#[test]
+fn missed_deadlines_are_counted() {
+    let mut hw = TestHardware::new();
+    let mut scheduler: CanScheduler<4> = CanScheduler::new(3);
+    scheduler.register(message("A", 20, 0));
+    send_times(&mut scheduler, &mut hw, 10, 20);
+    // A stall of 100 ms skips four instances
+    hw.millis += 100;
+    scheduler.update(&mut hw);
+    assert_eq!(hw.take_sent().len(), 1);
+    assert_eq!(scheduler.total_missed_deadlines(), 4);
+    // The phase is kept
+    let times = send_times(&mut scheduler, &mut hw, 10, 40);
+    assert_eq!(times, vec![140, 160]);
+}
+
+#[test]
+fn disabled_and_skipped_messages_dont_count_as_missed() {
+    let mut hw = TestHardware::new();
+    let mut scheduler: CanScheduler<4> = CanScheduler::new(3);
+    scheduler.register(PeriodicMessage {
+        enable: Some(|| false),
+        ..message("Disabled", 20, 0)
+    });
+    scheduler.register(PeriodicMessage {
+        encode: |_| None,
+        ..message("Skipped", 20, 0)
+    });
+    let times = send_times(&mut scheduler, &mut hw, 10, 200);
+    assert!(times.is_empty());
+    assert_eq!(scheduler.total_missed_deadlines(), 0);
+}
+
+#[test]
+fn zero_period_is_rejected() {
+    let mut hw = TestHardware::new();
+    let mut scheduler: CanScheduler<4> = CanScheduler::new(3);
+    scheduler.register(message("A", 0, 0));
+    assert!(send_times(&mut scheduler, &mut hw, 10, 100).is_empty());
+}
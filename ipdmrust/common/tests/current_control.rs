@@ -0,0 +1,129 @@
+// LCUR1 current control tests
+
+mod util;
+
+use common::current_control::*;
+use common::dtc::DtcStore;
+use common::*;
+use util::*;
+
+const CONFIG: CurrentControlConfig = CurrentControlConfig {
+    kp: 0.1,
+    ki: 2.0,
+    peak_current: f32::NAN,
+    peak_ms: 0,
+    max_error: 0.2,
+    unreachable_ms: 1000,
+    unreachable_dtc: 0x902092,
+};
+
+// A resistive-inductive load that draws max_current at 100% duty, with a time
+// constant of 50 ms
+struct Load {
+    max_current: f32,
+    current: f32,
+}
+
+impl Load {
+    fn step(&mut self, hw: &mut TestHardware) {
+        let target = hw.get_pwm_output(PwmOutput::LCUR1) * self.max_current;
+        self.current += (target - self.current) * (TICK_MS as f32 / 50.0).min(1.0);
+        hw.analog_inputs.insert(AnalogInput::CurrentL, self.current);
+    }
+}
+
+fn run(control: &mut CurrentControl, hw: &mut TestHardware, load: &mut Load, ms: u64) {
+    run_ticks(hw, ms, |hw| {
+        load.step(hw);
+        control.update(hw);
+    });
+}
+
+fn assert_near(a: f32, b: f32, tolerance: f32) {
+    assert!((a - b).abs() <= tolerance, "{} != {}", a, b);
+}
+
+#[test]
+fn current_follows_target() {
+    let mut hw = TestHardware::new();
+    let mut control = CurrentControl::new(CONFIG);
+    let mut load = Load {
+        max_current: 4.0,
+        current: 0.0,
+    };
+    control.set_target(1.5);
+    run(&mut control, &mut hw, &mut load, 3000);
+    assert_near(load.current, 1.5, 0.02);
+    assert_near(control.get_duty(), 1.5 / 4.0, 0.01);
+
+    // The supply voltage drops, the duty cycle compensates
+    load.max_current = 3.0;
+    run(&mut control, &mut hw, &mut load, 3000);
+    assert_near(load.current, 1.5, 0.02);
+    assert_near(control.get_duty(), 0.5, 0.01);
+    assert!(!control.is_unreachable());
+
+    control.set_target(0.0);
+    run(&mut control, &mut hw, &mut load, TICK_MS);
+    assert_eq!(hw.get_pwm_output(PwmOutput::LCUR1), 0.0);
+}
+
+#[test]
+fn peak_and_hold() {
+    const PEAK: CurrentControlConfig = CurrentControlConfig {
+        peak_current: 3.0,
+        peak_ms: 500,
+        ..CONFIG
+    };
+    let mut hw = TestHardware::new();
+    let mut control = CurrentControl::new(PEAK);
+    let mut load = Load {
+        max_current: 4.0,
+        current: 0.0,
+    };
+    control.set_target(1.0);
+    assert_eq!(control.get_setpoint(), 3.0);
+    run(&mut control, &mut hw, &mut load, 400);
+    assert_near(load.current, 3.0, 0.2);
+    run(&mut control, &mut hw, &mut load, 2000);
+    assert_eq!(control.get_setpoint(), 1.0);
+    assert_near(load.current, 1.0, 0.02);
+
+    // Changing the target while on doesn't peak again
+    control.set_target(1.2);
+    assert_eq!(control.get_setpoint(), 1.2);
+    // Turning off and on does
+    control.set_target(0.0);
+    run(&mut control, &mut hw, &mut load, TICK_MS);
+    control.set_target(1.0);
+    run(&mut control, &mut hw, &mut load, TICK_MS);
+    assert_eq!(control.get_setpoint(), 3.0);
+}
+
+#[test]
+fn unreachable_setpoint_is_reported() {
+    let mut hw = TestHardware::new();
+    let mut control = CurrentControl::new(CONFIG);
+    let mut load = Load {
+        max_current: 1.0,
+        current: 0.0,
+    };
+    control.set_target(2.0);
+    run(&mut control, &mut hw, &mut load, 1000);
+    assert!(!control.is_unreachable());
+    run(&mut control, &mut hw, &mut load, 1000);
+    assert!(control.is_unreachable());
+    assert_eq!(control.get_duty(), 1.0);
+    let mut dtcs = DtcStore::<4>::new();
+    control.update_dtcs(&mut dtcs);
+    assert!(dtcs.is_failed(0x902092));
+
+    // The integrator doesn't wind up while saturated, so a reachable target
+    // is reached again
+    control.set_target(0.5);
+    run(&mut control, &mut hw, &mut load, 2000);
+    assert_near(load.current, 0.5, 0.05);
+    assert!(!control.is_unreachable());
+    control.update_dtcs(&mut dtcs);
+    assert!(!dtcs.is_failed(0x902092));
+}
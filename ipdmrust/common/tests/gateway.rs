@@ -0,0 +1,190 @@
+// CAN gateway tests
+
+mod util;
+
+use common::gateway::*;
+use common::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use util::*;
+
+fn frame(id: u16, data: &[u8]) -> bxcan::Frame {
+    bxcan::Frame::new_data(
+        bxcan::StandardId::new(id).unwrap(),
+        bxcan::Data::new(data).unwrap(),
+    )
+}
+
+fn forward(
+    direction: GatewayDirection,
+    id: u16,
+    to_id: Option<bxcan::Id>,
+    min_interval_ms: u64,
+) -> GatewayRule {
+    GatewayRule {
+        name: "Forward",
+        direction,
+        id: CanMapId::Exact(standard_id(id)),
+        enable: None,
+        action: GatewayAction::Forward {
+            to_id,
+            rewrite: &[],
+            function: None,
+            min_interval_ms,
+        },
+    }
+}
+
+// Returns the IDs and data of the frames sent by the gateway
+fn sent(gateway: &mut Gateway<4>, hw: &mut TestHardware) -> Vec<(bxcan::Id, Vec<u8>)> {
+    gateway.update(hw);
+    hw.take_sent()
+        .iter()
+        .map(|frame| (frame.id(), frame_data(frame).to_vec()))
+        .collect()
+}
+
+#[test]
+fn rx_frames_are_forwarded_with_rewrite() {
+    let mut hw = TestHardware::new();
+    let mut gateway: Gateway<4> = Gateway::default();
+    gateway.register(GatewayRule {
+        action: GatewayAction::Forward {
+            to_id: Some(standard_id(0x201)),
+            rewrite: &[
+                ByteRewrite {
+                    byte: 0,
+                    mask: 0x0f,
+                    value: 0x05,
+                },
+                // Outside the frame, ignored
+                ByteRewrite {
+                    byte: 4,
+                    mask: 0xff,
+                    value: 0xff,
+                },
+            ],
+            function: Some(|data: &mut [u8]| {
+                data[1] = data[1].wrapping_add(1);
+                true
+            }),
+            min_interval_ms: 0,
+        },
+        ..forward(GatewayDirection::Rx, 0x101, None, 0)
+    });
+
+    gateway.on_can(&frame(0x101, &[0xa0, 0x10, 0x20]), 0);
+    assert_eq!(
+        sent(&mut gateway, &mut hw),
+        vec![(standard_id(0x201), vec![0xa5, 0x11, 0x20])]
+    );
+    // Other IDs aren't forwarded
+    gateway.on_can(&frame(0x102, &[0xa0, 0x10, 0x20]), 0);
+    assert!(sent(&mut gateway, &mut hw).is_empty());
+}
+
+#[test]
+fn function_can_drop_the_copy() {
+    let mut hw = TestHardware::new();
+    let mut gateway: Gateway<4> = Gateway::new();
+    gateway.register(GatewayRule {
+        action: GatewayAction::Forward {
+            to_id: None,
+            rewrite: &[],
+            function: Some(|data: &mut [u8]| data.len() == 8),
+            min_interval_ms: 0,
+        },
+        ..forward(GatewayDirection::Rx, 0x101, None, 0)
+    });
+    gateway.on_can(&frame(0x101, &[1, 2, 3]), 0);
+    assert!(sent(&mut gateway, &mut hw).is_empty());
+    gateway.on_can(&frame(0x101, &[0; 8]), 0);
+    assert_eq!(
+        sent(&mut gateway, &mut hw),
+        vec![(standard_id(0x101), vec![0; 8])]
+    );
+}
+
+#[test]
+fn forwarding_is_rate_limited() {
+    let mut hw = TestHardware::new();
+    let mut gateway: Gateway<4> = Gateway::new();
+    gateway.register(forward(GatewayDirection::Rx, 0x101, None, 100));
+    let mut count = 0;
+    for millis in (0..500).step_by(10) {
+        gateway.on_can(&frame(0x101, &[1]), millis);
+        count += sent(&mut gateway, &mut hw).len();
+    }
+    assert_eq!(count, 5);
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[test]
+fn disabled_rules_are_skipped() {
+    let mut hw = TestHardware::new();
+    let mut gateway: Gateway<4> = Gateway::new();
+    gateway.register(GatewayRule {
+        enable: Some(|| ENABLED.load(Ordering::Relaxed)),
+        ..forward(GatewayDirection::Rx, 0x101, None, 0)
+    });
+    gateway.on_can(&frame(0x101, &[1]), 0);
+    assert!(sent(&mut gateway, &mut hw).is_empty());
+    ENABLED.store(true, Ordering::Relaxed);
+    gateway.on_can(&frame(0x101, &[1]), 0);
+    assert_eq!(sent(&mut gateway, &mut hw).len(), 1);
+}
+
+#[test]
+fn tx_rules_block_and_rate_limit() {
+    let mut gateway: Gateway<4> = Gateway::new();
+    gateway.register(GatewayRule {
+        action: GatewayAction::Block,
+        ..forward(GatewayDirection::Tx, 0x100, None, 0)
+    });
+    gateway.register(GatewayRule {
+        action: GatewayAction::RateLimit {
+            min_interval_ms: 100,
+        },
+        ..forward(GatewayDirection::Tx, 0x101, None, 0)
+    });
+    // Tx rules don't apply to received frames and vice versa
+    gateway.register(forward(GatewayDirection::Rx, 0x102, None, 0));
+
+    assert!(!gateway.filter_tx(&frame(0x100, &[1]), 0));
+    assert!(gateway.filter_tx(&frame(0x101, &[1]), 0));
+    assert!(!gateway.filter_tx(&frame(0x101, &[1]), 50));
+    assert!(gateway.filter_tx(&frame(0x101, &[1]), 100));
+    assert!(gateway.filter_tx(&frame(0x102, &[1]), 100));
+    gateway.on_can(&frame(0x100, &[1]), 100);
+    let mut hw = TestHardware::new();
+    assert!(sent(&mut gateway, &mut hw).is_empty());
+}
+
+#[test]
+fn wrapped_hardware_applies_tx_rules() {
+    let mut hw = TestHardware::new();
+    let mut gateway: Gateway<4> = Gateway::new();
+    gateway.register(GatewayRule {
+        action: GatewayAction::Block,
+        ..forward(GatewayDirection::Tx, 0x100, None, 0)
+    });
+    gateway.register(forward(
+        GatewayDirection::Tx,
+        0x101,
+        Some(standard_id(0x301)),
+        0,
+    ));
+    {
+        let mut tx = gateway.wrap(&mut hw);
+        tx.send_can(frame(0x100, &[1]));
+        tx.send_can(frame(0x101, &[2]));
+    }
+    // The translated copy follows the original
+    assert_eq!(
+        hw.take_sent()
+            .iter()
+            .map(|frame| frame.id())
+            .collect::<Vec<_>>(),
+        vec![standard_id(0x101), standard_id(0x301)]
+    );
+}
@@ -0,0 +1,164 @@
+// H-bridge tests
+
+mod util;
+
+use common::hbridge::*;
+use common::output_layers::{OutputHw, OutputLayers};
+use common::*;
+use util::*;
+
+const A: HalfBridge = HalfBridge {
+    high: DigitalOutput::HOUT7,
+    low: DigitalOutput::LOUT1,
+};
+const B: HalfBridge = HalfBridge {
+    high: DigitalOutput::HOUT8,
+    low: DigitalOutput::LOUT6,
+};
+
+const CONFIG: HBridgesConfig = HBridgesConfig {
+    bridges: &[
+        HBridgeConfig {
+            name: "Lock",
+            a: A,
+            b: Some(B),
+            dead_time_ms: 100,
+            max_pulse_ms: 1000,
+            stall_current: 5.0,
+            stall_ms: 100,
+            stall_blanking_ms: 200,
+        },
+        HBridgeConfig {
+            name: "Flap",
+            a: HalfBridge {
+                high: DigitalOutput::HOUT2,
+                low: DigitalOutput::LOUT4,
+            },
+            b: None,
+            dead_time_ms: 100,
+            max_pulse_ms: 1000,
+            stall_current: f32::NAN,
+            stall_ms: 0,
+            stall_blanking_ms: 0,
+        },
+    ],
+};
+
+// Outputs as (a.high, a.low, b.high, b.low)
+fn outputs(hw: &mut TestHardware) -> (bool, bool, bool, bool) {
+    (
+        hw.get_digital_output(A.high),
+        hw.get_digital_output(A.low),
+        hw.get_digital_output(B.high),
+        hw.get_digital_output(B.low),
+    )
+}
+
+fn run(bridges: &mut HBridges, hw: &mut TestHardware, ms: u64) {
+    run_ticks(hw, ms, |hw| {
+        bridges.update(hw);
+        let (a_high, a_low, b_high, b_low) = outputs(hw);
+        assert!(!(a_high && a_low || b_high && b_low), "Shoot-through");
+    });
+}
+
+#[test]
+fn reversing_waits_for_dead_time() {
+    let mut hw = TestHardware::new();
+    let mut bridges = HBridges::new(CONFIG);
+    bridges.set_command(0, HBridgeCommand::Forward);
+    run(&mut bridges, &mut hw, TICK_MS);
+    assert_eq!(outputs(&mut hw), (true, false, false, true));
+    run(&mut bridges, &mut hw, 200);
+
+    bridges.set_command(0, HBridgeCommand::Reverse);
+    assert_eq!(bridges.get_command(0), HBridgeCommand::Reverse);
+    run(&mut bridges, &mut hw, TICK_MS);
+    assert_eq!(outputs(&mut hw), (false, false, false, false));
+    run(&mut bridges, &mut hw, 80);
+    assert_eq!(outputs(&mut hw), (false, false, false, false));
+    run(&mut bridges, &mut hw, 20);
+    assert_eq!(outputs(&mut hw), (false, true, true, false));
+
+    bridges.set_command(0, HBridgeCommand::Brake);
+    run(&mut bridges, &mut hw, 120);
+    assert_eq!(outputs(&mut hw), (false, true, false, true));
+    assert_eq!(bridges.last_stop(0), Some(HBridgeStop::Command));
+
+    bridges.set_command(0, HBridgeCommand::Coast);
+    run(&mut bridges, &mut hw, 100);
+    assert_eq!(outputs(&mut hw), (false, false, false, false));
+}
+
+#[test]
+fn pulses_are_limited() {
+    let mut hw = TestHardware::new();
+    let mut bridges = HBridges::new(CONFIG);
+    bridges.pulse(0, HBridgeCommand::Forward, 300);
+    run(&mut bridges, &mut hw, 300);
+    assert_eq!(outputs(&mut hw), (true, false, false, true));
+    run(&mut bridges, &mut hw, 20);
+    assert_eq!(outputs(&mut hw), (false, false, false, false));
+    run(&mut bridges, &mut hw, 100);
+    assert_eq!(outputs(&mut hw), (false, true, false, true));
+    assert_eq!(bridges.last_stop(0), Some(HBridgeStop::PulseLimit));
+
+    // Longer than max_pulse_ms
+    bridges.pulse(0, HBridgeCommand::Reverse, 5000);
+    run(&mut bridges, &mut hw, 120);
+    assert_eq!(outputs(&mut hw), (false, true, true, false));
+    run(&mut bridges, &mut hw, 1000);
+    assert_eq!(bridges.get_command(0), HBridgeCommand::Brake);
+}
+
+#[test]
+fn stall_brakes_after_blanking() {
+    let mut hw = TestHardware::new();
+    let mut bridges = HBridges::new(CONFIG);
+    // Inrush is ignored
+    hw.analog_inputs.insert(AnalogInput::Current3, 8.0);
+    bridges.set_command(0, HBridgeCommand::Forward);
+    run(&mut bridges, &mut hw, 220);
+    hw.analog_inputs.insert(AnalogInput::Current3, 2.0);
+    run(&mut bridges, &mut hw, 200);
+    assert_eq!(bridges.get_command(0), HBridgeCommand::Forward);
+
+    // End of travel
+    hw.analog_inputs.insert(AnalogInput::Current3, 6.0);
+    run(&mut bridges, &mut hw, 80);
+    assert_eq!(bridges.get_command(0), HBridgeCommand::Forward);
+    run(&mut bridges, &mut hw, 40);
+    assert_eq!(bridges.get_command(0), HBridgeCommand::Brake);
+    assert_eq!(bridges.last_stop(0), Some(HBridgeStop::Stall));
+    assert_eq!(outputs(&mut hw), (false, false, false, false));
+}
+
+#[test]
+fn half_bridge_cant_reverse() {
+    let mut hw = TestHardware::new();
+    let mut bridges = HBridges::new(CONFIG);
+    bridges.set_command(1, HBridgeCommand::Reverse);
+    assert_eq!(bridges.get_command(1), HBridgeCommand::Coast);
+    bridges.set_command(1, HBridgeCommand::Forward);
+    run(&mut bridges, &mut hw, TICK_MS);
+    assert!(hw.get_digital_output(DigitalOutput::HOUT2));
+    bridges.set_command(1, HBridgeCommand::Brake);
+    run(&mut bridges, &mut hw, 120);
+    assert!(!hw.get_digital_output(DigitalOutput::HOUT2));
+    assert!(hw.get_digital_output(DigitalOutput::LOUT4));
+}
+
+#[test]
+fn bridge_outputs_cant_be_set_directly() {
+    let mut bridges = HBridges::new(CONFIG);
+    let mut test_hw = TestHardware::new();
+    let layers = OutputLayers {
+        hbridges: Some(&mut bridges),
+        ..Default::default()
+    };
+    let hw = &mut OutputHw::new(&mut test_hw, layers);
+    hw.set_digital_output(DigitalOutput::LOUT1, true);
+    hw.set_digital_output(DigitalOutput::HOUT9, true);
+    assert!(!test_hw.get_digital_output(DigitalOutput::LOUT1));
+    assert!(test_hw.get_digital_output(DigitalOutput::HOUT9));
+}
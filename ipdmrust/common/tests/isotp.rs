@@ -0,0 +1,272 @@
+// ISO-TP tests: Two endpoints talking to each other over a simulated bus
+
+mod util;
+
+use common::isotp::*;
+use common::*;
+use util::*;
+
+const TESTER_ID: u16 = 0x7e0;
+const ECU_ID: u16 = 0x7e8;
+const TICK_MS: u64 = 10;
+
+struct Endpoint<const N: usize> {
+    isotp: IsoTp<N>,
+    hw: TestHardware,
+    // Every frame sent by this endpoint, with a timestamp
+    log: Vec<(u64, bxcan::Frame)>,
+}
+
+impl<const N: usize> Endpoint<N> {
+    fn new(config: IsoTpConfig) -> Self {
+        Self {
+            isotp: IsoTp::new(config),
+            hw: TestHardware::new(),
+            log: Vec::new(),
+        }
+    }
+}
+
+fn config(tx_id: u16, rx_id: u16) -> IsoTpConfig {
+    IsoTpConfig::new(standard_id(tx_id), standard_id(rx_id))
+}
+
+// Runs both endpoints for the given time, delivering frames between them.
+// Returns the messages received by a and b.
+fn run<const N: usize, const M: usize>(
+    a: &mut Endpoint<N>,
+    b: &mut Endpoint<M>,
+    duration_ms: u64,
+) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    let mut received_a = Vec::new();
+    let mut received_b = Vec::new();
+    let end_ms = a.hw.millis + duration_ms;
+    while a.hw.millis < end_ms {
+        a.hw.millis += TICK_MS;
+        b.hw.millis += TICK_MS;
+        a.isotp.update(&mut a.hw);
+        b.isotp.update(&mut b.hw);
+        for frame in a.hw.take_sent() {
+            a.log.push((a.hw.millis, frame.clone()));
+            b.isotp.on_can(&frame, b.hw.millis);
+        }
+        for frame in b.hw.take_sent() {
+            b.log.push((b.hw.millis, frame.clone()));
+            a.isotp.on_can(&frame, a.hw.millis);
+        }
+        if let Some(message) = a.isotp.take_received() {
+            received_a.push(message.to_vec());
+        }
+        if let Some(message) = b.isotp.take_received() {
+            received_b.push(message.to_vec());
+        }
+    }
+    (received_a, received_b)
+}
+
+fn test_message(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + 3) as u8).collect()
+}
+
+#[test]
+fn single_frame() {
+    let mut tester = Endpoint::<64>::new(config(TESTER_ID, ECU_ID));
+    let mut ecu = Endpoint::<64>::new(config(ECU_ID, TESTER_ID));
+
+    tester.isotp.send(&[0x22, 0xf1, 0x90]).unwrap();
+    let (_, received) = run(&mut tester, &mut ecu, 50);
+    assert_eq!(received, vec![vec![0x22, 0xf1, 0x90]]);
+    assert_eq!(tester.log.len(), 1);
+    assert_eq!(frame_data(&tester.log[0].1)[0], 0x03);
+}
+
+#[test]
+fn multi_frame_both_directions() {
+    let mut tester = Endpoint::<256>::new(config(TESTER_ID, ECU_ID));
+    let mut ecu = Endpoint::<256>::new(config(ECU_ID, TESTER_ID));
+
+    let request = test_message(20);
+    let response = test_message(200);
+    tester.isotp.send(&request).unwrap();
+    let (_, received) = run(&mut tester, &mut ecu, 200);
+    assert_eq!(received, vec![request]);
+
+    ecu.isotp.send(&response).unwrap();
+    let (received, _) = run(&mut tester, &mut ecu, 500);
+    assert_eq!(received, vec![response]);
+    assert!(ecu.isotp.is_tx_idle());
+    assert_eq!(tester.isotp.take_error(), None);
+    assert_eq!(ecu.isotp.take_error(), None);
+}
+
+#[test]
+fn maximum_length() {
+    let mut tester = Endpoint::<ISOTP_MAX_LEN>::new(config(TESTER_ID, ECU_ID));
+    let mut ecu = Endpoint::<ISOTP_MAX_LEN>::new(config(ECU_ID, TESTER_ID));
+
+    let message = test_message(ISOTP_MAX_LEN);
+    tester.isotp.send(&message).unwrap();
+    let (_, received) = run(&mut tester, &mut ecu, 20000);
+    assert_eq!(received, vec![message]);
+}
+
+#[test]
+fn too_long_to_send() {
+    let mut tester = Endpoint::<16>::new(config(TESTER_ID, ECU_ID));
+    assert_eq!(
+        tester.isotp.send(&test_message(17)),
+        Err(IsoTpError::TooLong)
+    );
+}
+
+#[test]
+fn busy_while_sending() {
+    let mut tester = Endpoint::<64>::new(config(TESTER_ID, ECU_ID));
+    tester.isotp.send(&test_message(30)).unwrap();
+    assert_eq!(tester.isotp.send(&test_message(30)), Err(IsoTpError::Busy));
+}
+
+#[test]
+fn block_size_and_st_min() {
+    let mut tester = Endpoint::<256>::new(config(TESTER_ID, ECU_ID));
+    let mut ecu_config = config(ECU_ID, TESTER_ID);
+    ecu_config.block_size = 4;
+    ecu_config.st_min_ms = 25;
+    let mut ecu = Endpoint::<256>::new(ecu_config);
+
+    let message = test_message(100);
+    tester.isotp.send(&message).unwrap();
+    let (_, received) = run(&mut tester, &mut ecu, 2000);
+    assert_eq!(received, vec![message]);
+
+    // 100 bytes = FF (6 bytes) + 14 CFs. With a block size of 4 the receiver
+    // sends 1 + 3 flow control frames.
+    let flow_controls = ecu
+        .log
+        .iter()
+        .filter(|(_, f)| frame_data(f)[0] & 0xf0 == 0x30)
+        .count();
+    assert_eq!(flow_controls, 4);
+
+    let cf_times: Vec<u64> = tester
+        .log
+        .iter()
+        .filter(|(_, f)| frame_data(f)[0] & 0xf0 == 0x20)
+        .map(|(t, _)| *t)
+        .collect();
+    assert_eq!(cf_times.len(), 14);
+    for pair in cf_times.windows(2) {
+        assert!(pair[1] - pair[0] >= 25, "STmin violated: {:?}", pair);
+    }
+}
+
+#[test]
+fn back_to_back_consecutive_frames_are_limited_per_update() {
+    let mut tester_config = config(TESTER_ID, ECU_ID);
+    tester_config.max_frames_per_update = 3;
+    let mut tester = Endpoint::<256>::new(tester_config);
+    let mut ecu = Endpoint::<256>::new(config(ECU_ID, TESTER_ID));
+
+    tester.isotp.send(&test_message(100)).unwrap();
+    run(&mut tester, &mut ecu, 500);
+
+    let mut per_tick = std::collections::HashMap::new();
+    for (t, _) in &tester.log {
+        *per_tick.entry(*t).or_insert(0) += 1;
+    }
+    assert!(per_tick.values().all(|n| *n <= 3));
+}
+
+#[test]
+fn padding() {
+    let mut tester = Endpoint::<64>::new(config(TESTER_ID, ECU_ID));
+    let mut ecu_config = config(ECU_ID, TESTER_ID);
+    ecu_config.padding = None;
+    let mut ecu = Endpoint::<64>::new(ecu_config);
+
+    tester.isotp.send(&[0x3e, 0x00]).unwrap();
+    ecu.isotp.send(&[0x7e, 0x00]).unwrap();
+    let (received_tester, received_ecu) = run(&mut tester, &mut ecu, 50);
+    assert_eq!(received_tester, vec![vec![0x7e, 0x00]]);
+    assert_eq!(received_ecu, vec![vec![0x3e, 0x00]]);
+
+    assert_eq!(
+        frame_data(&tester.log[0].1),
+        &[0x02, 0x3e, 0x00, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa]
+    );
+    assert_eq!(frame_data(&ecu.log[0].1), &[0x02, 0x7e, 0x00]);
+}
+
+#[test]
+fn receiver_overflow() {
+    let mut tester = Endpoint::<256>::new(config(TESTER_ID, ECU_ID));
+    let mut ecu = Endpoint::<64>::new(config(ECU_ID, TESTER_ID));
+
+    tester.isotp.send(&test_message(100)).unwrap();
+    let (_, received) = run(&mut tester, &mut ecu, 200);
+    assert!(received.is_empty());
+    assert_eq!(tester.isotp.take_error(), Some(IsoTpError::Overflow));
+    assert!(tester.isotp.is_tx_idle());
+}
+
+#[test]
+fn flow_control_timeout() {
+    let mut tester = Endpoint::<256>::new(config(TESTER_ID, ECU_ID));
+    // Nobody listens on this ID
+    let mut ecu = Endpoint::<256>::new(config(ECU_ID, 0x123));
+
+    tester.isotp.send(&test_message(100)).unwrap();
+    run(&mut tester, &mut ecu, 900);
+    assert!(!tester.isotp.is_tx_idle());
+    run(&mut tester, &mut ecu, 200);
+    assert!(tester.isotp.is_tx_idle());
+    assert_eq!(tester.isotp.take_error(), Some(IsoTpError::Timeout));
+}
+
+#[test]
+fn consecutive_frame_timeout() {
+    let mut ecu = Endpoint::<256>::new(config(ECU_ID, TESTER_ID));
+    let first_frame = bxcan::Frame::new_data(
+        bxcan::StandardId::new(TESTER_ID).unwrap(),
+        bxcan::Data::new(&[0x10, 0x20, 1, 2, 3, 4, 5, 6]).unwrap(),
+    );
+    ecu.isotp.on_can(&first_frame, ecu.hw.millis);
+    ecu.isotp.update(&mut ecu.hw);
+    // Flow control was sent
+    assert_eq!(frame_data(&ecu.hw.take_sent()[0])[0], 0x30);
+
+    ecu.hw.millis += 1500;
+    ecu.isotp.update(&mut ecu.hw);
+    assert_eq!(ecu.isotp.take_error(), Some(IsoTpError::Timeout));
+    assert!(ecu.isotp.take_received().is_none());
+}
+
+#[test]
+fn wrong_sequence_number() {
+    let mut ecu = Endpoint::<256>::new(config(ECU_ID, TESTER_ID));
+    let frame = |data: &[u8]| {
+        bxcan::Frame::new_data(
+            bxcan::StandardId::new(TESTER_ID).unwrap(),
+            bxcan::Data::new(data).unwrap(),
+        )
+    };
+    ecu.isotp.on_can(&frame(&[0x10, 0x10, 1, 2, 3, 4, 5, 6]), 0);
+    ecu.isotp
+        .on_can(&frame(&[0x22, 7, 8, 9, 10, 11, 12, 13]), 10);
+    assert_eq!(
+        ecu.isotp.take_error(),
+        Some(IsoTpError::WrongSequenceNumber)
+    );
+    assert!(ecu.isotp.take_received().is_none());
+}
+
+#[test]
+fn ignores_other_ids() {
+    let mut ecu = Endpoint::<256>::new(config(ECU_ID, TESTER_ID));
+    let frame = bxcan::Frame::new_data(
+        bxcan::StandardId::new(0x100).unwrap(),
+        bxcan::Data::new(&[0x02, 0x3e, 0x00]).unwrap(),
+    );
+    assert!(!ecu.isotp.on_can(&frame, 0));
+    assert!(ecu.isotp.take_received().is_none());
+}
@@ -0,0 +1,272 @@
+// SAE J1939 tests
+
+mod util;
+
+use common::j1939::*;
+use common::*;
+use util::*;
+
+fn extended_frame(raw: u32, data: &[u8]) -> bxcan::Frame {
+    bxcan::Frame::new_data(
+        bxcan::ExtendedId::new(raw).unwrap(),
+        bxcan::Data::new(data).unwrap(),
+    )
+}
+
+fn raw_id(id: bxcan::Id) -> u32 {
+    match id {
+        bxcan::Id::Extended(id) => id.as_raw(),
+        bxcan::Id::Standard(_) => panic!("Standard ID"),
+    }
+}
+
+#[test]
+fn pdu1_id_round_trip() {
+    let id = extended_frame(0x18ea80f9, &[]).id();
+    let j1939_id = J1939Id::from_id(id).unwrap();
+    assert_eq!(
+        j1939_id,
+        J1939Id {
+            priority: 6,
+            pgn: PGN_REQUEST,
+            source_address: 0xf9,
+            destination_address: 0x80,
+        }
+    );
+    assert!(j1939_id.is_pdu1());
+    assert_eq!(j1939_id.to_id(), id);
+}
+
+#[test]
+fn pdu2_id_round_trip() {
+    // EEC1 from the engine
+    let id = extended_frame(0x0cf00400, &[]).id();
+    let j1939_id = J1939Id::from_id(id).unwrap();
+    assert_eq!(
+        j1939_id,
+        J1939Id {
+            priority: 3,
+            pgn: 0xf004,
+            source_address: 0x00,
+            destination_address: ADDRESS_GLOBAL,
+        }
+    );
+    assert!(!j1939_id.is_pdu1());
+    assert_eq!(j1939_id.to_id(), id);
+
+    // The data page is part of the PGN
+    let j1939_id = J1939Id::from_id(extended_frame(0x19fef117, &[]).id()).unwrap();
+    assert_eq!(j1939_id.pgn, 0x1fef1);
+    assert_eq!(raw_id(j1939_id.to_id()), 0x19fef117);
+
+    assert_eq!(J1939Id::from_id(standard_id(0x100)), None);
+}
+
+#[test]
+fn j1939_frame_builds_extended_id() {
+    // Request for the address claim to the global address
+    let id = J1939Id {
+        priority: 6,
+        pgn: PGN_REQUEST,
+        source_address: 0xf9,
+        destination_address: ADDRESS_GLOBAL,
+    };
+    let frame = j1939_frame(id, &[0x00, 0xee, 0x00]).unwrap();
+    assert_eq!(frame.id(), extended_id(0x18eafff9));
+    assert_eq!(frame.data().unwrap().as_ref(), &[0x00, 0xee, 0x00]);
+
+    // The destination address is ignored for PDU2 PGNs
+    let id = J1939Id {
+        priority: 3,
+        pgn: 0xf004,
+        source_address: 0x00,
+        destination_address: 0x80,
+    };
+    let frame = j1939_frame(id, &[0; 8]).unwrap();
+    assert_eq!(frame.id(), extended_id(0x0cf00400));
+
+    assert!(j1939_frame(id, &[0; 9]).is_none());
+}
+
+#[test]
+fn pgn_can_map_ignores_priority_and_destination() {
+    let map_id = CanMapId::J1939 {
+        pgn: 0xf004,
+        source_address: Some(0x00),
+    };
+    assert!(map_id.matches(extended_frame(0x0cf00400, &[]).id()));
+    assert!(map_id.matches(extended_frame(0x18f00400, &[]).id()));
+    assert!(!map_id.matches(extended_frame(0x0cf00401, &[]).id()));
+    assert!(!map_id.matches(extended_frame(0x0cf00500, &[]).id()));
+    let any_source = CanMapId::J1939 {
+        pgn: PGN_REQUEST,
+        source_address: None,
+    };
+    assert!(any_source.matches(extended_frame(0x18ea80f9, &[]).id()));
+    assert!(any_source.matches(extended_frame(0x18eafff9, &[]).id()));
+}
+
+const NAME: u64 = (1 << 63) | 0x1000;
+
+fn claim_frame(source_address: u8, name: u64) -> bxcan::Frame {
+    extended_frame(0x18eeff00 | source_address as u32, &name.to_le_bytes())
+}
+
+// Returns the source addresses of sent address claims
+fn sent_claims(hw: &mut TestHardware) -> Vec<u8> {
+    hw.take_sent()
+        .iter()
+        .map(|frame| {
+            let id = J1939Id::from_id(frame.id()).unwrap();
+            assert_eq!(id.pgn, PGN_ADDRESS_CLAIMED);
+            id.source_address
+        })
+        .collect()
+}
+
+fn run(claimer: &mut J1939AddressClaimer, hw: &mut TestHardware, ms: u64) {
+    let end = hw.millis + ms;
+    while hw.millis < end {
+        hw.millis += 10;
+        claimer.update(hw);
+    }
+}
+
+#[test]
+fn address_is_claimed_after_wait() {
+    let mut hw = TestHardware::new();
+    let mut claimer = J1939AddressClaimer::new(NAME, 0x80);
+    claimer.update(&mut hw);
+    assert_eq!(sent_claims(&mut hw), vec![0x80]);
+    assert_eq!(claimer.address(), None);
+    run(&mut claimer, &mut hw, 240);
+    assert_eq!(claimer.address(), None);
+    run(&mut claimer, &mut hw, 10);
+    assert_eq!(claimer.address(), Some(0x80));
+    assert_eq!(claimer.state(), AddressClaimState::Claimed);
+
+    // Requests for the address claim are answered
+    claimer.on_can(&extended_frame(0x18eafff9, &[0x00, 0xee, 0x00]));
+    claimer.update(&mut hw);
+    assert_eq!(sent_claims(&mut hw), vec![0x80]);
+    // Requests to other addresses aren't
+    claimer.on_can(&extended_frame(0x18ea81f9, &[0x00, 0xee, 0x00]));
+    claimer.update(&mut hw);
+    assert!(sent_claims(&mut hw).is_empty());
+}
+
+#[test]
+fn address_is_defended_against_higher_name() {
+    let mut hw = TestHardware::new();
+    let mut claimer = J1939AddressClaimer::new(NAME, 0x80);
+    run(&mut claimer, &mut hw, 300);
+    hw.take_sent();
+    claimer.on_can(&claim_frame(0x80, NAME + 1));
+    claimer.update(&mut hw);
+    assert_eq!(sent_claims(&mut hw), vec![0x80]);
+    assert_eq!(claimer.address(), Some(0x80));
+    // Our own claim echoed back changes nothing
+    claimer.on_can(&claim_frame(0x80, NAME));
+    claimer.update(&mut hw);
+    assert!(sent_claims(&mut hw).is_empty());
+}
+
+#[test]
+fn lost_address_moves_to_next_address() {
+    let mut hw = TestHardware::new();
+    let mut claimer = J1939AddressClaimer::new(NAME, 0x80);
+    run(&mut claimer, &mut hw, 300);
+    hw.take_sent();
+    claimer.on_can(&claim_frame(0x80, NAME - 1));
+    claimer.update(&mut hw);
+    assert_eq!(sent_claims(&mut hw), vec![0x81]);
+    assert_eq!(claimer.address(), None);
+    run(&mut claimer, &mut hw, 250);
+    assert_eq!(claimer.address(), Some(0x81));
+}
+
+#[test]
+fn address_cannot_be_claimed() {
+    // Not arbitrary address capable
+    let mut hw = TestHardware::new();
+    let mut claimer = J1939AddressClaimer::new(0x1000, 0x80);
+    claimer.update(&mut hw);
+    hw.take_sent();
+    claimer.on_can(&claim_frame(0x80, 0x0fff));
+    claimer.update(&mut hw);
+    assert_eq!(sent_claims(&mut hw), vec![ADDRESS_NULL]);
+    run(&mut claimer, &mut hw, 500);
+    assert_eq!(claimer.state(), AddressClaimState::CannotClaim);
+    assert_eq!(claimer.address(), None);
+
+    // The end of the range
+    let mut claimer = J1939AddressClaimer::new(NAME, 247);
+    claimer.update(&mut hw);
+    hw.take_sent();
+    claimer.on_can(&claim_frame(247, 0));
+    claimer.update(&mut hw);
+    assert_eq!(sent_claims(&mut hw), vec![ADDRESS_NULL]);
+    assert_eq!(claimer.state(), AddressClaimState::CannotClaim);
+}
+
+const SOURCE: u32 = 0x17;
+
+fn bam_announce(size: u16, packets: u8, pgn: u32) -> bxcan::Frame {
+    let size = size.to_le_bytes();
+    let pgn = pgn.to_le_bytes();
+    extended_frame(
+        0x1cecff00 | SOURCE,
+        &[32, size[0], size[1], packets, 0xff, pgn[0], pgn[1], pgn[2]],
+    )
+}
+
+fn bam_data(sequence: u8, data: &[u8]) -> bxcan::Frame {
+    let mut buf = [0xff; 8];
+    buf[0] = sequence;
+    buf[1..1 + data.len()].copy_from_slice(data);
+    extended_frame(0x1cebff00 | SOURCE, &buf)
+}
+
+#[test]
+fn bam_is_reassembled() {
+    let mut receiver: BamReceiver<64, 2> = BamReceiver::default();
+    let payload: Vec<u8> = (1..=16).collect();
+    receiver.on_can(&bam_announce(16, 3, 0xfeca), 0);
+    receiver.on_can(&bam_data(1, &payload[0..7]), 50);
+    receiver.on_can(&bam_data(2, &payload[7..14]), 100);
+    assert!(receiver.take_received().is_none());
+    receiver.on_can(&bam_data(3, &payload[14..16]), 150);
+    let message = receiver.take_received().unwrap();
+    assert_eq!(message.pgn, 0xfeca);
+    assert_eq!(message.source_address, SOURCE as u8);
+    assert_eq!(&message.data[..], &payload[..]);
+    assert!(receiver.take_received().is_none());
+}
+
+#[test]
+fn bam_errors_drop_the_session() {
+    let mut receiver: BamReceiver<64, 2> = BamReceiver::new();
+    let payload = [0u8; 7];
+
+    // Wrong sequence number
+    receiver.on_can(&bam_announce(16, 3, 0xfeca), 0);
+    receiver.on_can(&bam_data(1, &payload), 50);
+    receiver.on_can(&bam_data(3, &payload), 100);
+    receiver.on_can(&bam_data(3, &payload[..2]), 150);
+    assert!(receiver.take_received().is_none());
+
+    // Timeout
+    receiver.on_can(&bam_announce(16, 3, 0xfeca), 1000);
+    receiver.on_can(&bam_data(1, &payload), 1050);
+    receiver.run_timeout(1800);
+    receiver.on_can(&bam_data(2, &payload), 1800);
+    receiver.on_can(&bam_data(3, &payload[..2]), 1850);
+    assert!(receiver.take_received().is_none());
+
+    // Too long for the buffer and inconsistent packet count
+    receiver.on_can(&bam_announce(100, 15, 0xfeca), 2000);
+    receiver.on_can(&bam_data(1, &payload), 2050);
+    receiver.on_can(&bam_announce(16, 4, 0xfeca), 2100);
+    receiver.on_can(&bam_data(1, &payload), 2150);
+    assert!(receiver.take_received().is_none());
+}
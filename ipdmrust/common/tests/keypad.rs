@@ -0,0 +1,223 @@
+// CANopen keypad tests
+
+mod util;
+
+use common::keypad::*;
+use common::*;
+use std::sync::{Mutex, MutexGuard};
+use util::*;
+
+define_parameters! {
+    Fan {
+        display_name: "Fan",
+        unit: "",
+        default_value: 0.0,
+    },
+    Light {
+        display_name: "Light",
+        unit: "",
+        default_value: 0.0,
+    },
+    Heat {
+        display_name: "Heat",
+        unit: "",
+        default_value: 0.0,
+    },
+    Bogus {
+        display_name: "Bogus",
+        unit: "",
+        default_value: 0.0,
+    },
+}
+
+const NODE_ID: u8 = 0x15;
+
+const fn mapping(key: u8, parameter: ParameterId, mode: KeyMode) -> KeyMapping {
+    KeyMapping {
+        key,
+        parameter: parameter as usize,
+        mode,
+        led_on: LedColor::Green,
+        led_off: LedColor::Off,
+    }
+}
+
+const CONFIG: KeypadConfig = KeypadConfig {
+    node_id: NODE_ID,
+    keys: &[
+        mapping(1, ParameterId::Fan, KeyMode::Momentary),
+        mapping(2, ParameterId::Light, KeyMode::Hold { hold_ms: 500 }),
+        KeyMapping {
+            led_on: LedColor::Blue,
+            led_off: LedColor::Yellow,
+            ..mapping(3, ParameterId::Heat, KeyMode::Toggle)
+        },
+        KeyMapping {
+            led_on: LedColor::White,
+            led_off: LedColor::Magenta,
+            ..mapping(16, ParameterId::Bogus, KeyMode::Momentary)
+        },
+    ],
+    heartbeat_timeout_ms: 1000,
+    led_period_ms: 500,
+    led_brightness: 0x20,
+    backlight_brightness: 0x10,
+    own_node_id: 0x01,
+    heartbeat_period_ms: 1000,
+};
+
+// The parameters are global and the tests write them
+static LOCK: Mutex<()> = Mutex::new(());
+
+struct Tester {
+    keypad: Keypad,
+    hw: TestHardware,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Tester {
+    fn new(config: KeypadConfig) -> Self {
+        let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        init_parameters();
+        for param in get_parameters() {
+            param.value = 0.0;
+        }
+        let mut tester = Self {
+            keypad: Keypad::new(config),
+            hw: TestHardware::new(),
+            _lock: lock,
+        };
+        tester.receive(0x700, &[0x05]);
+        tester
+    }
+
+    fn receive(&mut self, base_id: u16, data: &[u8]) {
+        let frame = bxcan::Frame::new_data(
+            bxcan::StandardId::new(base_id + NODE_ID as u16).unwrap(),
+            bxcan::Data::new(data).unwrap(),
+        );
+        assert!(self.keypad.on_can(&frame, self.hw.millis));
+    }
+
+    fn press(&mut self, keys: u16) {
+        self.receive(0x180, &keys.to_le_bytes());
+    }
+
+    fn run(&mut self, ms: u64) {
+        let end = self.hw.millis + ms;
+        while self.hw.millis < end {
+            self.hw.millis += 20;
+            self.keypad.update(&mut self.hw);
+        }
+    }
+
+    // Returns the LED frames sent since the last call
+    fn leds(&mut self) -> Vec<Vec<u8>> {
+        self.hw
+            .take_sent()
+            .iter()
+            .filter(|frame| frame.id() == standard_id(0x200 + NODE_ID as u16))
+            .map(|frame| frame_data(frame).to_vec())
+            .collect()
+    }
+}
+
+fn value(id: ParameterId) -> f32 {
+    get_parameter(id).value
+}
+
+#[test]
+fn key_modes() {
+    let mut tester = Tester::new(CONFIG);
+
+    // Momentary
+    tester.press(0b0001);
+    assert_eq!(value(ParameterId::Fan), 1.0);
+    assert!(tester.keypad.is_active(1));
+    tester.press(0b0000);
+    assert_eq!(value(ParameterId::Fan), 0.0);
+    assert!(!tester.keypad.is_active(1));
+
+    // Hold
+    tester.press(0b0010);
+    tester.run(400);
+    assert_eq!(value(ParameterId::Light), 0.0);
+    tester.run(100);
+    assert_eq!(value(ParameterId::Light), 1.0);
+    tester.press(0b0000);
+    assert_eq!(value(ParameterId::Light), 0.0);
+
+    // Toggle
+    tester.press(0b0100);
+    assert_eq!(value(ParameterId::Heat), 1.0);
+    tester.press(0b0000);
+    assert_eq!(value(ParameterId::Heat), 1.0);
+    tester.press(0b0100);
+    tester.press(0b0000);
+    assert_eq!(value(ParameterId::Heat), 0.0);
+
+    // Unmapped keys are active while pressed
+    tester.press(0b1000);
+    assert!(tester.keypad.is_pressed(4));
+    assert!(tester.keypad.is_active(4));
+    assert!(!tester.keypad.is_pressed(0));
+    assert!(!tester.keypad.is_pressed(17));
+}
+
+#[test]
+fn timeout_releases_keys_except_toggles() {
+    let mut tester = Tester::new(CONFIG);
+    tester.press(0b0101);
+    assert_eq!(value(ParameterId::Fan), 1.0);
+    assert_eq!(value(ParameterId::Heat), 1.0);
+    tester.run(1000);
+    assert!(!tester.keypad.is_alive());
+    assert_eq!(value(ParameterId::Fan), 0.0);
+    assert_eq!(value(ParameterId::Heat), 1.0);
+    assert!(!tester.keypad.is_pressed(1));
+}
+
+#[test]
+fn led_encoding() {
+    let mut tester = Tester::new(CONFIG);
+    tester.run(20);
+    // Red, green and blue bitmaps: key 3 is yellow, key 16 magenta
+    assert_eq!(
+        tester.leds(),
+        vec![vec![0x04, 0x80, 0x04, 0x00, 0x00, 0x80]]
+    );
+    // Changes are sent immediately
+    tester.press(0b0101);
+    tester.run(20);
+    assert_eq!(
+        tester.leds(),
+        vec![vec![0x00, 0x80, 0x01, 0x00, 0x04, 0x80]]
+    );
+    tester.run(460);
+    assert!(tester.leds().is_empty());
+    // And periodically
+    tester.run(40);
+    assert_eq!(tester.leds().len(), 1);
+}
+
+#[test]
+fn invalid_keys_are_ignored() {
+    const KEYS: &[KeyMapping] = &[
+        mapping(0, ParameterId::Bogus, KeyMode::Hold { hold_ms: 0 }),
+        mapping(17, ParameterId::Bogus, KeyMode::Momentary),
+        mapping(1, ParameterId::Fan, KeyMode::Momentary),
+    ];
+    let mut tester = Tester::new(KeypadConfig {
+        keys: KEYS,
+        ..CONFIG
+    });
+    tester.press(0xffff);
+    tester.run(20);
+    assert_eq!(value(ParameterId::Bogus), 0.0);
+    assert_eq!(value(ParameterId::Fan), 1.0);
+    assert!(!tester.keypad.is_active(0));
+    assert_eq!(
+        tester.leds(),
+        vec![vec![0x00, 0x00, 0x01, 0x00, 0x00, 0x00]]
+    );
+}
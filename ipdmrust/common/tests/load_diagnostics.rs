@@ -0,0 +1,160 @@
+// Load diagnostics tests
+
+mod util;
+
+use common::dtc::DtcStore;
+use common::load_diagnostics::*;
+use common::smart_fuse::FuseChannel;
+use common::*;
+use util::*;
+
+const GROUP1: FuseChannel = FuseChannel::Group(OutputGroup::Group1);
+
+const CONFIG: LoadDiagnosticsConfig = LoadDiagnosticsConfig {
+    loads: &[
+        LoadConfig {
+            load: Load::Digital(DigitalOutput::HOUT1),
+            expected_current: 2.0,
+            open_load_dtc: 0x900113,
+        },
+        LoadConfig {
+            load: Load::Digital(DigitalOutput::HOUT2),
+            expected_current: f32::NAN,
+            open_load_dtc: 0x900213,
+        },
+        LoadConfig {
+            load: Load::Lcur1,
+            expected_current: 1.0,
+            open_load_dtc: 0x902013,
+        },
+    ],
+    stuck_on: &[StuckOnConfig {
+        channel: GROUP1,
+        dtc: 0x910112,
+    }],
+    min_current: 0.2,
+    open_load_ratio: 0.3,
+    settle_ms: 200,
+    filter_ms: 1000,
+};
+
+fn set_current(hw: &mut TestHardware, current: f32) {
+    hw.analog_inputs.insert(AnalogInput::Current1, current);
+}
+
+#[test]
+fn open_load_while_alone_in_group() {
+    let mut hw = TestHardware::new();
+    let mut diag = LoadDiagnostics::new(CONFIG);
+    let hout1 = Load::Digital(DigitalOutput::HOUT1);
+    hw.set_digital_output(DigitalOutput::HOUT1, true);
+    set_current(&mut hw, 1.9);
+    run_ticks(&mut hw, 2000, |hw| diag.update(hw));
+    assert!(!diag.is_open(hout1));
+
+    // The wire breaks
+    set_current(&mut hw, 0.0);
+    run_ticks(&mut hw, 900, |hw| diag.update(hw));
+    assert!(!diag.is_open(hout1));
+    run_ticks(&mut hw, 200, |hw| diag.update(hw));
+    assert!(diag.is_open(hout1));
+
+    let mut dtcs = DtcStore::<8>::new();
+    diag.update_dtcs(&mut dtcs);
+    assert!(dtcs.is_failed(0x900113));
+    assert!(!dtcs.is_failed(0x910112));
+
+    // And is fixed
+    set_current(&mut hw, 2.0);
+    run_ticks(&mut hw, TICK_MS, |hw| diag.update(hw));
+    assert!(!diag.is_open(hout1));
+    diag.update_dtcs(&mut dtcs);
+    assert!(!dtcs.is_failed(0x900113));
+}
+
+#[test]
+fn turn_on_step_is_checked_and_learned() {
+    let mut hw = TestHardware::new();
+    let mut diag = LoadDiagnostics::new(CONFIG);
+    let hout2 = Load::Digital(DigitalOutput::HOUT2);
+    hw.set_digital_output(DigitalOutput::HOUT1, true);
+    set_current(&mut hw, 2.0);
+    run_ticks(&mut hw, 1000, |hw| diag.update(hw));
+
+    // HOUT2 adds 3 A, which is learned
+    hw.set_digital_output(DigitalOutput::HOUT2, true);
+    run_ticks(&mut hw, TICK_MS, |hw| diag.update(hw));
+    set_current(&mut hw, 5.0);
+    run_ticks(&mut hw, 1000, |hw| diag.update(hw));
+    assert!(!diag.is_open(hout2));
+    hw.set_digital_output(DigitalOutput::HOUT2, false);
+    set_current(&mut hw, 2.0);
+    run_ticks(&mut hw, 1000, |hw| diag.update(hw));
+
+    // Adding only 0.5 A is below 30% of the learned 3 A
+    hw.set_digital_output(DigitalOutput::HOUT2, true);
+    run_ticks(&mut hw, TICK_MS, |hw| diag.update(hw));
+    set_current(&mut hw, 2.5);
+    run_ticks(&mut hw, 300, |hw| diag.update(hw));
+    assert!(diag.is_open(hout2));
+    // HOUT1 isn't blamed, it's not alone in the group
+    assert!(!diag.is_open(Load::Digital(DigitalOutput::HOUT1)));
+}
+
+#[test]
+fn turn_on_check_is_skipped_when_group_changes() {
+    let mut hw = TestHardware::new();
+    let mut diag = LoadDiagnostics::new(CONFIG);
+    let hout2 = Load::Digital(DigitalOutput::HOUT2);
+    hw.set_digital_output(DigitalOutput::HOUT1, true);
+    set_current(&mut hw, 2.0);
+    run_ticks(&mut hw, 1000, |hw| diag.update(hw));
+
+    // HOUT2 turns on while HOUT1 turns off, so the step says nothing
+    hw.set_digital_output(DigitalOutput::HOUT2, true);
+    run_ticks(&mut hw, 100, |hw| diag.update(hw));
+    hw.set_digital_output(DigitalOutput::HOUT1, false);
+    run_ticks(&mut hw, 100, |hw| diag.update(hw));
+    assert!(!diag.is_open(hout2));
+}
+
+#[test]
+fn lcur1_is_scaled_by_duty() {
+    let mut hw = TestHardware::new();
+    let mut diag = LoadDiagnostics::new(CONFIG);
+    hw.set_pwm_output(PwmOutput::LCUR1, 0.5);
+    hw.analog_inputs.insert(AnalogInput::CurrentL, 0.4);
+    run_ticks(&mut hw, 2000, |hw| diag.update(hw));
+    assert!(!diag.is_open(Load::Lcur1));
+    hw.analog_inputs.insert(AnalogInput::CurrentL, 0.1);
+    run_ticks(&mut hw, 2000, |hw| diag.update(hw));
+    assert!(diag.is_open(Load::Lcur1));
+}
+
+#[test]
+fn current_while_off_is_stuck_on() {
+    let mut hw = TestHardware::new();
+    let mut diag = LoadDiagnostics::new(CONFIG);
+    hw.set_digital_output(DigitalOutput::HOUT1, true);
+    set_current(&mut hw, 2.0);
+    run_ticks(&mut hw, 1000, |hw| diag.update(hw));
+
+    // Decaying current right after turn-off is fine
+    hw.set_digital_output(DigitalOutput::HOUT1, false);
+    run_ticks(&mut hw, 100, |hw| diag.update(hw));
+    set_current(&mut hw, 0.0);
+    run_ticks(&mut hw, 2000, |hw| diag.update(hw));
+    assert!(!diag.is_stuck_on(GROUP1));
+
+    set_current(&mut hw, 1.5);
+    run_ticks(&mut hw, 1100, |hw| diag.update(hw));
+    assert!(diag.is_stuck_on(GROUP1));
+    let mut dtcs = DtcStore::<8>::new();
+    diag.update_dtcs(&mut dtcs);
+    assert!(dtcs.is_failed(0x910112));
+    assert!(!dtcs.is_failed(0x900113));
+
+    set_current(&mut hw, 0.0);
+    run_ticks(&mut hw, TICK_MS, |hw| diag.update(hw));
+    assert!(!diag.is_stuck_on(GROUP1));
+}
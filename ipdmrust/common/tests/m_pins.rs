@@ -0,0 +1,79 @@
+// M pin configuration
+
+mod util;
+
+use common::*;
+use util::*;
+
+#[test]
+fn m_pins_map_to_inputs_and_outputs() {
+    for pin in MPin::ALL {
+        assert_eq!(pin.digital_input().m_pin(), Some(pin));
+        assert_eq!(pin.digital_output().m_pin(), Some(pin));
+        assert_eq!(pin.digital_output().group(), None);
+        if let Some(input) = pin.analog_input() {
+            assert_eq!(input.m_pin(), Some(pin));
+        }
+    }
+    assert_eq!(DigitalOutput::HOUT1.m_pin(), None);
+    assert_eq!(DigitalInput::Ignition.m_pin(), None);
+    assert_eq!(AnalogInput::AuxVoltage.m_pin(), None);
+    assert_eq!(MPin::M6.analog_input(), Some(AnalogInput::M6));
+    assert_eq!(MPin::M7.analog_input(), None);
+}
+
+#[test]
+fn modes_come_from_config() {
+    const CONFIG: &[MPinConfig] = &[
+        MPinConfig {
+            pin: MPin::M1,
+            mode: MPinMode::Output,
+        },
+        MPinConfig {
+            pin: MPin::M8,
+            mode: MPinMode::InputPullUp,
+        },
+        // M9 isn't connected to the ADC
+        MPinConfig {
+            pin: MPin::M9,
+            mode: MPinMode::Analog,
+        },
+    ];
+    assert_eq!(MPin::M1.mode(CONFIG), MPinMode::Output);
+    assert_eq!(MPin::M8.mode(CONFIG), MPinMode::InputPullUp);
+    assert_eq!(MPin::M9.mode(CONFIG), MPinMode::InputFloating);
+    // Unconfigured pins work like before
+    assert_eq!(MPin::M2.mode(CONFIG), MPinMode::Analog);
+    assert_eq!(MPin::M13.mode(CONFIG), MPinMode::InputFloating);
+}
+
+#[test]
+fn output_m_pins_read_back_and_print() {
+    let mut hw = TestHardware::new();
+    hw.m_pin_modes[MPin::M3 as usize] = MPinMode::Output;
+    hw.analog_inputs.insert(AnalogInput::M3, 5.0);
+    hw.analog_inputs.insert(AnalogInput::M4, 5.0);
+    hw.digital_inputs.insert(DigitalInput::M3, true);
+    hw.digital_inputs.insert(DigitalInput::M4, true);
+    hw.digital_inputs.insert(DigitalInput::M8, true);
+    hw.set_digital_output(DigitalOutput::M3, true);
+    hw.set_digital_output(DigitalOutput::M4, true);
+    hw.set_digital_output(DigitalOutput::M8, true);
+    assert_eq!(hw.get_m_pin_mode(MPin::M3), MPinMode::Output);
+    assert_eq!(hw.get_m_pin_mode(MPin::M4), MPinMode::Analog);
+    assert_eq!(hw.get_m_pin_mode(MPin::M8), MPinMode::InputFloating);
+
+    // Only the output pin can be set
+    assert!(hw.get_digital_output(DigitalOutput::M3));
+    assert!(!hw.get_digital_output(DigitalOutput::M4));
+    assert!(!hw.get_digital_output(DigitalOutput::M8));
+    // Reading a pin in another mode returns NaN or false
+    assert!(hw.get_analog_input(AnalogInput::M3).is_nan());
+    assert!(!hw.get_digital_input(DigitalInput::M3));
+    assert_eq!(hw.get_analog_input(AnalogInput::M4), 5.0);
+    assert!(!hw.get_digital_input(DigitalInput::M4));
+    assert!(hw.get_digital_input(DigitalInput::M8));
+
+    print_outputs(&mut hw);
+    print_m_pins(&mut hw);
+}
@@ -0,0 +1,208 @@
+// OBD-II responder tests
+
+mod util;
+
+use common::obd::*;
+use common::*;
+use std::sync::{Mutex, MutexGuard};
+use util::*;
+
+define_parameters! {
+    CoolantT {
+        display_name: "CoolantT",
+        unit: "degC",
+    },
+    Soc {
+        display_name: "SoC",
+        unit: "%",
+    },
+    AuxVoltage {
+        display_name: "AuxVoltage",
+        unit: "V",
+    },
+}
+
+const PIDS: &[ObdPid] = &[
+    ObdPid {
+        pid: 0x05,
+        parameter: ParameterId::CoolantT as usize,
+        encoding: ObdEncoding::U8 {
+            scale: 1.0,
+            offset: -40.0,
+        },
+    },
+    ObdPid {
+        pid: 0x2f,
+        parameter: ParameterId::Soc as usize,
+        encoding: ObdEncoding::U8 {
+            scale: 100.0 / 255.0,
+            offset: 0.0,
+        },
+    },
+    ObdPid {
+        pid: 0x42,
+        parameter: ParameterId::AuxVoltage as usize,
+        encoding: ObdEncoding::U16 {
+            scale: 0.001,
+            offset: 0.0,
+        },
+    },
+];
+
+const CONFIG: ObdConfig = ObdConfig {
+    functional_id: standard_id(0x7df),
+    request_id: standard_id(0x7e0),
+    response_id: standard_id(0x7e8),
+    pids: PIDS,
+    vin: b"TESTVIN0123456789",
+    calibration_id: b"CAL1",
+};
+
+// The parameters are global and the tests write them
+static LOCK: Mutex<()> = Mutex::new(());
+
+struct Tester {
+    responder: ObdResponder,
+    hw: TestHardware,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Tester {
+    fn new() -> Self {
+        let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        init_parameters();
+        get_parameter(ParameterId::CoolantT).value = 25.0;
+        get_parameter(ParameterId::Soc).value = 50.0;
+        get_parameter(ParameterId::AuxVoltage).value = 13.8;
+        Self {
+            responder: ObdResponder::new(CONFIG),
+            hw: TestHardware::new(),
+            _lock: lock,
+        }
+    }
+
+    // Sends a single frame request and returns the raw response frames
+    fn request_on(&mut self, id: u16, request: &[u8]) -> Vec<Vec<u8>> {
+        let mut data = vec![request.len() as u8];
+        data.extend_from_slice(request);
+        data.resize(8, 0);
+        let frame = bxcan::Frame::new_data(
+            bxcan::StandardId::new(id).unwrap(),
+            bxcan::Data::new(&data).unwrap(),
+        );
+        self.responder.on_can(&frame, self.hw.millis);
+        self.hw.millis += 10;
+        self.responder.update(&mut self.hw);
+        self.hw
+            .take_sent()
+            .iter()
+            .map(|frame| {
+                assert_eq!(frame.id(), standard_id(0x7e8));
+                frame_data(frame).to_vec()
+            })
+            .collect()
+    }
+
+    // Sends a request and returns the single frame response
+    fn request(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let frames = self.request_on(0x7df, request);
+        assert_eq!(self.request_on(0x7e0, request), frames);
+        assert!(frames.len() <= 1);
+        frames.first().map(|data| {
+            assert!(data[0] <= 7);
+            data[1..1 + data[0] as usize].to_vec()
+        })
+    }
+}
+
+#[test]
+fn supported_pids() {
+    let mut tester = Tester::new();
+    // 0x05 and 0x20 (the next range has PIDs)
+    assert_eq!(
+        tester.request(&[0x01, 0x00]),
+        Some(vec![0x41, 0x00, 0x08, 0x00, 0x00, 0x01])
+    );
+    // 0x2f and 0x40
+    assert_eq!(
+        tester.request(&[0x01, 0x20]),
+        Some(vec![0x41, 0x20, 0x00, 0x02, 0x00, 0x01])
+    );
+    // 0x42, nothing after
+    assert_eq!(
+        tester.request(&[0x01, 0x40]),
+        Some(vec![0x41, 0x40, 0x40, 0x00, 0x00, 0x00])
+    );
+    // Ranges after the last PID aren't supported
+    assert_eq!(tester.request(&[0x01, 0x60]), None);
+}
+
+#[test]
+fn pid_encodings() {
+    let mut tester = Tester::new();
+    // Offset
+    assert_eq!(tester.request(&[0x01, 0x05]), Some(vec![0x41, 0x05, 65]));
+    // Scale, rounded
+    assert_eq!(tester.request(&[0x01, 0x2f]), Some(vec![0x41, 0x2f, 128]));
+    // 16 bits, big endian
+    assert_eq!(
+        tester.request(&[0x01, 0x42]),
+        Some(vec![0x41, 0x42, 0x35, 0xe8])
+    );
+
+    // Values outside the range are clamped
+    get_parameter(ParameterId::CoolantT).value = -50.0;
+    assert_eq!(tester.request(&[0x01, 0x05]), Some(vec![0x41, 0x05, 0]));
+    get_parameter(ParameterId::CoolantT).value = 300.0;
+    assert_eq!(tester.request(&[0x01, 0x05]), Some(vec![0x41, 0x05, 255]));
+    get_parameter(ParameterId::AuxVoltage).value = 70.0;
+    assert_eq!(
+        tester.request(&[0x01, 0x42]),
+        Some(vec![0x41, 0x42, 0xff, 0xff])
+    );
+}
+
+#[test]
+fn multiple_pids_and_missing_values() {
+    let mut tester = Tester::new();
+    assert_eq!(
+        tester.request(&[0x01, 0x05, 0x2f]),
+        Some(vec![0x41, 0x05, 65, 0x2f, 128])
+    );
+    // Unknown PIDs and PIDs without a value are left out
+    get_parameter(ParameterId::Soc).value = f32::NAN;
+    assert_eq!(
+        tester.request(&[0x01, 0x05, 0x2f, 0x0c]),
+        Some(vec![0x41, 0x05, 65])
+    );
+    assert_eq!(tester.request(&[0x01, 0x2f, 0x0c]), None);
+    assert_eq!(tester.request(&[0x01]), None);
+}
+
+#[test]
+fn unsupported_modes_are_ignored() {
+    let mut tester = Tester::new();
+    // Every mode, including those whose positive response would overflow
+    for mode in 0x00..=0xff {
+        if mode == MODE_CURRENT_DATA || mode == MODE_VEHICLE_INFORMATION {
+            continue;
+        }
+        assert_eq!(tester.request(&[mode, 0x00]), None, "{:02x}", mode);
+    }
+}
+
+#[test]
+fn vehicle_information() {
+    let mut tester = Tester::new();
+    assert_eq!(
+        tester.request(&[0x09, 0x00]),
+        Some(vec![0x49, 0x00, 0x50, 0x00, 0x00, 0x00])
+    );
+    assert_eq!(tester.request(&[0x09, 0x03]), None);
+    // The VIN needs a multi-frame response
+    let frames = tester.request_on(0x7e0, &[0x09, 0x02]);
+    assert_eq!(
+        frames,
+        vec![vec![0x10, 20, 0x49, 0x02, 0x01, b'T', b'E', b'S']]
+    );
+}
@@ -0,0 +1,192 @@
+// Output manager tests
+
+mod util;
+
+use common::output_manager::*;
+use common::*;
+use util::*;
+
+const SHED_LOADS: &[ShedLoad] = &[
+    ShedLoad {
+        output: DigitalOutput::LOUT5,
+        priority: 0,
+        shed_below_v: 11.8,
+        restore_above_v: 12.4,
+    },
+    ShedLoad {
+        output: DigitalOutput::HOUT2,
+        priority: 1,
+        shed_below_v: 11.8,
+        restore_above_v: 12.4,
+    },
+    ShedLoad {
+        output: DigitalOutput::LOUT4,
+        priority: 2,
+        shed_below_v: 11.5,
+        restore_above_v: 12.2,
+    },
+];
+
+fn new_manager() -> OutputManager {
+    OutputManager::new(OutputManagerConfig {
+        stagger_ms: 200,
+        shed_loads: SHED_LOADS,
+        shed_delay_ms: 1000,
+        shed_step_ms: 500,
+        status_id: standard_id(0x20b),
+        status_period_ms: 1000,
+    })
+}
+
+fn new_hw() -> TestHardware {
+    let mut hw = TestHardware::new();
+    hw.analog_inputs.insert(AnalogInput::AuxVoltage, 12.8);
+    hw
+}
+
+#[test]
+fn turn_ons_within_a_group_are_staggered() {
+    let mut hw = new_hw();
+    let mut manager = new_manager();
+    for output in [
+        DigitalOutput::HOUT1,
+        DigitalOutput::HOUT2,
+        DigitalOutput::HOUT3,
+    ] {
+        manager.set_digital_output(&mut hw, output, true);
+    }
+    // The first one goes through right away
+    assert!(hw.get_digital_output(DigitalOutput::HOUT1));
+    assert!(!hw.get_digital_output(DigitalOutput::HOUT2));
+    assert!(manager.is_pending(DigitalOutput::HOUT2));
+
+    run_ticks(&mut hw, 180, |hw| manager.update(hw));
+    assert!(!hw.get_digital_output(DigitalOutput::HOUT2));
+    run_ticks(&mut hw, 20, |hw| manager.update(hw));
+    assert!(hw.get_digital_output(DigitalOutput::HOUT2));
+    assert!(!hw.get_digital_output(DigitalOutput::HOUT3));
+    run_ticks(&mut hw, 200, |hw| manager.update(hw));
+    assert!(hw.get_digital_output(DigitalOutput::HOUT3));
+    assert!(!manager.is_pending(DigitalOutput::HOUT3));
+
+    // Setting an output that is already on doesn't wait
+    manager.set_digital_output(&mut hw, DigitalOutput::HOUT1, true);
+    assert!(hw.get_digital_output(DigitalOutput::HOUT1));
+}
+
+#[test]
+fn groups_are_independent() {
+    let mut hw = new_hw();
+    let mut manager = new_manager();
+    manager.set_digital_output(&mut hw, DigitalOutput::HOUT1, true);
+    manager.set_digital_output(&mut hw, DigitalOutput::HOUT4, true);
+    manager.set_digital_output(&mut hw, DigitalOutput::HOUT7, true);
+    manager.set_digital_output(&mut hw, DigitalOutput::HOUT11, true);
+    for output in [
+        DigitalOutput::HOUT1,
+        DigitalOutput::HOUT4,
+        DigitalOutput::HOUT7,
+        DigitalOutput::HOUT11,
+    ] {
+        assert!(hw.get_digital_output(output));
+    }
+}
+
+#[test]
+fn turn_off_is_immediate_and_cancels_pending_turn_on() {
+    let mut hw = new_hw();
+    let mut manager = new_manager();
+    manager.set_digital_output(&mut hw, DigitalOutput::HOUT4, true);
+    manager.set_digital_output(&mut hw, DigitalOutput::HOUT5, true);
+    manager.set_digital_output(&mut hw, DigitalOutput::HOUT6, true);
+    manager.set_digital_output(&mut hw, DigitalOutput::HOUT5, false);
+    assert!(!manager.is_pending(DigitalOutput::HOUT5));
+    manager.set_digital_output(&mut hw, DigitalOutput::HOUT4, false);
+    assert!(!hw.get_digital_output(DigitalOutput::HOUT4));
+
+    // HOUT6 is next in line, HOUT5 never turns on
+    run_ticks(&mut hw, 200, |hw| manager.update(hw));
+    assert!(hw.get_digital_output(DigitalOutput::HOUT6));
+    run_ticks(&mut hw, 1000, |hw| manager.update(hw));
+    assert!(!hw.get_digital_output(DigitalOutput::HOUT5));
+}
+
+#[test]
+fn ungrouped_outputs_pass_through() {
+    let mut hw = new_hw();
+    let mut manager = new_manager();
+    manager.set_digital_output(&mut hw, DigitalOutput::HOUT1, true);
+    manager.set_digital_output(&mut hw, DigitalOutput::HOUT2, true);
+    manager.set_digital_output(&mut hw, DigitalOutput::LOUT1, true);
+    manager.set_digital_output(&mut hw, DigitalOutput::LOUT2, true);
+    assert!(hw.get_digital_output(DigitalOutput::LOUT1));
+    assert!(hw.get_digital_output(DigitalOutput::LOUT2));
+    assert!(!manager.is_pending(DigitalOutput::LOUT2));
+}
+
+fn shed_status(hw: &mut TestHardware) -> Vec<u8> {
+    let frame = hw
+        .take_sent()
+        .into_iter()
+        .rfind(|f| f.id() == standard_id(0x20b))
+        .unwrap();
+    frame_data(&frame).to_vec()
+}
+
+#[test]
+fn loads_are_shed_and_restored_by_priority() {
+    let mut hw = new_hw();
+    let mut manager = new_manager();
+    for output in [
+        DigitalOutput::LOUT4,
+        DigitalOutput::LOUT5,
+        DigitalOutput::HOUT2,
+    ] {
+        manager.set_digital_output(&mut hw, output, true);
+    }
+    run_ticks(&mut hw, 1000, |hw| manager.update(hw));
+    assert!(hw.get_digital_output(DigitalOutput::HOUT2));
+
+    // A short dip is ignored
+    hw.analog_inputs.insert(AnalogInput::AuxVoltage, 11.0);
+    run_ticks(&mut hw, 800, |hw| manager.update(hw));
+    hw.analog_inputs.insert(AnalogInput::AuxVoltage, 12.0);
+    run_ticks(&mut hw, 100, |hw| manager.update(hw));
+    assert!(hw.get_digital_output(DigitalOutput::LOUT5));
+
+    // Below the first threshold, the lowest priority goes first
+    hw.analog_inputs.insert(AnalogInput::AuxVoltage, 11.75);
+    run_ticks(&mut hw, 1020, |hw| manager.update(hw));
+    assert!(manager.is_shed(DigitalOutput::LOUT5));
+    assert!(!hw.get_digital_output(DigitalOutput::LOUT5));
+    assert!(hw.get_digital_output(DigitalOutput::HOUT2));
+    run_ticks(&mut hw, 500, |hw| manager.update(hw));
+    assert!(!hw.get_digital_output(DigitalOutput::HOUT2));
+    run_ticks(&mut hw, 2000, |hw| manager.update(hw));
+    assert!(hw.get_digital_output(DigitalOutput::LOUT4));
+    assert_eq!(
+        shed_status(&mut hw),
+        &[0x04, 0x00, 0x02, 0x00, 0x97, 0x04, 0x00, 0x00]
+    );
+
+    // The app's commands are remembered while shed
+    manager.set_digital_output(&mut hw, DigitalOutput::LOUT5, false);
+    manager.set_digital_output(&mut hw, DigitalOutput::HOUT2, true);
+    assert!(!hw.get_digital_output(DigitalOutput::HOUT2));
+
+    // Within the hysteresis nothing changes
+    hw.analog_inputs.insert(AnalogInput::AuxVoltage, 12.3);
+    run_ticks(&mut hw, 3000, |hw| manager.update(hw));
+    assert!(manager.is_shed(DigitalOutput::HOUT2));
+
+    // Restored highest priority first
+    hw.analog_inputs.insert(AnalogInput::AuxVoltage, 12.6);
+    run_ticks(&mut hw, 1020, |hw| manager.update(hw));
+    assert!(!manager.is_shed(DigitalOutput::HOUT2));
+    assert!(hw.get_digital_output(DigitalOutput::HOUT2));
+    assert!(manager.is_shed(DigitalOutput::LOUT5));
+    run_ticks(&mut hw, 500, |hw| manager.update(hw));
+    assert!(!manager.is_shed(DigitalOutput::LOUT5));
+    assert!(!hw.get_digital_output(DigitalOutput::LOUT5));
+    assert_eq!(shed_status(&mut hw)[0..4], [0, 0, 0, 0]);
+}
@@ -0,0 +1,167 @@
+// Output readback and diagnostics
+
+mod util;
+
+use common::output_layers::{OutputHw, OutputLayers};
+use common::remote_io::{RemoteIo, RemoteIoConfig};
+use common::soft_pwm::{SoftPwm, SoftPwmConfig, SoftPwmOutput};
+use common::*;
+use util::*;
+
+#[test]
+fn every_high_side_output_is_in_one_group() {
+    for output in DigitalOutput::ALL {
+        let groups: Vec<_> = OutputGroup::ALL
+            .into_iter()
+            .filter(|g| g.outputs().contains(&output))
+            .collect();
+        let high_side = !format!("{:?}", output).starts_with("LOUT") && output.m_pin().is_none();
+        assert_eq!(groups.len(), if high_side { 1 } else { 0 }, "{:?}", output);
+        assert_eq!(output.group(), groups.first().copied());
+    }
+}
+
+const REMOTE_IO_CONFIG: RemoteIoConfig = RemoteIoConfig {
+    command_id: standard_id(0x208),
+    status_id: standard_id(0x209),
+    status_period_ms: 500,
+    allowed_digital_outputs: &[],
+    allowed_pwm_outputs: &[],
+    max_lease_ms: 1000,
+};
+
+#[test]
+fn readback_returns_driven_state() {
+    let mut hw = TestHardware::new();
+    hw.set_digital_output(DigitalOutput::HOUT5, true);
+    hw.set_pwm_output(PwmOutput::SPWM2, 0.25);
+    assert!(hw.get_digital_output(DigitalOutput::HOUT5));
+    assert!(!hw.get_digital_output(DigitalOutput::HOUT6));
+    assert_eq!(hw.get_pwm_output(PwmOutput::SPWM2), 0.25);
+    assert_eq!(hw.get_pwm_output(PwmOutput::LPWM2), 0.0);
+
+    // Through the output layers, the readback comes from the hardware
+    let mut remote_io = RemoteIo::new(REMOTE_IO_CONFIG);
+    let layers = OutputLayers {
+        remote_io: Some(&mut remote_io),
+        ..Default::default()
+    };
+    let mut wrapped = OutputHw::new(&mut hw, layers);
+    wrapped.set_digital_output(DigitalOutput::LOUT2, true);
+    assert!(wrapped.get_digital_output(DigitalOutput::LOUT2));
+    assert!(wrapped.get_digital_output(DigitalOutput::HOUT5));
+}
+
+#[test]
+fn output_layers_pass_outputs_and_updates_down() {
+    let mut hw = TestHardware::new();
+    let mut remote_io = RemoteIo::new(REMOTE_IO_CONFIG);
+    let mut soft_pwm = SoftPwm::new(SoftPwmConfig {
+        outputs: &[SoftPwmOutput {
+            output: DigitalOutput::LOUT2,
+            frequency_hz: 1.0,
+            inverted: false,
+        }],
+    });
+    let mut wrapped = OutputHw::new(
+        &mut hw,
+        OutputLayers {
+            remote_io: Some(&mut remote_io),
+            soft_pwm: Some(&mut soft_pwm),
+            ..Default::default()
+        },
+    );
+
+    // Setting the output goes through RemoteIo to SoftPwm
+    wrapped.set_digital_output(DigitalOutput::LOUT2, true);
+    assert_eq!(wrapped.soft_pwm().get_duty(DigitalOutput::LOUT2), Some(1.0));
+    assert!(wrapped.get_digital_output(DigitalOutput::LOUT2));
+    wrapped.set_digital_output(DigitalOutput::LOUT3, true);
+    assert!(wrapped.get_digital_output(DigitalOutput::LOUT3));
+    wrapped.soft_pwm().set_duty(DigitalOutput::LOUT2, 0.5);
+
+    // Updating the layers updates SoftPwm
+    let mut on_ticks = 0;
+    for _ in 0..50 {
+        let mut wrapped = OutputHw::new(
+            &mut hw,
+            OutputLayers {
+                remote_io: Some(&mut remote_io),
+                soft_pwm: Some(&mut soft_pwm),
+                ..Default::default()
+            },
+        );
+        wrapped.update();
+        if wrapped.get_digital_output(DigitalOutput::LOUT2) {
+            on_ticks += 1;
+        }
+        hw.millis += 20;
+    }
+    assert_eq!(on_ticks, 25);
+}
+
+#[test]
+fn remote_io_status_reports_hardware_state() {
+    let mut hw = TestHardware::new();
+    hw.m_pin_modes[MPin::M5 as usize] = MPinMode::Output;
+    hw.m_pin_modes[MPin::M6 as usize] = MPinMode::Output;
+    let mut remote_io = RemoteIo::new(REMOTE_IO_CONFIG);
+    OutputHw::new(
+        &mut hw,
+        OutputLayers {
+            remote_io: Some(&mut remote_io),
+            ..Default::default()
+        },
+    )
+    .set_digital_output(DigitalOutput::HOUT1, true);
+    // Outputs changed below the remote I/O layer are reported as they are
+    hw.set_digital_output(DigitalOutput::HOUT1, false);
+    hw.set_digital_output(DigitalOutput::LOUT6, true);
+    hw.set_digital_output(DigitalOutput::M5, true);
+    // Beyond the 24 bits of the frame
+    hw.set_digital_output(DigitalOutput::M6, true);
+
+    hw.millis = REMOTE_IO_CONFIG.status_period_ms;
+    remote_io.update(&mut hw);
+    let sent = hw.take_sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].id(), REMOTE_IO_CONFIG.status_id);
+    let data = sent[0].data().unwrap();
+    let states = u32::from_le_bytes([data[0], data[1], data[2], 0]);
+    assert_eq!(
+        states,
+        (1 << DigitalOutput::LOUT6 as u32) | (1 << DigitalOutput::M5 as u32)
+    );
+    assert_eq!(&data[3..7], &[0, 0, 0, 0]);
+}
+
+#[test]
+fn diagnostics_report_group_state() {
+    let mut hw = TestHardware::new();
+    hw.set_digital_output(DigitalOutput::HOUT8, true);
+    hw.analog_inputs.insert(AnalogInput::Current3, 4.5);
+    hw.digital_inputs.insert(DigitalInput::Group3OC, true);
+
+    let diag = hw.get_output_diagnostics(DigitalOutput::HOUT8);
+    assert_eq!(
+        diag,
+        OutputDiagnostics {
+            commanded: true,
+            group: Some(OutputGroup::Group3),
+            group_tripped: true,
+            group_current: 4.5,
+        }
+    );
+
+    let diag = hw.get_output_diagnostics(DigitalOutput::HOUT1);
+    assert!(!diag.commanded);
+    assert_eq!(diag.group, Some(OutputGroup::Group1));
+    assert!(!diag.group_tripped);
+    assert_eq!(diag.group_current, 0.0);
+
+    // Low side outputs have no group measurement
+    let diag = hw.get_output_diagnostics(DigitalOutput::LOUT1);
+    assert_eq!(diag.group, None);
+    assert!(!diag.group_tripped);
+    assert!(diag.group_current.is_nan());
+}
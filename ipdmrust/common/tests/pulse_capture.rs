@@ -0,0 +1,124 @@
+// Pulse capture tests
+
+mod util;
+
+use common::pulse_capture::*;
+use common::*;
+use util::*;
+
+const CONFIG: PulseCaptureConfig = PulseCaptureConfig {
+    channels: &[CaptureChannelConfig {
+        input: CaptureInput::M12,
+        filter_ms: 100,
+        timeout_ms: 500,
+    }],
+};
+
+// Advances time while the input runs at the given frequency and duty cycle
+fn run(capture: &mut PulseCapture, hw: &mut TestHardware, ms: u64, frequency: f32, duty: f32) {
+    run_ticks(hw, ms, |hw| {
+        let m = &mut hw.pulse_measurements[CaptureInput::M12 as usize];
+        if frequency > 0.0 {
+            let periods = (frequency * TICK_MS as f32 / 1000.0).max(1.0) as u32;
+            m.count = m.count.wrapping_add(periods);
+            m.period_us = 1_000_000.0 / frequency;
+            m.high_us = m.period_us * duty;
+        }
+        capture.update(hw);
+    });
+}
+
+#[test]
+fn measurement_is_filtered() {
+    let mut hw = TestHardware::new();
+    let mut capture = PulseCapture::new(CONFIG);
+    run(&mut capture, &mut hw, TICK_MS, 0.0, 0.0);
+    run(&mut capture, &mut hw, TICK_MS, 100.0, 0.25);
+    assert_eq!(capture.get_frequency(CaptureInput::M12), 100.0);
+    assert_eq!(capture.get_period(CaptureInput::M12), 10.0);
+    assert_eq!(capture.get_duty(CaptureInput::M12), 0.25);
+    assert!(!capture.is_stopped(CaptureInput::M12));
+
+    // A step moves the value gradually
+    run(&mut capture, &mut hw, TICK_MS, 200.0, 0.75);
+    let frequency = capture.get_frequency(CaptureInput::M12);
+    assert!(frequency > 110.0 && frequency < 140.0, "{}", frequency);
+    run(&mut capture, &mut hw, 1000, 200.0, 0.75);
+    assert!((capture.get_frequency(CaptureInput::M12) - 200.0).abs() < 0.1);
+    assert!((capture.get_duty(CaptureInput::M12) - 0.75).abs() < 0.01);
+
+    // Unconfigured inputs read NaN
+    assert!(capture.get_frequency(CaptureInput::M11).is_nan());
+}
+
+#[test]
+fn stopped_signal_reads_pin_level() {
+    let mut hw = TestHardware::new();
+    let mut capture = PulseCapture::new(CONFIG);
+    run(&mut capture, &mut hw, TICK_MS, 0.0, 0.0);
+    run(&mut capture, &mut hw, 200, 50.0, 0.5);
+    assert_eq!(capture.get_frequency(CaptureInput::M12), 50.0);
+
+    // PWM at 100%
+    hw.digital_inputs.insert(DigitalInput::M12, true);
+    run(&mut capture, &mut hw, 480, 0.0, 0.0);
+    assert_eq!(capture.get_frequency(CaptureInput::M12), 50.0);
+    run(&mut capture, &mut hw, TICK_MS, 0.0, 0.0);
+    assert!(capture.is_stopped(CaptureInput::M12));
+    assert_eq!(capture.get_frequency(CaptureInput::M12), 0.0);
+    assert!(capture.get_period(CaptureInput::M12).is_nan());
+    assert_eq!(capture.get_duty(CaptureInput::M12), 1.0);
+
+    hw.digital_inputs.insert(DigitalInput::M12, false);
+    run(&mut capture, &mut hw, TICK_MS, 0.0, 0.0);
+    assert_eq!(capture.get_duty(CaptureInput::M12), 0.0);
+}
+
+#[test]
+fn first_period_after_stop_is_discarded() {
+    let mut hw = TestHardware::new();
+    let mut capture = PulseCapture::new(CONFIG);
+    run(&mut capture, &mut hw, TICK_MS, 0.0, 0.0);
+
+    // A single period spanning the pause
+    let m = &mut hw.pulse_measurements[CaptureInput::M12 as usize];
+    m.count += 1;
+    m.period_us = 3_000_000.0;
+    m.high_us = 1_000.0;
+    run(&mut capture, &mut hw, TICK_MS, 0.0, 0.0);
+    assert!(capture.is_stopped(CaptureInput::M12));
+    assert_eq!(capture.get_frequency(CaptureInput::M12), 0.0);
+
+    // The next one is valid
+    let m = &mut hw.pulse_measurements[CaptureInput::M12 as usize];
+    m.count += 1;
+    m.period_us = 20_000.0;
+    m.high_us = 5_000.0;
+    run(&mut capture, &mut hw, TICK_MS, 0.0, 0.0);
+    assert!(!capture.is_stopped(CaptureInput::M12));
+    assert_eq!(capture.get_frequency(CaptureInput::M12), 50.0);
+    assert_eq!(capture.get_duty(CaptureInput::M12), 0.25);
+}
+
+define_parameters! {
+    FanSpeed {
+        display_name: "Fan",
+        unit: "rpm",
+        capture_map: CaptureMap {
+            input: CaptureInput::M12,
+            quantity: CaptureQuantity::Frequency,
+            scale: 30.0,
+        },
+    },
+}
+
+#[test]
+fn capture_map_sets_parameter() {
+    init_parameters();
+    let mut hw = TestHardware::new();
+    let mut capture = PulseCapture::new(CONFIG);
+    run(&mut capture, &mut hw, TICK_MS, 0.0, 0.0);
+    run(&mut capture, &mut hw, 2 * TICK_MS, 40.0, 0.5);
+    capture.update_parameters(hw.millis);
+    assert_eq!(get_parameter(ParameterId::FanSpeed).value, 1200.0);
+}
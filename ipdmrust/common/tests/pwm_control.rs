@@ -0,0 +1,113 @@
+// PWM control tests
+
+mod util;
+
+use common::pwm_control::*;
+use common::*;
+use util::*;
+
+const CONFIG: PwmControlConfig = PwmControlConfig {
+    channels: &[
+        PwmChannelConfig {
+            output: PwmOutput::SPWM1,
+            frequency_hz: 1000.0,
+            ramp_up_ms: 0,
+            ramp_down_ms: 0,
+        },
+        PwmChannelConfig {
+            output: PwmOutput::LPWM2,
+            frequency_hz: 100.0,
+            ramp_up_ms: 2000,
+            ramp_down_ms: 500,
+        },
+    ],
+};
+
+fn assert_near(a: f32, b: f32) {
+    assert!((a - b).abs() < 0.001, "{} != {}", a, b);
+}
+
+#[test]
+fn frequencies_are_set_per_timer() {
+    let mut hw = TestHardware::new();
+    let mut pwm = PwmControl::new(CONFIG);
+    hw.set_pwm_frequency(PwmTimer::Tim3, 1000.0);
+    run_ticks(&mut hw, TICK_MS, |hw| pwm.update(hw));
+    assert_eq!(hw.get_pwm_frequency(PwmTimer::Tim3), 100.0);
+    assert_eq!(hw.get_pwm_frequency(PwmTimer::Tim4), 1000.0);
+    assert!(!pwm.has_conflict(PwmTimer::Tim3));
+    assert!(!pwm.has_conflict(PwmTimer::Tim4));
+
+    // Only on the first update
+    hw.set_pwm_frequency(PwmTimer::Tim3, 500.0);
+    run_ticks(&mut hw, TICK_MS, |hw| pwm.update(hw));
+    assert_eq!(hw.get_pwm_frequency(PwmTimer::Tim3), 500.0);
+}
+
+#[test]
+fn conflicting_frequencies_are_reported() {
+    const CONFLICTING: PwmControlConfig = PwmControlConfig {
+        channels: &[
+            PwmChannelConfig {
+                output: PwmOutput::SPWM1,
+                frequency_hz: 1000.0,
+                ramp_up_ms: 0,
+                ramp_down_ms: 0,
+            },
+            PwmChannelConfig {
+                output: PwmOutput::SPWM2,
+                frequency_hz: f32::NAN,
+                ramp_up_ms: 0,
+                ramp_down_ms: 0,
+            },
+            PwmChannelConfig {
+                output: PwmOutput::LCUR1,
+                frequency_hz: 20000.0,
+                ramp_up_ms: 0,
+                ramp_down_ms: 0,
+            },
+        ],
+    };
+    let mut hw = TestHardware::new();
+    let mut pwm = PwmControl::new(CONFLICTING);
+    assert!(pwm.has_conflict(PwmTimer::Tim4));
+    assert!(!pwm.has_conflict(PwmTimer::Tim3));
+    assert_eq!(pwm.timer_frequency(PwmTimer::Tim4), 1000.0);
+    assert!(pwm.timer_frequency(PwmTimer::Tim3).is_nan());
+    run_ticks(&mut hw, TICK_MS, |hw| pwm.update(hw));
+    assert_eq!(hw.get_pwm_frequency(PwmTimer::Tim4), 1000.0);
+}
+
+#[test]
+fn duty_cycle_is_ramped() {
+    let mut hw = TestHardware::new();
+    let mut pwm = PwmControl::new(CONFIG);
+    run_ticks(&mut hw, TICK_MS, |hw| pwm.update(hw));
+
+    pwm.set_pwm_output(&mut hw, PwmOutput::LPWM2, 0.8);
+    assert_eq!(hw.get_pwm_output(PwmOutput::LPWM2), 0.0);
+    assert_eq!(pwm.get_target(PwmOutput::LPWM2), 0.8);
+    run_ticks(&mut hw, 1000, |hw| pwm.update(hw));
+    assert_near(hw.get_pwm_output(PwmOutput::LPWM2), 0.5);
+    run_ticks(&mut hw, 1000, |hw| pwm.update(hw));
+    assert_near(hw.get_pwm_output(PwmOutput::LPWM2), 0.8);
+
+    pwm.set_pwm_output(&mut hw, PwmOutput::LPWM2, 0.2);
+    run_ticks(&mut hw, 100, |hw| pwm.update(hw));
+    assert_near(hw.get_pwm_output(PwmOutput::LPWM2), 0.6);
+    run_ticks(&mut hw, 1000, |hw| pwm.update(hw));
+    assert_near(hw.get_pwm_output(PwmOutput::LPWM2), 0.2);
+}
+
+#[test]
+fn unramped_outputs_change_immediately() {
+    let mut hw = TestHardware::new();
+    let mut pwm = PwmControl::new(CONFIG);
+    pwm.set_pwm_output(&mut hw, PwmOutput::SPWM1, 0.5);
+    assert_eq!(hw.get_pwm_output(PwmOutput::SPWM1), 0.5);
+    pwm.set_pwm_output(&mut hw, PwmOutput::LPWM3, 0.7);
+    assert_eq!(hw.get_pwm_output(PwmOutput::LPWM3), 0.7);
+    run_ticks(&mut hw, 1000, |hw| pwm.update(hw));
+    assert_eq!(hw.get_pwm_output(PwmOutput::SPWM1), 0.5);
+    assert_eq!(hw.get_pwm_output(PwmOutput::LPWM3), 0.7);
+}
@@ -0,0 +1,204 @@
+// openinverter SDO server tests
+
+mod util;
+
+use common::sdo::*;
+use common::*;
+use std::sync::{Mutex, MutexGuard};
+use util::*;
+
+define_parameters! {
+    Voltage {
+        display_name: "Voltage",
+        decimals: 1,
+        unit: "V",
+        default_value: 12.5,
+    },
+    Limit {
+        display_name: "Limit",
+        unit: "A",
+        default_value: 10.0,
+        min_value: 0.0,
+        max_value: 32.0,
+        category: "Charging",
+        writable: true,
+    },
+}
+
+const NODE_ID: u8 = 9;
+
+// The parameters are global and the tests write them
+static LOCK: Mutex<()> = Mutex::new(());
+
+struct Tester {
+    server: SdoServer<512>,
+    hw: TestHardware,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Tester {
+    fn new() -> Self {
+        let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        init_parameters();
+        get_parameter(ParameterId::Limit).value = 10.0;
+        Self {
+            server: SdoServer::new(NODE_ID),
+            hw: TestHardware::new(),
+            _lock: lock,
+        }
+    }
+
+    fn request(&mut self, data: [u8; 8]) -> Option<Vec<u8>> {
+        let frame = bxcan::Frame::new_data(
+            bxcan::StandardId::new(0x600 + NODE_ID as u16).unwrap(),
+            bxcan::Data::new(&data).unwrap(),
+        );
+        assert!(self.server.on_can(&frame, self.hw.millis));
+        self.hw.millis += 10;
+        self.server.update(&mut self.hw);
+        let sent = self.hw.take_sent();
+        assert!(sent.len() <= 1);
+        sent.first().map(|frame| {
+            assert_eq!(frame.id(), standard_id(0x580 + NODE_ID as u16));
+            frame_data(frame).to_vec()
+        })
+    }
+
+    fn upload_param(&mut self, id: u8) -> Option<Vec<u8>> {
+        self.request([0x40, 0x00, 0x21, id, 0, 0, 0, 0])
+    }
+
+    fn download_param(&mut self, id: u8, value: i32) -> Option<Vec<u8>> {
+        let mut data = [0x23, 0x00, 0x21, id, 0, 0, 0, 0];
+        data[4..8].copy_from_slice(&value.to_le_bytes());
+        self.request(data)
+    }
+
+    // Initiates an upload of the parameter JSON and returns its size
+    fn initiate_json_upload(&mut self) -> usize {
+        let response = self.request([0x40, 0x01, 0x50, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(response[..4], [0x41, 0x01, 0x50, 0x00]);
+        u32::from_le_bytes(response[4..8].try_into().unwrap()) as usize
+    }
+
+    // Reads the segments of an initiated upload
+    fn upload_segments(&mut self, size: usize) -> String {
+        let mut json = Vec::new();
+        let mut toggle = 0;
+        loop {
+            let response = self.request([0x60 | toggle, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+            assert_eq!(response[0] & 0x10, toggle);
+            let n = 7 - ((response[0] >> 1) & 0x07) as usize;
+            json.extend_from_slice(&response[1..1 + n]);
+            toggle ^= 0x10;
+            if response[0] & 0x01 != 0 {
+                break;
+            }
+        }
+        assert_eq!(json.len(), size);
+        String::from_utf8(json).unwrap()
+    }
+
+    fn upload_json(&mut self) -> String {
+        let size = self.initiate_json_upload();
+        self.upload_segments(size)
+    }
+}
+
+fn abort_code(response: &[u8]) -> u32 {
+    assert_eq!(response[0], 0x80);
+    u32::from_le_bytes(response[4..8].try_into().unwrap())
+}
+
+#[test]
+fn fixed_point_conversion() {
+    assert_eq!(parameter_to_fixed(1.5), 48);
+    assert_eq!(parameter_to_fixed(-2.0), -64);
+    assert_eq!(parameter_to_fixed(f32::NAN), 0);
+    assert_eq!(fixed_to_parameter(48), 1.5);
+}
+
+#[test]
+fn parameters_are_read_and_written() {
+    let mut tester = Tester::new();
+    assert_eq!(
+        tester.upload_param(0),
+        Some(vec![0x43, 0x00, 0x21, 0, 0x90, 0x01, 0, 0])
+    );
+    assert_eq!(
+        tester.download_param(1, 16 * 32),
+        Some(vec![0x60, 0x00, 0x21, 1, 0, 0, 0, 0])
+    );
+    assert_eq!(get_parameter(ParameterId::Limit).value, 16.0);
+
+    let read_only = tester.download_param(0, 0).unwrap();
+    assert_eq!(abort_code(&read_only), SdoAbort::ReadOnly as u32);
+    let out_of_range = tester.download_param(1, 33 * 32).unwrap();
+    assert_eq!(abort_code(&out_of_range), SdoAbort::ValueRange as u32);
+    let negative = tester.download_param(1, -32).unwrap();
+    assert_eq!(abort_code(&negative), SdoAbort::ValueRange as u32);
+    assert_eq!(get_parameter(ParameterId::Limit).value, 16.0);
+
+    let missing = tester.upload_param(2).unwrap();
+    assert_eq!(abort_code(&missing), SdoAbort::ObjectDoesNotExist as u32);
+}
+
+#[test]
+fn parameter_json_is_uploaded_in_segments() {
+    let mut tester = Tester::new();
+    let json = tester.upload_json();
+    assert_eq!(
+        json,
+        concat!(
+            r#"{"Voltage":{"unit":"V","value":12.5,"isparam":false,"id":0,"i":0},"#,
+            r#""Limit":{"unit":"A","value":10,"minimum":0,"maximum":32,"default":10,"#,
+            r#""category":"Charging","isparam":true,"id":1,"i":1}}"#
+        )
+    );
+
+    // Values are from the start of the upload
+    let size = tester.initiate_json_upload();
+    get_parameter(ParameterId::Limit).value = 20.0;
+    assert_eq!(tester.upload_segments(size), json);
+    assert!(tester.upload_json().contains(r#""value":20,"#));
+
+    // A download ends the upload
+    tester.initiate_json_upload();
+    tester.download_param(1, 16 * 32);
+    let segment = tester.request([0x60, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+    assert_eq!(abort_code(&segment), SdoAbort::InvalidCommand as u32);
+}
+
+#[test]
+fn upload_errors_abort() {
+    let mut tester = Tester::new();
+    tester.initiate_json_upload();
+    // Wrong toggle bit
+    let response = tester.request([0x70, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+    assert_eq!(abort_code(&response), SdoAbort::ToggleBit as u32);
+
+    // Timeout
+    tester.initiate_json_upload();
+    tester.hw.millis += 1000;
+    tester.server.update(&mut tester.hw);
+    let sent = tester.hw.take_sent();
+    assert_eq!(abort_code(frame_data(&sent[0])), SdoAbort::Timeout as u32);
+}
+
+#[test]
+fn json_that_doesnt_fit_is_aborted() {
+    init_parameters();
+    let mut server: SdoServer<64> = SdoServer::new(NODE_ID);
+    let mut hw = TestHardware::new();
+    let frame = bxcan::Frame::new_data(
+        bxcan::StandardId::new(0x600 + NODE_ID as u16).unwrap(),
+        bxcan::Data::new(&[0x40, 0x01, 0x50, 0, 0, 0, 0, 0]).unwrap(),
+    );
+    server.on_can(&frame, 0);
+    server.update(&mut hw);
+    let sent = hw.take_sent();
+    assert_eq!(
+        abort_code(frame_data(&sent[0])),
+        SdoAbort::OutOfMemory as u32
+    );
+}
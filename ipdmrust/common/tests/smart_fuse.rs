@@ -0,0 +1,245 @@
+// Smart fuse tests
+
+mod util;
+
+use common::smart_fuse::*;
+use common::*;
+use util::*;
+
+const FUSES: &[FuseConfig] = &[
+    FuseConfig {
+        channel: FuseChannel::Group(OutputGroup::Group1),
+        rated_current: 8.0,
+        i2t_limit: 50.0,
+        overcurrent_filter_ms: 100,
+        inrush_blanking_ms: 0,
+        retry_delay_ms: 1000,
+        max_retry_delay_ms: 3000,
+        max_trips: 4,
+        trip_memory_ms: 60000,
+    },
+    FuseConfig {
+        channel: FuseChannel::Lcur1,
+        rated_current: 2.0,
+        i2t_limit: 5.0,
+        overcurrent_filter_ms: 0,
+        inrush_blanking_ms: 0,
+        retry_delay_ms: 500,
+        max_retry_delay_ms: 500,
+        max_trips: 2,
+        trip_memory_ms: 60000,
+    },
+];
+
+fn new_fuse() -> SmartFuse {
+    SmartFuse::new(SmartFuseConfig {
+        channels: FUSES,
+        status_id: standard_id(0x20a),
+        status_period_ms: 1000,
+    })
+}
+
+const GROUP1: FuseChannel = FuseChannel::Group(OutputGroup::Group1);
+
+// Runs until the channel leaves the given state. Returns the time it took.
+fn time_in_state(
+    fuse: &mut SmartFuse,
+    hw: &mut TestHardware,
+    channel: FuseChannel,
+    state: fn(FuseState) -> bool,
+) -> u64 {
+    let start = hw.millis;
+    while state(fuse.state(channel).unwrap()) {
+        assert!(hw.millis - start < 100_000);
+        hw.millis += TICK_MS;
+        fuse.update(hw);
+    }
+    hw.millis - start
+}
+
+fn is_ok(state: FuseState) -> bool {
+    state == FuseState::Ok
+}
+
+fn is_tripped(state: FuseState) -> bool {
+    matches!(state, FuseState::Tripped { .. })
+}
+
+#[test]
+fn overcurrent_trips_with_backoff_and_latches() {
+    let mut hw = TestHardware::new();
+    let mut fuse = new_fuse();
+    fuse.set_digital_output(&mut hw, DigitalOutput::HOUT2, true);
+    fuse.set_digital_output(&mut hw, DigitalOutput::HOUT4, true);
+    run_ticks(&mut hw, 200, |hw| fuse.update(hw));
+    assert_eq!(fuse.state(GROUP1), Some(FuseState::Ok));
+
+    // Short glitches are filtered out
+    hw.digital_inputs.insert(DigitalInput::Group1OC, true);
+    run_ticks(&mut hw, 60, |hw| fuse.update(hw));
+    hw.digital_inputs.insert(DigitalInput::Group1OC, false);
+    run_ticks(&mut hw, 200, |hw| fuse.update(hw));
+    assert_eq!(fuse.state(GROUP1), Some(FuseState::Ok));
+
+    hw.digital_inputs.insert(DigitalInput::Group1OC, true);
+    let mut delays = Vec::new();
+    for _ in 0..3 {
+        time_in_state(&mut fuse, &mut hw, GROUP1, is_ok);
+        // The group is off, other groups aren't affected
+        assert!(!hw.get_digital_output(DigitalOutput::HOUT2));
+        assert!(hw.get_digital_output(DigitalOutput::HOUT4));
+        // Commands while tripped are remembered but not applied
+        fuse.set_digital_output(&mut hw, DigitalOutput::HOUT3, true);
+        assert!(!hw.get_digital_output(DigitalOutput::HOUT3));
+        delays.push(time_in_state(&mut fuse, &mut hw, GROUP1, is_tripped));
+        assert!(hw.get_digital_output(DigitalOutput::HOUT2));
+        assert!(hw.get_digital_output(DigitalOutput::HOUT3));
+    }
+    assert_eq!(delays, vec![1000, 2000, 3000]);
+
+    time_in_state(&mut fuse, &mut hw, GROUP1, is_ok);
+    assert_eq!(fuse.state(GROUP1), Some(FuseState::Latched));
+    assert_eq!(fuse.trips(GROUP1), 4);
+    hw.digital_inputs.insert(DigitalInput::Group1OC, false);
+    run_ticks(&mut hw, 100_000, |hw| fuse.update(hw));
+    assert_eq!(fuse.state(GROUP1), Some(FuseState::Latched));
+    assert!(!hw.get_digital_output(DigitalOutput::HOUT2));
+
+    // Reported on CAN
+    let status = hw
+        .take_sent()
+        .into_iter()
+        .rfind(|f| f.id() == standard_id(0x20a))
+        .unwrap();
+    assert_eq!(frame_data(&status), &[0x00, 0x01, 4, 0, 0, 0, 0, 0]);
+
+    fuse.reset(&mut hw);
+    assert_eq!(fuse.state(GROUP1), Some(FuseState::Ok));
+    assert_eq!(fuse.trips(GROUP1), 0);
+    assert!(hw.get_digital_output(DigitalOutput::HOUT2));
+}
+
+#[test]
+fn overcurrent_is_ignored_while_group_is_off() {
+    let mut hw = TestHardware::new();
+    let mut fuse = new_fuse();
+    hw.digital_inputs.insert(DigitalInput::Group1OC, true);
+    run_ticks(&mut hw, 1000, |hw| fuse.update(hw));
+    assert_eq!(fuse.state(GROUP1), Some(FuseState::Ok));
+}
+
+#[test]
+fn i2t_trips_on_sustained_overload() {
+    let mut hw = TestHardware::new();
+    let mut fuse = new_fuse();
+    fuse.set_digital_output(&mut hw, DigitalOutput::HOUT1, true);
+
+    // At the rating nothing happens
+    hw.analog_inputs.insert(AnalogInput::Current1, 8.0);
+    run_ticks(&mut hw, 60_000, |hw| fuse.update(hw));
+    assert_eq!(fuse.state(GROUP1), Some(FuseState::Ok));
+
+    // 10 A accumulates 36 A²s per second -> trips after ~1.4 s
+    hw.analog_inputs.insert(AnalogInput::Current1, 10.0);
+    let t = time_in_state(&mut fuse, &mut hw, GROUP1, is_ok);
+    assert!((1300..=1500).contains(&t), "{}", t);
+    assert!(!hw.get_digital_output(DigitalOutput::HOUT1));
+
+    // The accumulator drains when the current is lower, so short overloads
+    // are fine
+    hw.analog_inputs.insert(AnalogInput::Current1, 0.0);
+    time_in_state(&mut fuse, &mut hw, GROUP1, is_tripped);
+    for _ in 0..10 {
+        hw.analog_inputs.insert(AnalogInput::Current1, 10.0);
+        run_ticks(&mut hw, 1000, |hw| fuse.update(hw));
+        hw.analog_inputs.insert(AnalogInput::Current1, 4.0);
+        run_ticks(&mut hw, 2000, |hw| fuse.update(hw));
+    }
+    assert_eq!(fuse.state(GROUP1), Some(FuseState::Ok));
+    assert_eq!(fuse.trips(GROUP1), 1);
+}
+
+#[test]
+fn lcur1_uses_current_l() {
+    let mut hw = TestHardware::new();
+    let mut fuse = new_fuse();
+    let lcur1 = FuseChannel::Lcur1;
+    fuse.set_pwm_output(&mut hw, PwmOutput::LCUR1, 0.5);
+    hw.analog_inputs.insert(AnalogInput::CurrentL, 3.0);
+    time_in_state(&mut fuse, &mut hw, lcur1, is_ok);
+    assert_eq!(hw.get_pwm_output(PwmOutput::LCUR1), 0.0);
+    time_in_state(&mut fuse, &mut hw, lcur1, is_tripped);
+    assert_eq!(hw.get_pwm_output(PwmOutput::LCUR1), 0.5);
+    time_in_state(&mut fuse, &mut hw, lcur1, is_ok);
+    assert_eq!(fuse.state(lcur1), Some(FuseState::Latched));
+    // Unprotected PWM outputs pass through
+    fuse.set_pwm_output(&mut hw, PwmOutput::SPWM1, 0.3);
+    assert_eq!(hw.get_pwm_output(PwmOutput::SPWM1), 0.3);
+}
+
+#[test]
+fn ignition_cycle_clears_latched_faults() {
+    let mut hw = TestHardware::new();
+    let mut fuse = new_fuse();
+    hw.digital_inputs.insert(DigitalInput::Ignition, true);
+    fuse.set_digital_output(&mut hw, DigitalOutput::HOUT1, true);
+    hw.digital_inputs.insert(DigitalInput::Group1OC, true);
+    while fuse.state(GROUP1) != Some(FuseState::Latched) {
+        run_ticks(&mut hw, TICK_MS, |hw| fuse.update(hw));
+    }
+    hw.digital_inputs.insert(DigitalInput::Group1OC, false);
+
+    hw.digital_inputs.insert(DigitalInput::Ignition, false);
+    run_ticks(&mut hw, 1000, |hw| fuse.update(hw));
+    assert_eq!(fuse.state(GROUP1), Some(FuseState::Latched));
+    hw.digital_inputs.insert(DigitalInput::Ignition, true);
+    run_ticks(&mut hw, TICK_MS, |hw| fuse.update(hw));
+    assert_eq!(fuse.state(GROUP1), Some(FuseState::Ok));
+    assert!(hw.get_digital_output(DigitalOutput::HOUT1));
+}
+
+#[test]
+fn trip_count_is_forgotten() {
+    let mut hw = TestHardware::new();
+    let mut fuse = new_fuse();
+    fuse.set_digital_output(&mut hw, DigitalOutput::HOUT1, true);
+    hw.digital_inputs.insert(DigitalInput::Group1OC, true);
+    time_in_state(&mut fuse, &mut hw, GROUP1, is_ok);
+    hw.digital_inputs.insert(DigitalInput::Group1OC, false);
+    time_in_state(&mut fuse, &mut hw, GROUP1, is_tripped);
+    assert_eq!(fuse.trips(GROUP1), 1);
+    run_ticks(&mut hw, 60_000, |hw| fuse.update(hw));
+    assert_eq!(fuse.trips(GROUP1), 0);
+}
+
+const BLANKED_FUSES: &[FuseConfig] = &[FuseConfig {
+    inrush_blanking_ms: 500,
+    ..FUSES[0]
+}];
+
+#[test]
+fn inrush_is_blanked_after_turn_on() {
+    let mut hw = TestHardware::new();
+    let mut fuse = SmartFuse::new(SmartFuseConfig {
+        channels: BLANKED_FUSES,
+        status_id: standard_id(0x20a),
+        status_period_ms: 1000,
+    });
+    // An inrush that would trip within ~0.3 s without blanking
+    hw.analog_inputs.insert(AnalogInput::Current1, 20.0);
+    fuse.set_digital_output(&mut hw, DigitalOutput::HOUT1, true);
+    run_ticks(&mut hw, 400, |hw| fuse.update(hw));
+    hw.analog_inputs.insert(AnalogInput::Current1, 5.0);
+    run_ticks(&mut hw, 1000, |hw| fuse.update(hw));
+    assert_eq!(fuse.state(GROUP1), Some(FuseState::Ok));
+
+    // Another output of the group turning on starts a new window
+    hw.analog_inputs.insert(AnalogInput::Current1, 20.0);
+    fuse.set_digital_output(&mut hw, DigitalOutput::HOUT2, true);
+    run_ticks(&mut hw, 400, |hw| fuse.update(hw));
+    assert_eq!(fuse.state(GROUP1), Some(FuseState::Ok));
+
+    // After the window it counts
+    let t = time_in_state(&mut fuse, &mut hw, GROUP1, is_ok);
+    assert!(t <= 400, "{}", t);
+}
@@ -0,0 +1,117 @@
+// Software PWM tests
+
+mod util;
+
+use common::soft_pwm::*;
+use common::*;
+use util::*;
+
+const CONFIG: SoftPwmConfig = SoftPwmConfig {
+    outputs: &[
+        SoftPwmOutput {
+            output: DigitalOutput::LOUT2,
+            frequency_hz: 1.0,
+            inverted: false,
+        },
+        SoftPwmOutput {
+            output: DigitalOutput::LOUT3,
+            frequency_hz: 1.0,
+            inverted: true,
+        },
+        SoftPwmOutput {
+            output: DigitalOutput::HOUT5,
+            frequency_hz: 100.0,
+            inverted: false,
+        },
+        SoftPwmOutput {
+            output: DigitalOutput::LOUT4,
+            frequency_hz: 1.0 / 120.0,
+            inverted: false,
+        },
+    ],
+};
+
+// Returns the number of ticks each output was on during ms
+fn run(pwm: &mut SoftPwm, hw: &mut TestHardware, ms: u64, outputs: &[DigitalOutput]) -> Vec<u64> {
+    let mut on_ticks = vec![0; outputs.len()];
+    run_ticks(hw, ms, |hw| {
+        pwm.update(hw);
+        for (i, output) in outputs.iter().enumerate() {
+            if hw.get_digital_output(*output) {
+                on_ticks[i] += 1;
+            }
+        }
+    });
+    on_ticks
+}
+
+#[test]
+fn duty_cycle_is_time_proportioned() {
+    let mut hw = TestHardware::new();
+    let mut pwm = SoftPwm::new(CONFIG);
+    let outputs = [DigitalOutput::LOUT2, DigitalOutput::LOUT3];
+    pwm.set_duty(DigitalOutput::LOUT2, 0.3);
+    pwm.set_duty(DigitalOutput::LOUT3, 0.7);
+    // Each second, 15 of the 50 ticks are on
+    assert_eq!(run(&mut pwm, &mut hw, 10_000, &outputs), vec![150, 350]);
+
+    // The inverted output is the complement of the normal one
+    for _ in 0..100 {
+        run(&mut pwm, &mut hw, TICK_MS, &outputs);
+        assert_ne!(
+            hw.get_digital_output(DigitalOutput::LOUT2),
+            hw.get_digital_output(DigitalOutput::LOUT3)
+        );
+    }
+
+    pwm.set_duty(DigitalOutput::LOUT2, 1.0);
+    pwm.set_duty(DigitalOutput::LOUT3, 0.0);
+    assert_eq!(run(&mut pwm, &mut hw, 1000, &outputs), vec![50, 0]);
+    pwm.set_duty(DigitalOutput::LOUT2, -1.0);
+    pwm.set_duty(DigitalOutput::LOUT3, f32::NAN);
+    assert_eq!(run(&mut pwm, &mut hw, 1000, &outputs), vec![0, 0]);
+}
+
+#[test]
+fn frequency_is_limited() {
+    let mut hw = TestHardware::new();
+    let mut pwm = SoftPwm::new(CONFIG);
+    // 100 Hz is limited to 10 Hz: on for 2 ticks of every 5
+    pwm.set_duty(DigitalOutput::HOUT5, 0.4);
+    let mut states = Vec::new();
+    for _ in 0..10 {
+        run(&mut pwm, &mut hw, TICK_MS, &[]);
+        states.push(hw.get_digital_output(DigitalOutput::HOUT5));
+    }
+    assert_eq!(
+        states,
+        vec![true, false, false, false, true, true, false, false, false, true]
+    );
+}
+
+#[test]
+fn slow_periods_are_exact() {
+    let mut hw = TestHardware::new();
+    let mut pwm = SoftPwm::new(CONFIG);
+    let outputs = [DigitalOutput::LOUT4];
+    // 60 s on, 60 s off
+    pwm.set_duty(DigitalOutput::LOUT4, 0.5);
+    assert_eq!(
+        run(&mut pwm, &mut hw, 60_000 - TICK_MS, &outputs),
+        vec![2999]
+    );
+    assert_eq!(run(&mut pwm, &mut hw, 60_000, &outputs), vec![0]);
+    assert_eq!(run(&mut pwm, &mut hw, 120_000, &outputs), vec![3000]);
+}
+
+#[test]
+fn other_outputs_are_not_touched() {
+    let mut hw = TestHardware::new();
+    let mut pwm = SoftPwm::new(CONFIG);
+    assert!(!pwm.is_soft_pwm(DigitalOutput::LOUT1));
+    hw.set_digital_output(DigitalOutput::LOUT1, true);
+    pwm.set_duty(DigitalOutput::LOUT1, 0.0);
+    run(&mut pwm, &mut hw, 1000, &[]);
+    assert!(hw.get_digital_output(DigitalOutput::LOUT1));
+    assert_eq!(pwm.get_duty(DigitalOutput::LOUT1), None);
+}
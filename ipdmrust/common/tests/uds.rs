@@ -0,0 +1,178 @@
+// UDS server tests
+
+mod util;
+
+use common::dtc::DtcStore;
+use common::uds::*;
+use common::*;
+use util::*;
+
+const CONFIG: UdsConfig = UdsConfig {
+    functional_id: Some(standard_id(0x7df)),
+    static_dids: &[(0xf190, b"TESTVIN")],
+    ..UdsConfig::new(standard_id(0x7e0), standard_id(0x7e8))
+};
+
+define_parameters! {
+    Voltage {
+        display_name: "Voltage",
+        unit: "V",
+        default_value: 12.5,
+    },
+    Limit {
+        display_name: "Limit",
+        unit: "A",
+        default_value: 10.0,
+        writable: true,
+    },
+}
+
+struct Tester {
+    server: UdsServer<64>,
+    hw: TestHardware,
+    dtcs: DtcStore<4>,
+}
+
+impl Tester {
+    fn new() -> Self {
+        init_parameters();
+        Self {
+            server: UdsServer::new(CONFIG),
+            hw: TestHardware::new(),
+            dtcs: DtcStore::new(),
+        }
+    }
+
+    // Sends a single frame request and returns the single frame response
+    fn request_on(&mut self, id: u16, request: &[u8]) -> Option<Vec<u8>> {
+        let mut data = vec![request.len() as u8];
+        data.extend_from_slice(request);
+        data.resize(8, 0);
+        let frame = bxcan::Frame::new_data(
+            bxcan::StandardId::new(id).unwrap(),
+            bxcan::Data::new(&data).unwrap(),
+        );
+        self.server.on_can(&frame, self.hw.millis);
+        self.hw.millis += 10;
+        self.server.update(&mut self.hw, &mut self.dtcs);
+        let sent = self.hw.take_sent();
+        assert!(sent.len() <= 1);
+        sent.first().map(|frame| {
+            assert_eq!(frame.id(), standard_id(0x7e8));
+            let data = frame_data(frame);
+            data[1..1 + data[0] as usize].to_vec()
+        })
+    }
+
+    fn request(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        self.request_on(0x7e0, request)
+    }
+}
+
+#[test]
+fn unsupported_services_are_rejected() {
+    let mut tester = Tester::new();
+    // Every SID, including those whose positive response SID would overflow
+    for sid in 0x40..=0xff {
+        assert_eq!(
+            tester.request(&[sid]),
+            Some(vec![0x7f, sid, 0x11]),
+            "{:02x}",
+            sid
+        );
+        // Not answered when functionally addressed
+        assert_eq!(tester.request_on(0x7df, &[sid]), None, "{:02x}", sid);
+    }
+}
+
+#[test]
+fn session_control_and_tester_present() {
+    let mut tester = Tester::new();
+    assert_eq!(tester.server.session(), Session::Default);
+    assert_eq!(
+        tester.request(&[0x10, 0x03]),
+        Some(vec![0x50, 0x03, 0x00, 50, 0x01, 0xf4])
+    );
+    assert_eq!(tester.server.session(), Session::Extended);
+    assert_eq!(
+        tester.request(&[0x22, 0xf1, 0x86]),
+        Some(vec![0x62, 0xf1, 0x86, 0x03])
+    );
+    assert_eq!(tester.request(&[0x3e, 0x00]), Some(vec![0x7e, 0x00]));
+    // Suppressed positive response
+    assert_eq!(tester.request(&[0x3e, 0x80]), None);
+    assert_eq!(tester.request(&[0x10, 0x02]), Some(vec![0x7f, 0x10, 0x12]));
+    assert_eq!(tester.request(&[0x10]), Some(vec![0x7f, 0x10, 0x13]));
+
+    // The session times out without requests
+    tester.hw.millis += 5000;
+    tester.server.update(&mut tester.hw, &mut tester.dtcs);
+    assert_eq!(tester.server.session(), Session::Default);
+}
+
+#[test]
+fn read_data_by_identifier() {
+    let mut tester = Tester::new();
+    let mut expected = vec![0x62, 0x40, 0x00];
+    expected.extend_from_slice(&12.5f32.to_be_bytes());
+    assert_eq!(tester.request(&[0x22, 0x40, 0x00]), Some(expected));
+    // Functional requests are answered too
+    assert_eq!(
+        tester.request_on(0x7df, &[0x22, 0x40, 0x00]),
+        tester.request(&[0x22, 0x40, 0x00])
+    );
+    assert_eq!(
+        tester.request(&[0x22, 0x40, 0x02]),
+        Some(vec![0x7f, 0x22, 0x31])
+    );
+    assert_eq!(tester.request(&[0x22, 0x40]), Some(vec![0x7f, 0x22, 0x13]));
+}
+
+#[test]
+fn write_needs_extended_session_and_writable_parameter() {
+    let mut tester = Tester::new();
+    let mut request = vec![0x2e, 0x40, 0x01];
+    request.extend_from_slice(&16.0f32.to_be_bytes());
+    assert_eq!(tester.request(&request), Some(vec![0x7f, 0x2e, 0x7f]));
+
+    tester.request(&[0x10, 0x03]);
+    assert_eq!(tester.request(&request), Some(vec![0x6e, 0x40, 0x01]));
+    assert_eq!(get_parameter(ParameterId::Limit).value, 16.0);
+
+    // Read-only
+    request[2] = 0x00;
+    assert_eq!(tester.request(&request), Some(vec![0x7f, 0x2e, 0x31]));
+    // Not finite
+    let mut request = vec![0x2e, 0x40, 0x01];
+    request.extend_from_slice(&f32::NAN.to_be_bytes());
+    assert_eq!(tester.request(&request), Some(vec![0x7f, 0x2e, 0x31]));
+    assert_eq!(get_parameter(ParameterId::Limit).value, 16.0);
+}
+
+#[test]
+fn read_and_clear_dtcs() {
+    let mut tester = Tester::new();
+    tester.dtcs.set(0xc11100, true);
+    tester.dtcs.set(0xc16400, true);
+    tester.dtcs.set(0xc16400, false);
+
+    // reportNumberOfDTCByStatusMask, test failed
+    assert_eq!(
+        tester.request(&[0x19, 0x01, 0x01]),
+        Some(vec![0x59, 0x01, 0x0b, 0x01, 0x00, 0x01])
+    );
+    // reportDTCByStatusMask, test failed
+    assert_eq!(
+        tester.request(&[0x19, 0x02, 0x01]),
+        Some(vec![0x59, 0x02, 0x0b, 0xc1, 0x11, 0x00, 0x0b])
+    );
+
+    assert_eq!(
+        tester.request(&[0x14, 0x12, 0x34, 0x56]),
+        Some(vec![0x7f, 0x14, 0x31])
+    );
+    assert_eq!(tester.request(&[0x14, 0xc1, 0x11, 0x00]), Some(vec![0x54]));
+    assert!(!tester.dtcs.is_failed(0xc11100));
+    assert_eq!(tester.request(&[0x14, 0xff, 0xff, 0xff]), Some(vec![0x54]));
+    assert_eq!(tester.dtcs.iter().count(), 0);
+}
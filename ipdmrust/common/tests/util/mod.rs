@@ -0,0 +1,133 @@
+// Shared helpers for desktop tests
+// Not every test uses every helper
+#![allow(dead_code)]
+
+use common::*;
+use std::collections::HashMap;
+
+// Logic tick length used when advancing time in tests
+pub const TICK_MS: u64 = 20;
+
+// Collects sent CAN frames, remembers output states and provides a manually
+// advanced clock. Inputs that haven't been set read as zero/false. M pins
+// follow m_pin_modes like on the real hardware.
+pub struct TestHardware {
+    pub millis: u64,
+    pub sent: Vec<bxcan::Frame>,
+    pub analog_inputs: HashMap<AnalogInput, f32>,
+    pub digital_inputs: HashMap<DigitalInput, bool>,
+    pub digital_outputs: [bool; DigitalOutput::ALL.len()],
+    pub pwm_outputs: [f32; PwmOutput::ALL.len()],
+    pub pwm_frequencies: [f32; PwmTimer::ALL.len()],
+    pub m_pin_modes: [MPinMode; MPin::ALL.len()],
+    pub pulse_measurements: [PulseMeasurement; CaptureInput::ALL.len()],
+}
+
+impl TestHardware {
+    pub fn new() -> Self {
+        Self {
+            millis: 0,
+            sent: Vec::new(),
+            analog_inputs: HashMap::new(),
+            digital_inputs: HashMap::new(),
+            digital_outputs: [false; DigitalOutput::ALL.len()],
+            pwm_outputs: [0.0; PwmOutput::ALL.len()],
+            pwm_frequencies: [1000.0; PwmTimer::ALL.len()],
+            m_pin_modes: MPin::ALL.map(|pin| pin.default_mode()),
+            pulse_measurements: [PulseMeasurement::NONE; CaptureInput::ALL.len()],
+        }
+    }
+
+    pub fn take_sent(&mut self) -> Vec<bxcan::Frame> {
+        std::mem::take(&mut self.sent)
+    }
+}
+
+// Advances the clock by ms in TICK_MS steps, calling tick after each step
+pub fn run_ticks(hw: &mut TestHardware, ms: u64, mut tick: impl FnMut(&mut TestHardware)) {
+    let end = hw.millis + ms;
+    while hw.millis < end {
+        hw.millis += TICK_MS;
+        tick(hw);
+    }
+}
+
+impl CanPort for TestHardware {
+    fn millis(&mut self) -> u64 {
+        self.millis
+    }
+
+    fn send_can(&mut self, frame: bxcan::Frame) {
+        self.sent.push(frame);
+    }
+}
+
+impl HardwareInterface for TestHardware {
+    fn reboot(&mut self) {}
+
+    fn activate_dfu(&mut self) {}
+
+    fn get_analog_input(&mut self, input: AnalogInput) -> f32 {
+        if input
+            .m_pin()
+            .is_some_and(|pin| self.m_pin_modes[pin as usize] != MPinMode::Analog)
+        {
+            return f32::NAN;
+        }
+        self.analog_inputs.get(&input).copied().unwrap_or(0.0)
+    }
+
+    fn get_digital_input(&mut self, input: DigitalInput) -> bool {
+        if input.m_pin().is_some_and(|pin| {
+            matches!(
+                self.m_pin_modes[pin as usize],
+                MPinMode::Analog | MPinMode::Output
+            )
+        }) {
+            return false;
+        }
+        self.digital_inputs.get(&input).copied().unwrap_or(false)
+    }
+
+    fn set_digital_output(&mut self, output: DigitalOutput, value: bool) {
+        if output
+            .m_pin()
+            .is_some_and(|pin| self.m_pin_modes[pin as usize] != MPinMode::Output)
+        {
+            return;
+        }
+        self.digital_outputs[output as usize] = value;
+    }
+
+    fn get_digital_output(&mut self, output: DigitalOutput) -> bool {
+        self.digital_outputs[output as usize]
+    }
+
+    fn set_pwm_output(&mut self, output: PwmOutput, value: f32) {
+        self.pwm_outputs[output as usize] = value;
+    }
+
+    fn get_pwm_output(&mut self, output: PwmOutput) -> f32 {
+        self.pwm_outputs[output as usize]
+    }
+
+    fn set_pwm_frequency(&mut self, timer: PwmTimer, frequency_hz: f32) {
+        self.pwm_frequencies[timer as usize] = frequency_hz;
+    }
+
+    fn get_pwm_frequency(&mut self, timer: PwmTimer) -> f32 {
+        self.pwm_frequencies[timer as usize]
+    }
+
+    fn get_m_pin_mode(&mut self, pin: MPin) -> MPinMode {
+        self.m_pin_modes[pin as usize]
+    }
+
+    fn get_pulse_measurement(&mut self, input: CaptureInput) -> PulseMeasurement {
+        self.pulse_measurements[input as usize]
+    }
+}
+
+pub fn frame_data(frame: &bxcan::Frame) -> &[u8] {
+    frame.data().map_or(&[], |d| &d[..])
+}
@@ -40,6 +40,9 @@ struct HardwareImplementation {
     ms_counter: u64,
     can_sim: CanSimulator,
     digital_output_states: HashMap<DigitalOutput, bool>,
+    pwm_output_states: HashMap<PwmOutput, f32>,
+    pwm_frequencies: HashMap<PwmTimer, f32>,
+    m_pin_modes: HashMap<MPin, MPinMode>,
 }
 
 impl HardwareImplementation {
@@ -48,17 +51,29 @@ impl HardwareImplementation {
             ms_counter: 0,
             can_sim: CanSimulator::new(),
             digital_output_states: HashMap::new(),
+            pwm_output_states: HashMap::new(),
+            pwm_frequencies: HashMap::new(),
+            m_pin_modes: MPin::ALL
+                .into_iter()
+                .map(|pin| (pin, pin.mode(app::M_PIN_CONFIG)))
+                .collect(),
         }
     }
 }
 
 impl HardwareImplementation {}
 
-impl HardwareInterface for HardwareImplementation {
+impl CanPort for HardwareImplementation {
     fn millis(&mut self) -> u64 {
         self.ms_counter
     }
 
+    fn send_can(&mut self, frame: bxcan::Frame) {
+        info!("send_can(): {:?}", frame);
+    }
+}
+
+impl HardwareInterface for HardwareImplementation {
     fn reboot(&mut self) {
         warn!("reboot() does nothing in desktop mode");
     }
@@ -67,11 +82,13 @@ impl HardwareInterface for HardwareImplementation {
         warn!("activate_dfu() does nothing in desktop mode");
     }
 
-    fn send_can(&mut self, frame: bxcan::Frame) {
-        info!("send_can(): {:?}", frame);
-    }
-
     fn get_analog_input(&mut self, input: AnalogInput) -> f32 {
+        if input
+            .m_pin()
+            .is_some_and(|pin| self.get_m_pin_mode(pin) != MPinMode::Analog)
+        {
+            return f32::NAN;
+        }
         // TODO: ???
         14.0
     }
@@ -81,6 +98,13 @@ impl HardwareInterface for HardwareImplementation {
     }
 
     fn set_digital_output(&mut self, output: DigitalOutput, value: bool) {
+        if output
+            .m_pin()
+            .is_some_and(|pin| self.get_m_pin_mode(pin) != MPinMode::Output)
+        {
+            warn!("set_digital_output(): {:?} is not an output", output);
+            return;
+        }
         if let Some(old_value) = self.digital_output_states.get(&output) {
             if value != *old_value {
                 info!("set_digital_output(): {:?}: {:?}", output, value);
@@ -91,7 +115,36 @@ impl HardwareInterface for HardwareImplementation {
         self.digital_output_states.insert(output, value);
     }
 
-    fn set_pwm_output(&mut self, output: PwmOutput, value: f32) {}
+    fn get_digital_output(&mut self, output: DigitalOutput) -> bool {
+        self.digital_output_states
+            .get(&output)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    fn set_pwm_output(&mut self, output: PwmOutput, value: f32) {
+        self.pwm_output_states.insert(output, value);
+    }
+
+    fn get_pwm_output(&mut self, output: PwmOutput) -> f32 {
+        self.pwm_output_states.get(&output).copied().unwrap_or(0.0)
+    }
+
+    fn set_pwm_frequency(&mut self, timer: PwmTimer, frequency_hz: f32) {
+        self.pwm_frequencies.insert(timer, frequency_hz);
+    }
+
+    fn get_pwm_frequency(&mut self, timer: PwmTimer) -> f32 {
+        self.pwm_frequencies.get(&timer).copied().unwrap_or(1000.0)
+    }
+
+    fn get_m_pin_mode(&mut self, pin: MPin) -> MPinMode {
+        self.m_pin_modes[&pin]
+    }
+
+    fn get_pulse_measurement(&mut self, _input: CaptureInput) -> PulseMeasurement {
+        PulseMeasurement::NONE
+    }
 }
 
 fn main() {
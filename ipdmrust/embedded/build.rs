@@ -19,10 +19,17 @@ use std::path::PathBuf;
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
+    // With the bootloader feature, the application is linked after the
+    // bootloader.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let memory_x: &[u8] = if env::var_os("CARGO_FEATURE_BOOTLOADER").is_some() {
+        include_bytes!("memory_bootloader.x")
+    } else {
+        include_bytes!("memory.x")
+    };
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory_x)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
@@ -31,6 +38,7 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory_bootloader.x");
 
     // Specify linker arguments.
 
@@ -0,0 +1,13 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* Built with the bootloader feature: The bootloader and the image record
+     take the first 64K (see common::can_update) */
+  FLASH : ORIGIN = 0x08010000, LENGTH = 448K
+  RAM : ORIGIN = 0x20000000, LENGTH = 112K
+}
+
+/* This is where the call stack will be allocated. */
+/* The stack is of the full descending type. */
+/* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
+_stack_start = ORIGIN(RAM) + LENGTH(RAM);
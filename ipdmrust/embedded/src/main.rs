@@ -6,6 +6,8 @@
 
 // Internal crates
 use command_accumulator::CommandAccumulator;
+use common::can_console::{CanConsole, CanConsoleConfig};
+use common::can_update::{self, UpdateEvent, UpdateMode, UpdateTarget, UpdateTargetConfig};
 use common::*;
 
 // Platform-specific dependencies
@@ -43,6 +45,34 @@ const CONSOLE_RX_BUF_SIZE: usize = 100;
 const MAINBOARD_RX_BUF_SIZE: usize = 200;
 const MAINBOARD_TX_BUF_SIZE: usize = 200;
 const CAN_ENABLE_LOOPBACK_MODE: bool = false;
+const CAN_CONSOLE_OUTPUT_BUF_SIZE: usize = 2048;
+
+// The console can also be used over CAN, using ISO-TP. The desktop cantools
+// crate has a client for it.
+const CAN_CONSOLE_CONFIG: CanConsoleConfig = CanConsoleConfig {
+    rx_id: standard_id(0x6f0),
+    tx_id: standard_id(0x6f8),
+    session_timeout_ms: 5000,
+};
+
+// Firmware update over CAN. Only builds with the bootloader feature can be
+// updated.
+const FIRMWARE_VERSION: u32 = can_update::parse_version(env!("CARGO_PKG_VERSION"));
+const UPDATE_TARGET_CONFIG: UpdateTargetConfig = UpdateTargetConfig {
+    mode: UpdateMode::Application,
+    version: FIRMWARE_VERSION,
+    max_size: if cfg!(feature = "bootloader") {
+        can_update::APP_MAX_SIZE
+    } else {
+        0
+    },
+    fallback_timeout_ms: 0,
+    image_valid: true,
+};
+
+// Lets the host tool find the version in the image
+#[used]
+static FIRMWARE_INFO: [u8; 12] = can_update::firmware_info(FIRMWARE_VERSION);
 
 // Log buffering system
 
@@ -50,6 +80,7 @@ struct MultiLogger {
     uart_buffer: Mutex<RefCell<Option<ArrayString<LOG_BUFFER_SIZE>>>>,
     usb_buffer: Mutex<RefCell<Option<ArrayString<LOG_BUFFER_SIZE>>>>,
     display_buffer: Mutex<RefCell<Option<ArrayString<LOG_BUFFER_SIZE>>>>,
+    can_buffer: Mutex<RefCell<Option<ArrayString<LOG_BUFFER_SIZE>>>>,
 }
 
 impl MultiLogger {
@@ -80,6 +111,15 @@ impl MultiLogger {
         });
         buf2
     }
+    fn get_can_buffer(&self) -> Option<ArrayString<LOG_BUFFER_SIZE>> {
+        let mut buf2: Option<ArrayString<LOG_BUFFER_SIZE>> = Some(ArrayString::new());
+        critical_section::with(|cs| {
+            // This replaces the logger buffer with an empty one, and we get the
+            // possibly filled in one
+            buf2 = self.can_buffer.borrow(cs).replace(buf2);
+        });
+        buf2
+    }
 }
 
 impl Log for MultiLogger {
@@ -124,6 +164,18 @@ impl Log for MultiLogger {
                         let _ = buffer.try_push_str(warning);
                     }
                 }
+                if let Some(ref mut buffer) = self.can_buffer.borrow(cs).borrow_mut().deref_mut() {
+                    let _ = buffer.write_fmt(format_args!(
+                        "[{}] {}\r\n",
+                        record.level(),
+                        record.args()
+                    ));
+                    if buffer.is_full() {
+                        let warning = " | LOG BUFFER FULL\r\n";
+                        buffer.truncate(buffer.capacity() - warning.len());
+                        let _ = buffer.try_push_str(warning);
+                    }
+                }
             });
             // Trigger write to hardware by triggering USART1 interrupt
             pac::NVIC::pend(pac::Interrupt::USART1);
@@ -141,6 +193,7 @@ static MULTI_LOGGER: MultiLogger = MultiLogger {
     uart_buffer: Mutex::new(RefCell::new(None)),
     usb_buffer: Mutex::new(RefCell::new(None)),
     display_buffer: Mutex::new(RefCell::new(None)),
+    can_buffer: Mutex::new(RefCell::new(None)),
 };
 
 // Function to initialize the logger
@@ -158,11 +211,30 @@ fn init_logger() {
             .display_buffer
             .borrow(cs)
             .replace(Some(ArrayString::new()));
+        MULTI_LOGGER
+            .can_buffer
+            .borrow(cs)
+            .replace(Some(ArrayString::new()));
     });
     log::set_logger(&MULTI_LOGGER).unwrap();
     log::set_max_level(log::LevelFilter::Info); // TODO: Adjust as needed
 }
 
+// Resets into the bootloader, telling it to wait for a firmware update
+fn enter_bootloader() -> ! {
+    unsafe {
+        let rcc = &*pac::RCC::ptr();
+        let pwr = &*pac::PWR::ptr();
+        let rtc = &*pac::RTC::ptr();
+        // The backup registers need the power interface clock and write
+        // access to the backup domain
+        rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+        pwr.cr.modify(|_, w| w.dbp().set_bit());
+        rtc.bkpr[0].write(|w| w.bits(can_update::BOOTLOADER_HANDOFF_MAGIC));
+    }
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
 // CAN driver
 
 pub struct CAN1 {
@@ -175,6 +247,11 @@ unsafe impl bxcan::FilterOwner for CAN1 {
     const NUM_FILTER_BANKS: u8 = 28;
 }
 
+// Supported range of set_pwm_frequency(). Above this the duty cycle
+// resolution gets poor.
+const MIN_PWM_FREQUENCY_HZ: f32 = 1.0;
+const MAX_PWM_FREQUENCY_HZ: f32 = 100_000.0;
+
 // TIM3 PWM
 
 type Tim3Pwm = hal::timer::PwmHz<
@@ -243,13 +320,209 @@ type Group4OCPin = gpio::Pin<'C', 9, gpio::Input>;
 
 type IgnInputPin = gpio::Pin<'D', 15, gpio::Input>;
 
-type M7Pin = gpio::Pin<'C', 13, gpio::Input>;
-type M8Pin = gpio::Pin<'A', 8, gpio::Input>;
-type M9Pin = gpio::Pin<'E', 7, gpio::Input>;
-type M10Pin = gpio::Pin<'E', 8, gpio::Input>;
-type M11Pin = gpio::Pin<'E', 9, gpio::Input>;
-type M12Pin = gpio::Pin<'B', 3, gpio::Input>;
-type M13Pin = gpio::Pin<'B', 4, gpio::Input>;
+// M pins are configured at startup according to app::M_PIN_CONFIG. Digital
+// inputs and outputs are dynamic pins owned by HardwareImplementation. Analog
+// inputs (M1...M6) are owned and read by adc_task instead.
+type M1Pin = gpio::DynamicPin<'B', 0>;
+type M2Pin = gpio::DynamicPin<'C', 0>;
+type M3Pin = gpio::DynamicPin<'C', 1>;
+type M4Pin = gpio::DynamicPin<'C', 2>;
+type M5Pin = gpio::DynamicPin<'C', 3>;
+type M6Pin = gpio::DynamicPin<'C', 4>;
+type M7Pin = gpio::DynamicPin<'C', 13>;
+type M8Pin = gpio::DynamicPin<'A', 8>;
+type M9Pin = gpio::DynamicPin<'E', 7>;
+type M10Pin = gpio::DynamicPin<'E', 8>;
+type M11Pin = gpio::DynamicPin<'E', 9>;
+type M12Pin = gpio::DynamicPin<'B', 3>;
+type M13Pin = gpio::DynamicPin<'B', 4>;
+
+// Returns None if the pin is an analog input
+fn configure_m_pin<const P: char, const N: u8, MODE: gpio::PinMode>(
+    pin: gpio::Pin<P, N, MODE>,
+    mode: MPinMode,
+) -> Option<gpio::DynamicPin<P, N>> {
+    let mut pin = pin.into_dynamic();
+    match mode {
+        MPinMode::Analog => return None,
+        MPinMode::InputFloating => pin.make_floating_input(),
+        MPinMode::InputPullUp | MPinMode::Capture => pin.make_pull_up_input(),
+        MPinMode::InputPullDown => pin.make_pull_down_input(),
+        MPinMode::Output => pin.make_push_pull_output_in_state(gpio::PinState::Low),
+    }
+    Some(pin)
+}
+
+// Returns None if the pin isn't an analog input
+fn configure_m_adc_pin<const P: char, const N: u8, MODE: gpio::PinMode>(
+    pin: gpio::Pin<P, N, MODE>,
+    mode: MPinMode,
+) -> (
+    Option<gpio::DynamicPin<P, N>>,
+    Option<gpio::Pin<P, N, gpio::Analog>>,
+) {
+    if mode == MPinMode::Analog {
+        (None, Some(pin.into_analog()))
+    } else {
+        (configure_m_pin(pin, mode), None)
+    }
+}
+
+// M13 gets an EXTI interrupt on both edges when used for capture. The EXTI
+// configuration stays in place when the pin is turned into a dynamic pin.
+fn configure_m_exti_capture_pin<const P: char, const N: u8, MODE: gpio::PinMode>(
+    pin: gpio::Pin<P, N, MODE>,
+    mode: MPinMode,
+    syscfg: &mut hal::syscfg::SysCfg,
+    exti: &mut pac::EXTI,
+) -> Option<gpio::DynamicPin<P, N>> {
+    use hal::gpio::ExtiPin;
+    if mode != MPinMode::Capture {
+        return configure_m_pin(pin, mode);
+    }
+    let mut pin = pin.into_pull_up_input();
+    pin.make_interrupt_source(syscfg);
+    pin.trigger_on_edge(exti, gpio::Edge::RisingFalling);
+    pin.enable_interrupt(exti);
+    configure_m_pin(pin, mode)
+}
+
+fn read_m_pin<const P: char, const N: u8>(pin: &Option<gpio::DynamicPin<P, N>>) -> bool {
+    pin.as_ref().is_some_and(|p| p.is_high().unwrap_or(false))
+}
+
+fn write_m_pin<const P: char, const N: u8>(pin: &mut Option<gpio::DynamicPin<P, N>>, value: bool) {
+    if let Some(p) = pin.as_mut() {
+        let _ = if value { p.set_high() } else { p.set_low() };
+    }
+}
+
+// Pulse capture
+//
+// M11 (PE9, TIM1_CH1) and M12 (PB3, TIM2_CH2) use timer input capture in PWM
+// input mode: The rising edge captures the period and resets the counter, and
+// the falling edge captures the high time. TIM1 is 16 bits and ticks every
+// 10 us, so periods of more than 655 ms are discarded. TIM2 is 32 bits and
+// ticks every 1 us.
+//
+// PB4 (M13) is TIM3_CH1, which is taken by LPWM2, so M13 timestamps its edges
+// in the EXTI4 interrupt using the DWT cycle counter instead. At SYSCLK = 42
+// MHz the counter wraps after ~100 s; the first period after a pause is
+// discarded by common::pulse_capture anyway.
+//
+// The capture interrupts run at a lower priority than CAN. The timers latch
+// the captured values in hardware, so only M13 is affected by the latency.
+
+// Both timer clocks are 42 MHz, as APB1 and APB2 aren't divided
+const TIM1_CAPTURE_PRESCALER: u16 = 420;
+const TIM1_CAPTURE_US_PER_TICK: f32 = 10.0;
+const TIM2_CAPTURE_PRESCALER: u16 = 42;
+const TIM2_CAPTURE_US_PER_TICK: f32 = 1.0;
+const CYCLES_PER_US: f32 = 42.0;
+
+#[derive(Clone, Copy)]
+pub struct CaptureState {
+    last_rising: Option<u32>,
+    high_cycles: Option<u32>,
+    measurement: PulseMeasurement,
+}
+
+impl CaptureState {
+    const fn new() -> Self {
+        Self {
+            last_rising: None,
+            high_cycles: None,
+            measurement: PulseMeasurement::NONE,
+        }
+    }
+
+    // Called on each rising edge with the timer's captured period and high
+    // time. high_ticks is None if there was no falling edge.
+    fn on_timer_capture(&mut self, period_ticks: u32, high_ticks: Option<u32>, us_per_tick: f32) {
+        self.measurement = PulseMeasurement {
+            count: self.measurement.count.wrapping_add(1),
+            period_us: period_ticks as f32 * us_per_tick,
+            high_us: high_ticks.map_or(f32::NAN, |t| t as f32 * us_per_tick),
+        };
+    }
+
+    fn on_edge(&mut self, rising: bool, cycles: u32) {
+        if rising {
+            if let Some(last_rising) = self.last_rising {
+                let period = cycles.wrapping_sub(last_rising);
+                self.measurement = PulseMeasurement {
+                    count: self.measurement.count.wrapping_add(1),
+                    period_us: period as f32 / CYCLES_PER_US,
+                    high_us: self
+                        .high_cycles
+                        .map_or(f32::NAN, |c| c as f32 / CYCLES_PER_US),
+                };
+            }
+            self.last_rising = Some(cycles);
+            self.high_cycles = None;
+        } else if let Some(last_rising) = self.last_rising {
+            self.high_cycles = Some(cycles.wrapping_sub(last_rising));
+        }
+    }
+}
+
+// Called from the EXTI interrupt. The level is read right after the edge.
+fn on_capture_edge(state: &mut CaptureState, gpio_idr: u32, pin: u8) {
+    let cycles = cortex_m::peripheral::DWT::cycle_count();
+    unsafe { (*pac::EXTI::ptr()).pr.write(|w| w.bits(1 << pin)) };
+    state.on_edge(gpio_idr & (1 << pin) != 0, cycles);
+}
+
+// M11: Period from TI1 rising edges into CCR1, high time from TI1 falling
+// edges into CCR2
+fn configure_tim1_capture(tim: &pac::TIM1) {
+    use hal::rcc::{Enable, Reset};
+    unsafe {
+        pac::TIM1::enable_unchecked();
+        pac::TIM1::reset_unchecked();
+    }
+    tim.psc.write(|w| w.psc().bits(TIM1_CAPTURE_PRESCALER - 1));
+    tim.arr.write(|w| w.arr().bits(0xffff));
+    tim.ccmr1_input()
+        .write(|w| unsafe { w.cc1s().bits(0b01).cc2s().bits(0b10) });
+    tim.ccer
+        .write(|w| w.cc1e().set_bit().cc2p().set_bit().cc2e().set_bit());
+    // Reset the counter on TI1FP1
+    tim.smcr
+        .write(|w| unsafe { w.ts().bits(0b101).sms().bits(0b100) });
+    tim.egr.write(|w| w.ug().set_bit());
+    tim.dier.write(|w| w.cc1ie().set_bit());
+    // Only overflows set UIF, not the resets
+    tim.cr1.write(|w| w.urs().set_bit().cen().set_bit());
+}
+
+// M12: Period from TI2 rising edges into CCR2, high time from TI2 falling
+// edges into CCR1
+fn configure_tim2_capture(tim: &pac::TIM2) {
+    use hal::rcc::{Enable, Reset};
+    unsafe {
+        pac::TIM2::enable_unchecked();
+        pac::TIM2::reset_unchecked();
+    }
+    tim.psc.write(|w| w.psc().bits(TIM2_CAPTURE_PRESCALER - 1));
+    tim.arr.write(|w| w.bits(0xffff_ffff));
+    tim.ccmr1_input()
+        .write(|w| unsafe { w.cc1s().bits(0b10).cc2s().bits(0b01) });
+    tim.ccer
+        .write(|w| w.cc1p().set_bit().cc1e().set_bit().cc2e().set_bit());
+    // Reset the counter on TI2FP2
+    tim.smcr
+        .write(|w| unsafe { w.ts().bits(0b110).sms().bits(0b100) });
+    tim.egr.write(|w| w.ug().set_bit());
+    tim.dier.write(|w| w.cc2ie().set_bit());
+    // Only overflows set UIF, not the resets
+    tim.cr1.write(|w| w.urs().set_bit().cen().set_bit());
+}
+
+// Reads the level of a pin that is used by a timer
+fn read_capture_pin(gpio_idr: u32, pin: u8) -> bool {
+    gpio_idr & (1 << pin) != 0
+}
 
 type Boot0ControlPin = gpio::Pin<'B', 8, gpio::Output<gpio::PushPull>>;
 type WakeupOutputPin = gpio::Pin<'A', 15, gpio::Output<gpio::PushPull>>;
@@ -296,13 +569,22 @@ struct HardwareImplementation {
     group3oc_pin: Group3OCPin,
     group4oc_pin: Group4OCPin,
     ign_input_pin: IgnInputPin,
-    m7_pin: M7Pin,
-    m8_pin: M8Pin,
-    m9_pin: M9Pin,
-    m10_pin: M10Pin,
-    m11_pin: M11Pin,
-    m12_pin: M12Pin,
-    m13_pin: M13Pin,
+    m_pin_modes: [MPinMode; MPin::ALL.len()],
+    m_output_states: [bool; MPin::ALL.len()],
+    m1_pin: Option<M1Pin>,
+    m2_pin: Option<M2Pin>,
+    m3_pin: Option<M3Pin>,
+    m4_pin: Option<M4Pin>,
+    m5_pin: Option<M5Pin>,
+    m6_pin: Option<M6Pin>,
+    m7_pin: Option<M7Pin>,
+    m8_pin: Option<M8Pin>,
+    m9_pin: Option<M9Pin>,
+    m10_pin: Option<M10Pin>,
+    m11_pin: Option<M11Pin>,
+    m12_pin: Option<M12Pin>,
+    m13_pin: Option<M13Pin>,
+    pulse_measurements: [PulseMeasurement; CaptureInput::ALL.len()],
     hout1_pin: HOUT1Pin,
     hout2_pin: HOUT2Pin,
     hout3_pin: HOUT3Pin,
@@ -323,12 +605,19 @@ struct HardwareImplementation {
     lout6_pin: LOUT6Pin,
 }
 
-impl HardwareInterface for HardwareImplementation {
+impl CanPort for HardwareImplementation {
     fn millis(&mut self) -> u64 {
         // NOTE: This rolls over at 49.71 days
         Systick::now().duration_since_epoch().to_millis() as u64
     }
 
+    fn send_can(&mut self, frame: bxcan::Frame) {
+        //info!("send_can(): {:?}", frame);
+        self.can_tx_buf.push(frame);
+    }
+}
+
+impl HardwareInterface for HardwareImplementation {
     fn reboot(&mut self) {
         cortex_m::peripheral::SCB::sys_reset();
     }
@@ -339,11 +628,6 @@ impl HardwareInterface for HardwareImplementation {
         cortex_m::peripheral::SCB::sys_reset();
     }
 
-    fn send_can(&mut self, frame: bxcan::Frame) {
-        //info!("send_can(): {:?}", frame);
-        self.can_tx_buf.push(frame);
-    }
-
     fn get_analog_input(&mut self, input: AnalogInput) -> f32 {
         match input {
             AnalogInput::AuxVoltage => self.adc_result_vbat,
@@ -369,18 +653,30 @@ impl HardwareInterface for HardwareImplementation {
             DigitalInput::Group3OC => self.group3oc_pin.is_low(),
             DigitalInput::Group4OC => self.group4oc_pin.is_low(),
             DigitalInput::Ignition => self.ign_input_pin.is_high(),
-            DigitalInput::M7 => self.m7_pin.is_high(),
-            DigitalInput::M8 => self.m8_pin.is_high(),
-            DigitalInput::M9 => self.m9_pin.is_high(),
-            DigitalInput::M10 => self.m10_pin.is_high(),
-            DigitalInput::M11 => self.m11_pin.is_high(),
-            DigitalInput::M12 => self.m12_pin.is_high(),
-            DigitalInput::M13 => self.m13_pin.is_high(),
+            DigitalInput::M1 => read_m_pin(&self.m1_pin),
+            DigitalInput::M2 => read_m_pin(&self.m2_pin),
+            DigitalInput::M3 => read_m_pin(&self.m3_pin),
+            DigitalInput::M4 => read_m_pin(&self.m4_pin),
+            DigitalInput::M5 => read_m_pin(&self.m5_pin),
+            DigitalInput::M6 => read_m_pin(&self.m6_pin),
+            DigitalInput::M7 => read_m_pin(&self.m7_pin),
+            DigitalInput::M8 => read_m_pin(&self.m8_pin),
+            DigitalInput::M9 => read_m_pin(&self.m9_pin),
+            DigitalInput::M10 => read_m_pin(&self.m10_pin),
+            DigitalInput::M11 if self.m_pin_modes[MPin::M11 as usize] == MPinMode::Capture => {
+                read_capture_pin(unsafe { (*pac::GPIOE::ptr()).idr.read().bits() }, 9)
+            }
+            DigitalInput::M11 => read_m_pin(&self.m11_pin),
+            DigitalInput::M12 if self.m_pin_modes[MPin::M12 as usize] == MPinMode::Capture => {
+                read_capture_pin(unsafe { (*pac::GPIOB::ptr()).idr.read().bits() }, 3)
+            }
+            DigitalInput::M12 => read_m_pin(&self.m12_pin),
+            DigitalInput::M13 => read_m_pin(&self.m13_pin),
         }
     }
 
-    fn set_digital_output(&mut self, output: DigitalOutput, value: bool) {
-        let old_value = match output {
+    fn get_digital_output(&mut self, output: DigitalOutput) -> bool {
+        match output {
             DigitalOutput::Wakeup => self.wakeup_output_pin.is_set_high(),
             DigitalOutput::HOUT1 => self.hout1_pin.is_set_high(),
             DigitalOutput::HOUT2 => self.hout2_pin.is_set_high(),
@@ -399,8 +695,33 @@ impl HardwareInterface for HardwareImplementation {
             DigitalOutput::LOUT3 => self.lout3_pin.is_set_high(),
             DigitalOutput::LOUT4 => self.lout4_pin.is_set_high(),
             DigitalOutput::LOUT5 => self.lout5_pin.is_set_high(),
-            DigitalOutput::LOUT6 => self.lout6_pin.is_set_high(), // TODO: M* pins
-        };
+            DigitalOutput::LOUT6 => self.lout6_pin.is_set_high(),
+            DigitalOutput::M1
+            | DigitalOutput::M2
+            | DigitalOutput::M3
+            | DigitalOutput::M4
+            | DigitalOutput::M5
+            | DigitalOutput::M6
+            | DigitalOutput::M7
+            | DigitalOutput::M8
+            | DigitalOutput::M9
+            | DigitalOutput::M10
+            | DigitalOutput::M11
+            | DigitalOutput::M12
+            | DigitalOutput::M13 => output
+                .m_pin()
+                .is_some_and(|pin| self.m_output_states[pin as usize]),
+        }
+    }
+
+    fn set_digital_output(&mut self, output: DigitalOutput, value: bool) {
+        if let Some(pin) = output.m_pin() {
+            if self.m_pin_modes[pin as usize] != MPinMode::Output {
+                return;
+            }
+        }
+
+        let old_value = self.get_digital_output(output);
 
         if value != old_value {
             info!(
@@ -428,10 +749,52 @@ impl HardwareInterface for HardwareImplementation {
             DigitalOutput::LOUT3 => self.lout3_pin.set_state(value.into()),
             DigitalOutput::LOUT4 => self.lout4_pin.set_state(value.into()),
             DigitalOutput::LOUT5 => self.lout5_pin.set_state(value.into()),
-            DigitalOutput::LOUT6 => self.lout6_pin.set_state(value.into()), // TODO: M* pins
+            DigitalOutput::LOUT6 => self.lout6_pin.set_state(value.into()),
+            DigitalOutput::M1 => write_m_pin(&mut self.m1_pin, value),
+            DigitalOutput::M2 => write_m_pin(&mut self.m2_pin, value),
+            DigitalOutput::M3 => write_m_pin(&mut self.m3_pin, value),
+            DigitalOutput::M4 => write_m_pin(&mut self.m4_pin, value),
+            DigitalOutput::M5 => write_m_pin(&mut self.m5_pin, value),
+            DigitalOutput::M6 => write_m_pin(&mut self.m6_pin, value),
+            DigitalOutput::M7 => write_m_pin(&mut self.m7_pin, value),
+            DigitalOutput::M8 => write_m_pin(&mut self.m8_pin, value),
+            DigitalOutput::M9 => write_m_pin(&mut self.m9_pin, value),
+            DigitalOutput::M10 => write_m_pin(&mut self.m10_pin, value),
+            DigitalOutput::M11 => write_m_pin(&mut self.m11_pin, value),
+            DigitalOutput::M12 => write_m_pin(&mut self.m12_pin, value),
+            DigitalOutput::M13 => write_m_pin(&mut self.m13_pin, value),
+        }
+        if let Some(pin) = output.m_pin() {
+            self.m_output_states[pin as usize] = value;
         }
     }
 
+    fn get_pwm_output(&mut self, output: PwmOutput) -> f32 {
+        let (duty, max_duty) = match output {
+            PwmOutput::LCUR1 => (
+                self.tim4_pwm.get_duty(hal::timer::Channel::C1),
+                self.tim4_pwm.get_max_duty(),
+            ),
+            PwmOutput::SPWM1 => (
+                self.tim4_pwm.get_duty(hal::timer::Channel::C2),
+                self.tim4_pwm.get_max_duty(),
+            ),
+            PwmOutput::SPWM2 => (
+                self.tim4_pwm.get_duty(hal::timer::Channel::C3),
+                self.tim4_pwm.get_max_duty(),
+            ),
+            PwmOutput::LPWM2 => (
+                self.tim3_pwm.get_duty(hal::timer::Channel::C1),
+                self.tim3_pwm.get_max_duty(),
+            ),
+            PwmOutput::LPWM3 => (
+                self.tim3_pwm.get_duty(hal::timer::Channel::C2),
+                self.tim3_pwm.get_max_duty(),
+            ),
+        };
+        duty as f32 / max_duty as f32
+    }
+
     fn set_pwm_output(&mut self, output: PwmOutput, value: f32) {
         match output {
             PwmOutput::LCUR1 => set_lcur1(value, &mut self.tim4_pwm),
@@ -441,6 +804,44 @@ impl HardwareInterface for HardwareImplementation {
             PwmOutput::LPWM3 => set_lpwm3(value, &mut self.tim3_pwm),
         }
     }
+
+    fn set_pwm_frequency(&mut self, timer: PwmTimer, frequency_hz: f32) {
+        if frequency_hz.is_nan() {
+            return;
+        }
+        let frequency_hz = frequency_hz.clamp(MIN_PWM_FREQUENCY_HZ, MAX_PWM_FREQUENCY_HZ) as u32;
+        // Duty registers are relative to the period, so they are rewritten
+        let mut duties = [0.0; 3];
+        for (i, &output) in timer.outputs().iter().enumerate() {
+            duties[i] = self.get_pwm_output(output);
+        }
+        match timer {
+            PwmTimer::Tim3 => self.tim3_pwm.set_period(frequency_hz.Hz()),
+            PwmTimer::Tim4 => self.tim4_pwm.set_period(frequency_hz.Hz()),
+        }
+        for (i, &output) in timer.outputs().iter().enumerate() {
+            self.set_pwm_output(output, duties[i]);
+        }
+    }
+
+    fn get_pwm_frequency(&mut self, timer: PwmTimer) -> f32 {
+        match timer {
+            PwmTimer::Tim3 => self.tim3_pwm.get_period().raw() as f32,
+            PwmTimer::Tim4 => self.tim4_pwm.get_period().raw() as f32,
+        }
+    }
+
+    fn get_m_pin_mode(&mut self, pin: MPin) -> MPinMode {
+        self.m_pin_modes[pin as usize]
+    }
+
+    fn get_pulse_measurement(&mut self, input: CaptureInput) -> PulseMeasurement {
+        if self.m_pin_modes[input.m_pin() as usize] == MPinMode::Capture {
+            self.pulse_measurements[input as usize]
+        } else {
+            PulseMeasurement::NONE
+        }
+    }
 }
 
 // Panic output and input methods
@@ -477,6 +878,7 @@ mod rtic_app {
         adc_result_m4: f32,
         adc_result_m5: f32,
         adc_result_m6: f32,
+        capture_states: [CaptureState; CaptureInput::ALL.len()],
     }
 
     #[local]
@@ -488,6 +890,8 @@ mod rtic_app {
         //usart3_rx: hal::serial::Rx<hal::pac::USART3, u8>,
         //usart3_tx: hal::serial::Tx<hal::pac::USART3, u8>,
         command_accumulator: CommandAccumulator<50>,
+        capture_tim1: pac::TIM1,
+        capture_tim2: pac::TIM2,
         i2c1: hal::i2c::I2c<hal::pac::I2C1>,
         adc1: Adc<pac::ADC1>,
         // Analog input pins
@@ -498,13 +902,13 @@ mod rtic_app {
         adc_pa5: gpio::Pin<'A', 5, gpio::Analog>,
         adc_pa6: gpio::Pin<'A', 6, gpio::Analog>,
         adc_pa7: gpio::Pin<'A', 7, gpio::Analog>,
-        adc_pb0: gpio::Pin<'B', 0, gpio::Analog>,
+        adc_pb0: Option<gpio::Pin<'B', 0, gpio::Analog>>,
         adc_pb1: gpio::Pin<'B', 1, gpio::Analog>,
-        adc_pc0: gpio::Pin<'C', 0, gpio::Analog>,
-        adc_pc1: gpio::Pin<'C', 1, gpio::Analog>,
-        adc_pc2: gpio::Pin<'C', 2, gpio::Analog>,
-        adc_pc3: gpio::Pin<'C', 3, gpio::Analog>,
-        adc_pc4: gpio::Pin<'C', 4, gpio::Analog>,
+        adc_pc0: Option<gpio::Pin<'C', 0, gpio::Analog>>,
+        adc_pc1: Option<gpio::Pin<'C', 1, gpio::Analog>>,
+        adc_pc2: Option<gpio::Pin<'C', 2, gpio::Analog>>,
+        adc_pc3: Option<gpio::Pin<'C', 3, gpio::Analog>>,
+        adc_pc4: Option<gpio::Pin<'C', 4, gpio::Analog>>,
         adc_pc5: gpio::Pin<'C', 5, gpio::Analog>,
         // Digital input pins
         // Output pins
@@ -535,6 +939,11 @@ mod rtic_app {
             .freeze(); // Apply the configuration
 
         let mut syscfg = cx.device.SYSCFG.constrain();
+        let mut exti = cx.device.EXTI;
+
+        // Cycle counter for pulse capture timestamps
+        cx.core.DCB.enable_trace();
+        cx.core.DWT.enable_cycle_counter();
 
         // Pin assignments
 
@@ -553,13 +962,35 @@ mod rtic_app {
 
         let mut ign_input_pin = gpiod.pd15.into_input();
 
-        let mut m7_pin = gpioc.pc13.into_input();
-        let mut m8_pin = gpioa.pa8.into_input();
-        let mut m9_pin = gpioe.pe7.into_input();
-        let mut m10_pin = gpioe.pe8.into_input();
-        let mut m11_pin = gpioe.pe9.into_input();
-        let mut m12_pin = gpiob.pb3.into_input();
-        let mut m13_pin = gpiob.pb4.into_input();
+        let m_pin_modes = MPin::ALL.map(|pin| pin.mode(app::M_PIN_CONFIG));
+        let (m1_pin, adc_pb0) = configure_m_adc_pin(gpiob.pb0, m_pin_modes[0]);
+        let (m2_pin, adc_pc0) = configure_m_adc_pin(gpioc.pc0, m_pin_modes[1]);
+        let (m3_pin, adc_pc1) = configure_m_adc_pin(gpioc.pc1, m_pin_modes[2]);
+        let (m4_pin, adc_pc2) = configure_m_adc_pin(gpioc.pc2, m_pin_modes[3]);
+        let (m5_pin, adc_pc3) = configure_m_adc_pin(gpioc.pc3, m_pin_modes[4]);
+        let (m6_pin, adc_pc4) = configure_m_adc_pin(gpioc.pc4, m_pin_modes[5]);
+        let m7_pin = configure_m_pin(gpioc.pc13, m_pin_modes[6]);
+        let m8_pin = configure_m_pin(gpioa.pa8, m_pin_modes[7]);
+        let m9_pin = configure_m_pin(gpioe.pe7, m_pin_modes[8]);
+        let m10_pin = configure_m_pin(gpioe.pe8, m_pin_modes[9]);
+        let capture_tim1 = cx.device.TIM1;
+        let m11_pin = if m_pin_modes[10] == MPinMode::Capture {
+            gpioe.pe9.into_alternate::<1>().internal_pull_up(true);
+            configure_tim1_capture(&capture_tim1);
+            None
+        } else {
+            configure_m_pin(gpioe.pe9, m_pin_modes[10])
+        };
+        let capture_tim2 = cx.device.TIM2;
+        let m12_pin = if m_pin_modes[11] == MPinMode::Capture {
+            gpiob.pb3.into_alternate::<1>().internal_pull_up(true);
+            configure_tim2_capture(&capture_tim2);
+            None
+        } else {
+            configure_m_pin(gpiob.pb3, m_pin_modes[11])
+        };
+        let m13_pin =
+            configure_m_exti_capture_pin(gpiob.pb4, m_pin_modes[12], &mut syscfg, &mut exti);
 
         // Output pins
 
@@ -708,13 +1139,7 @@ mod rtic_app {
         let adc_pa5 = gpioa.pa5.into_analog(); // Group 1 current
         let adc_pa6 = gpioa.pa6.into_analog(); // Group 2 current
         let adc_pa7 = gpioa.pa7.into_analog(); // Group 4 current
-        let adc_pb0 = gpiob.pb0.into_analog(); // M1
         let adc_pb1 = gpiob.pb1.into_analog(); // PcbT
-        let adc_pc0 = gpioc.pc0.into_analog(); // M2
-        let adc_pc1 = gpioc.pc1.into_analog(); // M3
-        let adc_pc2 = gpioc.pc2.into_analog(); // M4
-        let adc_pc3 = gpioc.pc3.into_analog(); // M5
-        let adc_pc4 = gpioc.pc4.into_analog(); // M6
         let adc_pc5 = gpioc.pc5.into_analog(); // Group L current
 
         let adc_config = AdcConfig::default()
@@ -791,6 +1216,14 @@ mod rtic_app {
             group3oc_pin,
             group4oc_pin,
             ign_input_pin,
+            m_pin_modes,
+            m_output_states: [false; MPin::ALL.len()],
+            m1_pin,
+            m2_pin,
+            m3_pin,
+            m4_pin,
+            m5_pin,
+            m6_pin,
             m7_pin,
             m8_pin,
             m9_pin,
@@ -798,6 +1231,7 @@ mod rtic_app {
             m11_pin,
             m12_pin,
             m13_pin,
+            pulse_measurements: [PulseMeasurement::NONE; CaptureInput::ALL.len()],
             hout1_pin,
             hout2_pin,
             hout3_pin,
@@ -852,6 +1286,7 @@ mod rtic_app {
                 adc_result_m4: 0.0,
                 adc_result_m5: 0.0,
                 adc_result_m6: 0.0,
+                capture_states: [CaptureState::new(); CaptureInput::ALL.len()],
             },
             Local {
                 usart1_rx: usart1_rx,
@@ -861,6 +1296,8 @@ mod rtic_app {
                 //usart3_rx: usart3_rx,
                 //usart3_tx: usart3_tx,
                 command_accumulator: CommandAccumulator::new(),
+                capture_tim1,
+                capture_tim2,
                 i2c1: i2c1,
                 adc1: adc1,
                 adc_pa1,
@@ -917,6 +1354,7 @@ mod rtic_app {
             adc_result_m4,
             adc_result_m5,
             adc_result_m6,
+            capture_states,
         ],
         local = [
             command_accumulator,
@@ -925,6 +1363,17 @@ mod rtic_app {
     )]
     async fn logic_task(mut cx: logic_task::Context) {
         let mut state = app::MainState::new();
+        let mut can_console: CanConsole<CAN_CONSOLE_OUTPUT_BUF_SIZE> =
+            CanConsole::new(CAN_CONSOLE_CONFIG);
+        let mut update_target = UpdateTarget::new(UPDATE_TARGET_CONFIG);
+        // Referencing the firmware info keeps it in the image
+        info!(
+            "Firmware version {:#08x}",
+            can_update::find_firmware_version(&unsafe {
+                core::ptr::read_volatile(&FIRMWARE_INFO)
+            })
+            .unwrap_or(0)
+        );
 
         loop {
             // Update values
@@ -941,6 +1390,10 @@ mod rtic_app {
             cx.local.hw.adc_result_m4 = cx.shared.adc_result_m4.lock(|v| *v);
             cx.local.hw.adc_result_m5 = cx.shared.adc_result_m5.lock(|v| *v);
             cx.local.hw.adc_result_m6 = cx.shared.adc_result_m6.lock(|v| *v);
+            cx.local.hw.pulse_measurements = cx
+                .shared
+                .capture_states
+                .lock(|v| v.map(|state| state.measurement));
 
             state.update(cx.local.hw);
 
@@ -948,8 +1401,28 @@ mod rtic_app {
             while let Some(received_frame) =
                 cx.shared.can_rx_buf.lock(|can_rx_buf| can_rx_buf.dequeue())
             {
-                state.on_can(received_frame);
+                let millis = cx.local.hw.millis();
+                if !can_console.on_can(&received_frame, millis)
+                    && !update_target.on_can(&received_frame, millis)
+                {
+                    state.on_can(received_frame);
+                }
+            }
+
+            // Mirror log output to the CAN console and take its input
+            if let Some(buf) = MULTI_LOGGER.get_can_buffer() {
+                can_console.write_output(&buf);
+            }
+            can_console.update(cx.local.hw);
+            while let Some(b) = can_console.read_input() {
+                cx.shared.console_rxbuf.lock(|rxbuf| rxbuf.push(b));
             }
+
+            if let Some(UpdateEvent::EnterBootloader) = update_target.update(cx.local.hw, None) {
+                info!("-!- Entering bootloader for firmware update");
+                enter_bootloader();
+            }
+
             // Handle CAN transmit buffer
             while let Some(frame) = cx.local.hw.can_tx_buf.dequeue() {
                 cx.shared
@@ -1099,48 +1572,37 @@ mod rtic_app {
                 .adc_result_currentL
                 .lock(|v| *v = *v * (1.0 - f) + result * f);
 
-            // General external inputs (with scaling, without filtering)
+            // General external inputs (with scaling, without filtering). NaN
+            // for pins that aren't analog inputs.
 
-            let result = cx
-                .local
-                .adc1
-                .convert(cx.local.adc_pb0, SampleTime::Cycles_480) as f32
-                * 0.00749;
+            let result = cx.local.adc_pb0.as_ref().map_or(f32::NAN, |pin| {
+                cx.local.adc1.convert(pin, SampleTime::Cycles_480) as f32 * 0.00749
+            });
             cx.shared.adc_result_m1.lock(|v| *v = result);
 
-            let result = cx
-                .local
-                .adc1
-                .convert(cx.local.adc_pc0, SampleTime::Cycles_480) as f32
-                * 0.00749;
+            let result = cx.local.adc_pc0.as_ref().map_or(f32::NAN, |pin| {
+                cx.local.adc1.convert(pin, SampleTime::Cycles_480) as f32 * 0.00749
+            });
             cx.shared.adc_result_m2.lock(|v| *v = result);
 
-            let result = cx
-                .local
-                .adc1
-                .convert(cx.local.adc_pc1, SampleTime::Cycles_480) as f32
-                * 0.00749;
+            let result = cx.local.adc_pc1.as_ref().map_or(f32::NAN, |pin| {
+                cx.local.adc1.convert(pin, SampleTime::Cycles_480) as f32 * 0.00749
+            });
             cx.shared.adc_result_m3.lock(|v| *v = result);
 
-            let result = cx
-                .local
-                .adc1
-                .convert(cx.local.adc_pc2, SampleTime::Cycles_480) as f32
-                * 0.00749;
+            let result = cx.local.adc_pc2.as_ref().map_or(f32::NAN, |pin| {
+                cx.local.adc1.convert(pin, SampleTime::Cycles_480) as f32 * 0.00749
+            });
             cx.shared.adc_result_m4.lock(|v| *v = result);
 
-            let result = cx
-                .local
-                .adc1
-                .convert(cx.local.adc_pc3, SampleTime::Cycles_480) as f32
-                * 0.00749;
+            let result = cx.local.adc_pc3.as_ref().map_or(f32::NAN, |pin| {
+                cx.local.adc1.convert(pin, SampleTime::Cycles_480) as f32 * 0.00749
+            });
             cx.shared.adc_result_m5.lock(|v| *v = result);
 
-            let result = cx
-                .local
-                .adc1
-                .convert(cx.local.adc_pc4, SampleTime::Cycles_480) as f32
-                * 0.00749;
+            let result = cx.local.adc_pc4.as_ref().map_or(f32::NAN, |pin| {
+                cx.local.adc1.convert(pin, SampleTime::Cycles_480) as f32 * 0.00749
+            });
             cx.shared.adc_result_m6.lock(|v| *v = result);
 
             Systick::delay(20.millis()).await;
@@ -1334,6 +1796,57 @@ mod rtic_app {
         );
     }
 
+    // Pulse capture (M11 = PE9, M12 = PB3, M13 = PB4)
+
+    #[task(priority = 7, binds = TIM1_CC, shared = [capture_states], local = [capture_tim1])]
+    fn tim1_cc(mut cx: tim1_cc::Context) {
+        let tim = cx.local.capture_tim1;
+        let sr = tim.sr.read();
+        // Reading the captures clears their flags
+        let high = tim.ccr2().read().bits();
+        let period = tim.ccr1().read().bits();
+        tim.sr.write(|w| unsafe { w.bits(!1) });
+        if sr.uif().bit_is_set() {
+            // The counter overflowed, so the period is too long to measure
+            return;
+        }
+        cx.shared.capture_states.lock(|states| {
+            states[CaptureInput::M11 as usize].on_timer_capture(
+                period,
+                sr.cc2if().bit_is_set().then_some(high),
+                TIM1_CAPTURE_US_PER_TICK,
+            );
+        });
+    }
+
+    #[task(priority = 7, binds = TIM2, shared = [capture_states], local = [capture_tim2])]
+    fn tim2(mut cx: tim2::Context) {
+        let tim = cx.local.capture_tim2;
+        let sr = tim.sr.read();
+        // Reading the captures clears their flags
+        let high = tim.ccr1().read().bits();
+        let period = tim.ccr2().read().bits();
+        tim.sr.write(|w| unsafe { w.bits(!1) });
+        if sr.uif().bit_is_set() {
+            return;
+        }
+        cx.shared.capture_states.lock(|states| {
+            states[CaptureInput::M12 as usize].on_timer_capture(
+                period,
+                sr.cc1if().bit_is_set().then_some(high),
+                TIM2_CAPTURE_US_PER_TICK,
+            );
+        });
+    }
+
+    #[task(priority = 7, binds = EXTI4, shared = [capture_states])]
+    fn exti4(mut cx: exti4::Context) {
+        let idr = unsafe { (*pac::GPIOB::ptr()).idr.read().bits() };
+        cx.shared.capture_states.lock(|states| {
+            on_capture_edge(&mut states[CaptureInput::M13 as usize], idr, 4);
+        });
+    }
+
     #[task(
         priority = 8,
         binds = CAN1_RX0,